@@ -15,14 +15,16 @@
 //! the extended functionalities that are yet exposed via the [`boring`] APIs
 
 use boring::error::ErrorStack;
+use boring::ex_data::Index;
 use boring::pkey::{HasPrivate, PKeyRef};
-use boring::ssl::{Ssl, SslAcceptor, SslRef};
-use boring::x509::store::X509StoreRef;
-use boring::x509::verify::X509VerifyParamRef;
+use boring::ssl::{Ssl, SslAcceptor, SslContext, SslContextBuilder, SslRef};
+use boring::x509::store::{X509StoreBuilderRef, X509StoreRef};
+use boring::x509::verify::{X509VerifyFlags, X509VerifyParamRef};
 use boring::x509::X509Ref;
 use foreign_types_shared::ForeignTypeRef;
 use libc::*;
 use std::ffi::CString;
+use std::sync::OnceLock;
 
 fn cvt(r: c_int) -> Result<c_int, ErrorStack> {
     if r != 1 {
@@ -65,6 +67,35 @@ pub fn ssl_set_verify_cert_store(
     Ok(())
 }
 
+/// Load the CRLs in the PEM file at `path` into `store` and check the leaf certificates
+/// verified with `store` against them
+///
+/// See [X509_load_crl_file](https://www.openssl.org/docs/man1.1.1/man3/X509_load_crl_file.html).
+pub fn x509_store_add_crl_file(
+    store: &mut X509StoreBuilderRef,
+    path: &str,
+) -> Result<(), ErrorStack> {
+    let path = CString::new(path).map_err(|_| ErrorStack::get())?;
+    unsafe {
+        let lookup =
+            boring_sys::X509_STORE_add_lookup(store.as_ptr(), boring_sys::X509_LOOKUP_file());
+        if lookup.is_null() {
+            return Err(ErrorStack::get());
+        }
+        // returns the number of CRLs loaded
+        if boring_sys::X509_load_crl_file(
+            lookup,
+            path.as_ptr(),
+            boring_sys::X509_FILETYPE_PEM as c_int,
+        ) <= 0
+        {
+            return Err(ErrorStack::get());
+        }
+    }
+    store.set_flags(X509VerifyFlags::CRL_CHECK);
+    Ok(())
+}
+
 /// Load the certificate into `ssl`
 ///
 /// See [SSL_use_certificate](https://www.openssl.org/docs/man1.1.1/man3/SSL_use_certificate.html).
@@ -137,6 +168,114 @@ pub fn ssl_use_second_key_share(ssl: &mut SslRef, enabled: bool) {
 #[cfg(not(feature = "pq_use_second_keyshare"))]
 pub fn ssl_use_second_key_share(_ssl: &mut SslRef, _enabled: bool) {}
 
+/// The length of a session ticket key: a 16-byte key name, a 32-byte HMAC-SHA256 secret and a
+/// 32-byte AES-256 key, in this order
+pub const TICKET_KEY_LEN: usize = 80;
+
+type TicketKeyLookup = Box<dyn Fn(Option<&[u8]>) -> Option<[u8; TICKET_KEY_LEN]> + Send + Sync>;
+
+static TICKET_KEY_INDEX: OnceLock<Index<SslContext, TicketKeyLookup>> = OnceLock::new();
+
+/// Encrypt and decrypt the session tickets of `ctx` with the keys given by `lookup`
+///
+/// `lookup(None)` returns the key to encrypt new tickets with, `lookup(Some(name))` the key with
+/// the given name to decrypt a ticket with. Tickets decrypted with a key other than the current
+/// one are renewed, tickets of unknown keys fall back to full handshakes. Clients are told that
+/// tickets are valid for `lifetime` seconds, which is also the session timeout of `ctx`.
+///
+/// See [SSL_CTX_set_tlsext_ticket_key_cb](https://commondatastorage.googleapis.com/chromium-boringssl-docs/ssl.h.html#SSL_CTX_set_tlsext_ticket_key_cb).
+pub fn ssl_ctx_set_ticket_key_callback<F>(
+    ctx: &mut SslContextBuilder,
+    lookup: F,
+    lifetime: u32,
+) -> Result<(), ErrorStack>
+where
+    F: Fn(Option<&[u8]>) -> Option<[u8; TICKET_KEY_LEN]> + Send + Sync + 'static,
+{
+    let index = *TICKET_KEY_INDEX.get_or_init(|| SslContext::new_ex_index().unwrap());
+    let lookup: TicketKeyLookup = Box::new(lookup);
+    ctx.set_ex_data(index, lookup);
+    unsafe {
+        boring_sys::SSL_CTX_set_timeout(ctx.as_ptr(), lifetime);
+        cvt(boring_sys::SSL_CTX_set_tlsext_ticket_key_cb(
+            ctx.as_ptr(),
+            Some(raw_ticket_key),
+        ))
+        .map(|_| ())
+    }
+}
+
+unsafe extern "C" fn raw_ticket_key(
+    ssl: *mut boring_sys::SSL,
+    key_name: *mut u8,
+    iv: *mut u8,
+    cipher_ctx: *mut boring_sys::EVP_CIPHER_CTX,
+    hmac_ctx: *mut boring_sys::HMAC_CTX,
+    enc: c_int,
+) -> c_int {
+    let ssl = SslRef::from_ptr(ssl);
+    let Some(lookup) = TICKET_KEY_INDEX
+        .get()
+        .and_then(|index| ssl.ssl_context().ex_data(*index))
+    else {
+        return -1;
+    };
+    let name = std::slice::from_raw_parts_mut(key_name, 16);
+
+    if enc == 1 {
+        let Some(key) = lookup(None) else {
+            return -1;
+        };
+        name.copy_from_slice(&key[..16]);
+        if boring_sys::RAND_bytes(iv, 16) != 1
+            || boring_sys::EVP_EncryptInit_ex(
+                cipher_ctx,
+                boring_sys::EVP_aes_256_cbc(),
+                std::ptr::null_mut(),
+                key[48..].as_ptr(),
+                iv,
+            ) != 1
+            || boring_sys::HMAC_Init_ex(
+                hmac_ctx,
+                key[16..48].as_ptr() as *const c_void,
+                32,
+                boring_sys::EVP_sha256(),
+                std::ptr::null_mut(),
+            ) != 1
+        {
+            return -1;
+        }
+        1
+    } else {
+        let Some(key) = lookup(Some(name)) else {
+            // unknown key, fall back to a full handshake
+            return 0;
+        };
+        if boring_sys::HMAC_Init_ex(
+            hmac_ctx,
+            key[16..48].as_ptr() as *const c_void,
+            32,
+            boring_sys::EVP_sha256(),
+            std::ptr::null_mut(),
+        ) != 1
+            || boring_sys::EVP_DecryptInit_ex(
+                cipher_ctx,
+                boring_sys::EVP_aes_256_cbc(),
+                std::ptr::null_mut(),
+                key[48..].as_ptr(),
+                iv,
+            ) != 1
+        {
+            return -1;
+        }
+        match lookup(None) {
+            Some(current) if current[..16] == *name => 1,
+            // renew the tickets of the previous keys
+            _ => 2,
+        }
+    }
+}
+
 /// Clear the error stack
 ///
 /// SSL calls should check and clear the BoringSSL error stack. But some calls fail to do so.
@@ -26,7 +26,11 @@ pub use boring_tokio as tokio_ssl;
 pub mod ext;
 
 // export commonly used libs
+pub use ssl_lib::bn;
+pub use ssl_lib::ec;
+pub use ssl_lib::ecdsa;
 pub use ssl_lib::error;
+pub use ssl_lib::ex_data;
 pub use ssl_lib::hash;
 pub use ssl_lib::nid;
 pub use ssl_lib::pkey;
@@ -19,16 +19,22 @@ pub mod prometheus_http_app;
 
 use crate::server::ShutdownWatch;
 use async_trait::async_trait;
+use http::HeaderValue;
 use log::{debug, error};
 use std::future::poll_fn;
 use std::sync::Arc;
 
 use crate::protocols::http::v2::server;
+#[cfg(feature = "quic")]
+use crate::protocols::http::v3::server as h3_server;
 use crate::protocols::http::ServerSession;
 use crate::protocols::Digest;
 use crate::protocols::Stream;
 use crate::protocols::ALPN;
 
+#[cfg(feature = "quic")]
+use crate::listeners::QuicConnection;
+
 // https://datatracker.ietf.org/doc/html/rfc9113#section-3.4
 const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 
@@ -54,6 +60,20 @@ pub trait ServerApp {
         shutdown: &ShutdownWatch,
     ) -> Option<Stream>;
 
+    /// Whenever a new QUIC connection is established on a QUIC endpoint of the service, this
+    /// function will be called with the established [`QuicConnection`].
+    ///
+    /// The default implementation closes the connection because the application doesn't
+    /// support QUIC.
+    #[cfg(feature = "quic")]
+    async fn process_new_quic(self: &Arc<Self>, conn: QuicConnection, _shutdown: &ShutdownWatch)
+    where
+        Self: Send + Sync,
+    {
+        debug!("QUIC is not supported by the app, closing the connection");
+        conn.connection.close(0u32.into(), b"");
+    }
+
     /// This callback will be called once after the service stops listening to its endpoints.
     async fn cleanup(&self) {}
 }
@@ -63,6 +83,16 @@ pub trait ServerApp {
 pub struct HttpServerOptions {
     /// Use HTTP/2 for plaintext.
     pub h2c: bool,
+    /// The `Alt-Svc` header to add to the responses sent over HTTP/1.x and HTTP/2, so that
+    /// the clients learn about the service's HTTP/3 endpoint, e.g., `h3=":443"; ma=86400`.
+    ///
+    /// The header is not added if the response already has one.
+    pub alt_svc: Option<HeaderValue>,
+    /// The settings and abuse limits of the HTTP/2 connections.
+    ///
+    /// The settings are ignored if [`HttpServerApp::h2_options()`] returns custom options, but
+    /// the stream error limits still apply.
+    pub h2: server::H2Settings,
 }
 
 /// This trait defines the interface of an HTTP application.
@@ -88,6 +118,15 @@ pub trait HttpServerApp {
         None
     }
 
+    /// Provide options on how HTTP/3 connection should be established. This function will be called
+    /// every time a new HTTP/3 **connection** needs to be established.
+    ///
+    /// A `None` means to use the built-in default options. See [`h3_server::H3Options`] for more details.
+    #[cfg(feature = "quic")]
+    fn h3_options(&self) -> Option<h3_server::H3Options> {
+        None
+    }
+
     /// Provide HTTP server options used to override default behavior. This function will be called
     /// every time a new connection is processed.
     ///
@@ -110,6 +149,11 @@ where
         shutdown: &ShutdownWatch,
     ) -> Option<Stream> {
         let mut h2c = self.server_options().as_ref().map_or(false, |o| o.h2c);
+        let alt_svc = self.server_options().and_then(|o| o.alt_svc.clone());
+        let h2_settings = self
+            .server_options()
+            .map(|o| o.h2.clone())
+            .unwrap_or_default();
 
         // try to read h2 preface
         if h2c {
@@ -139,8 +183,10 @@ where
                 socket_digest: stream.get_socket_digest(),
             });
 
-            let h2_options = self.h2_options();
-            let h2_conn = server::handshake(stream, h2_options).await;
+            let h2_options = self
+                .h2_options()
+                .unwrap_or_else(|| h2_settings.h2_options());
+            let h2_conn = server::handshake(stream, Some(h2_options)).await;
             let mut h2_conn = match h2_conn {
                 Err(e) => {
                     error!("H2 handshake error {e}");
@@ -149,6 +195,7 @@ where
                 Ok(c) => c,
             };
 
+            let error_budget = Arc::new(server::StreamErrorBudget::new(&h2_settings));
             let mut shutdown = shutdown.clone();
             loop {
                 // this loop ends when the client decides to close the h2 conn
@@ -160,17 +207,27 @@ where
                             .await.map_err(|e| error!("H2 error waiting for shutdown {e}"));
                         return None;
                     }
+                    _ = error_budget.exceeded() => {
+                        debug!("H2 stream error limit exceeded, closing the connection");
+                        h2_conn.abrupt_shutdown(h2::Reason::ENHANCE_YOUR_CALM);
+                        let _ = poll_fn(|cx| h2_conn.poll_closed(cx))
+                            .await.map_err(|e| debug!("H2 error waiting for shutdown {e}"));
+                        return None;
+                    }
                     h2_stream = server::HttpSession::from_h2_conn(&mut h2_conn, digest.clone()) => h2_stream
                 };
-                let h2_stream = match h2_stream {
+                let mut h2_stream = match h2_stream {
                     Err(e) => {
                         // It is common for the client to just disconnect TCP without properly
                         // closing H2. So we don't log the errors here
                         debug!("H2 error when accepting new stream {e}");
+                        server::StreamErrorBudget::record_connection_error(&e);
                         return None;
                     }
                     Ok(s) => s?, // None means the connection is ready to be closed
                 };
+                h2_stream.set_alt_svc(alt_svc.clone());
+                h2_stream.set_error_budget(error_budget.clone());
                 let app = self.clone();
                 let shutdown = shutdown.clone();
                 pingora_runtime::current_handle().spawn(async move {
@@ -180,8 +237,70 @@ where
             }
         } else {
             // No ALPN or ALPN::H1 and h2c was not configured, fallback to HTTP/1.1
-            self.process_new_http(ServerSession::new_http1(stream), shutdown)
-                .await
+            let mut session = ServerSession::new_http1(stream);
+            session.set_alt_svc(alt_svc);
+            self.process_new_http(session, shutdown).await
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    async fn process_new_quic(self: &Arc<Self>, conn: QuicConnection, shutdown: &ShutdownWatch)
+    where
+        Self: Send + Sync,
+    {
+        // the connection digest is shared by all the requests
+        let digest = Arc::new(conn.digest);
+
+        let h3_options = self.h3_options();
+        let mut h3_conn = match h3_server::handshake(conn.connection, h3_options).await {
+            Err(e) => {
+                error!("H3 handshake error {e}");
+                return;
+            }
+            Ok(c) => c,
+        };
+
+        let mut shutdown = shutdown.clone();
+        let mut closing = false;
+        loop {
+            // this loop ends when the client decides to close the h3 conn, or after a graceful
+            // shutdown once the ongoing requests are done
+            let pending = tokio::select! {
+                _ = shutdown.changed(), if !closing => {
+                    closing = true;
+                    // let the client know that no more requests will be accepted
+                    if let Err(e) = h3_conn.shutdown(0).await {
+                        debug!("H3 error when shutting down {e}");
+                        return;
+                    }
+                    continue;
+                }
+                pending = h3_server::accept(&mut h3_conn, digest.clone()) => pending
+            };
+            let pending = match pending {
+                Err(e) => {
+                    // It is common for the client to just go away without properly closing the
+                    // connection, so we don't log the errors here
+                    debug!("H3 error when accepting new stream {e}");
+                    return;
+                }
+                Ok(Some(p)) => p,
+                Ok(None) => return, // the connection is ready to be closed
+            };
+            let app = self.clone();
+            let shutdown = shutdown.clone();
+            pingora_runtime::current_handle().spawn(async move {
+                // the request header is read in its own task to not block the other requests
+                let h3_stream = match pending.read_request().await {
+                    Err(e) => {
+                        debug!("H3 error when reading request header {e}");
+                        return;
+                    }
+                    Ok(s) => s,
+                };
+                app.process_new_http(ServerSession::new_http3(h3_stream), &shutdown)
+                    .await;
+            });
         }
     }
 
@@ -189,3 +308,71 @@ where
         self.http_cleanup().await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_http::ResponseHeader;
+    use tokio::io::duplex;
+    use tokio::sync::watch;
+
+    struct ResetTarget(HttpServerOptions);
+
+    #[async_trait]
+    impl HttpServerApp for ResetTarget {
+        async fn process_new_http(
+            self: &Arc<Self>,
+            mut session: ServerSession,
+            _shutdown: &ShutdownWatch,
+        ) -> Option<Stream> {
+            let resp = ResponseHeader::build(200, None).unwrap();
+            session.write_response_header(Box::new(resp)).await.unwrap();
+            // fails once the client resets the stream
+            while let Ok(Some(_)) = session.read_request_body().await {}
+            None
+        }
+
+        fn server_options(&self) -> Option<&HttpServerOptions> {
+            Some(&self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_h2_reset_flood() {
+        let (client, server) = duplex(65536);
+        let options = HttpServerOptions {
+            h2c: true,
+            ..Default::default()
+        };
+        let app = Arc::new(ResetTarget(options));
+        tokio::spawn(async move {
+            let (_tx, shutdown) = watch::channel(false);
+            app.process_new(Box::new(server), &shutdown).await;
+        });
+
+        let (mut h2, connection) = h2::client::handshake(client).await.unwrap();
+        let connection = tokio::spawn(connection);
+        // the default limits allow a few resets, but not a flood of them
+        for _ in 0..1000 {
+            let req = http::Request::builder()
+                .uri("http://pingora.org/")
+                .body(())
+                .unwrap();
+            let Ok((resp, mut body)) = h2.send_request(req, false) else {
+                break;
+            };
+            if resp.await.is_err() {
+                break;
+            }
+            body.send_reset(h2::Reason::CANCEL);
+            h2 = match h2.ready().await {
+                Ok(h2) => h2,
+                Err(_) => break,
+            };
+        }
+        let connection = tokio::time::timeout(std::time::Duration::from_secs(5), connection);
+        let e = connection.await.unwrap().unwrap().unwrap_err();
+        assert!(e.is_go_away(), "{e}");
+        assert_eq!(e.reason(), Some(h2::Reason::ENHANCE_YOUR_CALM));
+    }
+}
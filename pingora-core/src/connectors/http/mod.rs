@@ -14,28 +14,45 @@
 
 //! Connecting to HTTP servers
 
-use crate::connectors::ConnectorOptions;
+use crate::connectors::{ConnectorOptions, TransportConnector};
 use crate::protocols::http::client::HttpSession;
+use crate::protocols::UniqueIDType;
 use crate::upstreams::peer::Peer;
+#[cfg(feature = "quic")]
+use crate::upstreams::peer::ALPN;
+use parking_lot::RwLock;
 use pingora_error::Result;
+use pingora_pool::PoolNode;
+use std::collections::HashMap;
 use std::time::Duration;
 
 pub mod v1;
 pub mod v2;
+#[cfg(feature = "quic")]
+pub mod v3;
 
 pub struct Connector {
     h1: v1::Connector,
     h2: v2::Connector,
+    #[cfg(feature = "quic")]
+    h3: v3::Connector,
 }
 
 impl Connector {
     pub fn new(options: Option<ConnectorOptions>) -> Self {
         Connector {
             h1: v1::Connector::new(options.clone()),
+            #[cfg(feature = "quic")]
+            h3: v3::Connector::new(options.clone()),
             h2: v2::Connector::new(options),
         }
     }
 
+    /// The [TransportConnector] to establish raw L4 or TLS connections with, e.g. for tunnels.
+    pub fn transport(&self) -> &TransportConnector {
+        self.h1.transport()
+    }
+
     /// Get an [HttpSession] to the given server.
     ///
     /// The second return value indicates whether the session is connected via a reused stream.
@@ -46,6 +63,15 @@ impl Connector {
         // NOTE: maybe TODO: we do not yet enforce that only TLS traffic can use h2, which is the
         // de facto requirement for h2, because non TLS traffic lack the negotiation mechanism.
 
+        // h3 runs over QUIC instead of TCP, so it is never mixed with the other versions
+        #[cfg(feature = "quic")]
+        if peer
+            .get_peer_options()
+            .is_some_and(|o| matches!(o.alpn, ALPN::H3))
+        {
+            return self.h3.get_http_session(peer).await;
+        }
+
         // We assume no peer option == no ALPN == h1 only
         let h1_only = peer
             .get_peer_options()
@@ -85,6 +111,8 @@ impl Connector {
         match session {
             HttpSession::H1(h1) => self.h1.release_http_session(h1, peer, idle_timeout).await,
             HttpSession::H2(h2) => self.h2.release_http_session(h2, peer, idle_timeout),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(h3) => self.h3.release_http_session(*h3, peer, idle_timeout),
         }
     }
 
@@ -94,6 +122,54 @@ impl Connector {
     }
 }
 
+// The pool of the multiplexed (h2 or h3) connections that have ongoing streams
+pub(crate) struct InUsePool<T> {
+    // TODO: use pingora hashmap to shard the lock contention
+    pools: RwLock<HashMap<u64, PoolNode<T>>>,
+}
+
+impl<T> InUsePool<T> {
+    pub(crate) fn new() -> Self {
+        InUsePool {
+            pools: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn insert(&self, reuse_hash: u64, id: UniqueIDType, conn: T) {
+        {
+            let pools = self.pools.read();
+            if let Some(pool) = pools.get(&reuse_hash) {
+                pool.insert(id, conn);
+                return;
+            }
+        } // drop read lock
+
+        let pool = PoolNode::new();
+        pool.insert(id, conn);
+        let mut pools = self.pools.write();
+        pools.insert(reuse_hash, pool);
+    }
+
+    // retrieve a conn ref to create a new stream
+    // the caller should return the conn ref to this pool if there are still
+    // capacity left for more streams
+    pub(crate) fn get(&self, reuse_hash: u64) -> Option<T> {
+        let pools = self.pools.read();
+        pools.get(&reuse_hash)?.get_any().map(|v| v.1)
+    }
+
+    // release a stream, this functional will cause the conn ref to be returned (if exist)
+    // the caller should update the ref and then decide where to put it (in use pool or idle)
+    pub(crate) fn release(&self, reuse_hash: u64, id: UniqueIDType) -> Option<T> {
+        let pools = self.pools.read();
+        if let Some(pool) = pools.get(&reuse_hash) {
+            pool.remove(id)
+        } else {
+            None
+        }
+    }
+}
+
 #[cfg(test)]
 #[cfg(feature = "any_tls")]
 mod tests {
@@ -123,6 +199,8 @@ mod tests {
         match &h2 {
             HttpSession::H1(_) => panic!("expect h2"),
             HttpSession::H2(h2_stream) => assert!(!h2_stream.ping_timedout()),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h2"),
         }
 
         connector.release_http_session(h2, &peer, None).await;
@@ -133,6 +211,8 @@ mod tests {
         match &h2 {
             HttpSession::H1(_) => panic!("expect h2"),
             HttpSession::H2(h2_stream) => assert!(!h2_stream.ping_timedout()),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h2"),
         }
     }
 
@@ -148,6 +228,8 @@ mod tests {
                 get_http(http, 200).await;
             }
             HttpSession::H2(_) => panic!("expect h1"),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h1"),
         }
         connector.release_http_session(h1, &peer, None).await;
 
@@ -157,6 +239,8 @@ mod tests {
         match &mut h1 {
             HttpSession::H1(_) => {}
             HttpSession::H2(_) => panic!("expect h1"),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h1"),
         }
     }
 
@@ -178,6 +262,8 @@ mod tests {
                 get_http(http, 200).await;
             }
             HttpSession::H2(_) => panic!("expect h1"),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h1"),
         }
         connector.release_http_session(h1, &peer, None).await;
 
@@ -190,6 +276,8 @@ mod tests {
         match &mut h1 {
             HttpSession::H1(_) => {}
             HttpSession::H2(_) => panic!("expect h1"),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h1"),
         }
     }
 
@@ -207,6 +295,8 @@ mod tests {
                 get_http(http, 200).await;
             }
             HttpSession::H2(_) => panic!("expect h1"),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h1"),
         }
         connector.release_http_session(h1, &peer, None).await;
 
@@ -217,6 +307,8 @@ mod tests {
         match &mut h1 {
             HttpSession::H1(_) => {}
             HttpSession::H2(_) => panic!("expect h1"),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h1"),
         }
     }
 }
@@ -30,6 +30,11 @@ impl Connector {
         }
     }
 
+    /// The [TransportConnector] this connector establishes its connections with
+    pub fn transport(&self) -> &TransportConnector {
+        &self.transport
+    }
+
     pub async fn get_http_session<P: Peer + Send + Sync + 'static>(
         &self,
         peer: &P,
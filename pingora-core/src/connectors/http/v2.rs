@@ -12,20 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::HttpSession;
+use super::{HttpSession, InUsePool};
 use crate::connectors::{ConnectorOptions, TransportConnector};
 use crate::protocols::http::v1::client::HttpSession as Http1Session;
-use crate::protocols::http::v2::client::{drive_connection, Http2Session};
+use crate::protocols::http::v2::client::{drive_connection_notify_settings, Http2Session};
 use crate::protocols::{Digest, Stream, UniqueIDType};
 use crate::upstreams::peer::{Peer, ALPN};
 
 use bytes::Bytes;
 use h2::client::SendRequest;
 use log::debug;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::Mutex;
 use pingora_error::{Error, ErrorType::*, OrErr, Result};
-use pingora_pool::{ConnectionMeta, ConnectionPool, PoolNode};
-use std::collections::HashMap;
+use pingora_pool::{ConnectionMeta, ConnectionPool};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -46,6 +45,8 @@ impl Stub {
 pub(crate) struct ConnectionRefInner {
     connection_stub: Stub,
     closed: watch::Receiver<bool>,
+    // set once the SETTINGS of the server are received
+    settings: watch::Receiver<bool>,
     ping_timeout_occurred: Arc<AtomicBool>,
     id: UniqueIDType,
     // max concurrent streams this connection is allowed to create
@@ -68,6 +69,7 @@ impl ConnectionRef {
     pub fn new(
         send_req: SendRequest<Bytes>,
         closed: watch::Receiver<bool>,
+        settings: watch::Receiver<bool>,
         ping_timeout_occurred: Arc<AtomicBool>,
         id: UniqueIDType,
         max_streams: usize,
@@ -76,6 +78,7 @@ impl ConnectionRef {
         ConnectionRef(Arc::new(ConnectionRefInner {
             connection_stub: Stub(send_req),
             closed,
+            settings,
             ping_timeout_occurred,
             id,
             max_streams,
@@ -121,6 +124,10 @@ impl ConnectionRef {
         *self.0.closed.borrow()
     }
 
+    pub fn settings(&self) -> watch::Receiver<bool> {
+        self.0.settings.clone()
+    }
+
     // different from is_closed, existing streams can still be processed but can no longer create
     // new stream.
     pub fn is_shutting_down(&self) -> bool {
@@ -162,53 +169,6 @@ impl ConnectionRef {
     }
 }
 
-struct InUsePool {
-    // TODO: use pingora hashmap to shard the lock contention
-    pools: RwLock<HashMap<u64, PoolNode<ConnectionRef>>>,
-}
-
-impl InUsePool {
-    fn new() -> Self {
-        InUsePool {
-            pools: RwLock::new(HashMap::new()),
-        }
-    }
-
-    fn insert(&self, reuse_hash: u64, conn: ConnectionRef) {
-        {
-            let pools = self.pools.read();
-            if let Some(pool) = pools.get(&reuse_hash) {
-                pool.insert(conn.id(), conn);
-                return;
-            }
-        } // drop read lock
-
-        let pool = PoolNode::new();
-        pool.insert(conn.id(), conn);
-        let mut pools = self.pools.write();
-        pools.insert(reuse_hash, pool);
-    }
-
-    // retrieve a h2 conn ref to create a new stream
-    // the caller should return the conn ref to this pool if there are still
-    // capacity left for more streams
-    fn get(&self, reuse_hash: u64) -> Option<ConnectionRef> {
-        let pools = self.pools.read();
-        pools.get(&reuse_hash)?.get_any().map(|v| v.1)
-    }
-
-    // release a h2_stream, this functional will cause an ConnectionRef to be returned (if exist)
-    // the caller should update the ref and then decide where to put it (in use pool or idle)
-    fn release(&self, reuse_hash: u64, id: UniqueIDType) -> Option<ConnectionRef> {
-        let pools = self.pools.read();
-        if let Some(pool) = pools.get(&reuse_hash) {
-            pool.remove(id)
-        } else {
-            None
-        }
-    }
-}
-
 const DEFAULT_POOL_SIZE: usize = 128;
 
 /// Http2 connector
@@ -218,7 +178,7 @@ pub struct Connector {
     // the h2 connection idle pool
     idle_pool: Arc<ConnectionPool<ConnectionRef>>,
     // the pool of h2 connections that have ongoing streams
-    in_use_pool: InUsePool,
+    in_use_pool: InUsePool<ConnectionRef>,
 }
 
 impl Connector {
@@ -272,7 +232,7 @@ impl Connector {
             .await?
             .expect("newly created connections should have at least one free stream");
         if conn.more_streams_allowed() {
-            self.in_use_pool.insert(peer.reuse_hash(), conn);
+            self.in_use_pool.insert(peer.reuse_hash(), conn.id(), conn);
         }
         Ok(HttpSession::H2(h2_stream))
     }
@@ -306,7 +266,7 @@ impl Connector {
         if let Some(conn) = maybe_conn {
             let h2_stream = conn.spawn_stream().await?;
             if conn.more_streams_allowed() {
-                self.in_use_pool.insert(reuse_hash, conn);
+                self.in_use_pool.insert(reuse_hash, conn.id(), conn);
             }
             Ok(h2_stream)
         } else {
@@ -362,7 +322,7 @@ impl Connector {
                 });
             }
         } else {
-            self.in_use_pool.insert(reuse_hash, conn);
+            self.in_use_pool.insert(reuse_hash, conn.id(), conn);
             drop(locked);
         }
     }
@@ -436,12 +396,14 @@ async fn handshake(
     }
 
     let (closed_tx, closed_rx) = watch::channel(false);
+    let (settings_tx, settings_rx) = watch::channel(false);
 
     current_handle().spawn(async move {
-        drive_connection(
+        drive_connection_notify_settings(
             connection,
             id,
             closed_tx,
+            settings_tx,
             h2_ping_interval,
             ping_timeout_clone,
         )
@@ -450,6 +412,7 @@ async fn handshake(
     Ok(ConnectionRef::new(
         send_req,
         closed_rx,
+        settings_rx,
         ping_timeout_occurred,
         id,
         max_allowed_streams,
@@ -472,6 +435,8 @@ mod tests {
         match h2 {
             HttpSession::H1(_) => panic!("expect h2"),
             HttpSession::H2(h2_stream) => assert!(!h2_stream.ping_timedout()),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h2"),
         }
     }
 
@@ -486,9 +451,53 @@ mod tests {
         match h2 {
             HttpSession::H1(_) => {}
             HttpSession::H2(_) => panic!("expect h1"),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h1"),
         }
     }
 
+    #[tokio::test]
+    async fn test_connect_h2c_extended_connect() {
+        use h2::ext::Protocol;
+        use pingora_http::RequestHeader;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (io, _) = listener.accept().await.unwrap();
+            let mut conn = h2::server::Builder::new()
+                .enable_connect_protocol()
+                .handshake::<_, Bytes>(io)
+                .await
+                .unwrap();
+            let (req, mut respond) = conn.accept().await.unwrap().unwrap();
+            assert_eq!(req.method(), http::Method::CONNECT);
+            assert_eq!(
+                req.extensions().get::<Protocol>().unwrap().as_str(),
+                "websocket"
+            );
+            let resp = http::Response::builder().status(200).body(()).unwrap();
+            respond.send_response(resp, true).unwrap();
+            while conn.accept().await.is_some() {}
+        });
+
+        let connector = Connector::new(None);
+        let mut peer = HttpPeer::new(addr, false, "".into());
+        peer.options.set_http_version(2, 2);
+        let HttpSession::H2(mut h2) = connector.new_http_session(&peer).await.unwrap() else {
+            panic!("expect h2");
+        };
+        // the SETTINGS of the server enabling extended CONNECT are known right away
+        let mut req = RequestHeader::build("CONNECT", b"http://pingora.org/chat", None).unwrap();
+        req.extensions_mut()
+            .insert(Protocol::from_static("websocket"));
+        h2.wait_for_settings().await;
+        h2.write_request_header(Box::new(req), false).unwrap();
+        h2.read_response_header().await.unwrap();
+        assert_eq!(h2.response_header().unwrap().status, 200);
+    }
+
     #[tokio::test]
     async fn test_connect_h1_plaintext() {
         let connector = Connector::new(None);
@@ -498,6 +507,8 @@ mod tests {
         match h2 {
             HttpSession::H1(_) => {}
             HttpSession::H2(_) => panic!("expect h1"),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h1"),
         }
     }
 
@@ -512,6 +523,8 @@ mod tests {
         let h2_1 = match h2 {
             HttpSession::H1(_) => panic!("expect h2"),
             HttpSession::H2(h2_stream) => h2_stream,
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h2"),
         };
 
         let id = h2_1.conn.id();
@@ -544,6 +557,8 @@ mod tests {
         let h2_1 = match h2 {
             HttpSession::H1(_) => panic!("expect h2"),
             HttpSession::H2(h2_stream) => h2_stream,
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => panic!("expect h2"),
         };
 
         let id = h2_1.conn.id();
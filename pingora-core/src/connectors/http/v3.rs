@@ -0,0 +1,491 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connecting to HTTP/3 servers over QUIC
+
+use super::{HttpSession, InUsePool};
+use crate::connectors::l4::bind_to_random;
+use crate::connectors::tls::{self, quic_config, verify_spki_pins};
+use crate::connectors::ConnectorOptions;
+use crate::protocols::http::v3::client::Http3Session;
+use crate::protocols::http::v3::connection_digest;
+use crate::protocols::l4::socket::SocketAddr;
+use crate::protocols::{Digest, SocketDigest, UniqueIDType};
+use crate::upstreams::peer::Peer;
+
+use bytes::Bytes;
+use h3::client::SendRequest;
+use log::debug;
+use parking_lot::Mutex;
+use pingora_error::{Error, ErrorType::*, OrErr, Result};
+use pingora_pool::{ConnectionMeta, ConnectionPool};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{EndpointConfig, TokioRuntime};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr as InetSocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+pub(crate) struct ConnectionRefInner {
+    send_req: SendRequest<h3_quinn::OpenStreams, Bytes>,
+    closed: watch::Receiver<bool>,
+    id: UniqueIDType,
+    // max concurrent streams this connection is allowed to create
+    max_streams: usize,
+    // how many concurrent streams already active
+    current_streams: AtomicUsize,
+    // The connection is gracefully shutting down, no more stream is allowed
+    shutting_down: AtomicBool,
+    // The UDP socket of the connection is not shared, so all the streams share its digest
+    pub(crate) digest: Digest,
+    // To serialize certain operations when trying to release the connect back to the pool,
+    pub(crate) release_lock: Arc<Mutex<()>>,
+}
+
+#[derive(Clone)]
+pub(crate) struct ConnectionRef(Arc<ConnectionRefInner>);
+
+impl ConnectionRef {
+    pub fn new(
+        send_req: SendRequest<h3_quinn::OpenStreams, Bytes>,
+        closed: watch::Receiver<bool>,
+        id: UniqueIDType,
+        max_streams: usize,
+        digest: Digest,
+    ) -> Self {
+        ConnectionRef(Arc::new(ConnectionRefInner {
+            send_req,
+            closed,
+            id,
+            max_streams,
+            current_streams: AtomicUsize::new(0),
+            shutting_down: false.into(),
+            digest,
+            release_lock: Arc::new(Mutex::new(())),
+        }))
+    }
+
+    pub fn more_streams_allowed(&self) -> bool {
+        !self.is_shutting_down()
+            && self.0.max_streams > self.0.current_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.0.current_streams.load(Ordering::Relaxed) == 0
+    }
+
+    pub fn release_stream(&self) {
+        self.0.current_streams.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn id(&self) -> UniqueIDType {
+        self.0.id
+    }
+
+    pub fn digest(&self) -> &Digest {
+        &self.0.digest
+    }
+
+    pub fn digest_mut(&mut self) -> Option<&mut Digest> {
+        Arc::get_mut(&mut self.0).map(|inner| &mut inner.digest)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.0.closed.borrow()
+    }
+
+    // different from is_closed, existing streams can still be processed but can no longer create
+    // new stream.
+    pub fn is_shutting_down(&self) -> bool {
+        self.0.shutting_down.load(Ordering::Relaxed)
+    }
+
+    // the server sent GOAWAY
+    pub fn set_shutting_down(&self) {
+        self.0.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    // spawn a stream if more stream is allowed, otherwise return None
+    pub fn spawn_stream(&self) -> Option<Http3Session> {
+        if self.is_closed() || self.is_shutting_down() {
+            return None;
+        }
+        // Atomically check if the current_stream is over the limit
+        // load(), compare and then fetch_add() cannot guarantee the same
+        let current_streams = self.0.current_streams.fetch_add(1, Ordering::SeqCst);
+        if current_streams >= self.0.max_streams {
+            // already over the limit, reset the counter to the previous value
+            self.0.current_streams.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        // the request stream itself is only opened when the request header is sent
+        Some(Http3Session::new(self.0.send_req.clone(), self.clone()))
+    }
+}
+
+const DEFAULT_POOL_SIZE: usize = 128;
+
+/// Http3 connector
+pub struct Connector {
+    tls_ctx: tls::Connector,
+    bind_to_v4: Vec<InetSocketAddr>,
+    bind_to_v6: Vec<InetSocketAddr>,
+    // the h3 connection idle pool
+    idle_pool: Arc<ConnectionPool<ConnectionRef>>,
+    // the pool of h3 connections that have ongoing streams
+    in_use_pool: InUsePool<ConnectionRef>,
+}
+
+impl Connector {
+    /// Create a new [Connector] from the given [ConnectorOptions]
+    pub fn new(options: Option<ConnectorOptions>) -> Self {
+        let pool_size = options
+            .as_ref()
+            .map_or(DEFAULT_POOL_SIZE, |o| o.keepalive_pool_size);
+        let bind_to_v4 = options
+            .as_ref()
+            .map_or_else(Vec::new, |o| o.bind_to_v4.clone());
+        let bind_to_v6 = options
+            .as_ref()
+            .map_or_else(Vec::new, |o| o.bind_to_v6.clone());
+        Connector {
+            tls_ctx: tls::Connector::new(options),
+            bind_to_v4,
+            bind_to_v6,
+            idle_pool: Arc::new(ConnectionPool::new(pool_size)),
+            in_use_pool: InUsePool::new(),
+        }
+    }
+
+    /// Get an [HttpSession] to the given server.
+    ///
+    /// The second return value indicates whether the session is connected via a reused
+    /// connection.
+    pub async fn get_http_session<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+    ) -> Result<(HttpSession, bool)> {
+        if let Some(h3) = self.reused_http_session(peer) {
+            return Ok((HttpSession::H3(Box::new(h3)), true));
+        }
+        let h3 = self.new_http_session(peer).await?;
+        Ok((HttpSession::H3(Box::new(h3)), false))
+    }
+
+    /// Create a new Http3 connection to the given server
+    pub async fn new_http_session<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+    ) -> Result<Http3Session> {
+        let max_streams = peer.get_peer_options().map_or(1, |o| o.max_h2_streams);
+        let conn = self.connect(peer, max_streams).await?;
+        let h3_stream = conn
+            .spawn_stream()
+            .expect("newly created connections should have at least one free stream");
+        if conn.more_streams_allowed() {
+            self.in_use_pool.insert(peer.reuse_hash(), conn.id(), conn);
+        }
+        Ok(h3_stream)
+    }
+
+    /// Try to create a new http3 stream from any existing H3 connection.
+    ///
+    /// None means there is no "free" connection left.
+    pub fn reused_http_session<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+    ) -> Option<Http3Session> {
+        // check in use pool first so that we use fewer total connections
+        // then idle pool, see v2::Connector::reused_http_session() for the trade-offs
+        let reuse_hash = peer.reuse_hash();
+        let conn = self
+            .in_use_pool
+            .get(reuse_hash)
+            .or_else(|| self.idle_pool.get(&reuse_hash))?;
+        let h3_stream = conn.spawn_stream();
+        if conn.more_streams_allowed() {
+            self.in_use_pool.insert(reuse_hash, conn.id(), conn);
+        }
+        h3_stream
+    }
+
+    /// Release a finished h3 stream.
+    ///
+    /// This function will terminate the [Http3Session]. The corresponding h3 connection will now
+    /// have one more free stream to use.
+    ///
+    /// The h3 connection will be closed after `idle_timeout` if it has no active streams.
+    pub fn release_http_session<P: Peer + Send + Sync + 'static>(
+        &self,
+        session: Http3Session,
+        peer: &P,
+        idle_timeout: Option<Duration>,
+    ) {
+        let id = session.conn.id();
+        let reuse_hash = peer.reuse_hash();
+        // get a ref to the connection, which we might need below, before dropping the h3
+        let conn = session.conn();
+
+        // see v2::Connector::release_http_session() for why the lock is needed
+        let locked = conn.0.release_lock.lock_arc();
+        // this drop() will both drop the actual stream and call the conn.release_stream()
+        drop(session);
+        // find and remove the conn stored in in_use_pool so that it could be put in the idle pool
+        // if necessary
+        let conn = self.in_use_pool.release(reuse_hash, id).unwrap_or(conn);
+        if conn.is_closed() || conn.is_shutting_down() {
+            // should never be put back to the pool
+            return;
+        }
+        if conn.is_idle() {
+            drop(locked);
+            let meta = ConnectionMeta {
+                key: reuse_hash,
+                id,
+            };
+            let closed = conn.0.closed.clone();
+            let (notify_evicted, watch_use) = self.idle_pool.put(&meta, conn);
+            if let Some(to) = idle_timeout {
+                let pool = self.idle_pool.clone(); //clone the arc
+                let rt = pingora_runtime::current_handle();
+                rt.spawn(async move {
+                    pool.idle_timeout(&meta, to, notify_evicted, closed, watch_use)
+                        .await;
+                });
+            }
+        } else {
+            self.in_use_pool.insert(reuse_hash, conn.id(), conn);
+            drop(locked);
+        }
+    }
+
+    // Each connection has its own UDP socket so that it can be identified by the fd like the
+    // TCP based ones.
+    async fn connect<P: Peer>(&self, peer: &P, max_streams: usize) -> Result<ConnectionRef> {
+        // Safe guard: new_http_session() assumes there should be at least one free stream
+        if max_streams == 0 {
+            return Error::e_explain(H3Error, "zero max_stream configured");
+        }
+        let SocketAddr::Inet(addr) = peer.address() else {
+            return Error::e_explain(
+                ConnectError,
+                format!("{peer} is not an IP address, which QUIC requires"),
+            );
+        };
+        let addr = *addr;
+
+        let bind_addr = bind_to_random(peer, &self.bind_to_v4, &self.bind_to_v6)
+            .and_then(|b| b.addr)
+            .unwrap_or_else(|| match addr {
+                InetSocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                InetSocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            });
+        let socket = UdpSocket::bind(bind_addr).or_err_with(BindError, || {
+            format!("Failed to bind UDP socket to {bind_addr}")
+        })?;
+        let local_addr = socket.local_addr().ok();
+        #[cfg(unix)]
+        let (id, socket_digest) = {
+            let fd = socket.as_raw_fd();
+            (fd, SocketDigest::from_raw_fd(fd))
+        };
+        #[cfg(windows)]
+        let (id, socket_digest) = {
+            let sock = socket.as_raw_socket();
+            (sock as UniqueIDType, SocketDigest::from_raw_socket(sock))
+        };
+        let endpoint = quinn::Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(TokioRuntime),
+        )
+        .or_err(SocketError, "Failed to create QUIC endpoint")?;
+
+        let (tls, mut server_name) = quic_config(peer, &self.tls_ctx.ctx)?;
+        if server_name.is_empty() {
+            server_name = addr.ip().to_string();
+        }
+        let crypto = QuicClientConfig::try_from(tls)
+            .explain_err(InternalError, |e| format!("Invalid QUIC TLS config: {e}"))?;
+        let connecting = endpoint
+            .connect_with(
+                quinn::ClientConfig::new(Arc::new(crypto)),
+                addr,
+                &server_name,
+            )
+            .explain_err(ConnectError, |e| {
+                format!("Failed to connect to {peer}: {e}")
+            })?;
+
+        // the QUIC handshake covers both the L4 and TLS steps of the other connections
+        let connection = match peer
+            .total_connection_timeout()
+            .or_else(|| peer.connection_timeout())
+        {
+            Some(t) => pingora_timeout::timeout(t, connecting).await.map_err(|_| {
+                Error::explain(
+                    ConnectTimedout,
+                    format!("connecting to server {peer}, timeout {t:?}"),
+                )
+            })?,
+            None => connecting.await,
+        }
+        .or_err_with(HandshakeError, || {
+            format!("while QUIC handshaking with {peer}")
+        })?;
+        debug!("QUIC handshake to server {} done.", addr);
+
+        let digest = connection_digest(&connection, socket_digest, local_addr);
+        verify_spki_pins(peer, digest.ssl_digest.as_deref())?;
+
+        let (mut driver, send_req) = h3::client::new(h3_quinn::Connection::new(connection.clone()))
+            .await
+            .or_err(HandshakeError, "during H3 handshake")?;
+        debug!("H3 handshake to server done.");
+
+        let (closed_tx, closed_rx) = watch::channel(false);
+        pingora_runtime::current_handle().spawn(async move {
+            // the driver returns when the connection is closed or all the SendRequest are gone
+            let e = driver.wait_idle().await;
+            debug!("H3 connection {id} closed: {e}");
+            // the endpoint lives as long as the connection and the socket with it
+            connection.close(0u32.into(), b"");
+            endpoint.wait_idle().await;
+            let _ = closed_tx.send(true);
+        });
+
+        Ok(ConnectionRef::new(
+            send_req,
+            closed_rx,
+            id,
+            max_streams,
+            digest,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listeners::{QuicListenerEndpoint, QuicSettings};
+    use crate::protocols::http::v3::server;
+    use crate::upstreams::peer::HttpPeer;
+    use http::Method;
+    use pingora_http::{RequestHeader, ResponseHeader};
+
+    // answer every request with 200 and the request body, until the client closes the connection
+    async fn serve(listener: QuicListenerEndpoint) {
+        let conn = listener.accept().await.unwrap().handshake().await.unwrap();
+        let mut h3_conn = server::handshake(conn.connection, None).await.unwrap();
+        let digest = Arc::new(conn.digest);
+        while let Ok(Some(pending)) = server::accept(&mut h3_conn, digest.clone()).await {
+            tokio::spawn(async move {
+                let mut session = pending.read_request().await.unwrap();
+                let mut body = vec![];
+                while let Some(chunk) = session.read_body_bytes().await.unwrap() {
+                    body.extend_from_slice(&chunk);
+                }
+                let resp = ResponseHeader::build(200, None).unwrap();
+                session
+                    .write_response_header(Box::new(resp), false)
+                    .await
+                    .unwrap();
+                session.write_body(body.into(), true).await.unwrap();
+                session.finish().await.unwrap();
+            });
+        }
+    }
+
+    async fn bind() -> QuicListenerEndpoint {
+        let settings = QuicSettings::new(
+            &format!(
+                "{}/tests/keys/server_rustls.crt",
+                env!("CARGO_MANIFEST_DIR")
+            ),
+            &format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR")),
+        )
+        .unwrap();
+        #[cfg(unix)]
+        let listener = QuicListenerEndpoint::bind("127.0.0.1:0", None, &settings, None).await;
+        #[cfg(windows)]
+        let listener = QuicListenerEndpoint::bind("127.0.0.1:0", None, &settings).await;
+        listener.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_h3_stream_reuse() {
+        let listener = bind().await;
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener));
+
+        let mut options = ConnectorOptions::new(1);
+        options.ca_file = Some(format!(
+            "{}/tests/keys/server_rustls.crt",
+            env!("CARGO_MANIFEST_DIR")
+        ));
+        let connector = Connector::new(Some(options));
+        let mut peer = HttpPeer::new(addr, true, "openrusty.org".into());
+        peer.options.set_http_version(3, 3);
+        peer.options.max_h2_streams = 2;
+
+        let (session, reused) = connector.get_http_session(&peer).await.unwrap();
+        assert!(!reused);
+        let HttpSession::H3(mut h3_1) = session else {
+            panic!("expect h3")
+        };
+        let id = h3_1.conn.id();
+        // the second stream shares the connection
+        let mut h3_2 = connector.reused_http_session(&peer).unwrap();
+        assert_eq!(id, h3_2.conn.id());
+        // max stream is 2
+        assert!(connector.reused_http_session(&peer).is_none());
+
+        for (h3, body) in [(&mut *h3_1, "one"), (&mut h3_2, "two")] {
+            let mut req = RequestHeader::build(Method::POST, b"/", None).unwrap();
+            req.insert_header("Host", "openrusty.org").unwrap();
+            h3.write_request_header(Box::new(req), false).await.unwrap();
+            h3.write_request_body(body.into(), true).await.unwrap();
+            h3.read_response_header().await.unwrap();
+            assert_eq!(h3.response_header().unwrap().status, 200);
+            assert_eq!(h3.read_response_body().await.unwrap().unwrap(), body);
+            assert!(h3.read_response_body().await.unwrap().is_none());
+            assert!(h3.response_finished());
+        }
+
+        connector.release_http_session(*h3_1, &peer, None);
+        connector.release_http_session(h3_2, &peer, None);
+
+        // all streams are released, now the connection is idle
+        let (session, reused) = connector.get_http_session(&peer).await.unwrap();
+        assert!(reused);
+        let HttpSession::H3(h3_3) = session else {
+            panic!("expect h3")
+        };
+        assert_eq!(id, h3_3.conn.id());
+        assert_eq!(
+            h3_3.digest().unwrap().ssl_digest.as_ref().unwrap().version,
+            "TLSv1_3"
+        );
+
+        drop(h3_3);
+        drop(connector);
+        server.await.unwrap();
+    }
+}
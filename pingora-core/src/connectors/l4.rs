@@ -19,9 +19,10 @@ use crate::protocols::l4::ext::{
 };
 use crate::protocols::l4::socket::SocketAddr;
 use crate::protocols::l4::stream::Stream;
-use crate::protocols::{GetSocketDigest, SocketDigest};
-use crate::upstreams::peer::Peer;
+use crate::protocols::{ConnectAttempt, ConnectOutcome, GetSocketDigest, SocketDigest};
+use crate::upstreams::peer::{Peer, Socks5Proxy};
 use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::debug;
 use pingora_error::{Context, Error, ErrorType::*, OrErr, Result};
 use rand::seq::SliceRandom;
@@ -30,6 +31,7 @@ use std::net::SocketAddr as InetSocketAddr;
 use std::os::unix::io::AsRawFd;
 #[cfg(windows)]
 use std::os::windows::io::AsRawSocket;
+use std::time::{Duration, Instant, SystemTime};
 
 /// The interface to establish a L4 connection
 #[async_trait]
@@ -98,62 +100,28 @@ where
             .await
             .err_context(|| format!("Fail to establish CONNECT proxy: {}", peer));
     }
-    let peer_addr = peer.address();
+    let socks5_proxy = peer.get_socks5_proxy();
+    // the connection is to the SOCKS5 proxy, which then connects to the peer
+    let mut peer_addr = match socks5_proxy {
+        Some(proxy) => proxy.next_hop.clone(),
+        None => peer.address().clone(),
+    };
+    let mut connect_attempts = vec![];
     let mut stream: Stream =
         if let Some(custom_l4) = peer.get_peer_options().and_then(|o| o.custom_l4.as_ref()) {
-            custom_l4.connect(peer_addr).await?
+            custom_l4.connect(&peer_addr).await?
         } else {
-            match peer_addr {
-                SocketAddr::Inet(addr) => {
-                    let connect_future = tcp_connect(addr, bind_to.as_ref(), |socket| {
-                        #[cfg(unix)]
-                        let raw = socket.as_raw_fd();
-                        #[cfg(windows)]
-                        let raw = socket.as_raw_socket();
-
-                        if peer.tcp_fast_open() {
-                            set_tcp_fastopen_connect(raw)?;
-                        }
-                        if let Some(recv_buf) = peer.tcp_recv_buf() {
-                            debug!("Setting recv buf size");
-                            set_recv_buf(raw, recv_buf)?;
-                        }
-                        if let Some(dscp) = peer.dscp() {
-                            debug!("Setting dscp");
-                            set_dscp(raw, dscp)?;
-                        }
-
-                        if let Some(tweak_hook) = peer
-                            .get_peer_options()
-                            .and_then(|o| o.upstream_tcp_sock_tweak_hook.clone())
-                        {
-                            tweak_hook(socket)?;
-                        }
-
-                        Ok(())
-                    });
-                    let conn_res = match peer.connection_timeout() {
-                        Some(t) => pingora_timeout::timeout(t, connect_future)
-                            .await
-                            .explain_err(ConnectTimedout, |_| {
-                                format!("timeout {t:?} connecting to server {peer}")
-                            })?,
-                        None => connect_future.await,
-                    };
-                    match conn_res {
-                        Ok(socket) => {
-                            debug!("connected to new server: {}", peer.address());
-                            Ok(socket.into())
-                        }
-                        Err(e) => {
-                            let c = format!("Fail to connect to {peer}");
-                            match e.etype() {
-                                SocketError | BindError => Error::e_because(InternalError, c, e),
-                                _ => Err(e.more_context(c)),
-                            }
-                        }
-                    }
+            match &peer_addr {
+                SocketAddr::Inet(addr)
+                    if socks5_proxy.is_none() && !peer.alternative_addresses().is_empty() =>
+                {
+                    let (stream, addr, attempts) =
+                        happy_eyeballs_connect(peer, *addr, bind_to.as_ref()).await?;
+                    peer_addr = SocketAddr::Inet(addr);
+                    connect_attempts = attempts;
+                    Ok(stream)
                 }
+                SocketAddr::Inet(addr) => inet_connect(peer, addr, bind_to.as_ref()).await,
                 #[cfg(unix)]
                 SocketAddr::Unix(addr) => {
                     let connect_future = connect_uds(
@@ -184,6 +152,7 @@ where
                 }
             }?
         };
+    stream.set_connect_attempts(connect_attempts);
 
     let tracer = peer.get_tracer();
     if let Some(t) = tracer {
@@ -203,13 +172,196 @@ where
     let digest = SocketDigest::from_raw_socket(stream.as_raw_socket());
     digest
         .peer_addr
-        .set(Some(peer_addr.clone()))
+        .set(Some(peer_addr))
         .expect("newly created OnceCell must be empty");
     stream.set_socket_digest(digest);
 
+    if let Some(proxy) = socks5_proxy {
+        socks5_connect(peer, proxy, &mut stream)
+            .await
+            .err_context(|| format!("Fail to establish SOCKS5 proxy: {}", peer))?;
+    }
+
     Ok(stream)
 }
 
+async fn socks5_connect<P: Peer>(peer: &P, proxy: &Socks5Proxy, stream: &mut Stream) -> Result<()> {
+    let credentials = proxy
+        .auth
+        .as_ref()
+        .map(|auth| (auth.username.as_str(), auth.password.as_str()));
+    let fut = socks5::connect(stream, &proxy.host, proxy.port, credentials);
+    let bound = match peer.connection_timeout() {
+        Some(t) => pingora_timeout::timeout(t, fut)
+            .await
+            .explain_err(ConnectTimedout, |_| "establishing SOCKS5 proxy")?,
+        None => fut.await,
+    }?;
+    debug!("SOCKS5 proxy established: {proxy}, bound to {bound}");
+    Ok(())
+}
+
+// Connect to the given address of the peer
+async fn inet_connect<P>(
+    peer: &P,
+    addr: &InetSocketAddr,
+    bind_to: Option<&BindTo>,
+) -> Result<Stream>
+where
+    P: Peer + Send + Sync,
+{
+    let connect_future = tcp_connect(addr, bind_to, |socket| {
+        #[cfg(unix)]
+        let raw = socket.as_raw_fd();
+        #[cfg(windows)]
+        let raw = socket.as_raw_socket();
+
+        if peer.tcp_fast_open() {
+            set_tcp_fastopen_connect(raw)?;
+        }
+        if let Some(recv_buf) = peer.tcp_recv_buf() {
+            debug!("Setting recv buf size");
+            set_recv_buf(raw, recv_buf)?;
+        }
+        if let Some(dscp) = peer.dscp() {
+            debug!("Setting dscp");
+            set_dscp(raw, dscp)?;
+        }
+
+        if let Some(tweak_hook) = peer
+            .get_peer_options()
+            .and_then(|o| o.upstream_tcp_sock_tweak_hook.clone())
+        {
+            tweak_hook(socket)?;
+        }
+
+        Ok(())
+    });
+    let conn_res = match peer.connection_timeout() {
+        Some(t) => pingora_timeout::timeout(t, connect_future)
+            .await
+            .explain_err(ConnectTimedout, |_| {
+                format!("timeout {t:?} connecting to server {peer}")
+            })?,
+        None => connect_future.await,
+    };
+    match conn_res {
+        Ok(socket) => {
+            debug!("connected to new server: {addr}");
+            Ok(socket.into())
+        }
+        Err(e) => {
+            let c = format!("Fail to connect to {peer}");
+            match e.etype() {
+                SocketError | BindError => Error::e_because(InternalError, c, e),
+                _ => Err(e.more_context(c)),
+            }
+        }
+    }
+}
+
+// The order to try the addresses in: alternate between the IP families starting with the one of
+// the first address, see RFC 8305 4
+fn interleave_addresses(first: InetSocketAddr, others: &[InetSocketAddr]) -> Vec<InetSocketAddr> {
+    let (same, other): (Vec<_>, Vec<_>) = others
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    let mut same = std::iter::once(first).chain(same);
+    let mut other = other.into_iter();
+    let mut addrs = Vec::with_capacity(others.len() + 1);
+    loop {
+        match (same.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+    addrs
+}
+
+// The local address to bind to only applies to the destinations of the same IP family
+fn bind_to_for(bind_to: Option<&BindTo>, addr: &InetSocketAddr) -> Option<BindTo> {
+    let mut bind_to = bind_to?.clone();
+    if bind_to
+        .addr
+        .is_some_and(|local| local.is_ipv6() != addr.is_ipv6())
+    {
+        bind_to.addr = None;
+    }
+    Some(bind_to)
+}
+
+// Race the connections to all the addresses of the peer with the staggered start of Happy Eyeballs,
+// see RFC 8305 5. Return the first established connection along with the address it is connected
+// to and the timing of all the attempts.
+async fn happy_eyeballs_connect<P>(
+    peer: &P,
+    addr: InetSocketAddr,
+    bind_to: Option<&BindTo>,
+) -> Result<(Stream, InetSocketAddr, Vec<ConnectAttempt>)>
+where
+    P: Peer + Send + Sync,
+{
+    let delay = peer.connection_attempt_delay();
+    let mut pending = interleave_addresses(addr, peer.alternative_addresses()).into_iter();
+    let mut attempts: Vec<(ConnectAttempt, Instant)> = vec![];
+    let mut in_flight = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = pending.next() {
+            let index = attempts.len();
+            let attempt = ConnectAttempt {
+                addr,
+                start_ts: SystemTime::now(),
+                duration: Duration::ZERO,
+                outcome: ConnectOutcome::Abandoned,
+            };
+            attempts.push((attempt, Instant::now()));
+            let bind_to = bind_to_for(bind_to, &addr);
+            in_flight
+                .push(async move { (index, inet_connect(peer, &addr, bind_to.as_ref()).await) });
+        } else if in_flight.is_empty() {
+            // all failed
+            return Err(last_error.expect("at least one attempt"));
+        }
+
+        let finished = if pending.len() > 0 {
+            // start the next attempt if none finishes in time
+            match pingora_timeout::timeout(delay, in_flight.next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            }
+        } else {
+            in_flight.next().await
+        };
+        let Some((index, result)) = finished else {
+            continue;
+        };
+        let (attempt, start) = &mut attempts[index];
+        attempt.duration = start.elapsed();
+        match result {
+            Ok(stream) => {
+                attempt.outcome = ConnectOutcome::Succeeded;
+                let addr = attempt.addr;
+                // the ones still in flight are abandoned
+                let now = Instant::now();
+                for (attempt, start) in attempts.iter_mut() {
+                    if attempt.outcome == ConnectOutcome::Abandoned {
+                        attempt.duration = now - *start;
+                    }
+                }
+                let attempts = attempts.into_iter().map(|(attempt, _)| attempt).collect();
+                return Ok((stream, addr, attempts));
+            }
+            Err(e) => {
+                debug!("Fail to connect to {}: {e}", attempt.addr);
+                attempt.outcome = ConnectOutcome::Failed;
+                last_error = Some(e);
+            }
+        }
+    }
+}
+
 pub(crate) fn bind_to_random<P: Peer>(
     peer: &P,
     v4_list: &[InetSocketAddr],
@@ -255,7 +407,7 @@ pub(crate) fn bind_to_random<P: Peer>(
     bind_to
 }
 
-use crate::protocols::raw_connect;
+use crate::protocols::{raw_connect, socks5};
 
 #[cfg(unix)]
 async fn proxy_connect<P: Peer>(peer: &P) -> Result<Stream> {
@@ -532,6 +684,74 @@ mod tests {
         assert!(!err.retry());
     }
 
+    // a SOCKS5 proxy answering one CONNECT to pingora.org:443 with the given reply code, then
+    // greeting the client
+    async fn mock_socks5_server(reply: u8) -> InetSocketAddr {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0; 11];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).await.unwrap();
+            let mut request = [0; 18];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x0bpingora.org\x01\xbb");
+            stream
+                .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            if reply == 0 {
+                stream.write_all(b"hello").await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_connect_socks5_proxy() {
+        use tokio::io::AsyncReadExt;
+
+        let proxy = mock_socks5_server(0).await;
+        let auth = Some(crate::upstreams::peer::Socks5Auth::new("user", "pass"));
+        let peer = HttpPeer::new_socks5_proxy(
+            SocketAddr::Inet(proxy),
+            "pingora.org",
+            443,
+            false,
+            "".into(),
+            auth.clone(),
+        );
+        let mut stream = connect(&peer, None).await.unwrap();
+        let mut greeting = [0; 5];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+        assert_eq!(
+            stream.get_socket_digest().unwrap().peer_addr(),
+            Some(&SocketAddr::Inet(proxy))
+        );
+        assert!(peer.matches_fd(stream.as_raw_fd()));
+
+        let proxy = mock_socks5_server(5).await;
+        let peer = HttpPeer::new_socks5_proxy(
+            SocketAddr::Inet(proxy),
+            "pingora.org",
+            443,
+            false,
+            "".into(),
+            auth,
+        );
+        let err = connect(&peer, None).await.unwrap_err();
+        assert_eq!(err.etype(), &ConnectRefused);
+    }
+
     #[cfg(target_os = "linux")]
     #[tokio::test(flavor = "multi_thread")]
     async fn test_bind_to_port_range_on_connect() {
@@ -660,4 +880,107 @@ mod tests {
         bind_to.set_port_range(Some((1000, 2000))).unwrap();
         assert_eq!(bind_to.port_range, Some((1000, 2000)));
     }
+
+    #[test]
+    fn test_interleave_addresses() {
+        let addr = |s: &str| s.parse::<InetSocketAddr>().unwrap();
+        let v6_1 = addr("[2001:db8::1]:443");
+        let v6_2 = addr("[2001:db8::2]:443");
+        let v4_1 = addr("192.0.2.1:443");
+        let v4_2 = addr("192.0.2.2:443");
+        let v4_3 = addr("192.0.2.3:443");
+        assert_eq!(
+            interleave_addresses(v6_1, &[v4_1, v4_2, v6_2, v4_3]),
+            vec![v6_1, v4_1, v6_2, v4_2, v4_3]
+        );
+        assert_eq!(
+            interleave_addresses(v4_1, &[v6_1, v4_2]),
+            vec![v4_1, v6_1, v4_2]
+        );
+        assert_eq!(interleave_addresses(v4_1, &[]), vec![v4_1]);
+    }
+
+    #[test]
+    fn test_bind_to_for() {
+        let v4: InetSocketAddr = "192.0.2.1:443".parse().unwrap();
+        let v6: InetSocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let bind_to = BindTo {
+            addr: "10.0.0.1:0".parse().ok(),
+            ..Default::default()
+        };
+        assert!(bind_to_for(None, &v4).is_none());
+        assert_eq!(bind_to_for(Some(&bind_to), &v4).unwrap().addr, bind_to.addr);
+        assert!(bind_to_for(Some(&bind_to), &v6).unwrap().addr.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_fallback() {
+        use crate::protocols::{ConnectOutcome, GetTimingDigest};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // nothing listens to the port of a dropped listener
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let mut peer = BasicPeer::new(&closed.to_string());
+        peer.options.alternative_addresses = vec![addr];
+        // the refused attempt is followed right away
+        peer.options.connection_attempt_delay = Duration::from_secs(10);
+
+        let stream = tokio::time::timeout(Duration::from_secs(5), connect(&peer, None))
+            .await
+            .unwrap()
+            .unwrap();
+        let peer_addr = stream.get_socket_digest().unwrap().peer_addr().cloned();
+        assert_eq!(peer_addr, Some(SocketAddr::Inet(addr)));
+
+        let timing = stream.get_timing_digest()[0].clone().unwrap();
+        let attempts = timing.connect_attempts;
+        assert_eq!(attempts.len(), 2);
+        assert_eq!(attempts[0].addr, closed);
+        assert_eq!(attempts[0].outcome, ConnectOutcome::Failed);
+        assert_eq!(attempts[1].addr, addr);
+        assert_eq!(attempts[1].outcome, ConnectOutcome::Succeeded);
+        assert!(attempts[1].start_ts >= attempts[0].start_ts);
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_delay() {
+        use crate::protocols::{ConnectOutcome, GetTimingDigest};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 192.0.2.1 is effectively a blackhole
+        let mut peer = BasicPeer::new("192.0.2.1:79");
+        peer.options.alternative_addresses = vec![addr];
+        peer.options.connection_attempt_delay = Duration::from_millis(10);
+
+        let stream = tokio::time::timeout(Duration::from_secs(5), connect(&peer, None))
+            .await
+            .unwrap()
+            .unwrap();
+        let timing = stream.get_timing_digest()[0].clone().unwrap();
+        let attempts = timing.connect_attempts;
+        assert_eq!(attempts.len(), 2);
+        // abandoned unless the network is unreachable
+        assert_ne!(attempts[0].outcome, ConnectOutcome::Succeeded);
+        assert_eq!(attempts[1].outcome, ConnectOutcome::Succeeded);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_matches_fd_alternative_addresses() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+        let mut peer = HttpPeer::new("192.0.2.1:79", false, "".into());
+        assert!(!peer.matches_fd(stream.as_raw_fd()));
+        peer.options.alternative_addresses = vec![addr];
+        assert!(peer.matches_fd(stream.as_raw_fd()));
+        let peer = HttpPeer::new_with_addresses(&[addr], false, "".into());
+        assert!(peer.matches_fd(stream.as_raw_fd()));
+    }
 }
@@ -24,6 +24,7 @@ mod tls;
 #[cfg(not(feature = "any_tls"))]
 use crate::tls::connectors as tls;
 
+use crate::protocols::proxy_protocol::write_proxy_header;
 use crate::protocols::Stream;
 use crate::server::configuration::ServerConf;
 use crate::upstreams::peer::{Peer, ALPN};
@@ -72,6 +73,15 @@ pub struct ConnectorOptions {
     pub bind_to_v4: Vec<SocketAddr>,
     /// Bind to any of the given source IPv4 addresses
     pub bind_to_v6: Vec<SocketAddr>,
+    /// How many upstream peers to keep TLS sessions of for session resumption
+    ///
+    /// Sessions are only resumed with the peer they are established with, i.e. the same
+    /// address, SNI and certificate verification settings. Whether a connection is resumed is
+    /// recorded in its [SslDigest](crate::protocols::tls::SslDigest).
+    ///
+    /// 0 leaves the default of the TLS backend: rustls resumes sessions per server name with
+    /// its own in-memory cache, OpenSSL and BoringSSL do not resume sessions.
+    pub tls_session_cache_size: usize,
 }
 
 impl ConnectorOptions {
@@ -110,6 +120,7 @@ impl ConnectorOptions {
             offload_threadpool,
             bind_to_v4,
             bind_to_v6,
+            tls_session_cache_size: 0,
         }
     }
 
@@ -123,6 +134,7 @@ impl ConnectorOptions {
             offload_threadpool: None,
             bind_to_v4: vec![],
             bind_to_v6: vec![],
+            tls_session_cache_size: 0,
         }
     }
 }
@@ -323,7 +335,10 @@ async fn do_connect_inner<P: Peer + Send + Sync>(
     alpn_override: Option<ALPN>,
     tls_ctx: &TlsConnector,
 ) -> Result<Stream> {
-    let stream = l4_connect(peer, bind_to).await?;
+    let mut stream = l4_connect(peer, bind_to).await?;
+    if let Some(header) = peer.proxy_protocol() {
+        write_proxy_header(&mut stream, header).await?;
+    }
     if peer.tls() {
         let tls_stream = tls::connect(stream, peer, alpn_override, tls_ctx).await?;
         Ok(Box::new(tls_stream))
@@ -428,6 +443,108 @@ mod tests {
         assert!(reused);
     }
 
+    // A TLS listener that writes "hello" on every connection. Return its address and the path of
+    // its certificate.
+    async fn tls_hello_server() -> (std::net::SocketAddr, String) {
+        use crate::listeners::tls::TlsSettings;
+        use crate::protocols::l4::stream::Stream as L4Stream;
+
+        let cert = format!(
+            "{}/tests/keys/server_rustls.crt",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let key = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        let acceptor = Arc::new(TlsSettings::intermediate(&cert, &key).unwrap().build());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((io, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.tls_handshake(L4Stream::from(io)).await else {
+                        return;
+                    };
+                    // the session tickets are sent along
+                    let _ = stream.write_all(b"hello").await;
+                    let _ = stream.flush().await;
+                    let mut buf = [0; 1];
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+        (addr, cert)
+    }
+
+    #[tokio::test]
+    async fn test_tls_session_resumption() {
+        let (addr, cert) = tls_hello_server().await;
+        let mut options = ConnectorOptions::new(1);
+        options.ca_file = Some(cert.clone());
+        options.tls_session_cache_size = 16;
+        let connector = TransportConnector::new(Some(options));
+        let mut peer = BasicPeer::new(&addr.to_string());
+        peer.sni = "openrusty.org".to_string();
+
+        let mut resumed = vec![];
+        for _ in 0..2 {
+            let mut stream = connector.new_stream(&peer).await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            resumed.push(stream.get_ssl_digest().unwrap().resumed);
+        }
+        assert_eq!(resumed, vec![false, true]);
+
+        // sessions are not shared with the same address under a different SNI
+        peer.sni = "cat.com".to_string();
+        let stream = connector.new_stream(&peer).await.unwrap();
+        assert!(!stream.get_ssl_digest().unwrap().resumed);
+
+        // without the cache, only rustls resumes sessions by default
+        let mut options = ConnectorOptions::new(1);
+        options.ca_file = Some(cert);
+        let connector = TransportConnector::new(Some(options));
+        peer.sni = "openrusty.org".to_string();
+        let mut resumed = vec![];
+        for _ in 0..2 {
+            let mut stream = connector.new_stream(&peer).await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            resumed.push(stream.get_ssl_digest().unwrap().resumed);
+        }
+        assert_eq!(resumed, vec![false, cfg!(feature = "rustls")]);
+    }
+
+    #[tokio::test]
+    async fn test_tls_spki_pins() {
+        let (addr, cert) = tls_hello_server().await;
+        // openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | sha256sum
+        let pin = [
+            0xdd, 0xa3, 0x70, 0x5b, 0xb3, 0x40, 0x36, 0x86, 0x76, 0x27, 0x07, 0x83, 0x03, 0x65,
+            0x08, 0x68, 0xd0, 0x9a, 0xbf, 0x25, 0xaa, 0x5a, 0x41, 0x93, 0x7b, 0xc0, 0x79, 0xd0,
+            0x80, 0x59, 0x8c, 0x42,
+        ];
+
+        let mut options = ConnectorOptions::new(1);
+        options.ca_file = Some(cert);
+        options.tls_session_cache_size = 16;
+        let connector = TransportConnector::new(Some(options));
+        let mut peer = BasicPeer::new(&addr.to_string());
+        peer.sni = "openrusty.org".to_string();
+        peer.options.spki_pins = vec![[0; 32], pin];
+        // the pins are checked on resumed sessions as well
+        for _ in 0..2 {
+            let mut stream = connector.new_stream(&peer).await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            connector.release_stream(stream, peer.reuse_hash(), None);
+        }
+
+        // a connection established with other pins is not reused
+        peer.options.spki_pins = vec![[0; 32]];
+        let err = connector.get_stream(&peer).await.unwrap_err();
+        assert_eq!(err.etype(), &ErrorType::CertPinMismatch);
+    }
+
     #[cfg(unix)]
     const MOCK_UDS_PATH: &str = "/tmp/test_unix_transport_connector.sock";
 
@@ -462,6 +579,49 @@ mod tests {
         assert!(reused);
     }
 
+    #[tokio::test]
+    async fn test_connect_proxy_protocol() {
+        use crate::protocols::proxy_protocol::{ProxyHeader, ProxyProtocolVersion};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 47];
+            stream.read_exact(&mut buf).await.unwrap();
+            tx.send(buf).unwrap();
+            // keep the connection open so that it can be reused
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        });
+
+        let connector = TransportConnector::new(None);
+        let mut peer = BasicPeer::new(&addr.to_string());
+        let reuse_hash = peer.reuse_hash();
+        peer.options.proxy_protocol = Some(ProxyHeader::new(
+            ProxyProtocolVersion::V1,
+            "192.168.0.1:56324".parse().unwrap(),
+            "192.168.0.11:443".parse().unwrap(),
+        ));
+        // connections carrying a PROXY header are not shared with other clients
+        assert_ne!(peer.reuse_hash(), reuse_hash);
+
+        let stream = connector.new_stream(&peer).await.unwrap();
+        assert_eq!(
+            &rx.await.unwrap(),
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n"
+        );
+        connector.release_stream(stream, peer.reuse_hash(), None);
+        let mut other_client = peer.clone();
+        other_client.options.proxy_protocol = Some(ProxyHeader::new(
+            ProxyProtocolVersion::V1,
+            "192.168.0.2:56324".parse().unwrap(),
+            "192.168.0.11:443".parse().unwrap(),
+        ));
+        assert!(connector.reused_stream(&other_client).await.is_none());
+        assert!(connector.reused_stream(&peer).await.is_some());
+    }
+
     async fn do_test_conn_timeout(conf: Option<ConnectorOptions>) {
         let connector = TransportConnector::new(conf);
         let mut peer = BasicPeer::new(BLACK_HOLE);
@@ -13,14 +13,17 @@
 // limitations under the License.
 
 use log::debug;
+use once_cell::sync::Lazy;
 use pingora_error::{Error, ErrorType::*, OrErr, Result};
 use std::sync::{Arc, Once};
 
-use crate::connectors::tls::replace_leftmost_underscore;
+use super::session_cache::{session_key, SessionCache};
+use crate::connectors::tls::{replace_leftmost_underscore, verify_spki_pins};
 use crate::connectors::ConnectorOptions;
 use crate::protocols::tls::client::handshake;
 use crate::protocols::tls::SslStream;
 use crate::protocols::IO;
+use crate::tls::ex_data::Index;
 use crate::tls::ext::{
     add_host, clear_error_stack, ssl_add_chain_cert, ssl_set_groups_list,
     ssl_set_renegotiate_mode_freely, ssl_set_verify_cert_store, ssl_use_certificate,
@@ -28,7 +31,10 @@ use crate::tls::ext::{
 };
 #[cfg(feature = "boringssl")]
 use crate::tls::ssl::SslCurve;
-use crate::tls::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode, SslVersion};
+use crate::tls::ssl::{
+    Ssl, SslConnector, SslContext, SslFiletype, SslMethod, SslSession, SslSessionCacheMode,
+    SslVerifyMode, SslVersion,
+};
 use crate::tls::x509::store::X509StoreBuilder;
 use crate::upstreams::peer::{Peer, ALPN};
 
@@ -79,6 +85,14 @@ const BORINGSSL_CURVE_LIST: &[SslCurve] = &[
     SslCurve::SECP521R1,
 ];
 
+// The upstream TLS sessions, stored in the SslContext of the connector.
+// Sessions are kept serialized because OpenSSL marks the session of a connection that is not
+// shut down cleanly as not resumable, which upstream connections often are not.
+static SESSION_CACHE_INDEX: Lazy<Index<SslContext, Arc<SessionCache<Vec<u8>>>>> =
+    Lazy::new(|| SslContext::new_ex_index().unwrap());
+// The session_key() of the peer of each connection, to store its new sessions with
+static SESSION_KEY_INDEX: Lazy<Index<Ssl, u64>> = Lazy::new(|| Ssl::new_ex_index().unwrap());
+
 static INIT_CA_ENV: Once = Once::new();
 fn init_ssl_cert_env_vars() {
     // this sets env vars to pick up the root certs
@@ -145,6 +159,24 @@ impl Connector {
             init_ssl_cert_env_vars();
             builder.set_default_verify_paths().unwrap();
         }
+        if let Some(cache) = options
+            .as_ref()
+            .and_then(|conf| SessionCache::new(conf.tls_session_cache_size))
+        {
+            let cache = Arc::new(cache);
+            builder.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+            let new_sessions = cache.clone();
+            builder.set_new_session_callback(move |ssl, session| {
+                let Some(key) = ssl.ex_data(*SESSION_KEY_INDEX) else {
+                    return;
+                };
+                match session.to_der() {
+                    Ok(der) => new_sessions.put(*key, der),
+                    Err(e) => debug!("failed to serialize TLS session: {e}"),
+                }
+            });
+            builder.set_ex_data(*SESSION_CACHE_INDEX, cache);
+        }
 
         Connector {
             ctx: Arc::new(builder.build()),
@@ -247,10 +279,26 @@ where
         ssl_conf.set_alpn_protos(alpn.to_wire_preference()).unwrap();
     }
 
+    // resume the last session with this peer, see ConnectorOptions::tls_session_cache_size
+    // OpenSSL only keeps the leaf certificate of the server in serialized sessions, so pinned
+    // peers always do full handshakes to have their whole chain checked against the pins.
+    let cache = tls_ctx.context().ex_data(*SESSION_CACHE_INDEX);
+    if let Some(cache) = cache.filter(|_| peer.spki_pins().is_empty()) {
+        let key = session_key(peer);
+        if let Some(der) = cache.get(key) {
+            let session =
+                SslSession::from_der(&der).or_err(InternalError, "invalid TLS session")?;
+            // safety: the session is serialized from a connection of the same SslContext
+            unsafe { ssl_conf.set_session(&session) }
+                .or_err(InternalError, "failed to set TLS session")?;
+        }
+        ssl_conf.set_ex_data(*SESSION_KEY_INDEX, key);
+    }
+
     clear_error_stack();
     let connect_future = handshake(ssl_conf, peer.sni(), stream);
 
-    match peer.connection_timeout() {
+    let stream = match peer.connection_timeout() {
         Some(t) => match pingora_timeout::timeout(t, connect_future).await {
             Ok(res) => res,
             Err(_) => Error::e_explain(
@@ -259,5 +307,7 @@ where
             ),
         },
         None => connect_future.await,
-    }
+    }?;
+    verify_spki_pins(peer, stream.ssl_digest().as_deref())?;
+    Ok(stream)
 }
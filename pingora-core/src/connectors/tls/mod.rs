@@ -24,6 +24,14 @@ mod rustls;
 #[cfg(feature = "rustls")]
 pub use rustls::*;
 
+#[cfg(feature = "any_tls")]
+mod session_cache;
+
+#[cfg(feature = "any_tls")]
+use crate::{protocols::tls::SslDigest, upstreams::peer::Peer, utils::tls::get_spki_sha256};
+#[cfg(feature = "any_tls")]
+use pingora_error::{Error, ErrorType::CertPinMismatch, Result};
+
 ///    OpenSSL considers underscores in hostnames non-compliant.
 ///    We replace the underscore in the leftmost label as we must support these
 ///    hostnames for wildcard matches and we have not patched OpenSSL.
@@ -50,6 +58,29 @@ pub fn replace_leftmost_underscore(sni: &str) -> Option<String> {
     None
 }
 
+/// Check the certificate chain of an upstream connection against the SPKI pins of its peer
+///
+/// The chain passes if one of its certificates has a pinned public key.
+#[cfg(feature = "any_tls")]
+pub(crate) fn verify_spki_pins<P: Peer>(peer: &P, digest: Option<&SslDigest>) -> Result<()> {
+    let pins = peer.spki_pins();
+    if pins.is_empty() {
+        return Ok(());
+    }
+    let chain = digest.map_or(&[][..], |d| d.peer_cert_chain());
+    if chain
+        .iter()
+        .filter_map(|cert| get_spki_sha256(cert))
+        .any(|spki| pins.contains(&spki))
+    {
+        return Ok(());
+    }
+    Error::e_explain(
+        CertPinMismatch,
+        format!("no certificate of {peer} matches its SPKI pins"),
+    )
+}
+
 #[cfg(feature = "any_tls")]
 #[cfg(test)]
 mod tests {
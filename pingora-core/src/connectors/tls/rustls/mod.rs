@@ -12,8 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
+use ahash::AHasher;
 use log::debug;
 use pingora_error::{
     Error,
@@ -22,14 +24,20 @@ use pingora_error::{
 };
 use pingora_rustls::{
     load_ca_file_into_store, load_certs_and_key_files, load_platform_certs_incl_env_into_store,
-    version, CertificateDer, ClientConfig as RusTlsClientConfig, PrivateKeyDer, RootCertStore,
-    TlsConnector as RusTlsConnector,
+    version, CertificateDer, ClientConfig as RusTlsClientConfig, ClientSessionMemoryCache,
+    PrivateKeyDer, Resumption, RootCertStore, TlsConnector as RusTlsConnector,
 };
 
 use crate::protocols::tls::{client::handshake, TlsStream};
 use crate::{connectors::ConnectorOptions, listeners::ALPN, protocols::IO, upstreams::peer::Peer};
 
-use super::replace_leftmost_underscore;
+use super::session_cache::{session_key, SessionCache};
+use super::{replace_leftmost_underscore, verify_spki_pins};
+
+// Each peer has its own session store, which only ever sees the one server name of the peer.
+// The store keeps up to 8 tickets per server name and evicts a server name as soon as it is
+// full, so it is sized for two server names.
+const SESSIONS_PER_PEER: usize = 16;
 
 #[derive(Clone)]
 pub struct Connector {
@@ -48,6 +56,8 @@ impl Connector {
 pub struct TlsConnector {
     config: Arc<RusTlsClientConfig>,
     ca_certs: Arc<RootCertStore>,
+    // the client configs of the peers, each with the session store of its peer
+    peer_configs: Option<SessionCache<Arc<RusTlsClientConfig>>>,
 }
 
 impl TlsConnector {
@@ -101,26 +111,58 @@ impl TlsConnector {
             }
             None => builder.with_no_client_auth(),
         };
+        // without a session cache, rustls resumes the sessions per server name by default
+        let peer_configs = options
+            .as_ref()
+            .and_then(|conf| SessionCache::new(conf.tls_session_cache_size));
 
         Ok(Connector {
             ctx: Arc::new(TlsConnector {
                 config: Arc::new(config),
                 ca_certs: Arc::new(ca_certs),
+                peer_configs,
             }),
         })
     }
 }
 
-pub async fn connect<T, P>(
-    stream: T,
+// The client config to connect to the given peer
+//
+// With a session cache, each peer gets its own config whose session store only resumes the
+// sessions of that peer. The config is kept in the cache along with the sessions.
+fn peer_config<P: Peer>(
     peer: &P,
     alpn_override: Option<ALPN>,
     tls_ctx: &TlsConnector,
-) -> Result<TlsStream<T>>
-where
-    T: IO,
-    P: Peer + Send + Sync,
-{
+) -> Result<Arc<RusTlsClientConfig>> {
+    let Some(cache) = tls_ctx.peer_configs.as_ref() else {
+        return Ok(build_peer_config(peer, alpn_override, tls_ctx)?
+            .map_or_else(|| tls_ctx.config.clone(), Arc::new));
+    };
+
+    let mut hasher = AHasher::default();
+    session_key(peer).hash(&mut hasher);
+    alpn_override.as_ref().or(peer.get_alpn()).hash(&mut hasher);
+    let key = hasher.finish();
+    if let Some(config) = cache.get(key) {
+        return Ok(config);
+    }
+    let mut config = build_peer_config(peer, alpn_override, tls_ctx)?
+        .unwrap_or_else(|| RusTlsClientConfig::clone(&tls_ctx.config));
+    config.resumption =
+        Resumption::store(Arc::new(ClientSessionMemoryCache::new(SESSIONS_PER_PEER)));
+    let config = Arc::new(config);
+    cache.put(key, config.clone());
+    Ok(config)
+}
+
+// The client config to connect to the given peer, `None` if the default one of the connector
+// can be used as is
+fn build_peer_config<P: Peer>(
+    peer: &P,
+    alpn_override: Option<ALPN>,
+    tls_ctx: &TlsConnector,
+) -> Result<Option<RusTlsClientConfig>> {
     let config = &tls_ctx.config;
 
     // TODO: setup CA/verify cert store from peer
@@ -177,19 +219,11 @@ where
     // TODO: curve setup from peer
     // - second key share from peer, currently only used in boringssl with PQ features
 
-    let tls_conn = if let Some(cfg) = updated_config_opt {
-        RusTlsConnector::from(Arc::new(cfg))
-    } else {
-        RusTlsConnector::from(Arc::clone(config))
-    };
-
-    // TODO: for consistent behavior between TLS providers some additions are required
-    // - allowing to disable verification
-    // - the validation/replace logic would need adjustments to match the boringssl/openssl behavior
-    //   implementing a custom certificate_verifier could be used to achieve matching behavior
-    //let d_conf = config.dangerous();
-    //d_conf.set_certificate_verifier(...);
+    Ok(updated_config_opt)
+}
 
+// The name to verify the certificate of the peer against
+fn server_name<P: Peer>(peer: &P) -> String {
     let mut domain = peer.sni().to_string();
     if peer.verify_cert() && peer.verify_hostname() {
         // TODO: streamline logic with replacing first underscore within TLS implementations
@@ -197,10 +231,44 @@ where
             domain = sni_s;
         }
     }
+    domain
+}
 
+/// The TLS config and server name of a QUIC connection to the given peer
+///
+/// QUIC connections only negotiate TLS 1.3 and `h3` regardless of the ALPN of the peer.
+#[cfg(feature = "quic")]
+pub(crate) fn quic_config<P: Peer>(
+    peer: &P,
+    tls_ctx: &TlsConnector,
+) -> Result<(Arc<RusTlsClientConfig>, String)> {
+    let config = peer_config(peer, Some(ALPN::H3), tls_ctx)?;
+    Ok((config, server_name(peer)))
+}
+
+pub async fn connect<T, P>(
+    stream: T,
+    peer: &P,
+    alpn_override: Option<ALPN>,
+    tls_ctx: &TlsConnector,
+) -> Result<TlsStream<T>>
+where
+    T: IO,
+    P: Peer + Send + Sync,
+{
+    let tls_conn = RusTlsConnector::from(peer_config(peer, alpn_override, tls_ctx)?);
+
+    // TODO: for consistent behavior between TLS providers some additions are required
+    // - allowing to disable verification
+    // - the validation/replace logic would need adjustments to match the boringssl/openssl behavior
+    //   implementing a custom certificate_verifier could be used to achieve matching behavior
+    //let d_conf = config.dangerous();
+    //d_conf.set_certificate_verifier(...);
+
+    let domain = server_name(peer);
     let connect_future = handshake(&tls_conn, &domain, stream);
 
-    match peer.connection_timeout() {
+    let stream = match peer.connection_timeout() {
         Some(t) => match pingora_timeout::timeout(t, connect_future).await {
             Ok(res) => res,
             Err(_) => Error::e_explain(
@@ -209,5 +277,7 @@ where
             ),
         },
         None => connect_future.await,
-    }
+    }?;
+    verify_spki_pins(peer, stream.ssl_digest().as_deref())?;
+    Ok(stream)
 }
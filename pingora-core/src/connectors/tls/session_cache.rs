@@ -0,0 +1,58 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Upstream TLS session resumption
+
+use crate::upstreams::peer::Peer;
+use ahash::AHasher;
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+
+/// An LRU cache of the TLS sessions of upstream peers, to resume them on reconnect
+pub(crate) struct SessionCache<S> {
+    sessions: Mutex<LruCache<u64, S>>,
+}
+
+impl<S: Clone> SessionCache<S> {
+    // None when the size is 0, i.e. session resumption is disabled
+    pub(crate) fn new(size: usize) -> Option<Self> {
+        NonZeroUsize::new(size).map(|size| SessionCache {
+            sessions: Mutex::new(LruCache::new(size)),
+        })
+    }
+
+    pub(crate) fn get(&self, key: u64) -> Option<S> {
+        self.sessions.lock().get(&key).cloned()
+    }
+
+    pub(crate) fn put(&self, key: u64, session: S) {
+        self.sessions.lock().put(key, session);
+    }
+}
+
+/// The key of the TLS sessions of the given peer in the [SessionCache]
+///
+/// A session is only resumed with the same peer, SNI and certificate verification settings that
+/// it is established with.
+pub(crate) fn session_key<P: Peer>(peer: &P) -> u64 {
+    let mut hasher = AHasher::default();
+    peer.reuse_hash().hash(&mut hasher);
+    peer.sni().hash(&mut hasher);
+    peer.verify_cert().hash(&mut hasher);
+    peer.verify_hostname().hash(&mut hasher);
+    peer.alternative_cn().hash(&mut hasher);
+    hasher.finish()
+}
@@ -15,8 +15,9 @@
 use log::warn;
 use pingora_error::{
     ErrorType::{AcceptError, BindError},
-    OrErr, Result,
+    OkOrErr, OrErr, Result,
 };
+use socket2::{Domain, Protocol, Socket, Type};
 use std::fs::Permissions;
 use std::io::ErrorKind;
 use std::net::{SocketAddr, ToSocketAddrs};
@@ -27,7 +28,7 @@ use std::os::unix::net::UnixListener as StdUnixListener;
 #[cfg(windows)]
 use std::os::windows::io::{AsRawSocket, FromRawSocket};
 use std::time::Duration;
-use tokio::net::TcpSocket;
+use tokio::net::{TcpSocket, UdpSocket};
 
 use crate::protocols::l4::ext::{set_dscp, set_tcp_fastopen_backlog};
 use crate::protocols::l4::listener::Listener;
@@ -86,6 +87,12 @@ pub struct TcpSocketOptions {
     /// Specifies the server should set the following DSCP value on outgoing connections.
     /// See the [RFC](https://datatracker.ietf.org/doc/html/rfc2474) for more details.
     pub dscp: Option<u8>,
+    /// Expect a [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+    /// header (either v1 or v2) at the beginning of every accepted connection.
+    ///
+    /// The addresses in the header will be reported as the client and server addresses of the
+    /// connection. Connections without a valid header are rejected.
+    pub proxy_protocol: bool,
     // TODO: allow configuring reuseaddr, backlog, etc. from here?
 }
 
@@ -177,8 +184,15 @@ fn from_raw_fd(address: &ServerAddress, fd: i32) -> Result<Listener> {
         #[cfg(unix)]
         ServerAddress::Uds(addr, perm) => {
             let std_listener = unsafe { StdUnixListener::from_raw_fd(fd) };
-            // set permissions just in case
-            uds::set_perms(addr, perm.clone())?;
+            // set permissions just in case, on the path the socket is bound to as `addr` may be
+            // the name of a socket passed by systemd
+            let local_addr = std_listener.local_addr().ok();
+            let path = local_addr
+                .as_ref()
+                .and_then(|a| a.as_pathname())
+                .and_then(|p| p.to_str())
+                .unwrap_or(addr);
+            uds::set_perms(path, perm.clone())?;
             Ok(uds::set_backlog(std_listener, LISTENER_BACKLOG)?.into())
         }
         ServerAddress::Tcp(_, _) => {
@@ -322,6 +336,13 @@ impl ListenerEndpoint {
         self.listen_addr.as_ref()
     }
 
+    /// Whether accepted connections are expected to start with a PROXY protocol header
+    pub fn proxy_protocol(&self) -> bool {
+        self.listen_addr
+            .tcp_sock_opts()
+            .is_some_and(|op| op.proxy_protocol)
+    }
+
     fn apply_stream_settings(&self, stream: &mut Stream) -> Result<()> {
         // settings are applied based on whether the underlying stream supports it
         stream.set_nodelay()?;
@@ -351,9 +372,144 @@ impl ListenerEndpoint {
     }
 }
 
+/// UDP socket configuration options, this is used for setting options on listening UDP sockets.
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+pub struct UdpSocketOptions {
+    /// IPV6_V6ONLY flag (if true, limit socket to IPv6 communication only).
+    pub ipv6_only: Option<bool>,
+    /// Set the size of the receive buffer (SO_RCVBUF) of the socket.
+    pub recv_buffer_size: Option<usize>,
+    /// Specifies the server should set the following DSCP value on outgoing datagrams.
+    /// See the [RFC](https://datatracker.ietf.org/doc/html/rfc2474) for more details.
+    pub dscp: Option<u8>,
+}
+
+// UDP sockets share the fd table with the stream listeners, which may listen to the same address
+pub(crate) fn udp_fd_key(addr: &str) -> String {
+    format!("udp://{addr}")
+}
+
+fn bind_udp(addr: &str, opt: Option<&UdpSocketOptions>) -> Result<UdpSocket> {
+    let sock_addr = addr
+        .to_socket_addrs() // NOTE: this could invoke a blocking network lookup
+        .or_err_with(BindError, || format!("Invalid listen address {addr}"))?
+        .next() // take the first one for now
+        .or_err_with(BindError, || format!("Invalid listen address {addr}"))?;
+
+    let socket = Socket::new(
+        Domain::for_address(sock_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )
+    .or_err_with(BindError, || format!("fail to create address {sock_addr}"))?;
+    if let Some(ipv6_only) = opt.and_then(|o| o.ipv6_only) {
+        socket
+            .set_only_v6(ipv6_only)
+            .or_err(BindError, "failed to set IPV6_V6ONLY")?;
+    }
+    if let Some(size) = opt.and_then(|o| o.recv_buffer_size) {
+        socket
+            .set_recv_buffer_size(size)
+            .or_err(BindError, "failed to set SO_RCVBUF")?;
+    }
+    socket
+        .bind(&sock_addr.into())
+        .or_err_with(BindError, || format!("bind() failed on {addr}"))?;
+    socket
+        .set_nonblocking(true)
+        .or_err(BindError, "failed to set nonblocking")?;
+    let socket = UdpSocket::from_std(socket.into())
+        .or_err(BindError, "Failed to convert to tokio socket")?;
+
+    if let Some(dscp) = opt.and_then(|o| o.dscp) {
+        #[cfg(unix)]
+        set_dscp(socket.as_raw_fd(), dscp)?;
+        #[cfg(windows)]
+        set_dscp(socket.as_raw_socket(), dscp)?;
+    }
+    Ok(socket)
+}
+
+#[cfg(unix)]
+fn udp_from_raw_fd(fd: i32) -> Result<UdpSocket> {
+    let std_socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    std_socket
+        .set_nonblocking(true)
+        .or_err(BindError, "failed to set nonblocking")?;
+    UdpSocket::from_std(std_socket).or_err(BindError, "Failed to convert to tokio socket")
+}
+
+/// A UDP socket bound to a listening address
+///
+/// Unlike [ListenerEndpoint], there is no connection to accept: datagrams from all the clients
+/// are received from and sent through the same socket.
+pub struct UdpListenerEndpoint {
+    listen_addr: String,
+    socket: UdpSocket,
+}
+
+impl UdpListenerEndpoint {
+    /// Bind a UDP socket to the given address.
+    ///
+    /// If `fds` contains a socket for this address, passed from the old process during graceful
+    /// upgrade, that socket is reused. Otherwise a new socket is bound and added to `fds`.
+    #[cfg(unix)]
+    pub async fn bind(
+        addr: &str,
+        opt: Option<&UdpSocketOptions>,
+        fds: Option<ListenFds>,
+    ) -> Result<Self> {
+        let socket = if let Some(fds_table) = fds {
+            let key = udp_fd_key(addr);
+            let mut table = fds_table.lock().await;
+            if let Some(fd) = table.get(&key) {
+                udp_from_raw_fd(*fd)?
+            } else {
+                let socket = bind_udp(addr, opt)?;
+                table.add(key, socket.as_raw_fd());
+                socket
+            }
+        } else {
+            bind_udp(addr, opt)?
+        };
+        Ok(UdpListenerEndpoint {
+            listen_addr: addr.to_string(),
+            socket,
+        })
+    }
+
+    /// Bind a UDP socket to the given address.
+    #[cfg(windows)]
+    pub async fn bind(addr: &str, opt: Option<&UdpSocketOptions>) -> Result<Self> {
+        Ok(UdpListenerEndpoint {
+            listen_addr: addr.to_string(),
+            socket: bind_udp(addr, opt)?,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.listen_addr
+    }
+
+    /// Return the bound socket.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Consume `self` and return the bound socket.
+    pub fn into_socket(self) -> UdpSocket {
+        self.socket
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    #[cfg(unix)]
+    use crate::server::Fds;
+    #[cfg(unix)]
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn test_listen_tcp() {
@@ -427,4 +583,65 @@ mod test {
             .await
             .expect("can connect to UDS listener");
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listen_uds_by_name() {
+        let addr = "/tmp/test_listen_uds_by_name";
+        let _ = std::fs::remove_file(addr);
+        let std_listener = StdUnixListener::bind(addr).unwrap();
+        std_listener.set_nonblocking(true).unwrap();
+        let fds = Arc::new(tokio::sync::Mutex::new(Fds::new()));
+        // e.g. a socket passed by systemd with its FileDescriptorName
+        fds.lock()
+            .await
+            .add("uds-name".to_string(), std_listener.as_raw_fd());
+        std::mem::forget(std_listener);
+
+        let mut builder = ListenerEndpoint::builder();
+        builder.listen_addr(ServerAddress::Uds("uds-name".into(), None));
+        let mut listener = builder.listen(Some(fds)).await.unwrap();
+
+        tokio::spawn(async move {
+            listener.accept().await.unwrap();
+        });
+        tokio::net::UnixStream::connect(addr)
+            .await
+            .expect("can connect to UDS listener");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listen_udp() {
+        let fds = Arc::new(tokio::sync::Mutex::new(Fds::new()));
+        let listener = UdpListenerEndpoint::bind("127.0.0.1:7105", None, Some(fds.clone()))
+            .await
+            .unwrap();
+        // the socket is recorded separately from a stream listener of the same address
+        let fd = *fds.lock().await.get("udp://127.0.0.1:7105").unwrap();
+        assert_eq!(fd, listener.socket().as_raw_fd());
+        assert!(fds.lock().await.get("127.0.0.1:7105").is_none());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hello", "127.0.0.1:7105").await.unwrap();
+        let mut buf = [0; 16];
+        let (n, from) = listener.socket().recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(from, client.local_addr().unwrap());
+
+        // the socket passed from the old process is reused
+        let fd = unsafe { libc::dup(fd) };
+        let upgraded_fds = Arc::new(tokio::sync::Mutex::new(Fds::new()));
+        upgraded_fds
+            .lock()
+            .await
+            .add("udp://127.0.0.1:7105".to_string(), fd);
+        let upgraded = UdpListenerEndpoint::bind("127.0.0.1:7105", None, Some(upgraded_fds))
+            .await
+            .unwrap();
+        assert_eq!(upgraded.socket().as_raw_fd(), fd);
+        client.send_to(b"again", "127.0.0.1:7105").await.unwrap();
+        let (n, _) = upgraded.socket().recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"again");
+    }
 }
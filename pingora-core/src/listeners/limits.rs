@@ -0,0 +1,269 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limits on the downstream connections accepted by the listeners
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+static CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pingora_limited_connections",
+        "current number of downstream connections of the listeners with connection limits",
+        &["name"]
+    )
+    .unwrap()
+});
+
+static REFUSED_CONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pingora_refused_connections_total",
+        "number of downstream connections closed right after accept() for exceeding a limit",
+        &["name"]
+    )
+    .unwrap()
+});
+
+/// The limits on the connections of a listening endpoint or of all the endpoints of a
+/// [Listeners](super::Listeners).
+#[non_exhaustive]
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionLimits {
+    /// The maximum number of concurrent connections. Unlimited if `None`.
+    pub max_connections: Option<usize>,
+    /// What to do with new connections once `max_connections` is reached: if true, stop
+    /// accepting so that they wait in the listen backlog until other connections close. Otherwise
+    /// accept and close them right away.
+    pub queue: bool,
+    /// The maximum number of connections accepted per second. Unlimited if `None`.
+    pub max_accept_rate: Option<u32>,
+}
+
+/// Enforce [ConnectionLimits] for the listeners sharing it.
+pub(crate) struct ConnectionLimiter {
+    name: String,
+    limits: ConnectionLimits,
+    slots: Option<Arc<Semaphore>>,
+    // when the next connection can be accepted under max_accept_rate
+    next_accept: Mutex<Instant>,
+}
+
+impl ConnectionLimiter {
+    /// Create a limiter whose metrics are labeled with `name`.
+    pub fn new(name: String, limits: ConnectionLimits) -> Self {
+        ConnectionLimiter {
+            name,
+            slots: limits
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max.min(Semaphore::MAX_PERMITS)))),
+            limits,
+            next_accept: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until a new connection would be within the limits, without taking any of them so
+    /// that other endpoints sharing this limiter can still accept.
+    ///
+    /// The connection accepted afterwards takes its share via [Self::pace()] and
+    /// [Self::reserve()].
+    pub async fn ready(&self) {
+        if self.limits.max_accept_rate.is_some_and(|r| r > 0) {
+            let at = *self.next_accept.lock();
+            tokio::time::sleep_until(at).await;
+        }
+        if let Some(slots) = self.slots.as_ref().filter(|_| self.limits.queue) {
+            // the semaphore is never closed
+            drop(slots.acquire().await);
+        }
+    }
+
+    /// Wait until the next connection can be accepted under the accept rate.
+    pub async fn pace(&self) {
+        let Some(rate) = self.limits.max_accept_rate.filter(|r| *r > 0) else {
+            return;
+        };
+        let interval = Duration::from_secs(1) / rate;
+        let at = {
+            let mut next = self.next_accept.lock();
+            let at = (*next).max(Instant::now());
+            *next = at + interval;
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+
+    /// Wait for a free connection slot if new connections are queued.
+    pub async fn reserve(&self) -> Option<OwnedSemaphorePermit> {
+        let slots = self.slots.as_ref().filter(|_| self.limits.queue)?;
+        // the semaphore is never closed
+        slots.clone().acquire_owned().await.ok()
+    }
+
+    /// Take a free connection slot for a connection accepted without [Self::reserve()].
+    ///
+    /// Return false if the connection should be refused.
+    pub fn admit(&self, permit: &mut ConnectionPermit) -> bool {
+        if let Some(slots) = self.slots.as_ref().filter(|_| !self.limits.queue) {
+            match slots.clone().try_acquire_owned() {
+                Ok(slot) => permit.slots.push(slot),
+                Err(_) => {
+                    REFUSED_CONNECTIONS.with_label_values(&[&self.name]).inc();
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Count a new connection until `permit` is dropped.
+    pub fn count(&self, permit: &mut ConnectionPermit) {
+        let gauge = CONNECTIONS.with_label_values(&[&self.name]);
+        gauge.inc();
+        permit.gauges.push(gauge);
+    }
+}
+
+/// The connection slots and the counts that an accepted connection holds until it is closed
+#[derive(Default)]
+pub(crate) struct ConnectionPermit {
+    slots: Vec<OwnedSemaphorePermit>,
+    gauges: Vec<prometheus::IntGauge>,
+}
+
+impl ConnectionPermit {
+    pub fn add_slot(&mut self, slot: OwnedSemaphorePermit) {
+        self.slots.push(slot);
+    }
+}
+
+impl std::fmt::Debug for ConnectionPermit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionPermit")
+            .field("slots", &self.slots.len())
+            .finish()
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        for gauge in self.gauges.iter() {
+            gauge.dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limiter(
+        max_connections: Option<usize>,
+        queue: bool,
+        rate: Option<u32>,
+    ) -> ConnectionLimiter {
+        let limits = ConnectionLimits {
+            max_connections,
+            queue,
+            max_accept_rate: rate,
+        };
+        ConnectionLimiter::new("test".into(), limits)
+    }
+
+    #[test]
+    fn test_admit() {
+        let limiter = limiter(Some(1), false, None);
+        let mut permit1 = ConnectionPermit::default();
+        assert!(limiter.admit(&mut permit1));
+        let mut permit2 = ConnectionPermit::default();
+        assert!(!limiter.admit(&mut permit2));
+        drop(permit1);
+        assert!(limiter.admit(&mut permit2));
+
+        // unlimited
+        let limiter = ConnectionLimiter::new("test".into(), ConnectionLimits::default());
+        for _ in 0..10 {
+            assert!(limiter.admit(&mut ConnectionPermit::default()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reserve() {
+        let limiter = limiter(Some(1), true, None);
+        let slot = limiter.reserve().await.unwrap();
+        let reserve = limiter.reserve();
+        tokio::pin!(reserve);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut reserve)
+                .await
+                .is_err()
+        );
+        drop(slot);
+        assert!(reserve.await.is_some());
+        // nothing to reserve without queueing
+        assert!(
+            ConnectionLimiter::new("test".into(), ConnectionLimits::default())
+                .reserve()
+                .await
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_count() {
+        let limiter = ConnectionLimiter::new("test_count".into(), ConnectionLimits::default());
+        let gauge = CONNECTIONS.with_label_values(&["test_count"]);
+        let mut permit = ConnectionPermit::default();
+        limiter.count(&mut permit);
+        assert_eq!(gauge.get(), 1);
+        drop(permit);
+        assert_eq!(gauge.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let limiter = limiter(Some(1), true, Some(100));
+        limiter.ready().await;
+        // nothing is taken by ready()
+        limiter.ready().await;
+        let slot = limiter.reserve().await.unwrap();
+        limiter.pace().await;
+        let ready = limiter.ready();
+        tokio::pin!(ready);
+        assert!(tokio::time::timeout(Duration::from_millis(20), &mut ready)
+            .await
+            .is_err());
+        drop(slot);
+        assert!(tokio::time::timeout(Duration::from_millis(20), ready)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pace() {
+        let limiter = limiter(None, false, Some(100));
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.pace().await;
+        }
+        // the first one goes right away, then one every 10ms
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(40), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(100), "{elapsed:?}");
+    }
+}
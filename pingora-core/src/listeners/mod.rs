@@ -15,6 +15,9 @@
 //! The listening endpoints (TCP and TLS) and their configurations.
 
 mod l4;
+mod limits;
+#[cfg(feature = "quic")]
+mod quic;
 
 #[cfg(feature = "any_tls")]
 pub mod tls;
@@ -22,20 +25,35 @@ pub mod tls;
 #[cfg(not(feature = "any_tls"))]
 pub use crate::tls::listeners as tls;
 
-use crate::protocols::{tls::TlsRef, Stream};
+use crate::protocols::proxy_protocol::{read_proxy_header, ProxyHeader};
+use crate::protocols::{tls::TlsRef, GetSocketDigest, SocketDigest, Stream};
 
 #[cfg(unix)]
 use crate::server::ListenFds;
 
 use async_trait::async_trait;
-use pingora_error::Result;
+use log::debug;
+use pingora_error::{ErrorType::ReadTimedout, OrErr, Result};
+use pingora_timeout::timeout;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+use std::time::Duration;
 use std::{fs::Permissions, sync::Arc};
 
 use l4::{ListenerEndpoint, Stream as L4Stream};
+use limits::ConnectionLimiter;
 use tls::{Acceptor, TlsSettings};
 
 pub use crate::protocols::tls::ALPN;
-pub use l4::{ServerAddress, TcpSocketOptions};
+#[cfg(unix)]
+pub(crate) use l4::udp_fd_key;
+pub use l4::{ServerAddress, TcpSocketOptions, UdpListenerEndpoint, UdpSocketOptions};
+pub use limits::ConnectionLimits;
+pub(crate) use limits::ConnectionPermit;
+#[cfg(feature = "quic")]
+pub use quic::{QuicConnection, QuicListenerEndpoint, QuicSettings, UninitializedQuicConnection};
 
 /// The APIs to customize things like certificate during TLS server side handshake
 #[async_trait]
@@ -43,24 +61,36 @@ pub trait TlsAccept {
     // TODO: return error?
     /// This function is called in the middle of a TLS handshake. Structs who
     /// implement this function should provide tls certificate and key to the
-    /// [TlsRef] via `ssl_use_certificate` and `ssl_use_private_key`.
-    /// Note. This is only supported for openssl and boringssl
+    /// [TlsRef] via `ssl_use_certificate` and `ssl_use_private_key` for openssl and boringssl,
+    /// or via `set_certified_key` for rustls.
     async fn certificate_callback(&self, _ssl: &mut TlsRef) -> () {
         // does nothing by default
     }
 }
 
+#[async_trait]
+impl<T: TlsAccept + Send + Sync> TlsAccept for Arc<T> {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) -> () {
+        self.as_ref().certificate_callback(ssl).await
+    }
+}
+
 pub type TlsAcceptCallbacks = Box<dyn TlsAccept + Send + Sync>;
 
+// How long to wait for the PROXY protocol header of a new connection
+const PROXY_PROTOCOL_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct TransportStackBuilder {
     l4: ServerAddress,
     tls: Option<TlsSettings>,
+    limits: Option<ConnectionLimits>,
 }
 
 impl TransportStackBuilder {
     pub async fn build(
         &mut self,
         #[cfg(unix)] upgrade_listeners: Option<ListenFds>,
+        shared_limiter: Option<Arc<ConnectionLimiter>>,
     ) -> Result<TransportStack> {
         let mut builder = ListenerEndpoint::builder();
 
@@ -72,9 +102,18 @@ impl TransportStackBuilder {
         #[cfg(windows)]
         let l4 = builder.listen().await?;
 
+        let limiters = self
+            .limits
+            .take()
+            .map(|limits| Arc::new(ConnectionLimiter::new(l4.as_str().to_string(), limits)))
+            .into_iter()
+            .chain(shared_limiter)
+            .collect();
+
         Ok(TransportStack {
             l4,
             tls: self.tls.take().map(|tls| Arc::new(tls.build())),
+            limiters,
         })
     }
 }
@@ -82,6 +121,8 @@ impl TransportStackBuilder {
 pub(crate) struct TransportStack {
     l4: ListenerEndpoint,
     tls: Option<Arc<Acceptor>>,
+    // the limits of this endpoint and of all the endpoints of its service
+    limiters: Vec<Arc<ConnectionLimiter>>,
 }
 
 impl TransportStack {
@@ -90,11 +131,43 @@ impl TransportStack {
     }
 
     pub async fn accept(&mut self) -> Result<UninitializedStream> {
-        let stream = self.l4.accept().await?;
-        Ok(UninitializedStream {
-            l4: stream,
-            tls: self.tls.clone(),
-        })
+        loop {
+            // only wait for the limits here: an idle endpoint must not hold what the other
+            // endpoints sharing the limits need
+            for limiter in self.limiters.iter() {
+                limiter.ready().await;
+            }
+            let mut stream = self.l4.accept().await?;
+            if !self.limiters.is_empty() {
+                let mut permit = ConnectionPermit::default();
+                for limiter in self.limiters.iter() {
+                    limiter.pace().await;
+                }
+                // another endpoint may have taken the slot in the meantime, so this connection
+                // waits for the next one
+                for limiter in self.limiters.iter() {
+                    if let Some(slot) = limiter.reserve().await {
+                        permit.add_slot(slot);
+                    }
+                }
+                if !self.limiters.iter().all(|l| l.admit(&mut permit)) {
+                    debug!(
+                        "Too many connections on {}, closing a new one",
+                        self.as_str()
+                    );
+                    continue;
+                }
+                for limiter in self.limiters.iter() {
+                    limiter.count(&mut permit);
+                }
+                stream.set_connection_permit(permit);
+            }
+            return Ok(UninitializedStream {
+                l4: stream,
+                tls: self.tls.clone(),
+                proxy_protocol: self.l4.proxy_protocol(),
+            });
+        }
     }
 
     pub fn cleanup(&mut self) {
@@ -105,10 +178,17 @@ impl TransportStack {
 pub(crate) struct UninitializedStream {
     l4: L4Stream,
     tls: Option<Arc<Acceptor>>,
+    proxy_protocol: bool,
 }
 
 impl UninitializedStream {
-    pub async fn handshake(self) -> Result<Stream> {
+    pub async fn handshake(mut self) -> Result<Stream> {
+        if self.proxy_protocol {
+            let header = timeout(PROXY_PROTOCOL_READ_TIMEOUT, read_proxy_header(&mut self.l4))
+                .await
+                .or_err(ReadTimedout, "while reading PROXY protocol header")??;
+            set_proxy_header(&mut self.l4, header);
+        }
         if let Some(tls) = self.tls {
             let tls_stream = tls.tls_handshake(self.l4).await?;
             Ok(Box::new(tls_stream))
@@ -118,15 +198,41 @@ impl UninitializedStream {
     }
 }
 
+// Replace the socket digest of the stream so that the addresses from the PROXY header are
+// reported instead of the ones of the load balancer.
+fn set_proxy_header(stream: &mut L4Stream, header: ProxyHeader) {
+    #[cfg(unix)]
+    let digest = SocketDigest::from_raw_fd(stream.as_raw_fd());
+    #[cfg(windows)]
+    let digest = SocketDigest::from_raw_socket(stream.as_raw_socket());
+    if header.is_proxied() {
+        let _ = digest.peer_addr.set(header.source.clone());
+        let _ = digest.local_addr.set(header.destination.clone());
+    } else if let Some(old) = stream.get_socket_digest() {
+        let _ = digest.peer_addr.set(old.peer_addr().cloned());
+    }
+    let _ = digest.proxy_header.set(Box::new(header));
+    stream.set_socket_digest(digest);
+}
+
 /// The struct to hold one more multiple listening endpoints
 pub struct Listeners {
     stacks: Vec<TransportStackBuilder>,
+    // the limits shared by all the endpoints, along with the name of their metrics
+    limits: Option<(String, ConnectionLimits)>,
+    #[cfg(feature = "quic")]
+    quic: Vec<(String, Option<UdpSocketOptions>, QuicSettings)>,
 }
 
 impl Listeners {
     /// Create a new [`Listeners`] with no listening endpoints.
     pub fn new() -> Self {
-        Listeners { stacks: vec![] }
+        Listeners {
+            stacks: vec![],
+            limits: None,
+            #[cfg(feature = "quic")]
+            quic: vec![],
+        }
     }
 
     /// Create a new [`Listeners`] with a TCP server endpoint from the given string.
@@ -188,6 +294,25 @@ impl Listeners {
         self.add_endpoint(ServerAddress::Tcp(addr.into(), sock_opt), Some(settings));
     }
 
+    /// Add a QUIC endpoint serving HTTP/3 to `self` with the given certificate and key paths.
+    #[cfg(feature = "quic")]
+    pub fn add_quic(&mut self, addr: &str, cert_path: &str, key_path: &str) -> Result<()> {
+        self.add_quic_with_settings(addr, None, QuicSettings::new(cert_path, key_path)?);
+        Ok(())
+    }
+
+    /// Add a QUIC endpoint serving HTTP/3 to `self` with the given socket and QUIC settings.
+    /// See [`QuicSettings`] and [`UdpSocketOptions`] for more details.
+    #[cfg(feature = "quic")]
+    pub fn add_quic_with_settings(
+        &mut self,
+        addr: &str,
+        sock_opt: Option<UdpSocketOptions>,
+        settings: QuicSettings,
+    ) {
+        self.quic.push((addr.to_string(), sock_opt, settings));
+    }
+
     /// Add the given [`ServerAddress`] to `self`.
     pub fn add_address(&mut self, addr: ServerAddress) {
         self.add_endpoint(addr, None);
@@ -195,7 +320,38 @@ impl Listeners {
 
     /// Add the given [`ServerAddress`] to `self` with the given [`TlsSettings`] if provided
     pub fn add_endpoint(&mut self, l4: ServerAddress, tls: Option<TlsSettings>) {
-        self.stacks.push(TransportStackBuilder { l4, tls })
+        self.stacks.push(TransportStackBuilder {
+            l4,
+            tls,
+            limits: None,
+        })
+    }
+
+    /// Add the given [`ServerAddress`] to `self` with the given [`TlsSettings`] if provided and
+    /// the [`ConnectionLimits`] of this endpoint alone.
+    ///
+    /// The current number of connections is reported by the `pingora_limited_connections` metric
+    /// labeled with the address.
+    pub fn add_endpoint_with_limits(
+        &mut self,
+        l4: ServerAddress,
+        tls: Option<TlsSettings>,
+        limits: ConnectionLimits,
+    ) {
+        self.stacks.push(TransportStackBuilder {
+            l4,
+            tls,
+            limits: Some(limits),
+        })
+    }
+
+    /// Set the [`ConnectionLimits`] on the connections of all the endpoints of `self` combined,
+    /// on top of the limits of each endpoint.
+    ///
+    /// The current number of connections is reported by the `pingora_limited_connections` metric
+    /// labeled with `name`.
+    pub fn set_connection_limits(&mut self, name: &str, limits: ConnectionLimits) {
+        self.limits = Some((name.to_string(), limits));
     }
 
     pub(crate) async fn build(
@@ -203,12 +359,17 @@ impl Listeners {
         #[cfg(unix)] upgrade_listeners: Option<ListenFds>,
     ) -> Result<Vec<TransportStack>> {
         let mut stacks = Vec::with_capacity(self.stacks.len());
+        let shared_limiter = self
+            .limits
+            .take()
+            .map(|(name, limits)| Arc::new(ConnectionLimiter::new(name, limits)));
 
         for b in self.stacks.iter_mut() {
             let new_stack = b
                 .build(
                     #[cfg(unix)]
                     upgrade_listeners.clone(),
+                    shared_limiter.clone(),
                 )
                 .await?;
 
@@ -218,6 +379,26 @@ impl Listeners {
         Ok(stacks)
     }
 
+    #[cfg(feature = "quic")]
+    pub(crate) async fn build_quic(
+        &mut self,
+        #[cfg(unix)] upgrade_listeners: Option<ListenFds>,
+    ) -> Result<Vec<QuicListenerEndpoint>> {
+        let mut endpoints = Vec::with_capacity(self.quic.len());
+        for (addr, sock_opt, settings) in self.quic.iter() {
+            let endpoint = QuicListenerEndpoint::bind(
+                addr,
+                sock_opt.as_ref(),
+                settings,
+                #[cfg(unix)]
+                upgrade_listeners.clone(),
+            )
+            .await?;
+            endpoints.push(endpoint);
+        }
+        Ok(endpoints)
+    }
+
     pub(crate) fn cleanup(&self) {
         // placeholder
     }
@@ -262,6 +443,59 @@ mod test {
         TcpStream::connect(addr2).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_listen_tcp_proxy_protocol() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let addr = "127.0.0.1:7104";
+        let mut listeners = Listeners::new();
+        listeners.add_tcp_with_settings(
+            addr,
+            TcpSocketOptions {
+                proxy_protocol: true,
+                ..Default::default()
+            },
+        );
+        let mut listener = listeners
+            .build(
+                #[cfg(unix)]
+                None,
+            )
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+
+        let server = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap();
+            let mut stream = stream.handshake().await.unwrap();
+            let digest = stream.get_socket_digest().unwrap();
+            assert_eq!(digest.peer_addr().unwrap().to_string(), "192.168.0.1:56324");
+            assert_eq!(digest.local_addr().unwrap().to_string(), "192.168.0.11:443");
+            assert!(digest.proxy_header().unwrap().is_proxied());
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // a connection without a valid header is rejected
+            let stream = listener.accept().await.unwrap();
+            let e = stream.handshake().await.unwrap_err();
+            assert_eq!(e.etype(), &pingora_error::ErrorType::InvalidProxyProtocol);
+        });
+
+        // make sure the above starts before the lines below
+        sleep(Duration::from_millis(10)).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nhello")
+            .await
+            .unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        server.await.unwrap();
+    }
+
     #[tokio::test]
     #[cfg(feature = "any_tls")]
     async fn test_listen_tls() {
@@ -303,4 +537,184 @@ mod test {
         let res = client.get(format!("https://{addr}")).send().await.unwrap();
         assert_eq!(res.status(), reqwest::StatusCode::OK);
     }
+
+    #[tokio::test]
+    #[cfg(feature = "any_tls")]
+    async fn test_listen_tls_fingerprint() {
+        use tokio::io::AsyncReadExt;
+
+        let addr = "127.0.0.1:7105";
+        let cert_path = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let key_path = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        let mut settings = TlsSettings::intermediate(&cert_path, &key_path).unwrap();
+        settings.enable_client_hello_fingerprint();
+        let mut listeners = Listeners::new();
+        listeners.add_tls_with_settings(addr, None, settings);
+        let mut listener = listeners
+            .build(
+                #[cfg(unix)]
+                None,
+            )
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+
+        let server = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap();
+            let mut stream = stream.handshake().await.unwrap();
+            let digest = stream.get_ssl_digest().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na")
+                .await
+                .unwrap();
+            digest
+        });
+        // make sure the above starts before the lines below
+        sleep(Duration::from_millis(10)).await;
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let res = client.get(format!("https://{addr}")).send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        let digest = server.await.unwrap();
+        assert_eq!(digest.ja3.as_ref().unwrap().len(), 32);
+        // no SNI is sent to an IP address
+        assert!(digest.ja4.as_ref().unwrap().starts_with("t13i"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits_refuse() {
+        use tokio::io::AsyncReadExt;
+
+        let addr = "127.0.0.1:7106";
+        let limits = ConnectionLimits {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        let mut listeners = Listeners::new();
+        listeners.add_endpoint_with_limits(ServerAddress::Tcp(addr.into(), None), None, limits);
+        let mut listener = listeners
+            .build(
+                #[cfg(unix)]
+                None,
+            )
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let stream = listener.accept().await.unwrap();
+                tx.send(stream.handshake().await.unwrap()).unwrap();
+            }
+        });
+
+        let _client1 = TcpStream::connect(addr).await.unwrap();
+        let server1 = rx.recv().await.unwrap();
+        // closed right away
+        let mut client2 = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(client2.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(rx.try_recv().is_err());
+
+        drop(server1);
+        let _client3 = TcpStream::connect(addr).await.unwrap();
+        rx.recv().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits_queue() {
+        let addr1 = "127.0.0.1:7107";
+        let addr2 = "127.0.0.1:7108";
+        let limits = ConnectionLimits {
+            max_connections: Some(1),
+            queue: true,
+            ..Default::default()
+        };
+        let mut listeners = Listeners::tcp(addr1);
+        listeners.add_tcp(addr2);
+        // shared by both endpoints
+        listeners.set_connection_limits("test_connection_limits_queue", limits);
+        let built = listeners
+            .build(
+                #[cfg(unix)]
+                None,
+            )
+            .await
+            .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for mut listener in built {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let stream = listener.accept().await.unwrap();
+                    tx.send(stream.handshake().await.unwrap()).unwrap();
+                }
+            });
+        }
+
+        let _client1 = TcpStream::connect(addr1).await.unwrap();
+        let server1 = rx.recv().await.unwrap();
+        // waits in the backlog of the other endpoint
+        let _client2 = TcpStream::connect(addr2).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+
+        drop(server1);
+        rx.recv().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits_queue_idle_endpoint() {
+        let addr1 = "127.0.0.1:7109";
+        let addr2 = "127.0.0.1:7110";
+        let limits = ConnectionLimits {
+            max_connections: Some(1),
+            queue: true,
+            ..Default::default()
+        };
+        let mut listeners = Listeners::tcp(addr1);
+        listeners.add_tcp(addr2);
+        listeners.set_connection_limits("test_connection_limits_queue_idle_endpoint", limits);
+        let built = listeners
+            .build(
+                #[cfg(unix)]
+                None,
+            )
+            .await
+            .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for mut listener in built {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let stream = listener.accept().await.unwrap();
+                    tx.send(stream.handshake().await.unwrap()).unwrap();
+                }
+            });
+        }
+        sleep(Duration::from_millis(10)).await;
+
+        // the idle first endpoint doesn't hold the only slot
+        let _client1 = TcpStream::connect(addr2).await.unwrap();
+        let server1 = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let _client2 = TcpStream::connect(addr2).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+
+        drop(server1);
+        tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
 }
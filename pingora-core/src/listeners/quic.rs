@@ -0,0 +1,225 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! QUIC listening endpoints for HTTP/3
+
+use super::{UdpListenerEndpoint, UdpSocketOptions};
+use crate::protocols::http::v3::{connection_digest, ALPN_H3};
+use crate::protocols::{Digest, SocketDigest};
+#[cfg(unix)]
+use crate::server::ListenFds;
+
+use log::debug;
+use pingora_error::{Error, ErrorType::*, OrErr, Result};
+use pingora_rustls::{load_certs_and_key_files, version, ServerConfig};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{EndpointConfig, IdleTimeout, TokioRuntime, TransportConfig, VarInt};
+use std::net::SocketAddr as InetSocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The QUIC settings of a listening endpoint
+///
+/// Only TLS 1.3 is used by QUIC, and the ALPN is always `h3`.
+pub struct QuicSettings {
+    cert_path: String,
+    key_path: String,
+    /// How long a connection without any traffic is kept. Default 30 seconds.
+    pub max_idle_timeout: Duration,
+    /// The number of requests a client can send concurrently on a connection. Default 100.
+    pub max_concurrent_streams: u32,
+}
+
+impl QuicSettings {
+    /// Create a new [`QuicSettings`] with the given certificate and key paths.
+    pub fn new(cert_path: &str, key_path: &str) -> Result<Self> {
+        Ok(QuicSettings {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            max_idle_timeout: Duration::from_secs(30),
+            max_concurrent_streams: 100,
+        })
+    }
+
+    fn build(&self) -> Result<quinn::ServerConfig> {
+        let Some((certs, key)) = load_certs_and_key_files(&self.cert_path, &self.key_path)? else {
+            return Error::e_explain(
+                InvalidCert,
+                format!(
+                    "No certificate or private key found in \"{}\" or \"{}\"",
+                    self.cert_path, self.key_path
+                ),
+            );
+        };
+        let mut tls = ServerConfig::builder_with_protocol_versions(&[&version::TLS13])
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .explain_err(InvalidCert, |e| format!("Invalid certified key: {e}"))?;
+        tls.alpn_protocols = vec![ALPN_H3.to_vec()];
+        let crypto = QuicServerConfig::try_from(tls)
+            .explain_err(InternalError, |e| format!("Invalid QUIC TLS config: {e}"))?;
+
+        let mut transport = TransportConfig::default();
+        let idle_timeout = IdleTimeout::try_from(self.max_idle_timeout)
+            .explain_err(InternalError, |e| format!("Invalid QUIC idle timeout: {e}"))?;
+        transport.max_idle_timeout(Some(idle_timeout));
+        transport.max_concurrent_bidi_streams(VarInt::from_u32(self.max_concurrent_streams));
+
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+        Ok(config)
+    }
+}
+
+/// A QUIC endpoint accepting the connections of the clients on a UDP socket
+///
+/// The socket takes part in graceful upgrade like [UdpListenerEndpoint]. The QUIC connections
+/// are not transferred though: once the new process reads from the socket, the packets of the
+/// connections established by the old process may be received by either process. Every process
+/// has its own stateless reset key, so the resets the new process answers those packets with are
+/// ignored by the clients of the old one.
+pub struct QuicListenerEndpoint {
+    listen_addr: String,
+    endpoint: quinn::Endpoint,
+    local_addr: Option<InetSocketAddr>,
+    #[cfg(unix)]
+    raw_fd: std::os::unix::io::RawFd,
+    #[cfg(windows)]
+    raw_sock: std::os::windows::io::RawSocket,
+}
+
+impl QuicListenerEndpoint {
+    /// Bind a QUIC endpoint to the given address.
+    ///
+    /// See [UdpListenerEndpoint::bind()] for how `fds` is used.
+    pub async fn bind(
+        addr: &str,
+        opt: Option<&UdpSocketOptions>,
+        settings: &QuicSettings,
+        #[cfg(unix)] fds: Option<ListenFds>,
+    ) -> Result<Self> {
+        let config = settings.build()?;
+        #[cfg(unix)]
+        let udp = UdpListenerEndpoint::bind(addr, opt, fds).await?;
+        #[cfg(windows)]
+        let udp = UdpListenerEndpoint::bind(addr, opt).await?;
+        let socket = udp
+            .into_socket()
+            .into_std()
+            .or_err(BindError, "Failed to convert to std socket")?;
+        let local_addr = socket.local_addr().ok();
+        #[cfg(unix)]
+        let raw_fd = socket.as_raw_fd();
+        #[cfg(windows)]
+        let raw_sock = socket.as_raw_socket();
+        let endpoint = quinn::Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(TokioRuntime),
+        )
+        .or_err_with(BindError, || {
+            format!("Failed to create QUIC endpoint on {addr}")
+        })?;
+        Ok(QuicListenerEndpoint {
+            listen_addr: addr.to_string(),
+            endpoint,
+            local_addr,
+            #[cfg(unix)]
+            raw_fd,
+            #[cfg(windows)]
+            raw_sock,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.listen_addr
+    }
+
+    /// Return the local address of the UDP socket.
+    pub fn local_addr(&self) -> Option<InetSocketAddr> {
+        self.local_addr
+    }
+
+    /// Wait for a new connection. `None` when the endpoint is closed.
+    pub async fn accept(&self) -> Option<UninitializedQuicConnection> {
+        let incoming = self.endpoint.accept().await?;
+        Some(UninitializedQuicConnection {
+            incoming,
+            local_addr: self.local_addr,
+            #[cfg(unix)]
+            raw_fd: self.raw_fd,
+            #[cfg(windows)]
+            raw_sock: self.raw_sock,
+        })
+    }
+
+    /// Stop accepting new connections. The established ones are not affected.
+    ///
+    /// The Initial packets of new connections are dropped instead of refused: during a graceful
+    /// upgrade the new process reads from the same socket, and the retransmissions of the clients
+    /// reach it eventually.
+    pub fn stop(&self) {
+        let endpoint = self.endpoint.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                debug!(
+                    "Ignoring QUIC connection from {}",
+                    incoming.remote_address()
+                );
+                incoming.ignore();
+            }
+        });
+    }
+}
+
+/// A new QUIC connection whose handshake is not done yet
+pub struct UninitializedQuicConnection {
+    incoming: quinn::Incoming,
+    local_addr: Option<InetSocketAddr>,
+    #[cfg(unix)]
+    raw_fd: std::os::unix::io::RawFd,
+    #[cfg(windows)]
+    raw_sock: std::os::windows::io::RawSocket,
+}
+
+impl UninitializedQuicConnection {
+    /// Complete the QUIC handshake.
+    pub async fn handshake(self) -> Result<QuicConnection> {
+        let connection = self
+            .incoming
+            .await
+            .or_err(HandshakeError, "while QUIC handshaking with client")?;
+        debug!("QUIC handshake done with {}", connection.remote_address());
+
+        #[cfg(unix)]
+        let socket_digest = SocketDigest::from_raw_fd(self.raw_fd);
+        #[cfg(windows)]
+        let socket_digest = SocketDigest::from_raw_socket(self.raw_sock);
+        let digest = connection_digest(&connection, socket_digest, self.local_addr);
+        Ok(QuicConnection { connection, digest })
+    }
+}
+
+/// An established QUIC connection
+pub struct QuicConnection {
+    /// The QUIC connection
+    pub connection: quinn::Connection,
+    /// The digest of the connection, shared by all the requests on it
+    pub digest: Digest,
+}
@@ -0,0 +1,196 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The CSR of an order, the self-signed certificate of a TLS-ALPN-01 challenge and the expiry of
+//! an issued certificate
+
+use pingora_error::{OkOrErr, OrErr, Result};
+use rcgen::{
+    CertificateParams, CustomExtension, DistinguishedName, DnType, KeyPair as CertKeyPair,
+    PKCS_ECDSA_P256_SHA256,
+};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime};
+
+use super::key::KeyPair;
+use super::{TlsAlpnCert, ACME_ERR};
+use crate::listeners::tls::ocsp::{
+    parse_generalized_time, Der, CONTEXT_0, GENERALIZED_TIME, SEQUENCE,
+};
+
+const INTEGER: u8 = 0x02;
+const UTC_TIME: u8 = 0x17;
+
+// The parameters of a certificate or a CSR for `names` with the first one as the common name
+fn params(names: &[String]) -> Result<CertificateParams> {
+    let first = names
+        .first()
+        .or_err(ACME_ERR, "no name for the certificate")?;
+    let mut params = CertificateParams::new(names).or_err(ACME_ERR, "invalid name")?;
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, first.as_str());
+    Ok(params)
+}
+
+/// A PKCS#10 certificate signing request for the given names, see RFC 2986 4
+pub(super) fn csr(names: &[String], key: &KeyPair) -> Result<Vec<u8>> {
+    let key = key.to_signing_key()?;
+    let csr = params(names)?
+        .serialize_request(&key)
+        .or_err(ACME_ERR, "fail to create CSR")?;
+    Ok(csr.der().to_vec())
+}
+
+/// A self-signed certificate for `name` with a new key, answering the TLS-ALPN-01 challenge of
+/// the given key authorization, see RFC 8737 3
+///
+/// The certificate is valid from a day ago for `validity`.
+pub(super) fn challenge_certificate(
+    name: &str,
+    key_authorization: &str,
+    validity: Duration,
+) -> Result<TlsAlpnCert> {
+    let key = CertKeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+        .or_err(ACME_ERR, "fail to generate key")?;
+    let now = SystemTime::now();
+    let mut params = params(&[name.to_string()])?;
+    params.not_before = (now - Duration::from_secs(24 * 3600)).into();
+    params.not_after = (now + validity).into();
+    let digest = Sha256::digest(key_authorization);
+    params
+        .custom_extensions
+        .push(CustomExtension::new_acme_identifier(&digest));
+    let cert = params
+        .self_signed(&key)
+        .or_err(ACME_ERR, "fail to create challenge certificate")?;
+    Ok(TlsAlpnCert {
+        cert: cert.der().to_vec(),
+        key: key.serialize_der(),
+    })
+}
+
+/// The notAfter of the given DER encoded certificate, see RFC 5280 4.1
+pub(super) fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let mut tbs = Der(Der(Der(cert).expect(SEQUENCE)?).expect(SEQUENCE)?);
+    if tbs.peek_tag()? == CONTEXT_0 {
+        tbs.read()?; // version
+    }
+    tbs.expect(INTEGER)?; // serialNumber
+    tbs.expect(SEQUENCE)?; // signature
+    tbs.expect(SEQUENCE)?; // issuer
+    let mut validity = Der(tbs.expect(SEQUENCE)?);
+    validity.read()?; // notBefore
+    match validity.read()? {
+        (UTC_TIME, time) => parse_utc_time(time),
+        (GENERALIZED_TIME, time) => parse_generalized_time(time),
+        _ => None,
+    }
+}
+
+// YYMMDDHHMMSSZ, where YY below 50 is in the 21st century, see RFC 5280 4.1.2.5.1
+fn parse_utc_time(time: &[u8]) -> Option<SystemTime> {
+    let year = std::str::from_utf8(time.get(..2)?)
+        .ok()?
+        .parse::<u8>()
+        .ok()?;
+    let century: &[u8] = if year < 50 { b"20" } else { b"19" };
+    parse_generalized_time(&[century, time].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_utc_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(parse_utc_time(b"231114221320Z"), Some(time));
+        assert_eq!(
+            parse_utc_time(b"991231235959Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(946_684_799))
+        );
+        assert_eq!(parse_utc_time(b"9912"), None);
+    }
+
+    #[test]
+    fn test_challenge_certificate() {
+        let validity = Duration::from_secs(7 * 24 * 3600);
+        let challenge = challenge_certificate("example.com", "token.thumb", validity).unwrap();
+        let cert = challenge.cert;
+        let expires = not_after(&cert).unwrap();
+        let expected = SystemTime::now() + validity;
+        assert!(expires <= expected && expires + Duration::from_secs(5) > expected);
+
+        #[cfg(feature = "openssl_derived")]
+        {
+            use crate::tls::pkey::PKey;
+            use crate::tls::x509::X509;
+            let cert = X509::from_der(&cert).unwrap();
+            let public_key = cert.public_key().unwrap();
+            assert!(cert.verify(&public_key).unwrap());
+            let key = PKey::private_key_from_der(&challenge.key).unwrap();
+            assert!(public_key.public_eq(&key));
+            let names = cert.subject_alt_names().unwrap();
+            assert_eq!(names.get(0).unwrap().dnsname(), Some("example.com"));
+        }
+        #[cfg(feature = "rustls")]
+        {
+            use crate::listeners::tls::ocsp::OCTET_STRING;
+            use x509_parser::prelude::{FromDer, X509Certificate};
+            let (_, cert) = X509Certificate::from_der(&cert).unwrap();
+            let acme_identifier = cert
+                .extensions()
+                .iter()
+                .find(|ext| ext.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+                .unwrap();
+            assert!(acme_identifier.critical);
+            let mut expected = vec![OCTET_STRING, 32];
+            expected.extend_from_slice(&Sha256::digest("token.thumb"));
+            assert_eq!(acme_identifier.value, expected);
+        }
+    }
+
+    #[test]
+    fn test_csr() {
+        let key = KeyPair::generate().unwrap();
+        let names = vec!["example.com".to_string(), "www.example.com".to_string()];
+        let csr = csr(&names, &key).unwrap();
+
+        #[cfg(feature = "openssl_derived")]
+        {
+            use crate::tls::x509::X509Req;
+            let req = X509Req::from_der(&csr).unwrap();
+            assert!(req.verify(&req.public_key().unwrap()).unwrap());
+        }
+        #[cfg(feature = "rustls")]
+        {
+            use x509_parser::prelude::{FromDer, X509CertificationRequest};
+            let (_, req) = X509CertificationRequest::from_der(&csr).unwrap();
+            assert_eq!(
+                req.certification_request_info.subject.to_string(),
+                "CN=example.com"
+            );
+            assert_eq!(
+                &req.certification_request_info
+                    .subject_pki
+                    .subject_public_key
+                    .data[..],
+                key.public_key().unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The ACME protocol, see RFC 8555
+
+use http::header;
+use log::debug;
+use pingora_error::{Error, OkOrErr, OrErr, Result};
+use pingora_http::RequestHeader;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::Duration;
+
+use super::key::{b64, KeyPair};
+use super::ACME_ERR;
+use crate::connectors::http::Connector;
+use crate::upstreams::peer::HttpPeer;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// ACME responses are small JSON documents and certificate chains
+const MAX_BODY_LEN: usize = 1024 * 1024;
+// how many times a request rejected for a bad nonce is retried, see RFC 8555 6.5
+const BAD_NONCE_RETRIES: usize = 3;
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+/// The resources of an ACME server, see RFC 8555 7.1.1
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// An error document, see RFC 7807
+#[derive(Debug, Deserialize)]
+pub(super) struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.detail)
+    }
+}
+
+/// See RFC 8555 7.1.3
+#[derive(Debug, Deserialize)]
+pub(super) struct Order {
+    pub status: String,
+    #[serde(default)]
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+    pub error: Option<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct Identifier {
+    pub value: String,
+}
+
+/// See RFC 8555 7.1.4
+#[derive(Debug, Deserialize)]
+pub(super) struct Authorization {
+    pub status: String,
+    pub identifier: Identifier,
+    #[serde(default)]
+    pub challenges: Vec<Challenge>,
+}
+
+/// See RFC 8555 8
+#[derive(Debug, Deserialize)]
+pub(super) struct Challenge {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    #[serde(default)]
+    pub token: String,
+    pub error: Option<Problem>,
+}
+
+struct Response {
+    status: u16,
+    location: Option<String>,
+    nonce: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json<T: for<'de> Deserialize<'de>>(&self, url: &str) -> Result<T> {
+        serde_json::from_slice(&self.body)
+            .or_err_with(ACME_ERR, || format!("invalid response from {url}"))
+    }
+
+    fn location(&self, url: &str) -> Result<String> {
+        self.location.clone().or_err_with(ACME_ERR, || {
+            format!("no Location in the response from {url}")
+        })
+    }
+}
+
+/// A client of an ACME server acting on behalf of the account of its key
+pub(super) struct AcmeClient {
+    connector: Connector,
+    directory: Directory,
+    key: KeyPair,
+    // the account URL once registered, which replaces the JWK in the requests
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetch the directory of the ACME server at `directory_url`
+    pub async fn new(connector: Connector, directory_url: &str, key: KeyPair) -> Result<Self> {
+        let response = request(&connector, "GET", directory_url, None).await?;
+        if response.status != 200 {
+            return Error::e_explain(
+                ACME_ERR,
+                format!(
+                    "fail to fetch directory {directory_url}: {}",
+                    response.status
+                ),
+            );
+        }
+        let directory = response.json(directory_url)?;
+        Ok(AcmeClient {
+            connector,
+            directory,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    /// The key authorization of the challenge with the given token, see RFC 8555 8.1
+    pub fn key_authorization(&self, token: &str) -> Result<String> {
+        Ok(format!("{token}.{}", self.key.thumbprint()?))
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let url = self.directory.new_nonce.clone();
+        request(&self.connector, "GET", &url, None)
+            .await?
+            .nonce
+            .or_err_with(ACME_ERR, || format!("no nonce from {url}"))
+    }
+
+    // The JWS of `payload` for `url`, see RFC 8555 6.2
+    fn sign(&self, url: &str, nonce: String, payload: &str) -> Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match self.kid.as_ref() {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.key.jwk()?,
+        }
+        let protected = b64(protected.to_string().as_bytes());
+        let payload = b64(payload.as_bytes());
+        let signature = b64(&self.key.sign(format!("{protected}.{payload}").as_bytes())?);
+        Ok(
+            json!({ "protected": protected, "payload": payload, "signature": signature })
+                .to_string()
+                .into_bytes(),
+        )
+    }
+
+    // POST the payload to `url`, or POST-as-GET if there is none
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response> {
+        let payload = payload.map_or_else(String::new, Value::to_string);
+        let mut retries = 0;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, nonce, &payload)?;
+            let mut response = request(&self.connector, "POST", url, Some(body)).await?;
+            self.nonce = response.nonce.take();
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem: Option<Problem> = serde_json::from_slice(&response.body).ok();
+            if problem.as_ref().is_some_and(|p| p.kind == BAD_NONCE) && retries < BAD_NONCE_RETRIES
+            {
+                debug!("bad nonce for {url}, retrying");
+                retries += 1;
+                continue;
+            }
+            let problem = problem.map_or_else(String::new, |p| p.to_string());
+            return Error::e_explain(
+                ACME_ERR,
+                format!("{url} responded {}: {problem}", response.status),
+            );
+        }
+    }
+
+    /// Find or create the account of the key, see RFC 8555 7.3
+    pub async fn register(
+        &mut self,
+        contact: &[String],
+        terms_of_service_agreed: bool,
+    ) -> Result<()> {
+        let url = self.directory.new_account.clone();
+        let payload = json!({
+            "termsOfServiceAgreed": terms_of_service_agreed,
+            "contact": contact,
+        });
+        let response = self.post(&url, Some(&payload)).await?;
+        self.kid = Some(response.location(&url)?);
+        Ok(())
+    }
+
+    /// Order a certificate for the given names, returning the URL of the order and the order
+    /// itself, see RFC 8555 7.4
+    pub async fn new_order(&mut self, names: &[String]) -> Result<(String, Order)> {
+        let url = self.directory.new_order.clone();
+        let identifiers: Vec<Value> = names
+            .iter()
+            .map(|name| json!({ "type": "dns", "value": name }))
+            .collect();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        Ok((response.location(&url)?, response.json(&url)?))
+    }
+
+    pub async fn order(&mut self, url: &str) -> Result<Order> {
+        self.post(url, None).await?.json(url)
+    }
+
+    pub async fn authorization(&mut self, url: &str) -> Result<Authorization> {
+        self.post(url, None).await?.json(url)
+    }
+
+    /// Tell the server that the challenge at `url` is ready to be validated, see RFC 8555 7.5.1
+    pub async fn respond(&mut self, url: &str) -> Result<()> {
+        self.post(url, Some(&json!({}))).await.map(|_| ())
+    }
+
+    /// Send the DER encoded CSR of the order, see RFC 8555 7.4
+    pub async fn finalize(&mut self, url: &str, csr: &[u8]) -> Result<Order> {
+        self.post(url, Some(&json!({ "csr": b64(csr) })))
+            .await?
+            .json(url)
+    }
+
+    /// Download the PEM certificate chain of a valid order
+    pub async fn certificate(&mut self, url: &str) -> Result<Vec<u8>> {
+        Ok(self.post(url, None).await?.body)
+    }
+}
+
+async fn request(
+    connector: &Connector,
+    method: &str,
+    url: &str,
+    body: Option<Vec<u8>>,
+) -> Result<Response> {
+    let uri: http::Uri = url
+        .parse()
+        .or_err_with(ACME_ERR, || format!("invalid URL {url}"))?;
+    let tls = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Error::e_explain(ACME_ERR, format!("unsupported URL {url}")),
+    };
+    let authority = uri
+        .authority()
+        .or_err_with(ACME_ERR, || format!("no host in URL {url}"))?;
+    let port = authority.port_u16().unwrap_or(if tls { 443 } else { 80 });
+    let addr = tokio::net::lookup_host((authority.host(), port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .or_err_with(ACME_ERR, || format!("fail to resolve {authority}"))?;
+
+    let mut peer = HttpPeer::new(addr, tls, authority.host().to_string());
+    peer.options.connection_timeout = Some(TIMEOUT);
+    peer.options.read_timeout = Some(TIMEOUT);
+    peer.options.write_timeout = Some(TIMEOUT);
+
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let mut req = RequestHeader::build(method, path.as_bytes(), None)?;
+    req.insert_header(header::HOST, authority.as_str())?;
+    req.insert_header(header::USER_AGENT, "pingora-acme")?;
+    if let Some(body) = body.as_ref() {
+        req.insert_header(header::CONTENT_TYPE, "application/jose+json")?;
+        req.insert_header(header::CONTENT_LENGTH, body.len())?;
+    }
+
+    let (mut session, _) = connector.get_http_session(&peer).await?;
+    session.write_request_header(Box::new(req)).await?;
+    if let Some(body) = body {
+        session.write_request_body(body.into(), true).await?;
+    }
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+    let resp = session
+        .response_header()
+        .or_err(ACME_ERR, "no response header")?;
+    let header = |name: &str| {
+        resp.headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let status = resp.status.as_u16();
+    let location = header(header::LOCATION.as_str());
+    let nonce = header("Replay-Nonce");
+
+    let mut body = vec![];
+    while let Some(chunk) = session.read_response_body().await? {
+        if body.len() + chunk.len() > MAX_BODY_LEN {
+            return Error::e_explain(ACME_ERR, format!("response from {url} is too large"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    connector
+        .release_http_session(session, &peer, Some(IDLE_TIMEOUT))
+        .await;
+    debug!("{method} {url}: {status}");
+    Ok(Response {
+        status,
+        location,
+        nonce,
+        body,
+    })
+}
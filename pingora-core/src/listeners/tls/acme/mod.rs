@@ -0,0 +1,995 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Certificates from an ACME certificate authority such as Let's Encrypt
+//!
+//! An [AcmeService] obtains a certificate for a set of names via the ACME protocol
+//! ([RFC 8555](https://www.rfc-editor.org/rfc/rfc8555)) and writes it to the directory of a
+//! [CertStore] as `<first name>.crt` and `<first name>.key`. The store is reloaded right away, so
+//! the listeners serving it pick up the certificate without a restart. When run as a
+//! [BackgroundService], the service renews the certificate `renew_before` its expiry.
+//!
+//! The certificate authority validates the names with one of two challenges, which the service
+//! publishes in its [AcmeChallenges]:
+//! - HTTP-01: the proxy serving port 80 of the names answers
+//!   `/.well-known/acme-challenge/<token>`. `pingora-proxy` does so once the challenges are set
+//!   to its `acme_challenges` field, other apps can call [AcmeChallenges::http_01_response()].
+//! - TLS-ALPN-01 ([RFC 8737](https://www.rfc-editor.org/rfc/rfc8737)): the TLS listener on
+//!   port 443 of the names answers the `acme-tls/1` handshakes of the certificate authority with
+//!   a challenge certificate. The challenges are set to the listener via
+//!   `TlsSettings::set_acme_challenges()`.
+//!
+//! The operator has to agree to the terms of service of the certificate authority by setting
+//! [AcmeService::terms_of_service_agreed] before any certificate is obtained.
+//!
+//! The account key is created in `account_key_path` on first use. The certificate key is kept
+//! across renewals.
+//!
+//! ```ignore
+//! let store = Arc::new(CertStore::new("/etc/pingora/certs")?);
+//! let mut acme = AcmeService::new(LETS_ENCRYPT_DIRECTORY, &["example.com"], store.clone());
+//! acme.terms_of_service_agreed = true;
+//! proxy.app_logic_mut().unwrap().acme_challenges = Some(acme.challenges());
+//! server.add_service(background_service("acme", acme));
+//! ```
+
+use async_trait::async_trait;
+use log::{error, info};
+use parking_lot::RwLock;
+use pingora_error::{Error, ErrorType::*, OkOrErr, OrErr, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::CertStore;
+use crate::connectors::http::Connector;
+use crate::connectors::ConnectorOptions;
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+
+mod cert;
+mod client;
+mod key;
+
+use client::AcmeClient;
+use key::{pem_decode, KeyPair};
+
+/// The directory of the production environment of Let's Encrypt
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// The directory of the staging environment of Let's Encrypt
+pub const LETS_ENCRYPT_STAGING_DIRECTORY: &str =
+    "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// The ALPN protocol of TLS-ALPN-01 challenges
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// The path prefix of HTTP-01 challenges
+pub const HTTP_01_PATH: &str = "/.well-known/acme-challenge/";
+
+/// The default time before the expiry of the certificate to renew it
+pub const DEFAULT_RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// The default interval to check whether the certificate needs to be renewed
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// The default interval to retry after a failed attempt to obtain the certificate
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+const ACCOUNT_KEY_FILE: &str = "acme-account.pem";
+// how long and how often the state of authorizations and orders is polled
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+// the validity of the certificates answering TLS-ALPN-01 challenges
+const CHALLENGE_CERT_VALIDITY: Duration = Duration::from_secs(7 * 24 * 3600);
+
+const ACME_ERR: pingora_error::ErrorType = Custom("ACMEError");
+
+/// The error of a TLS handshake that answered a TLS-ALPN-01 challenge, after which the
+/// connection is closed
+pub(crate) const CHALLENGE_ANSWERED: pingora_error::ErrorType = Custom("ACMEChallengeAnswered");
+
+/// The challenge used to prove the control of the names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeType {
+    /// Serve the key authorization over plain HTTP on port 80
+    Http01,
+    /// Serve a challenge certificate to `acme-tls/1` handshakes on port 443
+    TlsAlpn01,
+}
+
+impl ChallengeType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+/// The certificate and the PKCS#8 key answering a TLS-ALPN-01 challenge
+pub(crate) struct TlsAlpnCert {
+    pub(crate) cert: Vec<u8>,
+    pub(crate) key: Vec<u8>,
+}
+
+/// The pending challenges of an [AcmeService], to be answered by the HTTP proxy or the TLS
+/// listeners
+#[derive(Default)]
+pub struct AcmeChallenges {
+    // token -> key authorization
+    http_01: RwLock<HashMap<String, String>>,
+    // name -> challenge certificate
+    tls_alpn_01: RwLock<HashMap<String, Arc<TlsAlpnCert>>>,
+}
+
+impl AcmeChallenges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The body to respond to the HTTP-01 challenge request with the given path, if there is
+    /// such a challenge
+    pub fn http_01_response(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(HTTP_01_PATH)?;
+        self.http_01.read().get(token).cloned()
+    }
+
+    /// Whether any TLS-ALPN-01 challenge is pending, so that the listeners only look for the
+    /// challenge handshakes when needed
+    pub(crate) fn has_tls_alpn_01(&self) -> bool {
+        !self.tls_alpn_01.read().is_empty()
+    }
+
+    pub(crate) fn tls_alpn_01_cert(&self, server_name: &str) -> Option<Arc<TlsAlpnCert>> {
+        let name = server_name.trim_end_matches('.').to_ascii_lowercase();
+        self.tls_alpn_01.read().get(&name).cloned()
+    }
+
+    fn add(&self, kind: ChallengeType, name: &str, token: &str, key_auth: &str) -> Result<()> {
+        match kind {
+            ChallengeType::Http01 => {
+                self.http_01
+                    .write()
+                    .insert(token.to_string(), key_auth.to_string());
+            }
+            ChallengeType::TlsAlpn01 => {
+                let cert = cert::challenge_certificate(name, key_auth, CHALLENGE_CERT_VALIDITY)?;
+                self.tls_alpn_01
+                    .write()
+                    .insert(name.to_ascii_lowercase(), Arc::new(cert));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, kind: ChallengeType, name: &str, token: &str) {
+        match kind {
+            ChallengeType::Http01 => {
+                self.http_01.write().remove(token);
+            }
+            ChallengeType::TlsAlpn01 => {
+                self.tls_alpn_01.write().remove(&name.to_ascii_lowercase());
+            }
+        }
+    }
+}
+
+// A challenge answered until dropped
+struct PendingChallenge<'a> {
+    challenges: &'a AcmeChallenges,
+    kind: ChallengeType,
+    name: String,
+    token: String,
+}
+
+impl Drop for PendingChallenge<'_> {
+    fn drop(&mut self) {
+        self.challenges.remove(self.kind, &self.name, &self.token);
+    }
+}
+
+/// Obtain and renew a certificate from an ACME certificate authority
+pub struct AcmeService {
+    directory_url: String,
+    names: Vec<String>,
+    store: Arc<CertStore>,
+    challenges: Arc<AcmeChallenges>,
+    /// The contact URLs of the account, e.g. `mailto:admin@example.com`
+    pub contact: Vec<String>,
+    /// Whether the operator agrees to the terms of service of the certificate authority. The
+    /// account is only registered once this is set. Default is false.
+    pub terms_of_service_agreed: bool,
+    /// The challenge to validate the names with. Default is [ChallengeType::Http01].
+    pub challenge_type: ChallengeType,
+    /// The PEM file of the account key. Default is `acme-account.pem` in the directory of the
+    /// [CertStore].
+    pub account_key_path: PathBuf,
+    /// The CA file to verify the ACME server with instead of the system CAs
+    pub ca_file: Option<String>,
+    /// How long before its expiry the certificate is renewed. Default is [DEFAULT_RENEW_BEFORE].
+    pub renew_before: Duration,
+    /// How often the [BackgroundService] checks the certificate. Default is
+    /// [DEFAULT_CHECK_INTERVAL].
+    pub check_interval: Duration,
+    /// How soon the [BackgroundService] tries again after a failure. Default is
+    /// [DEFAULT_RETRY_INTERVAL].
+    pub retry_interval: Duration,
+}
+
+impl AcmeService {
+    /// Create a service obtaining a certificate for `names` from the ACME server of
+    /// `directory_url` into the given [CertStore]
+    ///
+    /// Wildcard names are not supported because they can only be validated via DNS.
+    pub fn new(directory_url: &str, names: &[&str], store: Arc<CertStore>) -> Self {
+        AcmeService {
+            directory_url: directory_url.to_string(),
+            names: names.iter().map(|n| n.to_ascii_lowercase()).collect(),
+            account_key_path: store.dir().join(ACCOUNT_KEY_FILE),
+            store,
+            challenges: Arc::new(AcmeChallenges::new()),
+            contact: vec![],
+            terms_of_service_agreed: false,
+            challenge_type: ChallengeType::Http01,
+            ca_file: None,
+            renew_before: DEFAULT_RENEW_BEFORE,
+            check_interval: DEFAULT_CHECK_INTERVAL,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+        }
+    }
+
+    /// The challenges of this service, to be answered by the HTTP proxy or the TLS listeners
+    pub fn challenges(&self) -> Arc<AcmeChallenges> {
+        self.challenges.clone()
+    }
+
+    fn paths(&self) -> (PathBuf, PathBuf) {
+        let dir = self.store.dir();
+        let name = &self.names[0];
+        (
+            dir.join(format!("{name}.crt")),
+            dir.join(format!("{name}.key")),
+        )
+    }
+
+    /// Whether the certificate is missing or expires within `renew_before`
+    pub fn needs_renewal(&self) -> bool {
+        if self.names.is_empty() {
+            return false;
+        }
+        let (cert_path, _) = self.paths();
+        let not_after = std::fs::read(cert_path)
+            .ok()
+            .and_then(|pem| pem_decode(&pem, "CERTIFICATE"))
+            .and_then(|cert| cert::not_after(&cert));
+        match not_after {
+            Some(not_after) => SystemTime::now() + self.renew_before >= not_after,
+            None => true,
+        }
+    }
+
+    /// Obtain the certificate if it needs to be renewed
+    ///
+    /// Return whether a new certificate was obtained.
+    pub async fn renew_if_due(&self) -> Result<bool> {
+        if !self.needs_renewal() {
+            return Ok(false);
+        }
+        self.issue().await?;
+        Ok(true)
+    }
+
+    /// Obtain a new certificate, save it and reload the [CertStore]
+    pub async fn issue(&self) -> Result<()> {
+        if self.names.is_empty() {
+            return Error::e_explain(ACME_ERR, "no name to obtain a certificate for");
+        }
+        if let Some(name) = self.names.iter().find(|n| n.contains('*')) {
+            return Error::e_explain(ACME_ERR, format!("wildcard name {name} is not supported"));
+        }
+        if !self.terms_of_service_agreed {
+            return Error::e_explain(
+                ACME_ERR,
+                "the terms of service of the certificate authority are not agreed to, see \
+                 AcmeService::terms_of_service_agreed",
+            );
+        }
+        let account_key = load_or_create_key(&self.account_key_path)?;
+        let mut options = ConnectorOptions::new(1);
+        options.ca_file = self.ca_file.clone();
+        let mut client = AcmeClient::new(
+            Connector::new(Some(options)),
+            &self.directory_url,
+            account_key,
+        )
+        .await?;
+        client
+            .register(&self.contact, self.terms_of_service_agreed)
+            .await?;
+
+        let (order_url, order) = client.new_order(&self.names).await?;
+        for url in order.authorizations.iter() {
+            self.authorize(&mut client, url).await?;
+        }
+
+        let (cert_path, key_path) = self.paths();
+        let (cert_key, new_key) = match std::fs::read(&key_path) {
+            Ok(pem) => (KeyPair::from_pem(&pem)?, false),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (KeyPair::generate()?, true),
+            Err(e) => return Err(e).or_err_with(ACME_ERR, || format!("fail to read {key_path:?}")),
+        };
+        let csr = cert::csr(&self.names, &cert_key)?;
+        let mut order = client.finalize(&order.finalize, &csr).await?;
+        for _ in 0..POLL_ATTEMPTS {
+            match order.status.as_str() {
+                "valid" => break,
+                "pending" | "ready" | "processing" => {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    order = client.order(&order_url).await?;
+                }
+                status => {
+                    let problem = order.error.map(|p| p.to_string()).unwrap_or_default();
+                    return Error::e_explain(
+                        ACME_ERR,
+                        format!("order {order_url} is {status}: {problem}"),
+                    );
+                }
+            }
+        }
+        let cert_url = order.certificate.or_err_with(ACME_ERR, || {
+            format!("order {order_url} is not issued in time")
+        })?;
+        let chain = client.certificate(&cert_url).await?;
+        if pem_decode(&chain, "CERTIFICATE").is_none() {
+            return Error::e_explain(ACME_ERR, format!("invalid certificate from {cert_url}"));
+        }
+
+        // the key goes first: a certificate without its key is not loaded by the store
+        if new_key {
+            save(&key_path, &cert_key.to_pem()?)?;
+        }
+        save(&cert_path, &chain)?;
+        info!("Obtained certificate {cert_path:?} for {:?}", self.names);
+        self.store.reload()
+    }
+
+    async fn authorize(&self, client: &mut AcmeClient, url: &str) -> Result<()> {
+        let authz = client.authorization(url).await?;
+        if authz.status == "valid" {
+            return Ok(());
+        }
+        let name = authz.identifier.value;
+        let kind = self.challenge_type;
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.kind == kind.as_str())
+            .or_err_with(ACME_ERR, || {
+                format!("no {} challenge for {name}", kind.as_str())
+            })?;
+
+        let key_auth = client.key_authorization(&challenge.token)?;
+        self.challenges
+            .add(kind, &name, &challenge.token, &key_auth)?;
+        let _pending = PendingChallenge {
+            challenges: &self.challenges,
+            kind,
+            name: name.clone(),
+            token: challenge.token.clone(),
+        };
+        client.respond(&challenge.url).await?;
+
+        for _ in 0..POLL_ATTEMPTS {
+            let authz = client.authorization(url).await?;
+            match authz.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => {
+                    let problem = authz
+                        .challenges
+                        .iter()
+                        .find_map(|c| c.error.as_ref())
+                        .map(|p| p.to_string())
+                        .unwrap_or_default();
+                    return Error::e_explain(
+                        ACME_ERR,
+                        format!("authorization of {name} is {status}: {problem}"),
+                    );
+                }
+            }
+        }
+        Error::e_explain(ACME_ERR, format!("authorization of {name} timed out"))
+    }
+}
+
+fn load_or_create_key(path: &Path) -> Result<KeyPair> {
+    match std::fs::read(path) {
+        Ok(pem) => KeyPair::from_pem(&pem),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = KeyPair::generate()?;
+            save(path, &key.to_pem()?)?;
+            info!("Created ACME account key {path:?}");
+            Ok(key)
+        }
+        Err(e) => Err(e).or_err_with(ACME_ERR, || format!("fail to read {path:?}")),
+    }
+}
+
+// Replace the file atomically so that the store never loads a partial file
+fn save(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(&tmp)
+        .or_err_with(ACME_ERR, || format!("fail to create {tmp:?}"))?;
+    file.write_all(data)
+        .and_then(|_| file.sync_all())
+        .or_err_with(ACME_ERR, || format!("fail to write {tmp:?}"))?;
+    std::fs::rename(&tmp, path)
+        .or_err_with(ACME_ERR, || format!("fail to rename {tmp:?} to {path:?}"))
+}
+
+#[async_trait]
+impl BackgroundService for AcmeService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            let wait = match self.renew_if_due().await {
+                Ok(_) => self.check_interval,
+                Err(e) => {
+                    error!("Failed to obtain certificate for {:?}: {e}", self.names);
+                    self.retry_interval
+                }
+            };
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listeners::tls::ocsp::{Der, OCTET_STRING, SEQUENCE};
+    use crate::protocols::http::ServerSession;
+    use crate::protocols::l4::stream::Stream as L4Stream;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+    use pingora_http::ResponseHeader;
+    use rcgen::{CertificateParams, PublicKeyData, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use tokio::net::TcpListener;
+
+    fn cert_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pingora-acme-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn b64_decode(data: &str) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(data).unwrap()
+    }
+
+    // The DER encoded value of the acmeIdentifier extension for the key authorization
+    fn acme_identifier(key_auth: &str) -> Vec<u8> {
+        let mut value = vec![OCTET_STRING, 32];
+        value.extend_from_slice(&Sha256::digest(key_auth));
+        value
+    }
+
+    // The P-256 public key of a CSR in its uncompressed form
+    struct CsrPublicKey(Vec<u8>);
+
+    impl PublicKeyData for CsrPublicKey {
+        fn der_bytes(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn algorithm(&self) -> &SignatureAlgorithm {
+            &PKCS_ECDSA_P256_SHA256
+        }
+    }
+
+    #[test]
+    fn test_http_01_response() {
+        let challenges = AcmeChallenges::new();
+        assert!(challenges
+            .http_01_response("/.well-known/acme-challenge/token")
+            .is_none());
+        challenges
+            .add(ChallengeType::Http01, "example.com", "token", "token.thumb")
+            .unwrap();
+        assert_eq!(
+            challenges
+                .http_01_response("/.well-known/acme-challenge/token")
+                .unwrap(),
+            "token.thumb"
+        );
+        assert!(challenges.http_01_response("/token").is_none());
+        assert!(!challenges.has_tls_alpn_01());
+        challenges.remove(ChallengeType::Http01, "example.com", "token");
+        assert!(challenges
+            .http_01_response("/.well-known/acme-challenge/token")
+            .is_none());
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        nonce: u64,
+        nonces: Vec<String>,
+        // the thumbprint of the account key
+        thumbprint: Option<String>,
+        // name -> authorization status
+        authorizations: HashMap<String, &'static str>,
+        orders: usize,
+        bad_nonces: usize,
+        cert: Option<Vec<u8>>,
+    }
+
+    // A minimal ACME server over plain HTTP that validates the challenges in process
+    struct MockCa {
+        base: String,
+        challenges: Arc<AcmeChallenges>,
+        issuer: rcgen::Certificate,
+        issuer_key: rcgen::KeyPair,
+        state: Mutex<MockState>,
+    }
+
+    impl MockCa {
+        async fn start(challenges: Arc<AcmeChallenges>) -> Arc<Self> {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer_key = rcgen::KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+            let issuer = CertificateParams::new(vec![])
+                .unwrap()
+                .self_signed(&issuer_key)
+                .unwrap();
+            let ca = Arc::new(MockCa {
+                base: format!("http://{}", listener.local_addr().unwrap()),
+                challenges,
+                issuer,
+                issuer_key,
+                state: Mutex::new(MockState::default()),
+            });
+            let server = ca.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        let mut stream: crate::protocols::Stream = Box::new(L4Stream::from(stream));
+                        loop {
+                            let mut session = ServerSession::new_http1(stream);
+                            session.set_keepalive(Some(60));
+                            if !session.read_request().await.unwrap_or(false) {
+                                return;
+                            }
+                            server.serve(&mut session).await;
+                            match session.finish().await {
+                                Ok(Some(s)) => stream = s,
+                                _ => return,
+                            }
+                        }
+                    });
+                }
+            });
+            ca
+        }
+
+        fn directory(&self) -> String {
+            format!("{}/dir", self.base)
+        }
+
+        fn new_nonce(&self) -> String {
+            let mut state = self.state.lock();
+            state.nonce += 1;
+            let nonce = format!("nonce-{}", state.nonce);
+            state.nonces.push(nonce.clone());
+            nonce
+        }
+
+        async fn serve(&self, session: &mut ServerSession) {
+            let method = session.req_header().method.to_string();
+            let path = session.req_header().uri.path().to_string();
+            let mut body = vec![];
+            while let Some(chunk) = session.read_request_body().await.unwrap() {
+                body.extend_from_slice(&chunk);
+            }
+            let (status, location, body) = if method == "POST" {
+                match self.verify(&path, &body) {
+                    Ok((protected, payload)) => self.post(&path, protected, payload),
+                    Err(problem) => (400, None, problem.to_string().into_bytes()),
+                }
+            } else {
+                self.get(&path)
+            };
+
+            let mut resp = ResponseHeader::build(status, None).unwrap();
+            resp.insert_header("Replay-Nonce", self.new_nonce())
+                .unwrap();
+            resp.insert_header("Content-Length", body.len()).unwrap();
+            if let Some(location) = location {
+                resp.insert_header("Location", location).unwrap();
+            }
+            session.write_response_header(Box::new(resp)).await.unwrap();
+            session
+                .write_response_body(Bytes::from(body), true)
+                .await
+                .unwrap();
+        }
+
+        fn get(&self, path: &str) -> (u16, Option<String>, Vec<u8>) {
+            match path {
+                "/dir" => {
+                    let directory = json!({
+                        "newNonce": format!("{}/nonce", self.base),
+                        "newAccount": format!("{}/account", self.base),
+                        "newOrder": format!("{}/order", self.base),
+                    });
+                    (200, None, directory.to_string().into_bytes())
+                }
+                "/nonce" => (200, None, vec![]),
+                _ => (404, None, vec![]),
+            }
+        }
+
+        // Check the nonce and the URL of a JWS and return its protected header and payload
+        fn verify(&self, path: &str, body: &[u8]) -> std::result::Result<(Value, Value), Value> {
+            let jws: Value = serde_json::from_slice(body).unwrap();
+            let protected: Value =
+                serde_json::from_slice(&b64_decode(jws["protected"].as_str().unwrap())).unwrap();
+            let payload = b64_decode(jws["payload"].as_str().unwrap());
+            let payload = if payload.is_empty() {
+                Value::Null
+            } else {
+                serde_json::from_slice(&payload).unwrap()
+            };
+            assert_eq!(protected["alg"], "ES256");
+            assert_eq!(protected["url"], format!("{}{path}", self.base));
+            assert_eq!(b64_decode(jws["signature"].as_str().unwrap()).len(), 64);
+
+            let mut state = self.state.lock();
+            let nonce = protected["nonce"].as_str().unwrap();
+            let Some(i) = state.nonces.iter().position(|n| n == nonce) else {
+                panic!("unknown nonce {nonce}");
+            };
+            state.nonces.remove(i);
+            // reject the first request to exercise the retry
+            if state.bad_nonces == 0 {
+                state.bad_nonces += 1;
+                return Err(json!({ "type": "urn:ietf:params:acme:error:badNonce" }));
+            }
+            if path != "/account" {
+                assert_eq!(protected["kid"], format!("{}/account/1", self.base));
+            }
+            Ok((protected, payload))
+        }
+
+        fn post(
+            &self,
+            path: &str,
+            protected: Value,
+            payload: Value,
+        ) -> (u16, Option<String>, Vec<u8>) {
+            let base = &self.base;
+            let mut state = self.state.lock();
+            let order = |state: &MockState| {
+                let valid = state.cert.is_some();
+                json!({
+                    "status": if valid { "valid" } else { "pending" },
+                    "authorizations": state
+                        .authorizations
+                        .keys()
+                        .map(|name| format!("{base}/authz/{name}"))
+                        .collect::<Vec<_>>(),
+                    "finalize": format!("{base}/finalize"),
+                    "certificate": valid.then(|| format!("{base}/cert")),
+                })
+            };
+
+            if path == "/account" {
+                let jwk = &protected["jwk"];
+                assert_eq!(jwk["crv"], "P-256");
+                let jwk = format!(
+                    r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                    jwk["x"].as_str().unwrap(),
+                    jwk["y"].as_str().unwrap()
+                );
+                state.thumbprint = Some(URL_SAFE_NO_PAD.encode(Sha256::digest(jwk)));
+                if payload["termsOfServiceAgreed"] != true {
+                    let problem =
+                        json!({ "type": "urn:ietf:params:acme:error:userActionRequired" });
+                    return (403, None, problem.to_string().into_bytes());
+                }
+                return (201, Some(format!("{base}/account/1")), b"{}".to_vec());
+            }
+            if path == "/order" {
+                state.orders += 1;
+                state.cert = None;
+                state.authorizations = payload["identifiers"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| (id["value"].as_str().unwrap().to_string(), "pending"))
+                    .collect();
+                let order = order(&state).to_string().into_bytes();
+                return (201, Some(format!("{base}/order/1")), order);
+            }
+            if path == "/order/1" {
+                return (200, None, order(&state).to_string().into_bytes());
+            }
+            if let Some(name) = path.strip_prefix("/authz/") {
+                let authz = json!({
+                    "status": state.authorizations[name],
+                    "identifier": { "type": "dns", "value": name },
+                    "challenges": [
+                        { "type": "http-01", "url": format!("{base}/http/{name}"), "token": format!("http-{name}") },
+                        { "type": "tls-alpn-01", "url": format!("{base}/tls/{name}"), "token": format!("tls-{name}") },
+                    ],
+                });
+                return (200, None, authz.to_string().into_bytes());
+            }
+            let thumbprint = state.thumbprint.clone().unwrap();
+            if let Some(name) = path.strip_prefix("/http/") {
+                let key_auth = format!("http-{name}.{thumbprint}");
+                let response = self
+                    .challenges
+                    .http_01_response(&format!("{HTTP_01_PATH}http-{name}"));
+                let valid = response == Some(key_auth);
+                state
+                    .authorizations
+                    .insert(name.to_string(), if valid { "valid" } else { "invalid" });
+                return (200, None, b"{}".to_vec());
+            }
+            if let Some(name) = path.strip_prefix("/tls/") {
+                let key_auth = format!("tls-{name}.{thumbprint}");
+                let expected = acme_identifier(&key_auth);
+                let valid = self
+                    .challenges
+                    .tls_alpn_01_cert(name)
+                    .is_some_and(|c| c.cert.windows(expected.len()).any(|w| w == expected));
+                state
+                    .authorizations
+                    .insert(name.to_string(), if valid { "valid" } else { "invalid" });
+                return (200, None, b"{}".to_vec());
+            }
+            if path == "/finalize" {
+                assert!(state.authorizations.values().all(|s| *s == "valid"));
+                let csr = b64_decode(payload["csr"].as_str().unwrap());
+                // CertificationRequestInfo: version, subject, subjectPKInfo
+                let mut info = Der(Der(Der(&csr).expect(SEQUENCE).unwrap())
+                    .expect(SEQUENCE)
+                    .unwrap());
+                info.read().unwrap();
+                info.read().unwrap();
+                let mut spki = Der(info.expect(SEQUENCE).unwrap());
+                spki.expect(SEQUENCE).unwrap();
+                let public_key = CsrPublicKey(spki.read().unwrap().1[1..].to_vec());
+                let names: Vec<_> = state.authorizations.keys().cloned().collect();
+                let mut params = CertificateParams::new(names).unwrap();
+                let now = SystemTime::now();
+                params.not_before = now.into();
+                params.not_after = (now + Duration::from_secs(90 * 24 * 3600)).into();
+                let cert = params
+                    .signed_by(&public_key, &self.issuer, &self.issuer_key)
+                    .unwrap();
+                state.cert = Some(cert.der().to_vec());
+                return (200, None, order(&state).to_string().into_bytes());
+            }
+            if path == "/cert" {
+                let cert = state.cert.as_ref().unwrap();
+                let pem = format!(
+                    "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                    base64::engine::general_purpose::STANDARD.encode(cert)
+                );
+                return (200, None, pem.into_bytes());
+            }
+            (404, None, vec![])
+        }
+    }
+
+    async fn test_issue(challenge_type: ChallengeType) {
+        let name = match challenge_type {
+            ChallengeType::Http01 => "http",
+            ChallengeType::TlsAlpn01 => "tls",
+        };
+        let dir = cert_dir(name);
+        let store = Arc::new(CertStore::new(&dir).unwrap());
+        let mut acme = AcmeService::new("", &["example.com"], store.clone());
+        let ca = MockCa::start(acme.challenges()).await;
+        acme.directory_url = ca.directory();
+        acme.challenge_type = challenge_type;
+        acme.terms_of_service_agreed = true;
+
+        assert!(acme.needs_renewal());
+        assert!(acme.renew_if_due().await.unwrap());
+        assert!(store.has_cert("example.com"));
+        assert!(dir.join("acme-account.pem").exists());
+        assert!(!acme.needs_renewal());
+        assert!(!acme.renew_if_due().await.unwrap());
+        assert_eq!(ca.state.lock().orders, 1);
+        // the challenges are gone once validated
+        assert!(!acme.challenges.has_tls_alpn_01());
+        assert!(acme.challenges.http_01.read().is_empty());
+
+        // renewals keep the account and the certificate key
+        let account_key = std::fs::read(dir.join("acme-account.pem")).unwrap();
+        let key = std::fs::read(dir.join("example.com.key")).unwrap();
+        let cert = std::fs::read(dir.join("example.com.crt")).unwrap();
+        acme.renew_before = Duration::from_secs(100 * 24 * 3600);
+        assert!(acme.renew_if_due().await.unwrap());
+        assert_eq!(ca.state.lock().orders, 2);
+        assert_eq!(
+            std::fs::read(dir.join("acme-account.pem")).unwrap(),
+            account_key
+        );
+        assert_eq!(std::fs::read(dir.join("example.com.key")).unwrap(), key);
+        assert_ne!(std::fs::read(dir.join("example.com.crt")).unwrap(), cert);
+    }
+
+    #[tokio::test]
+    async fn test_issue_http_01() {
+        test_issue(ChallengeType::Http01).await;
+    }
+
+    #[tokio::test]
+    async fn test_issue_tls_alpn_01() {
+        test_issue(ChallengeType::TlsAlpn01).await;
+    }
+
+    #[tokio::test]
+    async fn test_issue_error() {
+        let dir = cert_dir("error");
+        let store = Arc::new(CertStore::new(&dir).unwrap());
+        let mut acme = AcmeService::new("http://127.0.0.1:1/dir", &["example.com"], store.clone());
+        let e = acme.issue().await.unwrap_err();
+        assert!(e.to_string().contains("terms of service"));
+        acme.terms_of_service_agreed = true;
+        assert!(acme.issue().await.is_err());
+        assert!(!store.has_cert("example.com"));
+
+        let acme = AcmeService::new("http://127.0.0.1:1/dir", &["*.example.com"], store);
+        let e = acme.issue().await.unwrap_err();
+        assert!(e.to_string().contains("wildcard"));
+    }
+
+    #[cfg(feature = "openssl_derived")]
+    #[tokio::test]
+    async fn test_tls_alpn_01_handshake() {
+        use crate::listeners::tls::TlsSettings;
+        use crate::protocols::tls::SslStream;
+        use crate::tls::ssl;
+        use std::pin::Pin;
+
+        let cert = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let key = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        let challenges = Arc::new(AcmeChallenges::new());
+        challenges
+            .add(
+                ChallengeType::TlsAlpn01,
+                "example.com",
+                "token",
+                "token.thumb",
+            )
+            .unwrap();
+        let mut settings = TlsSettings::intermediate(&cert, &key).unwrap();
+        settings.set_acme_challenges(challenges);
+        let acceptor = Arc::new(settings.build());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut results = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let result = acceptor.tls_handshake(L4Stream::from(stream)).await;
+                results.push(result.map(|_| ()).map_err(|e| e.etype().clone()));
+            }
+            results
+        });
+
+        let connect = |alpn: &'static [u8]| async move {
+            let mut ssl = ssl::Ssl::new(
+                &ssl::SslContext::builder(ssl::SslMethod::tls())
+                    .unwrap()
+                    .build(),
+            )
+            .unwrap();
+            ssl.set_verify(ssl::SslVerifyMode::NONE);
+            ssl.set_hostname("example.com").unwrap();
+            ssl.set_alpn_protos(alpn).unwrap();
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut stream = SslStream::new(ssl, tcp).unwrap();
+            Pin::new(&mut stream).connect().await.unwrap();
+            let ssl = stream.ssl();
+            (
+                ssl.selected_alpn_protocol().map(|p| p.to_vec()),
+                ssl.peer_certificate().unwrap().to_der().unwrap(),
+            )
+        };
+
+        // the challenge handshake is answered with the challenge certificate
+        let (alpn, cert) = connect(b"\x0aacme-tls/1").await;
+        assert_eq!(alpn.as_deref(), Some(ACME_TLS_ALPN));
+        let expected = acme_identifier("token.thumb");
+        assert!(cert.windows(expected.len()).any(|w| w == expected));
+
+        // other handshakes are not affected
+        let (alpn, cert) = connect(b"\x08http/1.1").await;
+        assert!(alpn.is_none());
+        assert!(!cert.windows(expected.len()).any(|w| w == expected));
+
+        let results = server.await.unwrap();
+        assert_eq!(results[0], Err(CHALLENGE_ANSWERED));
+        assert_eq!(results[1], Ok(()));
+    }
+
+    /// Obtain a certificate from a local [Pebble](https://github.com/letsencrypt/pebble) server,
+    /// e.g. started with `PEBBLE_VA_ALWAYS_VALID=1 pebble -config test/config/pebble-config.json`
+    ///
+    /// `PEBBLE_CA` is the path of the `pebble.minica.pem` CA of its HTTPS API. Without
+    /// `PEBBLE_VA_ALWAYS_VALID`, the HTTP-01 challenge is served on port 5002 which Pebble
+    /// validates.
+    #[tokio::test]
+    #[ignore]
+    async fn test_pebble() {
+        let directory = std::env::var("PEBBLE_DIRECTORY")
+            .unwrap_or_else(|_| "https://localhost:14000/dir".to_string());
+        let dir = cert_dir("pebble");
+        let store = Arc::new(CertStore::new(&dir).unwrap());
+        let mut acme = AcmeService::new(&directory, &["localhost"], store.clone());
+        acme.ca_file = std::env::var("PEBBLE_CA").ok();
+        acme.contact = vec!["mailto:admin@example.com".to_string()];
+        acme.terms_of_service_agreed = true;
+
+        let challenges = acme.challenges();
+        let listener = TcpListener::bind("0.0.0.0:5002").await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut session = ServerSession::new_http1(Box::new(L4Stream::from(stream)));
+                if !session.read_request().await.unwrap_or(false) {
+                    continue;
+                }
+                let path = session.req_header().uri.path().to_string();
+                let body = challenges.http_01_response(&path).unwrap_or_default();
+                let mut resp = ResponseHeader::build(200, None).unwrap();
+                resp.insert_header("Content-Length", body.len()).unwrap();
+                session.write_response_header(Box::new(resp)).await.unwrap();
+                session
+                    .write_response_body(Bytes::from(body), true)
+                    .await
+                    .unwrap();
+                let _ = session.finish().await;
+            }
+        });
+
+        acme.issue().await.unwrap();
+        assert!(store.has_cert("localhost"));
+        assert!(!acme.needs_renewal());
+    }
+}
@@ -15,10 +15,19 @@
 use log::debug;
 use pingora_error::{ErrorType, OrErr, Result};
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
+#[cfg(feature = "acme")]
+use super::acme::{TlsAlpnCert, CHALLENGE_ANSWERED};
+use super::fingerprint_client_hello;
+#[cfg(feature = "acme")]
+use super::{acme_challenge_cert, AcmeChallenges};
+use super::{ClientCertMode, OcspStapler, TicketKeys};
 pub use crate::protocols::tls::ALPN;
 use crate::protocols::IO;
-use crate::tls::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use crate::tls::ext;
+use crate::tls::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use crate::tls::x509::{store::X509StoreBuilder, X509};
 use crate::{
     listeners::TlsAcceptCallbacks,
     protocols::tls::{
@@ -32,12 +41,18 @@ pub const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
 pub(crate) struct Acceptor {
     ssl_acceptor: SslAcceptor,
     callbacks: Option<TlsAcceptCallbacks>,
+    client_hello_fingerprint: bool,
+    #[cfg(feature = "acme")]
+    acme_challenges: Option<Arc<AcmeChallenges>>,
 }
 
 /// The TLS settings of a listening endpoint
 pub struct TlsSettings {
     accept_builder: SslAcceptorBuilder,
     callbacks: Option<TlsAcceptCallbacks>,
+    client_hello_fingerprint: bool,
+    #[cfg(feature = "acme")]
+    acme_challenges: Option<Arc<AcmeChallenges>>,
 }
 
 impl From<SslAcceptorBuilder> for TlsSettings {
@@ -45,6 +60,9 @@ impl From<SslAcceptorBuilder> for TlsSettings {
         TlsSettings {
             accept_builder: settings,
             callbacks: None,
+            client_hello_fingerprint: false,
+            #[cfg(feature = "acme")]
+            acme_challenges: None,
         }
     }
 }
@@ -83,6 +101,9 @@ impl TlsSettings {
         Ok(TlsSettings {
             accept_builder,
             callbacks: None,
+            client_hello_fingerprint: false,
+            #[cfg(feature = "acme")]
+            acme_challenges: None,
         })
     }
 
@@ -96,6 +117,9 @@ impl TlsSettings {
         Ok(TlsSettings {
             accept_builder,
             callbacks: Some(callbacks),
+            client_hello_fingerprint: false,
+            #[cfg(feature = "acme")]
+            acme_challenges: None,
         })
     }
 
@@ -116,26 +140,157 @@ impl TlsSettings {
         }
     }
 
+    /// Ask downstream clients for a certificate and verify it against the CA certificates in the
+    /// PEM file `ca_path`.
+    ///
+    /// If `crl_path` is set, the client certificate is also checked against the certificate
+    /// revocation lists in that PEM file. The details of the verified certificate are available
+    /// in the [SslDigest](crate::protocols::tls::SslDigest) of the connection.
+    pub fn set_client_cert_verification(
+        &mut self,
+        ca_path: &str,
+        crl_path: Option<&str>,
+        mode: ClientCertMode,
+    ) -> Result<()> {
+        let ca_pem = std::fs::read(ca_path)
+            .or_err_with(TLS_CONF_ERR, || format!("fail to read CA file {ca_path}"))?;
+        let cas = X509::stack_from_pem(&ca_pem)
+            .or_err_with(TLS_CONF_ERR, || format!("invalid CA file {ca_path}"))?;
+        let mut store = X509StoreBuilder::new().or_err(TLS_CONF_ERR, "fail to create store")?;
+        for ca in cas {
+            // advertise the acceptable CAs in the CertificateRequest
+            self.accept_builder
+                .add_client_ca(&ca)
+                .or_err(TLS_CONF_ERR, "fail to add client CA")?;
+            store.add_cert(ca).or_err(TLS_CONF_ERR, "fail to add CA")?;
+        }
+        if let Some(crl_path) = crl_path {
+            ext::x509_store_add_crl_file(&mut store, crl_path)
+                .or_err_with(TLS_CONF_ERR, || format!("fail to read CRL file {crl_path}"))?;
+        }
+        self.accept_builder
+            .set_verify_cert_store(store.build())
+            .or_err(TLS_CONF_ERR, "fail to set verify cert store")?;
+        // sessions can only be resumed in the same context when clients are verified
+        self.accept_builder
+            .set_session_id_context(b"pingora")
+            .or_err(TLS_CONF_ERR, "fail to set session id context")?;
+        let verify_mode = match mode {
+            ClientCertMode::Optional => SslVerifyMode::PEER,
+            ClientCertMode::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        };
+        self.accept_builder.set_verify(verify_mode);
+        Ok(())
+    }
+
+    /// Staple the OCSP response of the given [OcspStapler] to the handshakes of the clients that
+    /// request it
+    ///
+    /// The response should belong to the certificate of this endpoint. OpenSSL does not staple
+    /// responses for self-signed certificates.
+    pub fn set_ocsp_stapler(&mut self, stapler: Arc<OcspStapler>) -> Result<()> {
+        self.accept_builder
+            .set_status_callback(move |ssl| match stapler.staple() {
+                Some(response) => ssl.set_ocsp_status(&response).map(|_| true),
+                None => Ok(false),
+            })
+            .or_err(TLS_CONF_ERR, "fail to set OCSP status callback")
+    }
+
+    /// Encrypt session tickets with the given [TicketKeys] instead of a random key of this
+    /// endpoint, so that clients can resume their sessions with the other endpoints and
+    /// processes sharing the keys
+    pub fn set_ticket_keys(&mut self, keys: Arc<TicketKeys>) -> Result<()> {
+        let lifetime = keys.ticket_lifetime();
+        ext::ssl_ctx_set_ticket_key_callback(
+            &mut self.accept_builder,
+            move |name| keys.lookup(name),
+            lifetime,
+        )
+        .or_err(TLS_CONF_ERR, "fail to set session ticket key callback")
+    }
+
+    /// Compute the JA3 and JA4 fingerprints of the ClientHello of each connection, which is
+    /// default off.
+    ///
+    /// The fingerprints are available in the [SslDigest](crate::protocols::tls::SslDigest) of
+    /// the connection. This costs a copy of the ClientHello per handshake.
+    pub fn enable_client_hello_fingerprint(&mut self) {
+        self.client_hello_fingerprint = true;
+    }
+
+    /// Answer the TLS-ALPN-01 challenges of the given [AcmeChallenges] on this endpoint
+    ///
+    /// The `acme-tls/1` handshakes of the challenges are completed with the challenge
+    /// certificate and then closed. Other handshakes are not affected.
+    #[cfg(feature = "acme")]
+    pub fn set_acme_challenges(&mut self, challenges: Arc<AcmeChallenges>) {
+        self.acme_challenges = Some(challenges);
+    }
+
     pub(crate) fn build(self) -> Acceptor {
         Acceptor {
             ssl_acceptor: self.accept_builder.build(),
             callbacks: self.callbacks,
+            client_hello_fingerprint: self.client_hello_fingerprint,
+            #[cfg(feature = "acme")]
+            acme_challenges: self.acme_challenges,
         }
     }
 }
 
 impl Acceptor {
-    pub async fn tls_handshake<S: IO>(&self, stream: S) -> Result<SslStream<S>> {
+    pub async fn tls_handshake<S: IO>(&self, mut stream: S) -> Result<SslStream<S>> {
         debug!("new ssl session");
+        #[cfg(feature = "acme")]
+        if let Some(challenges) = self.acme_challenges.as_ref() {
+            if let Some(cert) = acme_challenge_cert(challenges, &mut stream).await {
+                return answer_acme_challenge(&cert, stream).await;
+            }
+        }
+        let fingerprint = if self.client_hello_fingerprint {
+            fingerprint_client_hello(&mut stream).await
+        } else {
+            None
+        };
         // TODO: be able to offload this handshake in a thread pool
-        if let Some(cb) = self.callbacks.as_ref() {
-            handshake_with_callback(&self.ssl_acceptor, stream, cb).await
+        let mut tls_stream = if let Some(cb) = self.callbacks.as_ref() {
+            handshake_with_callback(&self.ssl_acceptor, stream, cb).await?
         } else {
-            handshake(&self.ssl_acceptor, stream).await
+            handshake(&self.ssl_acceptor, stream).await?
+        };
+        if let Some(fingerprint) = fingerprint {
+            tls_stream.set_client_hello_fingerprint(fingerprint);
         }
+        Ok(tls_stream)
     }
 }
 
+// Complete the handshake of a TLS-ALPN-01 challenge with the challenge certificate, see RFC 8737 3
+#[cfg(feature = "acme")]
+async fn answer_acme_challenge<S: IO>(cert: &TlsAlpnCert, stream: S) -> Result<SslStream<S>> {
+    use crate::tls::pkey::PKey;
+    use pingora_error::Error;
+    use tokio::io::AsyncWriteExt;
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).or_err(
+        TLS_CONF_ERR,
+        "fail to create mozilla_intermediate_v5 Acceptor",
+    )?;
+    let x509 = X509::from_der(&cert.cert).or_err(TLS_CONF_ERR, "invalid challenge cert")?;
+    let key =
+        PKey::private_key_from_der(&cert.key).or_err(TLS_CONF_ERR, "invalid challenge key")?;
+    builder
+        .set_certificate(&x509)
+        .and_then(|_| builder.set_private_key(&key))
+        .or_err(TLS_CONF_ERR, "fail to set challenge cert")?;
+    builder.set_alpn_select_callback(alpn::acme_tls_only);
+
+    let mut tls_stream = handshake(&builder.build(), stream).await?;
+    let _ = tls_stream.shutdown().await;
+    Error::e_explain(CHALLENGE_ANSWERED, "answered ACME TLS-ALPN-01 challenge")
+}
+
 mod alpn {
     use super::*;
     use crate::tls::ssl::{select_next_proto, AlpnError, SslRef};
@@ -170,6 +325,16 @@ mod alpn {
         }
     }
 
+    // the wire format of acme-tls/1
+    #[cfg(feature = "acme")]
+    const ACME_TLS_WIRE: &[u8] = b"\x0aacme-tls/1";
+
+    #[cfg(feature = "acme")]
+    pub fn acme_tls_only<'a>(_ssl: &mut SslRef, alpn_in: &'a [u8]) -> Result<&'a [u8], AlpnError> {
+        // the TLS-ALPN-01 handshake must negotiate acme-tls/1, see RFC 8737 3
+        select_next_proto(ACME_TLS_WIRE, alpn_in).ok_or(AlpnError::ALERT_FATAL)
+    }
+
     pub fn h2_only<'a>(_ssl: &mut SslRef, alpn_in: &'a [u8]) -> Result<&'a [u8], AlpnError> {
         if !valid_alpn(alpn_in) {
             return Err(AlpnError::ALERT_FATAL);
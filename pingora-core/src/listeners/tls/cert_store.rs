@@ -0,0 +1,405 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A hot-reloadable store of certificates selected by SNI
+//!
+//! [CertStore] loads every `<name>.crt` and `<name>.key` pair of PEM files in a directory and
+//! indexes the certificates by the DNS names of their subject alternative names. Both exact
+//! names and wildcard names (`*.example.com`) are supported.
+//!
+//! The store implements [TlsAccept] so it can be handed to `TlsSettings::with_callbacks()` of any
+//! TLS backend. It also implements [BackgroundService]: when run as a background service it polls
+//! the directory and reloads the store whenever a file changes. [CertStore::reload()] can be used
+//! to reload it explicitly instead. Either way the new set of certificates replaces the old one
+//! atomically and in-flight handshakes keep using the set they started with.
+//!
+//! ```ignore
+//! let store = Arc::new(CertStore::new("/etc/pingora/certs")?);
+//! let tls_settings = TlsSettings::with_callbacks(Box::new(store.clone()))?;
+//! server.add_service(background_service("cert store", store));
+//! ```
+
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use parking_lot::{Mutex, RwLock};
+use pingora_error::{ErrorType::*, OrErr, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::listeners::TlsAccept;
+use crate::protocols::tls::TlsRef;
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+
+#[cfg(feature = "openssl_derived")]
+type Certificate = crate::utils::tls::CertKey;
+
+#[cfg(feature = "rustls")]
+type Certificate = pingora_rustls::CertifiedKey;
+
+const CERT_EXT: &str = "crt";
+const KEY_EXT: &str = "key";
+
+/// The default interval to check the certificate directory for changes
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct CertIndex {
+    exact: HashMap<String, Arc<Certificate>>,
+    // keyed by the parent domain of the wildcard, i.e. `example.com` for `*.example.com`
+    wildcard: HashMap<String, Arc<Certificate>>,
+}
+
+impl CertIndex {
+    fn insert(&mut self, name: &str, cert: &Arc<Certificate>, path: &Path) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let (map, key) = match name.strip_prefix("*.") {
+            Some(parent) => (&mut self.wildcard, parent.to_string()),
+            None => (&mut self.exact, name.clone()),
+        };
+        if map.contains_key(&key) {
+            warn!("{name} in {path:?} is already served by another certificate, ignoring");
+            return;
+        }
+        map.insert(key, cert.clone());
+    }
+
+    fn lookup(&self, server_name: &str) -> Option<&Arc<Certificate>> {
+        let name = server_name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(cert) = self.exact.get(&name) {
+            return Some(cert);
+        }
+        // a wildcard only covers a single label
+        let (_, parent) = name.split_once('.')?;
+        self.wildcard.get(parent)
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.wildcard.len()
+    }
+}
+
+// The modification time and size of each file in the directory
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+/// A directory of certificates, indexed by their DNS names
+pub struct CertStore {
+    dir: PathBuf,
+    index: RwLock<Arc<CertIndex>>,
+    fingerprint: Mutex<Fingerprint>,
+    /// How often the [BackgroundService] checks the directory for changes.
+    /// Default is [DEFAULT_POLL_INTERVAL].
+    pub poll_interval: Duration,
+}
+
+impl CertStore {
+    /// Create a store from the `<name>.crt` and `<name>.key` files in the given directory
+    ///
+    /// The `.crt` file contains the leaf certificate followed by its intermediates.
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let fingerprint = fingerprint(&dir)?;
+        let index = load_dir(&dir)?;
+        Ok(CertStore {
+            dir,
+            index: RwLock::new(Arc::new(index)),
+            fingerprint: Mutex::new(fingerprint),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        })
+    }
+
+    /// Reload all the certificates in the directory
+    ///
+    /// On error the current certificates stay in use. Pairs that fail to load are skipped and
+    /// logged, the other certificates are still loaded.
+    pub fn reload(&self) -> Result<()> {
+        let new_fingerprint = fingerprint(&self.dir)?;
+        let index = load_dir(&self.dir)?;
+        info!(
+            "Loaded {} certificate names from {:?}",
+            index.len(),
+            self.dir
+        );
+        *self.index.write() = Arc::new(index);
+        *self.fingerprint.lock() = new_fingerprint;
+        Ok(())
+    }
+
+    /// Reload the certificates if any file in the directory changed since the last load
+    ///
+    /// Return whether the certificates were reloaded.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        if fingerprint(&self.dir)? == *self.fingerprint.lock() {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// The directory of the certificates
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether a certificate is available for the given server name
+    pub fn has_cert(&self, server_name: &str) -> bool {
+        self.find(server_name).is_some()
+    }
+
+    fn find(&self, server_name: &str) -> Option<Arc<Certificate>> {
+        self.index.read().lookup(server_name).cloned()
+    }
+}
+
+fn fingerprint(dir: &Path) -> Result<Fingerprint> {
+    let mut files = fs::read_dir(dir)
+        .or_err_with(InternalError, || format!("fail to read cert dir {dir:?}"))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let meta = entry.metadata().ok();
+            (
+                entry.path(),
+                meta.as_ref().and_then(|m| m.modified().ok()),
+                meta.map_or(0, |m| m.len()),
+            )
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+fn load_dir(dir: &Path) -> Result<CertIndex> {
+    let mut certs = fs::read_dir(dir)
+        .or_err_with(InternalError, || format!("fail to read cert dir {dir:?}"))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == CERT_EXT))
+        .collect::<Vec<_>>();
+    // load in a stable order so that the same certificate wins when names overlap
+    certs.sort();
+
+    let mut index = CertIndex::default();
+    for cert_path in certs {
+        let key_path = cert_path.with_extension(KEY_EXT);
+        if !key_path.exists() {
+            warn!("No key file {key_path:?} for {cert_path:?}, skipping");
+            continue;
+        }
+        let (cert, names) = match load_cert(&cert_path, &key_path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                error!("Fail to load {cert_path:?}, skipping: {e}");
+                continue;
+            }
+        };
+        if names.is_empty() {
+            warn!("No DNS subject alternative names in {cert_path:?}, skipping");
+            continue;
+        }
+        let cert = Arc::new(cert);
+        for name in names {
+            index.insert(&name, &cert, &cert_path);
+        }
+    }
+    Ok(index)
+}
+
+#[cfg(feature = "openssl_derived")]
+fn load_cert(cert_path: &Path, key_path: &Path) -> Result<(Certificate, Vec<String>)> {
+    use crate::tls::{pkey::PKey, x509::X509};
+
+    let cert_pem =
+        fs::read(cert_path).or_err_with(InvalidCert, || format!("fail to read {cert_path:?}"))?;
+    let key_pem =
+        fs::read(key_path).or_err_with(InvalidCert, || format!("fail to read {key_path:?}"))?;
+    let certs = X509::stack_from_pem(&cert_pem)
+        .or_err_with(InvalidCert, || format!("invalid certificate {cert_path:?}"))?;
+    if certs.is_empty() {
+        return pingora_error::Error::e_explain(
+            InvalidCert,
+            format!("no certificate in {cert_path:?}"),
+        );
+    }
+    let key = PKey::private_key_from_pem(&key_pem)
+        .or_err_with(InvalidCert, || format!("invalid key {key_path:?}"))?;
+    let names = certs[0]
+        .subject_alt_names()
+        .map(|sans| {
+            sans.iter()
+                .filter_map(|san| san.dnsname().map(|n| n.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((Certificate::new(certs, key), names))
+}
+
+#[cfg(feature = "rustls")]
+fn load_cert(cert_path: &Path, key_path: &Path) -> Result<(Certificate, Vec<String>)> {
+    use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+    let cert = pingora_rustls::load_certified_key(
+        &cert_path.to_string_lossy(),
+        &key_path.to_string_lossy(),
+    )?;
+    let (_, leaf) = X509Certificate::from_der(
+        cert.end_entity_cert()
+            .or_err(InvalidCert, "no leaf certificate")?,
+    )
+    .or_err_with(InvalidCert, || format!("invalid certificate {cert_path:?}"))?;
+    let names = leaf
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|sans| {
+            sans.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(n) => Some(n.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((cert, names))
+}
+
+#[cfg(feature = "openssl_derived")]
+fn use_cert(ssl: &mut TlsRef, cert: &Certificate) -> Result<()> {
+    use crate::tls::ext;
+
+    ext::ssl_use_certificate(ssl, cert.leaf()).or_err(InvalidCert, "fail to use certificate")?;
+    ext::ssl_use_private_key(ssl, cert.key()).or_err(InvalidCert, "fail to use private key")?;
+    for chain_cert in cert.intermediates() {
+        ext::ssl_add_chain_cert(ssl, chain_cert).or_err(InvalidCert, "fail to add chain cert")?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "openssl_derived")]
+fn server_name(ssl: &TlsRef) -> Option<&str> {
+    ssl.servername(crate::tls::ssl::NameType::HOST_NAME)
+}
+
+#[cfg(feature = "rustls")]
+fn use_cert(ssl: &mut TlsRef, cert: &Arc<Certificate>) -> Result<()> {
+    ssl.set_certified_key(cert.clone());
+    Ok(())
+}
+
+#[cfg(feature = "rustls")]
+fn server_name(ssl: &TlsRef) -> Option<&str> {
+    ssl.server_name()
+}
+
+#[async_trait]
+impl TlsAccept for CertStore {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) -> () {
+        let Some(name) = server_name(ssl).map(|n| n.to_string()) else {
+            debug!("No SNI in the ClientHello, no certificate to serve");
+            return;
+        };
+        let Some(cert) = self.find(&name) else {
+            debug!("No certificate for {name}");
+            return;
+        };
+        if let Err(e) = use_cert(ssl, &cert) {
+            error!("Failed to set certificate for {name}: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CertStore {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.poll_interval) => {}
+            }
+            match self.reload_if_changed() {
+                Ok(true) => info!("Reloaded certificates in {:?}", self.dir),
+                Ok(false) => {}
+                // the files may be in the middle of being replaced, try again next time
+                Err(e) => error!("Failed to reload certificates in {:?}: {e}", self.dir),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pingora-cert-store-{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let keys = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/keys");
+        // SANs: openrusty.org, *.openrusty.org
+        fs::copy(keys.join("server.crt"), dir.join("openrusty.crt")).unwrap();
+        fs::copy(keys.join("key.pem"), dir.join("openrusty.key")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = cert_dir("lookup");
+        let store = CertStore::new(&dir).unwrap();
+        assert!(store.has_cert("openrusty.org"));
+        assert!(store.has_cert("OpenRusty.org."));
+        assert!(store.has_cert("www.openrusty.org"));
+        assert!(!store.has_cert("a.b.openrusty.org"));
+        assert!(!store.has_cert("example.org"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skip_without_key() {
+        let dir = cert_dir("no_key");
+        fs::remove_file(dir.join("openrusty.key")).unwrap();
+        let store = CertStore::new(&dir).unwrap();
+        assert!(!store.has_cert("openrusty.org"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = cert_dir("reload");
+        let store = CertStore::new(&dir).unwrap();
+        assert!(!store.reload_if_changed().unwrap());
+
+        fs::rename(dir.join("openrusty.crt"), dir.join("openrusty.crt.old")).unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert!(!store.has_cert("openrusty.org"));
+
+        // a broken certificate is skipped, the other ones are still loaded
+        fs::write(dir.join("openrusty.crt"), "not a certificate").unwrap();
+        fs::copy(dir.join("openrusty.crt.old"), dir.join("other.crt")).unwrap();
+        fs::copy(dir.join("openrusty.key"), dir.join("other.key")).unwrap();
+        store.reload().unwrap();
+        assert!(store.has_cert("openrusty.org"));
+        fs::remove_file(dir.join("other.crt")).unwrap();
+        store.reload().unwrap();
+        assert!(!store.has_cert("openrusty.org"));
+
+        fs::rename(dir.join("openrusty.crt.old"), dir.join("openrusty.crt")).unwrap();
+        store.reload().unwrap();
+        assert!(store.has_cert("openrusty.org"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -12,6 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "acme")]
+use crate::protocols::tls::client_hello::peek_client_hello_from;
+#[cfg(feature = "any_tls")]
+use crate::protocols::tls::fingerprint::{peek_client_hello_fingerprint, ClientHelloFingerprint};
+#[cfg(feature = "any_tls")]
+use crate::protocols::IO;
+#[cfg(feature = "acme")]
+use std::sync::Arc;
+
 #[cfg(feature = "openssl_derived")]
 mod boringssl_openssl;
 
@@ -23,3 +32,71 @@ mod rustls;
 
 #[cfg(feature = "rustls")]
 pub use rustls::*;
+
+/// Whether a TLS listener asks downstream clients for a certificate
+///
+/// See `TlsSettings::set_client_cert_verification()`.
+#[cfg(feature = "any_tls")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientCertMode {
+    /// Clients may connect without a certificate. A certificate that is presented must be valid.
+    Optional,
+    /// Clients without a valid certificate are rejected
+    Required,
+}
+
+// Fingerprint the ClientHello before the handshake consumes it. A malformed ClientHello is left
+// to the handshake to reject.
+#[cfg(feature = "any_tls")]
+async fn fingerprint_client_hello<S: IO>(stream: &mut S) -> Option<ClientHelloFingerprint> {
+    peek_client_hello_fingerprint(stream)
+        .await
+        .unwrap_or_else(|e| {
+            log::debug!("fail to fingerprint the ClientHello: {e}");
+            None
+        })
+}
+
+// The certificate to answer the TLS-ALPN-01 challenge with, if the ClientHello is the handshake
+// of one
+#[cfg(feature = "acme")]
+async fn acme_challenge_cert<S: IO>(
+    challenges: &AcmeChallenges,
+    stream: &mut S,
+) -> Option<Arc<acme::TlsAlpnCert>> {
+    if !challenges.has_tls_alpn_01() {
+        return None;
+    }
+    let client_hello = peek_client_hello_from(stream).await.unwrap_or_else(|e| {
+        log::debug!("fail to peek the ClientHello: {e}");
+        None
+    })?;
+    if !client_hello.offers_alpn(acme::ACME_TLS_ALPN) {
+        return None;
+    }
+    challenges.tls_alpn_01_cert(client_hello.sni.as_deref()?)
+}
+
+#[cfg(feature = "acme")]
+pub mod acme;
+
+#[cfg(feature = "acme")]
+pub use acme::{AcmeChallenges, AcmeService};
+
+#[cfg(feature = "any_tls")]
+pub mod cert_store;
+
+#[cfg(feature = "any_tls")]
+pub use cert_store::CertStore;
+
+#[cfg(feature = "any_tls")]
+pub mod ocsp;
+
+#[cfg(feature = "any_tls")]
+pub use ocsp::OcspStapler;
+
+#[cfg(feature = "any_tls")]
+pub mod ticket_keys;
+
+#[cfg(feature = "any_tls")]
+pub use ticket_keys::TicketKeys;
@@ -0,0 +1,447 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OCSP stapling for TLS listeners
+//!
+//! An [OcspStapler] holds the DER encoded OCSP response of the certificate of a listener. It is
+//! attached to the listener via `TlsSettings::set_ocsp_stapler()`, after which the response is
+//! stapled to the handshakes of the clients that ask for it.
+//!
+//! The response comes from an [OcspSource], e.g. [OcspFile] for a response that is fetched by
+//! another tool. When run as a [BackgroundService], the stapler fetches a new response once half
+//! of the validity period of the current one has passed. A response past its `nextUpdate` is
+//! never stapled.
+//!
+//! The age of the current response and the time left until it expires are exported as the
+//! `pingora_ocsp_staple_age_seconds` and `pingora_ocsp_staple_expires_in_seconds` gauges.
+
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use pingora_error::{Error, ErrorType::*, OkOrErr, OrErr, Result};
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+
+static STAPLE_AGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pingora_ocsp_staple_age_seconds",
+        "seconds since the thisUpdate of the stapled OCSP response",
+        &["name"]
+    )
+    .unwrap()
+});
+
+static STAPLE_EXPIRES_IN: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pingora_ocsp_staple_expires_in_seconds",
+        "seconds until the nextUpdate of the stapled OCSP response, negative when stale",
+        &["name"]
+    )
+    .unwrap()
+});
+
+/// The default interval to check whether the OCSP response needs to be refreshed
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// How long a response without nextUpdate is stapled for
+const MAX_AGE_WITHOUT_NEXT_UPDATE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+const OCSP_ERR: pingora_error::ErrorType = Custom("OCSPError");
+
+/// Where an [OcspStapler] gets the DER encoded OCSP response from
+#[async_trait]
+pub trait OcspSource {
+    /// Return the latest OCSP response
+    async fn fetch(&self) -> Result<Vec<u8>>;
+}
+
+/// A DER encoded OCSP response on disk, which is kept up to date by another process
+pub struct OcspFile(pub PathBuf);
+
+#[async_trait]
+impl OcspSource for OcspFile {
+    async fn fetch(&self) -> Result<Vec<u8>> {
+        std::fs::read(&self.0).or_err_with(OCSP_ERR, || format!("fail to read {:?}", self.0))
+    }
+}
+
+/// A parsed OCSP response
+#[derive(Debug)]
+pub(crate) struct OcspResponse {
+    pub(crate) der: Vec<u8>,
+    this_update: SystemTime,
+    next_update: Option<SystemTime>,
+}
+
+impl OcspResponse {
+    fn parse(der: Vec<u8>) -> Result<Self> {
+        let (this_update, next_update) = parse_validity(&der).or_err(
+            OCSP_ERR,
+            "invalid OCSP response, expecting a successful basic OCSP response",
+        )?;
+        Ok(OcspResponse {
+            der,
+            this_update,
+            next_update,
+        })
+    }
+
+    fn expires(&self) -> SystemTime {
+        self.next_update
+            .unwrap_or(self.this_update + MAX_AGE_WITHOUT_NEXT_UPDATE)
+    }
+
+    fn is_stale(&self, now: SystemTime) -> bool {
+        now >= self.expires()
+    }
+
+    // refresh once half of the validity period has passed
+    fn needs_refresh(&self, now: SystemTime) -> bool {
+        let validity = self
+            .expires()
+            .duration_since(self.this_update)
+            .unwrap_or_default();
+        now >= self.this_update + validity / 2
+    }
+}
+
+/// The OCSP response stapled to the TLS handshakes of a listener
+pub struct OcspStapler {
+    name: String,
+    source: Box<dyn OcspSource + Send + Sync>,
+    response: RwLock<Option<Arc<OcspResponse>>>,
+    /// How often the [BackgroundService] checks whether the response needs to be refreshed.
+    /// Default is [DEFAULT_CHECK_INTERVAL].
+    pub check_interval: Duration,
+}
+
+impl std::fmt::Debug for OcspStapler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OcspStapler")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OcspStapler {
+    /// Create a new stapler which gets its response from the given source
+    ///
+    /// The `name` is used as the label of the metrics. Nothing is stapled until the response is
+    /// fetched via [OcspStapler::refresh()] or the [BackgroundService].
+    pub fn new(name: &str, source: Box<dyn OcspSource + Send + Sync>) -> Self {
+        OcspStapler {
+            name: name.to_string(),
+            source,
+            response: RwLock::new(None),
+            check_interval: DEFAULT_CHECK_INTERVAL,
+        }
+    }
+
+    /// Create a new stapler and load the pre-fetched response in the given file
+    pub fn from_file(path: &str) -> Result<Self> {
+        let der = std::fs::read(path).or_err_with(OCSP_ERR, || format!("fail to read {path}"))?;
+        let stapler = Self::new(path, Box::new(OcspFile(path.into())));
+        stapler.set_response(der)?;
+        Ok(stapler)
+    }
+
+    /// Replace the current response with the given DER encoded OCSP response
+    pub fn set_response(&self, der: Vec<u8>) -> Result<()> {
+        let response = OcspResponse::parse(der)?;
+        if response.is_stale(SystemTime::now()) {
+            return Error::e_explain(OCSP_ERR, "OCSP response is already expired");
+        }
+        *self.response.write() = Some(Arc::new(response));
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Fetch a new response from the source
+    ///
+    /// On error the current response stays in use until it expires.
+    pub async fn refresh(&self) -> Result<()> {
+        let der = self.source.fetch().await?;
+        self.set_response(der)
+    }
+
+    /// The response to staple, or `None` if there is no response or it is stale
+    pub fn staple(&self) -> Option<Vec<u8>> {
+        self.fresh_response().map(|response| response.der.clone())
+    }
+
+    // The current response, or `None` if there is no response or it is stale. The same `Arc` is
+    // returned until the response is replaced.
+    pub(crate) fn fresh_response(&self) -> Option<Arc<OcspResponse>> {
+        let response = self.response.read().clone()?;
+        if response.is_stale(SystemTime::now()) {
+            debug!("OCSP response of {} is stale, not stapling", self.name);
+            return None;
+        }
+        Some(response)
+    }
+
+    fn needs_refresh(&self) -> bool {
+        match self.response.read().as_ref() {
+            Some(response) => response.needs_refresh(SystemTime::now()),
+            None => true,
+        }
+    }
+
+    fn update_metrics(&self) {
+        let Some(response) = self.response.read().clone() else {
+            return;
+        };
+        let now = SystemTime::now();
+        STAPLE_AGE
+            .with_label_values(&[&self.name])
+            .set(seconds_between(response.this_update, now));
+        STAPLE_EXPIRES_IN
+            .with_label_values(&[&self.name])
+            .set(seconds_between(now, response.expires()));
+    }
+}
+
+// b - a in seconds, negative if b is earlier
+fn seconds_between(a: SystemTime, b: SystemTime) -> i64 {
+    match b.duration_since(a) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+#[async_trait]
+impl BackgroundService for OcspStapler {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            if self.needs_refresh() {
+                if let Err(e) = self.refresh().await {
+                    error!("Failed to refresh OCSP response of {}: {e}", self.name);
+                }
+            }
+            self.update_metrics();
+            if self.staple().is_none() {
+                warn!("No valid OCSP response to staple for {}", self.name);
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.check_interval) => {}
+            }
+        }
+    }
+}
+
+// A minimal DER reader, just enough to find the validity of OCSP responses and certificates
+pub(super) struct Der<'a>(pub(super) &'a [u8]);
+
+impl<'a> Der<'a> {
+    // read the next element, returning its tag and content
+    pub(super) fn read(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&len, mut rest) = rest.split_first()?;
+        let len = if len < 0x80 {
+            len as usize
+        } else {
+            let n = (len & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return None;
+            }
+            let len = rest[..n].iter().fold(0usize, |l, b| (l << 8) | *b as usize);
+            rest = &rest[n..];
+            len
+        };
+        if rest.len() < len {
+            return None;
+        }
+        self.0 = &rest[len..];
+        Some((tag, &rest[..len]))
+    }
+
+    pub(super) fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read()? {
+            (t, content) if t == tag => Some(content),
+            _ => None,
+        }
+    }
+
+    pub(super) fn peek_tag(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+}
+
+pub(super) const SEQUENCE: u8 = 0x30;
+const ENUMERATED: u8 = 0x0a;
+const OID: u8 = 0x06;
+pub(super) const OCTET_STRING: u8 = 0x04;
+pub(super) const GENERALIZED_TIME: u8 = 0x18;
+pub(super) const CONTEXT_0: u8 = 0xa0;
+// 1.3.6.1.5.5.7.48.1.1
+const ID_PKIX_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+// Return thisUpdate and nextUpdate of the first SingleResponse, see RFC 6960 4.2.1
+fn parse_validity(der: &[u8]) -> Option<(SystemTime, Option<SystemTime>)> {
+    let mut ocsp_response = Der(Der(der).expect(SEQUENCE)?);
+    // responseStatus: successful(0)
+    if ocsp_response.expect(ENUMERATED)? != [0] {
+        return None;
+    }
+    let mut response_bytes = Der(Der(ocsp_response.expect(CONTEXT_0)?).expect(SEQUENCE)?);
+    if response_bytes.expect(OID)? != ID_PKIX_OCSP_BASIC {
+        return None;
+    }
+    let basic = Der(response_bytes.expect(OCTET_STRING)?).expect(SEQUENCE)?;
+    let mut tbs = Der(Der(basic).expect(SEQUENCE)?);
+    if tbs.peek_tag()? == CONTEXT_0 {
+        tbs.read()?; // version
+    }
+    tbs.read()?; // responderID
+    tbs.expect(GENERALIZED_TIME)?; // producedAt
+    let mut single = Der(Der(tbs.expect(SEQUENCE)?).expect(SEQUENCE)?);
+    single.expect(SEQUENCE)?; // certID
+    single.read()?; // certStatus
+    let this_update = parse_generalized_time(single.expect(GENERALIZED_TIME)?)?;
+    let next_update = match single.peek_tag() {
+        Some(CONTEXT_0) => Some(parse_generalized_time(
+            Der(single.expect(CONTEXT_0)?).expect(GENERALIZED_TIME)?,
+        )?),
+        _ => None,
+    };
+    Some((this_update, next_update))
+}
+
+// YYYYMMDDHHMMSS[.fff]Z
+pub(super) fn parse_generalized_time(time: &[u8]) -> Option<SystemTime> {
+    if time.len() < 15 || time.last() != Some(&b'Z') {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| -> Option<u32> {
+        std::str::from_utf8(&time[range]).ok()?.parse().ok()
+    };
+    let (year, month, day) = (num(0..4)?, num(4..6)?, num(6..8)?);
+    let (hour, minute, second) = (num(8..10)?, num(10..12)?, num(12..14)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    // days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let y = year as i64 - (month <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400 + (hour * 3600 + minute * 60 + second) as i64;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response_path() -> String {
+        format!("{}/tests/keys/ocsp_leaf.ocsp", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_generalized_time() {
+        let t = parse_generalized_time(b"20261017005606Z").unwrap();
+        assert_eq!(t.duration_since(UNIX_EPOCH).unwrap().as_secs(), 1792198566);
+        let t = parse_generalized_time(b"19700101000000Z").unwrap();
+        assert_eq!(t, UNIX_EPOCH);
+        assert!(parse_generalized_time(b"20261017005606").is_none());
+        assert!(parse_generalized_time(b"20261317005606Z").is_none());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let der = std::fs::read(response_path()).unwrap();
+        let response = OcspResponse::parse(der).unwrap();
+        let this_update = parse_generalized_time(b"20261017010845Z").unwrap();
+        let next_update = parse_generalized_time(b"20361014010845Z").unwrap();
+        assert_eq!(response.this_update, this_update);
+        assert_eq!(response.next_update, Some(next_update));
+
+        assert!(!response.is_stale(this_update));
+        assert!(!response.needs_refresh(this_update));
+        assert!(response.needs_refresh(this_update + Duration::from_secs(6 * 365 * 86400)));
+        assert!(response.is_stale(next_update));
+
+        assert!(OcspResponse::parse(b"not ocsp".to_vec()).is_err());
+        // responseStatus: tryLater(3)
+        assert!(OcspResponse::parse(vec![0x30, 0x03, 0x0a, 0x01, 0x03]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stapler() {
+        let stapler = OcspStapler::from_file(&response_path()).unwrap();
+        let der = std::fs::read(response_path()).unwrap();
+        assert_eq!(stapler.staple().unwrap(), der);
+        assert!(!stapler.needs_refresh());
+        // the same response is shared until it is refreshed
+        let response = stapler.fresh_response().unwrap();
+        assert!(Arc::ptr_eq(&response, &stapler.fresh_response().unwrap()));
+        stapler.refresh().await.unwrap();
+        assert!(!Arc::ptr_eq(&response, &stapler.fresh_response().unwrap()));
+
+        let stapler = OcspStapler::new("missing", Box::new(OcspFile("/nonexistent".into())));
+        assert!(stapler.staple().is_none());
+        assert!(stapler.needs_refresh());
+        assert!(stapler.refresh().await.is_err());
+        assert!(stapler.staple().is_none());
+    }
+
+    #[cfg(feature = "openssl_derived")]
+    #[tokio::test]
+    async fn test_staple_handshake() {
+        use crate::listeners::tls::TlsSettings;
+        use crate::protocols::tls::SslStream;
+        use crate::tls::ssl;
+        use std::pin::Pin;
+
+        let cert = format!("{}/tests/keys/ocsp_leaf.crt", env!("CARGO_MANIFEST_DIR"));
+        let key = format!(
+            "{}/tests/keys/ocsp_leaf.key.pem",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut settings = TlsSettings::intermediate(&cert, &key).unwrap();
+        let stapler = Arc::new(OcspStapler::from_file(&response_path()).unwrap());
+        settings.set_ocsp_stapler(stapler).unwrap();
+        let acceptor = settings.build();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move { acceptor.tls_handshake(server).await });
+
+        let stapled = Arc::new(parking_lot::Mutex::new(None));
+        let mut ssl_context = ssl::SslContext::builder(ssl::SslMethod::tls()).unwrap();
+        let stapled_ref = stapled.clone();
+        ssl_context
+            .set_status_callback(move |ssl| {
+                *stapled_ref.lock() = ssl.ocsp_status().map(|r| r.to_vec());
+                Ok(true)
+            })
+            .unwrap();
+        let mut ssl = ssl::Ssl::new(&ssl_context.build()).unwrap();
+        ssl.set_verify(ssl::SslVerifyMode::NONE);
+        ssl.set_status_type(ssl::StatusType::OCSP).unwrap();
+        let mut stream = SslStream::new(ssl, client).unwrap();
+        Pin::new(&mut stream).connect().await.unwrap();
+        let der = std::fs::read(response_path()).unwrap();
+        assert_eq!(stapled.lock().take(), Some(der));
+        server.await.unwrap().unwrap();
+    }
+}
@@ -12,16 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::RefCell;
 use std::sync::Arc;
 
+#[cfg(feature = "acme")]
+use super::acme::{TlsAlpnCert, ACME_TLS_ALPN, CHALLENGE_ANSWERED};
+use super::fingerprint_client_hello;
+use super::ocsp::OcspResponse;
+#[cfg(feature = "acme")]
+use super::{acme_challenge_cert, AcmeChallenges};
+use super::{ClientCertMode, OcspStapler, TicketKeys};
 use crate::listeners::TlsAcceptCallbacks;
 use crate::protocols::tls::{server::handshake, server::handshake_with_callback, TlsStream};
 use log::debug;
+use parking_lot::RwLock;
 use pingora_error::ErrorType::InternalError;
-use pingora_error::{Error, OrErr, Result};
-use pingora_rustls::load_certs_and_key_files;
-use pingora_rustls::ServerConfig;
+use pingora_error::{OrErr, Result};
+use pingora_rustls::{
+    load_ca_file_into_store, load_certified_key, load_certs_and_key_files, load_crls, ticketer,
+};
 use pingora_rustls::{version, TlsAcceptor as RusTlsAcceptor};
+use pingora_rustls::{
+    CertifiedKey, ClientCertVerifier, ClientHello, ResolvesServerCert, RootCertStore, ServerConfig,
+    WebPkiClientVerifier,
+};
 
 use crate::protocols::{ALPN, IO};
 
@@ -30,11 +44,69 @@ pub struct TlsSettings {
     alpn_protocols: Option<Vec<Vec<u8>>>,
     cert_path: String,
     key_path: String,
+    client_cert_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    ocsp_stapler: Option<Arc<OcspStapler>>,
+    ticket_keys: Option<Arc<TicketKeys>>,
+    callbacks: Option<TlsAcceptCallbacks>,
+    client_hello_fingerprint: bool,
+    #[cfg(feature = "acme")]
+    acme_challenges: Option<Arc<AcmeChallenges>>,
 }
 
 pub struct Acceptor {
     pub acceptor: RusTlsAcceptor,
+    config: Arc<ServerConfig>,
     callbacks: Option<TlsAcceptCallbacks>,
+    client_hello_fingerprint: bool,
+    #[cfg(feature = "acme")]
+    acme_challenges: Option<Arc<AcmeChallenges>>,
+}
+
+thread_local! {
+    // The certificate chosen by the callback of the connection being accepted on this thread,
+    // see Acceptor::accept_with_cert()
+    static CALLBACK_CERT: RefCell<Option<Arc<CertifiedKey>>> = const { RefCell::new(None) };
+}
+
+/// Serve the certificate chosen by the [TlsAccept](crate::listeners::TlsAccept) callback of a
+/// connection
+///
+/// rustls resolves the certificate synchronously while the connection is built from the
+/// ClientHello, so the certificate is handed over via [CALLBACK_CERT] on the same thread.
+#[derive(Debug)]
+struct CallbackCert;
+
+impl ResolvesServerCert for CallbackCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        CALLBACK_CERT.with(|cert| cert.borrow_mut().take())
+    }
+}
+
+/// Serve the certificate of the listener with the current response of its [OcspStapler]
+#[derive(Debug)]
+struct StapledCert {
+    key: Arc<CertifiedKey>,
+    stapler: Arc<OcspStapler>,
+    // the key with the response stapled, rebuilt once the response is refreshed
+    stapled: RwLock<Option<(Arc<OcspResponse>, Arc<CertifiedKey>)>>,
+}
+
+impl ResolvesServerCert for StapledCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let Some(response) = self.stapler.fresh_response() else {
+            return Some(self.key.clone());
+        };
+        if let Some((stapled_response, key)) = self.stapled.read().as_ref() {
+            if Arc::ptr_eq(stapled_response, &response) {
+                return Some(key.clone());
+            }
+        }
+        let mut key = CertifiedKey::clone(&self.key);
+        key.ocsp = Some(response.der.clone());
+        let key = Arc::new(key);
+        *self.stapled.write() = Some((response, key.clone()));
+        Some(key)
+    }
 }
 
 impl TlsSettings {
@@ -46,31 +118,60 @@ impl TlsSettings {
     ///
     /// Todo: Return a result instead of panicking XD
     pub fn build(self) -> Acceptor {
-        let Ok(Some((certs, key))) = load_certs_and_key_files(&self.cert_path, &self.key_path)
-        else {
-            panic!(
-                "Failed to load provided certificates \"{}\" or key \"{}\".",
-                self.cert_path, self.key_path
-            )
+        let builder =
+            ServerConfig::builder_with_protocol_versions(&[&version::TLS12, &version::TLS13]);
+        let builder = match self.client_cert_verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
         };
 
-        // TODO - Add support for client auth & custom CA support
-        let mut config =
-            ServerConfig::builder_with_protocol_versions(&[&version::TLS12, &version::TLS13])
-                .with_no_client_auth()
+        let mut config = if self.callbacks.is_some() {
+            // the certificate is decided per connection, see Acceptor::accept_with_cert()
+            builder.with_cert_resolver(Arc::new(CallbackCert))
+        } else if let Some(stapler) = self.ocsp_stapler {
+            let key = load_certified_key(&self.cert_path, &self.key_path).unwrap_or_else(|e| {
+                panic!(
+                    "Failed to load provided certificates \"{}\" or key \"{}\": {e}",
+                    self.cert_path, self.key_path
+                )
+            });
+            builder.with_cert_resolver(Arc::new(StapledCert {
+                key: Arc::new(key),
+                stapler,
+                stapled: RwLock::new(None),
+            }))
+        } else {
+            let Ok(Some((certs, key))) = load_certs_and_key_files(&self.cert_path, &self.key_path)
+            else {
+                panic!(
+                    "Failed to load provided certificates \"{}\" or key \"{}\".",
+                    self.cert_path, self.key_path
+                )
+            };
+            builder
                 .with_single_cert(certs, key)
                 .explain_err(InternalError, |e| {
                     format!("Failed to create server listener config: {e}")
                 })
-                .unwrap();
+                .unwrap()
+        };
 
         if let Some(alpn_protocols) = self.alpn_protocols {
             config.alpn_protocols = alpn_protocols;
         }
+        if let Some(keys) = self.ticket_keys {
+            let lifetime = keys.ticket_lifetime();
+            config.ticketer = ticketer(move |name| keys.lookup(name), lifetime);
+        }
 
+        let config = Arc::new(config);
         Acceptor {
-            acceptor: RusTlsAcceptor::from(Arc::new(config)),
-            callbacks: None,
+            acceptor: RusTlsAcceptor::from(config.clone()),
+            config,
+            callbacks: self.callbacks,
+            client_hello_fingerprint: self.client_hello_fingerprint,
+            #[cfg(feature = "acme")]
+            acme_challenges: self.acme_challenges,
         }
     }
 
@@ -92,29 +193,180 @@ impl TlsSettings {
             alpn_protocols: None,
             cert_path: cert_path.to_string(),
             key_path: key_path.to_string(),
+            client_cert_verifier: None,
+            ocsp_stapler: None,
+            ticket_keys: None,
+            callbacks: None,
+            client_hello_fingerprint: false,
+            #[cfg(feature = "acme")]
+            acme_challenges: None,
         })
     }
 
-    pub fn with_callbacks() -> Result<Self>
+    /// Create a new [`TlsSettings`] similar to [TlsSettings::intermediate()]. A struct that implements [TlsAcceptCallbacks]
+    /// is needed to provide the certificate during the TLS handshake.
+    ///
+    /// The callback runs once the ClientHello is received. It can inspect the SNI, ALPN and
+    /// signature schemes offered by the client via [TlsRef](crate::protocols::tls::TlsRef) and
+    /// choose the certificate to serve with `TlsRef::set_certified_key()`.
+    pub fn with_callbacks(callbacks: TlsAcceptCallbacks) -> Result<Self>
     where
         Self: Sized,
     {
-        // TODO: verify if/how callback in handshake can be done using Rustls
-        Error::e_explain(
-            InternalError,
-            "Certificate callbacks are not supported with feature \"rustls\".",
-        )
+        Ok(TlsSettings {
+            alpn_protocols: None,
+            cert_path: String::new(),
+            key_path: String::new(),
+            client_cert_verifier: None,
+            ocsp_stapler: None,
+            ticket_keys: None,
+            callbacks: Some(callbacks),
+            client_hello_fingerprint: false,
+            #[cfg(feature = "acme")]
+            acme_challenges: None,
+        })
+    }
+
+    /// Ask downstream clients for a certificate and verify it against the CA certificates in the
+    /// PEM file `ca_path`.
+    ///
+    /// If `crl_path` is set, the client certificate is also checked against the certificate
+    /// revocation lists in that PEM file. The details of the verified certificate are available
+    /// in the [SslDigest](crate::protocols::tls::SslDigest) of the connection.
+    pub fn set_client_cert_verification(
+        &mut self,
+        ca_path: &str,
+        crl_path: Option<&str>,
+        mode: ClientCertMode,
+    ) -> Result<()> {
+        let mut roots = RootCertStore::empty();
+        load_ca_file_into_store(ca_path, &mut roots)?;
+        let mut builder = WebPkiClientVerifier::builder(Arc::new(roots));
+        if let Some(crl_path) = crl_path {
+            builder = builder
+                .with_crls(load_crls(crl_path)?)
+                .only_check_end_entity_revocation();
+        }
+        if mode == ClientCertMode::Optional {
+            builder = builder.allow_unauthenticated();
+        }
+        let verifier = builder.build().explain_err(InternalError, |e| {
+            format!("Failed to create client certificate verifier: {e}")
+        })?;
+        self.client_cert_verifier = Some(verifier);
+        Ok(())
+    }
+
+    /// Staple the OCSP response of the given [OcspStapler] to the handshakes of the clients that
+    /// request it
+    ///
+    /// The response should belong to the certificate of this endpoint. With
+    /// [TlsSettings::with_callbacks()] the OCSP response is part of the `CertifiedKey` chosen by
+    /// the callback instead.
+    pub fn set_ocsp_stapler(&mut self, stapler: Arc<OcspStapler>) -> Result<()> {
+        self.ocsp_stapler = Some(stapler);
+        Ok(())
+    }
+
+    /// Encrypt session tickets with the given [TicketKeys], so that clients can resume their
+    /// sessions with the other endpoints and processes sharing the keys
+    ///
+    /// Without ticket keys, rustls resumes sessions from a cache of this endpoint only.
+    pub fn set_ticket_keys(&mut self, keys: Arc<TicketKeys>) -> Result<()> {
+        self.ticket_keys = Some(keys);
+        Ok(())
+    }
+
+    /// Compute the JA3 and JA4 fingerprints of the ClientHello of each connection, which is
+    /// default off.
+    ///
+    /// The fingerprints are available in the [SslDigest](crate::protocols::tls::SslDigest) of
+    /// the connection. This costs a copy of the ClientHello per handshake.
+    pub fn enable_client_hello_fingerprint(&mut self) {
+        self.client_hello_fingerprint = true;
+    }
+
+    /// Answer the TLS-ALPN-01 challenges of the given [AcmeChallenges] on this endpoint
+    ///
+    /// The `acme-tls/1` handshakes of the challenges are completed with the challenge
+    /// certificate and then closed. Other handshakes are not affected.
+    #[cfg(feature = "acme")]
+    pub fn set_acme_challenges(&mut self, challenges: Arc<AcmeChallenges>) {
+        self.acme_challenges = Some(challenges);
     }
 }
 
 impl Acceptor {
-    pub async fn tls_handshake<S: IO>(&self, stream: S) -> Result<TlsStream<S>> {
+    // Build the connection of a handshake via `accept` with the server config of this acceptor,
+    // serving the given certificate chosen by the callback
+    pub(crate) fn accept_with_cert<T>(
+        &self,
+        cert: Option<Arc<CertifiedKey>>,
+        accept: impl FnOnce(Arc<ServerConfig>) -> T,
+    ) -> T {
+        CALLBACK_CERT.with(|c| *c.borrow_mut() = cert);
+        let accepted = accept(self.config.clone());
+        // not taken if the handshake failed before resolving the certificate
+        CALLBACK_CERT.with(|c| c.borrow_mut().take());
+        accepted
+    }
+
+    pub async fn tls_handshake<S: IO>(&self, mut stream: S) -> Result<TlsStream<S>> {
         debug!("new tls session");
+        #[cfg(feature = "acme")]
+        if let Some(challenges) = self.acme_challenges.as_ref() {
+            if let Some(cert) = acme_challenge_cert(challenges, &mut stream).await {
+                return answer_acme_challenge(&cert, stream).await;
+            }
+        }
+        let fingerprint = if self.client_hello_fingerprint {
+            fingerprint_client_hello(&mut stream).await
+        } else {
+            None
+        };
         // TODO: be able to offload this handshake in a thread pool
-        if let Some(cb) = self.callbacks.as_ref() {
-            handshake_with_callback(self, stream, cb).await
+        let mut tls_stream = if let Some(cb) = self.callbacks.as_ref() {
+            handshake_with_callback(self, stream, cb).await?
         } else {
-            handshake(self, stream).await
+            handshake(self, stream).await?
+        };
+        if let Some(fingerprint) = fingerprint {
+            tls_stream.set_client_hello_fingerprint(fingerprint);
         }
+        Ok(tls_stream)
     }
 }
+
+// Complete the handshake of a TLS-ALPN-01 challenge with the challenge certificate, see RFC 8737 3
+#[cfg(feature = "acme")]
+async fn answer_acme_challenge<S: IO>(cert: &TlsAlpnCert, stream: S) -> Result<TlsStream<S>> {
+    use pingora_error::Error;
+    use pingora_rustls::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio::io::AsyncWriteExt;
+
+    let mut config =
+        ServerConfig::builder_with_protocol_versions(&[&version::TLS12, &version::TLS13])
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(cert.cert.clone())],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key.clone())),
+            )
+            .explain_err(InternalError, |e| {
+                format!("Failed to create challenge config: {e}")
+            })?;
+    // the handshake must negotiate acme-tls/1, see RFC 8737 3
+    config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+    let config = Arc::new(config);
+    let acceptor = Acceptor {
+        acceptor: RusTlsAcceptor::from(config.clone()),
+        config,
+        callbacks: None,
+        client_hello_fingerprint: false,
+        #[cfg(feature = "acme")]
+        acme_challenges: None,
+    };
+
+    let mut tls_stream = handshake(&acceptor, stream).await?;
+    let _ = tls_stream.shutdown().await;
+    Error::e_explain(CHALLENGE_ANSWERED, "answered ACME TLS-ALPN-01 challenge")
+}
@@ -0,0 +1,484 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shared TLS session ticket keys
+//!
+//! By default each TLS listener encrypts its session tickets with a random key of its own, so a
+//! client can only resume its session with the same listener of the same process. A
+//! [TicketKeys] is attached to listeners via `TlsSettings::set_ticket_keys()` instead, so that
+//! all the listeners and processes using the same keys accept each other's tickets.
+//!
+//! New tickets are encrypted with the current key. The previous keys are kept to decrypt the
+//! tickets issued before a rotation; such tickets are renewed with the current key.
+//!
+//! The keys are stored in a file of concatenated 80-byte keys with the current key first, the
+//! same format as the `ssl_session_ticket_key` files of nginx. When run as a
+//! [BackgroundService], [TicketKeys] rotates the keys every `rotate_interval` and writes them to
+//! the file, and it picks up the keys rotated by other processes sharing the file. The processes
+//! rotate the keys under an advisory lock of the `<file>.lock` file, so only one of them rotates
+//! the keys at a time and on top of the latest ones in the file. Because the
+//! keys live in the file, the new process of a graceful upgrade takes over the keys of the old
+//! one and clients resume their sessions across upgrades and restarts.
+
+use async_trait::async_trait;
+use log::{error, info};
+use parking_lot::RwLock;
+use pingora_error::{Error, ErrorType::*, OrErr, Result};
+use rand::RngCore;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+
+/// The default interval to rotate the keys
+pub const DEFAULT_ROTATE_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// The default number of previous keys kept to decrypt tickets
+pub const DEFAULT_PREVIOUS_KEYS: usize = 2;
+
+/// The default interval to check the key file for keys rotated by other processes
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Clients keep tickets for at most 7 days, see RFC 8446 4.6.1
+const MAX_TICKET_LIFETIME: Duration = Duration::from_secs(7 * 24 * 3600);
+
+const TICKET_KEY_ERR: pingora_error::ErrorType = Custom("TicketKeyError");
+
+// name 16 | HMAC secret 32 | AES key 32
+const KEY_LEN: usize = 80;
+const KEY_NAME_LEN: usize = 16;
+
+type TicketKey = [u8; KEY_LEN];
+
+#[derive(Debug)]
+struct KeySet {
+    // the current key first
+    keys: Vec<TicketKey>,
+    // when the keys were last rotated, the mtime of the file if there is one
+    updated: SystemTime,
+}
+
+/// The session ticket keys shared by TLS listeners
+pub struct TicketKeys {
+    path: Option<PathBuf>,
+    keys: RwLock<KeySet>,
+    /// How often the [BackgroundService] rotates the keys. Default is [DEFAULT_ROTATE_INTERVAL].
+    pub rotate_interval: Duration,
+    /// How many previous keys are kept after a rotation. Default is [DEFAULT_PREVIOUS_KEYS].
+    pub previous_keys: usize,
+    /// How often the [BackgroundService] checks the key file for keys rotated by other
+    /// processes. Default is [DEFAULT_CHECK_INTERVAL].
+    pub check_interval: Duration,
+}
+
+impl std::fmt::Debug for TicketKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketKeys")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for TicketKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TicketKeys {
+    fn with_keys(path: Option<PathBuf>, keys: Vec<TicketKey>, updated: SystemTime) -> Self {
+        TicketKeys {
+            path,
+            keys: RwLock::new(KeySet { keys, updated }),
+            rotate_interval: DEFAULT_ROTATE_INTERVAL,
+            previous_keys: DEFAULT_PREVIOUS_KEYS,
+            check_interval: DEFAULT_CHECK_INTERVAL,
+        }
+    }
+
+    /// Create new keys in memory, starting with a random key
+    ///
+    /// These keys are only shared by the listeners of this process.
+    pub fn new() -> Self {
+        Self::with_keys(None, vec![generate_key()], SystemTime::now())
+    }
+
+    /// Load the keys in the given file
+    ///
+    /// The file is created with a random key if it does not exist yet.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let exists = path.try_exists().or_err_with(TICKET_KEY_ERR, || {
+            format!("fail to access ticket key file {path:?}")
+        })?;
+        let keys = Self::with_keys(Some(path), vec![], SystemTime::UNIX_EPOCH);
+        if exists {
+            keys.reload()?;
+        } else {
+            // another process may create the file first, then its keys are used
+            keys.rotate_keys(false)?;
+        }
+        Ok(keys)
+    }
+
+    /// Make a new random key the current one
+    ///
+    /// The current key and the newest previous keys up to `previous_keys` are kept to decrypt
+    /// tickets. The keys are written to the key file if there is one.
+    pub fn rotate(&self) -> Result<()> {
+        self.rotate_keys(true).map(|_| ())
+    }
+
+    // Rotate the keys on top of the latest ones in the key file. Unless `force`, the keys are only
+    // rotated if they are still due, i.e. no other process rotated them in the meantime.
+    fn rotate_keys(&self, force: bool) -> Result<bool> {
+        let Some(path) = self.path.as_ref() else {
+            let keys = self.next_keys();
+            *self.keys.write() = KeySet {
+                keys,
+                updated: SystemTime::now(),
+            };
+            return Ok(true);
+        };
+        let _lock = lock(path)?;
+        let exists = path.try_exists().or_err_with(TICKET_KEY_ERR, || {
+            format!("fail to access ticket key file {path:?}")
+        })?;
+        if exists {
+            self.reload()?;
+        }
+        if !force && !self.needs_rotation() {
+            return Ok(false);
+        }
+        let keys = self.next_keys();
+        save_keys(path, &keys)?;
+        let updated = modified(path)?;
+        *self.keys.write() = KeySet { keys, updated };
+        Ok(true)
+    }
+
+    fn next_keys(&self) -> Vec<TicketKey> {
+        let mut keys = Vec::with_capacity(self.previous_keys + 1);
+        keys.push(generate_key());
+        keys.extend(self.keys.read().keys.iter().take(self.previous_keys));
+        keys
+    }
+
+    /// Load the keys in the key file again, e.g. after they were rotated by another process
+    pub fn reload(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        // the mtime is too coarse to tell whether the file changed, the keys are compared instead
+        let updated = modified(path)?;
+        let data =
+            std::fs::read(path).or_err_with(TICKET_KEY_ERR, || format!("fail to read {path:?}"))?;
+        let keys = parse_keys(&data).or_err_with(TICKET_KEY_ERR, || {
+            format!("invalid ticket key file {path:?}")
+        })?;
+        let mut set = self.keys.write();
+        if set.keys != keys {
+            *set = KeySet { keys, updated };
+        }
+        Ok(())
+    }
+
+    /// The key to encrypt new tickets with when `name` is `None`, otherwise the key with the
+    /// given name to decrypt a ticket with
+    pub(crate) fn lookup(&self, name: Option<&[u8]>) -> Option<TicketKey> {
+        let set = self.keys.read();
+        match name {
+            None => set.keys.first().copied(),
+            Some(name) => set
+                .keys
+                .iter()
+                .find(|key| key[..KEY_NAME_LEN] == *name)
+                .copied(),
+        }
+    }
+
+    /// How long clients may keep a ticket: until its key is rotated out
+    pub(crate) fn ticket_lifetime(&self) -> u32 {
+        let lifetime = self.rotate_interval * (self.previous_keys.max(1) as u32);
+        lifetime.min(MAX_TICKET_LIFETIME).as_secs() as u32
+    }
+
+    fn needs_rotation(&self) -> bool {
+        let updated = self.keys.read().updated;
+        matches!(SystemTime::now().duration_since(updated), Ok(age) if age >= self.rotate_interval)
+    }
+}
+
+fn generate_key() -> TicketKey {
+    let mut key = [0; KEY_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut key);
+    key
+}
+
+fn parse_keys(data: &[u8]) -> Result<Vec<TicketKey>> {
+    let keys = data.chunks_exact(KEY_LEN);
+    if data.is_empty() || !keys.remainder().is_empty() {
+        return Error::e_explain(
+            TICKET_KEY_ERR,
+            format!(
+                "{} bytes of keys, expecting a multiple of {KEY_LEN}",
+                data.len()
+            ),
+        );
+    }
+    Ok(keys.map(|key| key.try_into().unwrap()).collect())
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .or_err_with(TICKET_KEY_ERR, || format!("fail to stat {path:?}"))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Take the advisory lock shared by the processes using the key file, until the returned file is
+// dropped. The key file itself is replaced on every save so a separate file is locked.
+#[cfg(unix)]
+fn lock(path: &Path) -> Result<std::fs::File> {
+    use nix::fcntl::{flock, FlockArg};
+    use std::os::unix::io::AsRawFd;
+
+    let lock_path = with_suffix(path, ".lock");
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .or_err_with(TICKET_KEY_ERR, || format!("fail to open {lock_path:?}"))?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)
+        .or_err_with(TICKET_KEY_ERR, || format!("fail to lock {lock_path:?}"))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn lock(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Write to a temporary file first so that other processes never read a partial file. The name of
+// the temporary file is unique to this process so that processes never write to the same one.
+fn save_keys(path: &Path, keys: &[TicketKey]) -> Result<()> {
+    let tmp = with_suffix(path, &format!(".{}.tmp", std::process::id()));
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(&tmp)
+        .or_err_with(TICKET_KEY_ERR, || format!("fail to create {tmp:?}"))?;
+    file.write_all(&keys.concat())
+        .and_then(|_| file.sync_all())
+        .or_err_with(TICKET_KEY_ERR, || format!("fail to write {tmp:?}"))?;
+    std::fs::rename(&tmp, path).or_err_with(TICKET_KEY_ERR, || {
+        format!("fail to rename {tmp:?} to {path:?}")
+    })
+}
+
+#[async_trait]
+impl BackgroundService for TicketKeys {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            if let Err(e) = self.reload() {
+                error!("Failed to reload TLS session ticket keys: {e}");
+            }
+            if self.needs_rotation() {
+                match self.rotate_keys(false) {
+                    Ok(true) => info!("Rotated TLS session ticket keys"),
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to rotate TLS session ticket keys: {e}"),
+                }
+            }
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(self.check_interval) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn key_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("pingora_ticket_keys_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn name(key: &TicketKey) -> &[u8] {
+        &key[..KEY_NAME_LEN]
+    }
+
+    #[test]
+    fn test_rotate() {
+        let keys = TicketKeys::new();
+        let first = keys.lookup(None).unwrap();
+        assert_eq!(keys.lookup(Some(name(&first))), Some(first));
+        assert!(keys.lookup(Some(&[0; KEY_NAME_LEN])).is_none());
+
+        keys.rotate().unwrap();
+        let second = keys.lookup(None).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(keys.lookup(Some(name(&first))), Some(first));
+
+        keys.rotate().unwrap();
+        keys.rotate().unwrap();
+        assert_eq!(keys.keys.read().keys.len(), 1 + DEFAULT_PREVIOUS_KEYS);
+        assert!(keys.lookup(Some(name(&first))).is_none());
+        assert_eq!(keys.lookup(Some(name(&second))), Some(second));
+    }
+
+    #[test]
+    fn test_ticket_lifetime() {
+        let mut keys = TicketKeys::new();
+        assert_eq!(keys.ticket_lifetime(), 24 * 3600);
+        keys.rotate_interval = Duration::from_secs(30 * 24 * 3600);
+        assert_eq!(keys.ticket_lifetime(), 7 * 24 * 3600);
+        keys.rotate_interval = Duration::from_secs(60);
+        keys.previous_keys = 0;
+        assert_eq!(keys.ticket_lifetime(), 60);
+        assert!(!keys.needs_rotation());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let path = key_path("file");
+        let keys = TicketKeys::from_file(&path).unwrap();
+        let current = keys.lookup(None).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), current);
+
+        let other = TicketKeys::from_file(&path).unwrap();
+        assert_eq!(other.lookup(None), Some(current));
+        other.reload().unwrap();
+        assert_eq!(other.lookup(None), Some(current));
+
+        keys.rotate().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), 2 * KEY_LEN);
+        other.reload().unwrap();
+        assert_eq!(other.lookup(None), keys.lookup(None));
+        assert_eq!(other.lookup(Some(name(&current))), Some(current));
+
+        std::fs::write(&path, [0; 10]).unwrap();
+        assert!(TicketKeys::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{path}.lock")).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_shared_file() {
+        let path = key_path("shared");
+        let keys = TicketKeys::from_file(&path).unwrap();
+        let other = TicketKeys::from_file(&path).unwrap();
+        // both are due, the first one rotates and the other one picks up its keys instead
+        keys.keys.write().updated = SystemTime::UNIX_EPOCH;
+        other.keys.write().updated = SystemTime::UNIX_EPOCH;
+        assert!(keys.rotate_keys(false).unwrap());
+        assert!(!other.rotate_keys(false).unwrap());
+        assert_eq!(other.lookup(None), keys.lookup(None));
+        assert_eq!(std::fs::read(&path).unwrap().len(), 2 * KEY_LEN);
+
+        // an explicit rotation is on top of the keys rotated by the other process meanwhile
+        keys.rotate().unwrap();
+        let current = keys.lookup(None).unwrap();
+        other.rotate().unwrap();
+        assert_eq!(other.lookup(Some(name(&current))), Some(current));
+        assert_eq!(std::fs::read(&path).unwrap().len(), 3 * KEY_LEN);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{path}.lock")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_across_listeners() {
+        use crate::connectors::{ConnectorOptions, TransportConnector};
+        use crate::listeners::tls::TlsSettings;
+        use crate::protocols::l4::stream::Stream as L4Stream;
+        use crate::upstreams::peer::BasicPeer;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Two listeners, e.g. of the old and the new process of an upgrade, serve one connection
+        // each. Return whether the client resumed its session of the first with the second.
+        async fn resumed(keys: [Arc<TicketKeys>; 2]) -> Vec<bool> {
+            let cert = format!(
+                "{}/tests/keys/server_rustls.crt",
+                env!("CARGO_MANIFEST_DIR")
+            );
+            let key = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut acceptors = vec![];
+            for keys in keys {
+                let mut settings = TlsSettings::intermediate(&cert, &key).unwrap();
+                settings.set_ticket_keys(keys).unwrap();
+                acceptors.push(settings.build());
+            }
+            let server = tokio::spawn(async move {
+                for acceptor in acceptors {
+                    let (io, _) = listener.accept().await.unwrap();
+                    let Ok(mut stream) = acceptor.tls_handshake(L4Stream::from(io)).await else {
+                        continue;
+                    };
+                    // the session tickets are sent along
+                    let _ = stream.write_all(b"hello").await;
+                    let _ = stream.flush().await;
+                    let mut buf = [0; 1];
+                    let _ = stream.read(&mut buf).await;
+                }
+            });
+
+            let mut options = ConnectorOptions::new(1);
+            options.ca_file = Some(cert);
+            options.tls_session_cache_size = 16;
+            let connector = TransportConnector::new(Some(options));
+            let mut peer = BasicPeer::new(&addr.to_string());
+            peer.sni = "openrusty.org".to_string();
+
+            let mut resumed = vec![];
+            for _ in 0..2 {
+                let mut stream = connector.new_stream(&peer).await.unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                resumed.push(stream.get_ssl_digest().unwrap().resumed);
+            }
+            server.await.unwrap();
+            resumed
+        }
+
+        let path = key_path("handshake");
+        let shared = [
+            Arc::new(TicketKeys::from_file(&path).unwrap()),
+            Arc::new(TicketKeys::from_file(&path).unwrap()),
+        ];
+        assert_eq!(resumed(shared).await, vec![false, true]);
+        std::fs::remove_file(&path).unwrap();
+
+        let separate = [Arc::new(TicketKeys::new()), Arc::new(TicketKeys::new())];
+        assert_eq!(resumed(separate).await, vec![false, false]);
+    }
+}
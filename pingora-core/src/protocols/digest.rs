@@ -14,6 +14,7 @@
 
 //! Extra information about the connection
 
+use std::net::SocketAddr as InetSocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
@@ -21,6 +22,7 @@ use once_cell::sync::OnceCell;
 
 use super::l4::ext::{get_original_dest, get_recv_buf, get_tcp_info, TCP_INFO};
 use super::l4::socket::SocketAddr;
+use super::proxy_protocol::ProxyHeader;
 use super::raw_connect::ProxyDigest;
 use super::tls::digest::SslDigest;
 
@@ -49,16 +51,43 @@ pub trait ProtoDigest {
 pub struct TimingDigest {
     /// When this connection was established
     pub established_ts: SystemTime,
+    /// The attempts raced to establish this connection when the peer has several addresses,
+    /// in the order they started. Empty otherwise.
+    pub connect_attempts: Vec<ConnectAttempt>,
 }
 
 impl Default for TimingDigest {
     fn default() -> Self {
         TimingDigest {
             established_ts: SystemTime::UNIX_EPOCH,
+            connect_attempts: vec![],
         }
     }
 }
 
+/// How a connection attempt ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectOutcome {
+    /// The attempt established the connection
+    Succeeded,
+    /// The attempt failed
+    Failed,
+    /// The attempt was canceled because another one succeeded first
+    Abandoned,
+}
+
+/// The timing of one of the connection attempts to the addresses of a peer, see RFC 8305
+#[derive(Clone, Debug)]
+pub struct ConnectAttempt {
+    /// The address connected to
+    pub addr: InetSocketAddr,
+    /// When the attempt started
+    pub start_ts: SystemTime,
+    /// How long the attempt lasted until it ended
+    pub duration: Duration,
+    pub outcome: ConnectOutcome,
+}
+
 #[derive(Debug)]
 /// The interface to return socket-related information
 pub struct SocketDigest {
@@ -72,6 +101,8 @@ pub struct SocketDigest {
     pub local_addr: OnceCell<Option<SocketAddr>>,
     /// Original destination address
     pub original_dst: OnceCell<Option<SocketAddr>>,
+    /// The PROXY protocol header received at the beginning of this connection
+    pub proxy_header: OnceCell<Box<ProxyHeader>>,
 }
 
 impl SocketDigest {
@@ -82,6 +113,7 @@ impl SocketDigest {
             peer_addr: OnceCell::new(),
             local_addr: OnceCell::new(),
             original_dst: OnceCell::new(),
+            proxy_header: OnceCell::new(),
         }
     }
 
@@ -92,6 +124,7 @@ impl SocketDigest {
             peer_addr: OnceCell::new(),
             local_addr: OnceCell::new(),
             original_dst: OnceCell::new(),
+            proxy_header: OnceCell::new(),
         }
     }
 
@@ -123,6 +156,11 @@ impl SocketDigest {
             .as_ref()
     }
 
+    /// Return the PROXY protocol header of this connection, if any
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy_header.get().map(|h| h.as_ref())
+    }
+
     fn is_inet(&self) -> bool {
         self.local_addr().and_then(|p| p.as_inet()).is_some()
     }
@@ -19,12 +19,17 @@ use std::time::Duration;
 
 use super::v1::client::HttpSession as Http1Session;
 use super::v2::client::Http2Session;
+#[cfg(feature = "quic")]
+use super::v3::client::Http3Session;
 use crate::protocols::{Digest, SocketAddr, Stream};
 
-/// A type for Http client session. It can be either an Http1 connection or an Http2 stream.
+/// A type for Http client session. It can be either an Http1 connection, an Http2 stream or an
+/// Http3 stream.
 pub enum HttpSession {
     H1(Http1Session),
     H2(Http2Session),
+    #[cfg(feature = "quic")]
+    H3(Box<Http3Session>),
 }
 
 impl HttpSession {
@@ -32,6 +37,8 @@ impl HttpSession {
         match self {
             Self::H1(s) => Some(s),
             Self::H2(_) => None,
+            #[cfg(feature = "quic")]
+            Self::H3(_) => None,
         }
     }
 
@@ -39,6 +46,16 @@ impl HttpSession {
         match self {
             Self::H1(_) => None,
             Self::H2(s) => Some(s),
+            #[cfg(feature = "quic")]
+            Self::H3(_) => None,
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    pub fn as_http3(&self) -> Option<&Http3Session> {
+        match self {
+            Self::H3(s) => Some(s),
+            _ => None,
         }
     }
     /// Write the request header to the server
@@ -50,7 +67,14 @@ impl HttpSession {
                 h1.write_request_header(req).await?;
                 Ok(())
             }
-            HttpSession::H2(h2) => h2.write_request_header(req, false),
+            HttpSession::H2(h2) => {
+                if req.extensions.get::<h2::ext::Protocol>().is_some() {
+                    h2.wait_for_settings().await;
+                }
+                h2.write_request_header(req, false)
+            }
+            #[cfg(feature = "quic")]
+            HttpSession::H3(h3) => h3.write_request_header(req, false).await,
         }
     }
 
@@ -63,6 +87,8 @@ impl HttpSession {
                 Ok(())
             }
             HttpSession::H2(h2) => h2.write_request_body(data, end).await,
+            #[cfg(feature = "quic")]
+            HttpSession::H3(h3) => h3.write_request_body(data, end).await,
         }
     }
 
@@ -74,6 +100,8 @@ impl HttpSession {
                 Ok(())
             }
             HttpSession::H2(h2) => h2.finish_request_body(),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(h3) => h3.finish_request_body().await,
         }
     }
 
@@ -84,6 +112,8 @@ impl HttpSession {
         match self {
             HttpSession::H1(h1) => h1.read_timeout = Some(timeout),
             HttpSession::H2(h2) => h2.read_timeout = Some(timeout),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(h3) => h3.read_timeout = Some(timeout),
         }
     }
 
@@ -91,11 +121,13 @@ impl HttpSession {
     ///
     /// The timeout is per write operation, not on the overall time writing the entire request.
     ///
-    /// This is a noop for h2.
+    /// This is a noop for h2 and h3.
     pub fn set_write_timeout(&mut self, timeout: Duration) {
         match self {
             HttpSession::H1(h1) => h1.write_timeout = Some(timeout),
             HttpSession::H2(_) => { /* no write timeout because the actual write happens async*/ }
+            #[cfg(feature = "quic")]
+            HttpSession::H3(_) => { /* same as h2 */ }
         }
     }
 
@@ -109,6 +141,8 @@ impl HttpSession {
                 Ok(())
             }
             HttpSession::H2(h2) => h2.read_response_header().await,
+            #[cfg(feature = "quic")]
+            HttpSession::H3(h3) => h3.read_response_header().await,
         }
     }
 
@@ -119,6 +153,8 @@ impl HttpSession {
         match self {
             HttpSession::H1(h1) => h1.read_body_bytes().await,
             HttpSession::H2(h2) => h2.read_response_body().await,
+            #[cfg(feature = "quic")]
+            HttpSession::H3(h3) => h3.read_response_body().await,
         }
     }
 
@@ -127,16 +163,21 @@ impl HttpSession {
         match self {
             HttpSession::H1(h1) => h1.is_body_done(),
             HttpSession::H2(h2) => h2.response_finished(),
+            #[cfg(feature = "quic")]
+            HttpSession::H3(h3) => h3.response_finished(),
         }
     }
 
     /// Give up the http session abruptly.
     /// For H1 this will close the underlying connection
     /// For H2 this will send RST_STREAM frame to end this stream if the stream has not ended at all
+    /// For H3 this will reset the stream the same way
     pub async fn shutdown(&mut self) {
         match self {
             Self::H1(s) => s.shutdown().await,
             Self::H2(s) => s.shutdown(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.shutdown(),
         }
     }
 
@@ -147,6 +188,8 @@ impl HttpSession {
         match self {
             Self::H1(s) => s.resp_header(),
             Self::H2(s) => s.response_header(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.response_header(),
         }
     }
 
@@ -158,16 +201,20 @@ impl HttpSession {
         match self {
             Self::H1(s) => Some(s.digest()),
             Self::H2(s) => s.digest(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.digest(),
         }
     }
 
     /// Return a mutable [Digest] reference for the connection.
     ///
-    /// Will return `None` if this is an H2 session and multiple streams are open.
+    /// Will return `None` if this is an H2 or H3 session and multiple streams are open.
     pub fn digest_mut(&mut self) -> Option<&mut Digest> {
         match self {
             Self::H1(s) => Some(s.digest_mut()),
             Self::H2(s) => s.digest_mut(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.digest_mut(),
         }
     }
 
@@ -176,6 +223,8 @@ impl HttpSession {
         match self {
             Self::H1(s) => s.server_addr(),
             Self::H2(s) => s.server_addr(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.server_addr(),
         }
     }
 
@@ -184,15 +233,19 @@ impl HttpSession {
         match self {
             Self::H1(s) => s.client_addr(),
             Self::H2(s) => s.client_addr(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.client_addr(),
         }
     }
 
     /// Get the reference of the [Stream] that this HTTP/1 session is operating upon.
-    /// None if the HTTP session is over H2
+    /// None if the HTTP session is over H2 or H3
     pub fn stream(&self) -> Option<&Stream> {
         match self {
             Self::H1(s) => Some(s.stream()),
             Self::H2(_) => None,
+            #[cfg(feature = "quic")]
+            Self::H3(_) => None,
         }
     }
 }
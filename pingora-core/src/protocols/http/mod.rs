@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! HTTP/1.x and HTTP/2 implementation APIs
+//! HTTP/1.x, HTTP/2 and HTTP/3 implementation APIs
 
 mod body_buffer;
 pub mod bridge;
@@ -24,6 +24,9 @@ pub mod error_resp;
 pub mod server;
 pub mod v1;
 pub mod v2;
+#[cfg(feature = "quic")]
+pub mod v3;
+pub mod websocket;
 
 pub use server::Session as ServerSession;
 
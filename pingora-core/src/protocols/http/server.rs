@@ -17,6 +17,8 @@
 use super::error_resp;
 use super::v1::server::HttpSession as SessionV1;
 use super::v2::server::HttpSession as SessionV2;
+#[cfg(feature = "quic")]
+use super::v3::server::HttpSession as SessionV3;
 use super::HttpTask;
 use crate::protocols::{Digest, SocketAddr, Stream};
 use bytes::Bytes;
@@ -26,10 +28,12 @@ use pingora_error::Result;
 use pingora_http::{RequestHeader, ResponseHeader};
 use std::time::Duration;
 
-/// HTTP server session object for both HTTP/1.x and HTTP/2
+/// HTTP server session object for HTTP/1.x, HTTP/2 and HTTP/3
 pub enum Session {
     H1(SessionV1),
     H2(SessionV2),
+    #[cfg(feature = "quic")]
+    H3(Box<SessionV3>),
 }
 
 impl Session {
@@ -43,11 +47,23 @@ impl Session {
         Self::H2(session)
     }
 
-    /// Whether the session is HTTP/2. If not it is HTTP/1.x
+    /// Create a new [`Session`] from an established HTTP/3 stream
+    #[cfg(feature = "quic")]
+    pub fn new_http3(session: SessionV3) -> Self {
+        Self::H3(Box::new(session))
+    }
+
+    /// Whether the session is HTTP/2. If not it is HTTP/1.x or HTTP/3
     pub fn is_http2(&self) -> bool {
         matches!(self, Self::H2(_))
     }
 
+    /// Whether the session is HTTP/3
+    #[cfg(feature = "quic")]
+    pub fn is_http3(&self) -> bool {
+        matches!(self, Self::H3(_))
+    }
+
     /// Read the request header. This method is required to be called first before doing anything
     /// else with the session.
     /// - `Ok(true)`: successful
@@ -59,8 +75,11 @@ impl Session {
                 let read = s.read_request().await?;
                 Ok(read.is_some())
             }
-            // This call will always return `Ok(true)` for Http2 because the request is already read
+            // This call will always return `Ok(true)` for Http2 and Http3 because the request is
+            // already read
             Self::H2(_) => Ok(true),
+            #[cfg(feature = "quic")]
+            Self::H3(_) => Ok(true),
         }
     }
 
@@ -71,6 +90,8 @@ impl Session {
         match self {
             Self::H1(s) => s.req_header(),
             Self::H2(s) => s.req_header(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.req_header(),
         }
     }
 
@@ -81,6 +102,8 @@ impl Session {
         match self {
             Self::H1(s) => s.req_header_mut(),
             Self::H2(s) => s.req_header_mut(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.req_header_mut(),
         }
     }
 
@@ -103,6 +126,8 @@ impl Session {
         match self {
             Self::H1(s) => s.read_body_bytes().await,
             Self::H2(s) => s.read_body_bytes().await,
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.read_body_bytes().await,
         }
     }
 
@@ -116,6 +141,8 @@ impl Session {
                 Ok(())
             }
             Self::H2(s) => s.write_response_header(resp, false),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.write_response_header(resp, false).await,
         }
     }
 
@@ -127,6 +154,8 @@ impl Session {
                 Ok(())
             }
             Self::H2(s) => s.write_response_header_ref(resp, false),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.write_response_header_ref(resp, false).await,
         }
     }
 
@@ -144,6 +173,8 @@ impl Session {
                 Ok(())
             }
             Self::H2(s) => s.write_body(data, end).await,
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.write_body(data, end).await,
         }
     }
 
@@ -152,12 +183,14 @@ impl Session {
         match self {
             Self::H1(_) => Ok(()), // TODO: support trailers for h1
             Self::H2(s) => s.write_trailers(trailers),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.write_trailers(trailers).await,
         }
     }
 
     /// Finish the life of this request.
     /// For H1, if connection reuse is supported, a Some(Stream) will be returned, otherwise None.
-    /// For H2, always return None because H2 stream is not reusable.
+    /// For H2 and H3, always return None because their streams are not reusable.
     pub async fn finish(self) -> Result<Option<Stream>> {
         match self {
             Self::H1(mut s) => {
@@ -169,6 +202,11 @@ impl Session {
                 s.finish()?;
                 Ok(None)
             }
+            #[cfg(feature = "quic")]
+            Self::H3(mut s) => {
+                s.finish().await?;
+                Ok(None)
+            }
         }
     }
 
@@ -176,26 +214,32 @@ impl Session {
         match self {
             Self::H1(s) => s.response_duplex_vec(tasks).await,
             Self::H2(s) => s.response_duplex_vec(tasks).await,
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.response_duplex_vec(tasks).await,
         }
     }
 
     /// Set connection reuse. `duration` defines how long the connection is kept open for the next
-    /// request to reuse. Noop for h2
+    /// request to reuse. Noop for h2 and h3
     pub fn set_keepalive(&mut self, duration: Option<u64>) {
         match self {
             Self::H1(s) => s.set_server_keepalive(duration),
             Self::H2(_) => {}
+            #[cfg(feature = "quic")]
+            Self::H3(_) => {}
         }
     }
 
     /// Sets the downstream read timeout. This will trigger if we're unable
     /// to read from the stream after `timeout`.
     ///
-    /// This is a noop for h2.
+    /// This is a noop for h2 and h3.
     pub fn set_read_timeout(&mut self, timeout: Duration) {
         match self {
             Self::H1(s) => s.set_read_timeout(timeout),
             Self::H2(_) => {}
+            #[cfg(feature = "quic")]
+            Self::H3(_) => {}
         }
     }
 
@@ -203,11 +247,13 @@ impl Session {
     /// to write to the stream after `timeout`. If a `min_send_rate` is
     /// configured then the `min_send_rate` calculated timeout has higher priority.
     ///
-    /// This is a noop for h2.
+    /// This is a noop for h2 and h3.
     pub fn set_write_timeout(&mut self, timeout: Duration) {
         match self {
             Self::H1(s) => s.set_write_timeout(timeout),
             Self::H2(_) => {}
+            #[cfg(feature = "quic")]
+            Self::H3(_) => {}
         }
     }
 
@@ -220,11 +266,13 @@ impl Session {
     /// Calculated write timeout is guaranteed to be at least 1s if `min_send_rate`
     /// is greater than zero, a send rate of zero is a noop.
     ///
-    /// This is a noop for h2.
+    /// This is a noop for h2 and h3.
     pub fn set_min_send_rate(&mut self, rate: usize) {
         match self {
             Self::H1(s) => s.set_min_send_rate(rate),
             Self::H2(_) => {}
+            #[cfg(feature = "quic")]
+            Self::H3(_) => {}
         }
     }
 
@@ -233,11 +281,26 @@ impl Session {
     /// For HTTP/1.1 this is a noop if the response is Upgrade or Continue and
     /// Expect: 100-continue was set on the request.
     ///
-    /// This is a noop for h2 because informational responses are always ignored.
+    /// This is a noop for h2 and h3 because informational responses are always ignored.
     pub fn set_ignore_info_resp(&mut self, ignore: bool) {
         match self {
             Self::H1(s) => s.set_ignore_info_resp(ignore),
             Self::H2(_) => {} // always ignored
+            #[cfg(feature = "quic")]
+            Self::H3(_) => {} // always ignored
+        }
+    }
+
+    /// Sets the Alt-Svc header value to advertise alternative services, e.g., HTTP/3, to the
+    /// client. The header is added to the final response header unless it already has one.
+    ///
+    /// This is a noop for h3.
+    pub fn set_alt_svc(&mut self, alt_svc: Option<HeaderValue>) {
+        match self {
+            Self::H1(s) => s.set_alt_svc(alt_svc),
+            Self::H2(s) => s.set_alt_svc(alt_svc),
+            #[cfg(feature = "quic")]
+            Self::H3(_) => {}
         }
     }
 
@@ -247,6 +310,8 @@ impl Session {
         match self {
             Self::H1(s) => s.request_summary(),
             Self::H2(s) => s.request_summary(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.request_summary(),
         }
     }
 
@@ -256,16 +321,21 @@ impl Session {
         match self {
             Self::H1(s) => s.response_written(),
             Self::H2(s) => s.response_written(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.response_written(),
         }
     }
 
     /// Give up the http session abruptly.
     /// For H1 this will close the underlying connection
     /// For H2 this will send RESET frame to end this stream without impacting the connection
+    /// For H3 this will stop this stream without impacting the connection
     pub async fn shutdown(&mut self) {
         match self {
             Self::H1(s) => s.shutdown().await,
             Self::H2(s) => s.shutdown(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.shutdown(),
         }
     }
 
@@ -273,6 +343,8 @@ impl Session {
         match self {
             Self::H1(s) => s.get_headers_raw_bytes(),
             Self::H2(s) => s.pseudo_raw_h1_request_header(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.pseudo_raw_h1_request_header(),
         }
     }
 
@@ -281,6 +353,8 @@ impl Session {
         match self {
             Self::H1(s) => s.is_body_done(),
             Self::H2(s) => s.is_body_done(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.is_body_done(),
         }
     }
 
@@ -288,10 +362,13 @@ impl Session {
     /// for H1 chunked encoding, this will end the last empty chunk
     /// for H1 content-length, this has no effect.
     /// for H2, this will send an empty DATA frame with END_STREAM flag
+    /// for H3, this will close the sending side of the stream
     pub async fn finish_body(&mut self) -> Result<()> {
         match self {
             Self::H1(s) => s.finish_body().await.map(|_| ()),
             Self::H2(s) => s.finish(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.finish().await,
         }
     }
 
@@ -342,6 +419,8 @@ impl Session {
         match self {
             Self::H1(s) => s.is_body_empty(),
             Self::H2(s) => s.is_body_empty(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.is_body_empty(),
         }
     }
 
@@ -349,6 +428,8 @@ impl Session {
         match self {
             Self::H1(s) => s.retry_buffer_truncated(),
             Self::H2(s) => s.retry_buffer_truncated(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.retry_buffer_truncated(),
         }
     }
 
@@ -356,6 +437,8 @@ impl Session {
         match self {
             Self::H1(s) => s.enable_retry_buffering(),
             Self::H2(s) => s.enable_retry_buffering(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.enable_retry_buffering(),
         }
     }
 
@@ -363,6 +446,8 @@ impl Session {
         match self {
             Self::H1(s) => s.get_retry_buffer(),
             Self::H2(s) => s.get_retry_buffer(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.get_retry_buffer(),
         }
     }
 
@@ -372,6 +457,8 @@ impl Session {
         match self {
             Self::H1(s) => s.read_body_or_idle(no_body_expected).await,
             Self::H2(s) => s.read_body_or_idle(no_body_expected).await,
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.read_body_or_idle(no_body_expected).await,
         }
     }
 
@@ -379,6 +466,8 @@ impl Session {
         match self {
             Self::H1(s) => Some(s),
             Self::H2(_) => None,
+            #[cfg(feature = "quic")]
+            Self::H3(_) => None,
         }
     }
 
@@ -386,6 +475,16 @@ impl Session {
         match self {
             Self::H1(_) => None,
             Self::H2(s) => Some(s),
+            #[cfg(feature = "quic")]
+            Self::H3(_) => None,
+        }
+    }
+
+    #[cfg(feature = "quic")]
+    pub fn as_http3(&self) -> Option<&SessionV3> {
+        match self {
+            Self::H3(s) => Some(s.as_ref()),
+            _ => None,
         }
     }
 
@@ -397,14 +496,32 @@ impl Session {
                 Box::new(ResponseHeader::build(100, Some(0)).unwrap()),
                 false,
             ),
+            #[cfg(feature = "quic")]
+            Self::H3(_) => Ok(()), // informational responses are not forwarded
         }
     }
 
     /// Whether this request is for upgrade (e.g., websocket)
+    ///
+    /// For HTTP/2 this is an extended CONNECT request, see [Self::connect_protocol()].
     pub fn is_upgrade_req(&self) -> bool {
         match self {
             Self::H1(s) => s.is_upgrade_req(),
-            Self::H2(_) => false,
+            Self::H2(s) => s.connect_protocol().is_some(),
+            #[cfg(feature = "quic")]
+            Self::H3(_) => false,
+        }
+    }
+
+    /// The protocol of an HTTP/2 extended CONNECT request (RFC 8441), e.g. `websocket`
+    ///
+    /// `None` for all other requests.
+    pub fn connect_protocol(&self) -> Option<&str> {
+        match self {
+            Self::H1(_) => None,
+            Self::H2(s) => s.connect_protocol(),
+            #[cfg(feature = "quic")]
+            Self::H3(_) => None,
         }
     }
 
@@ -413,6 +530,8 @@ impl Session {
         match self {
             Self::H1(s) => s.body_bytes_sent(),
             Self::H2(s) => s.body_bytes_sent(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.body_bytes_sent(),
         }
     }
 
@@ -421,6 +540,8 @@ impl Session {
         match self {
             Self::H1(s) => s.body_bytes_read(),
             Self::H2(s) => s.body_bytes_read(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.body_bytes_read(),
         }
     }
 
@@ -429,6 +550,8 @@ impl Session {
         match self {
             Self::H1(s) => Some(s.digest()),
             Self::H2(s) => s.digest(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.digest(),
         }
     }
 
@@ -439,6 +562,8 @@ impl Session {
         match self {
             Self::H1(s) => Some(s.digest_mut()),
             Self::H2(s) => s.digest_mut(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.digest_mut(),
         }
     }
 
@@ -447,6 +572,8 @@ impl Session {
         match self {
             Self::H1(s) => s.client_addr(),
             Self::H2(s) => s.client_addr(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.client_addr(),
         }
     }
 
@@ -455,15 +582,19 @@ impl Session {
         match self {
             Self::H1(s) => s.server_addr(),
             Self::H2(s) => s.server_addr(),
+            #[cfg(feature = "quic")]
+            Self::H3(s) => s.server_addr(),
         }
     }
 
     /// Get the reference of the [Stream] that this HTTP/1 session is operating upon.
-    /// None if the HTTP session is over H2
+    /// None if the HTTP session is over H2 or H3
     pub fn stream(&self) -> Option<&Stream> {
         match self {
             Self::H1(s) => Some(s.stream()),
             Self::H2(_) => None,
+            #[cfg(feature = "quic")]
+            Self::H3(_) => None,
         }
     }
 }
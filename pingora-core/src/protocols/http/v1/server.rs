@@ -77,6 +77,8 @@ pub struct HttpSession {
     min_send_rate: Option<usize>,
     /// When this is enabled informational response headers will not be proxied downstream
     ignore_info_resp: bool,
+    /// The Alt-Svc header to add to the final response headers if they don't have one
+    alt_svc: Option<HeaderValue>,
 }
 
 impl HttpSession {
@@ -113,6 +115,7 @@ impl HttpSession {
             digest,
             min_send_rate: None,
             ignore_info_resp: false,
+            alt_svc: None,
         }
     }
 
@@ -351,6 +354,11 @@ impl HttpSession {
         }
     }
 
+    /// Is the request a `CONNECT` request to open a tunnel
+    pub fn is_connect_req(&self) -> bool {
+        self.get_method() == Some(&Method::CONNECT)
+    }
+
     /// Get the request header as raw bytes, `b""` when the header doesn't exist
     pub fn get_header_bytes(&self, name: impl AsHeaderName) -> &[u8] {
         self.get_header(name).map_or(b"", |v| v.as_bytes())
@@ -417,21 +425,36 @@ impl HttpSession {
             }
         }
 
+        // a successful CONNECT turns the connection into a tunnel, see RFC 9110 9.3.6
+        let tunnel = self.is_connect_req() && header.status.is_success();
+        if tunnel {
+            header.remove_header(&header::CONTENT_LENGTH);
+            header.remove_header(&header::TRANSFER_ENCODING);
+        }
+
         // no need to add these headers to 1xx responses
         if !header.status.is_informational() && self.update_resp_headers {
             /* update headers */
             header.insert_header(header::DATE, date::get_cached_date())?;
+            if let Some(alt_svc) = self.alt_svc.as_ref() {
+                if header.headers.get(header::ALT_SVC).is_none() {
+                    header.insert_header(header::ALT_SVC, alt_svc)?;
+                }
+            }
 
-            // TODO: make these lazy static
-            let connection_value = if self.will_keepalive() {
-                "keep-alive"
-            } else {
-                "close"
-            };
-            header.insert_header(header::CONNECTION, connection_value)?;
+            // the connection of a tunnel carries no more HTTP messages
+            if !tunnel {
+                // TODO: make these lazy static
+                let connection_value = if self.will_keepalive() {
+                    "keep-alive"
+                } else {
+                    "close"
+                };
+                header.insert_header(header::CONNECTION, connection_value)?;
+            }
         }
 
-        if header.status == 101 {
+        if header.status == 101 || tunnel {
             // make sure the connection is closed at the end when 101/upgrade is used
             self.set_keepalive(None);
         }
@@ -452,6 +475,11 @@ impl HttpSession {
                     // a peer discards any further data received.
                     // https://www.rfc-editor.org/rfc/rfc6455#section-1.4
                     self.upgraded = true;
+                    if tunnel {
+                        // the tunnelled data follows the CONNECT request, which has no body
+                        let preread = self.preread_body.as_ref().unwrap().get(&self.buf[..]);
+                        self.body_reader.init_http10(preread);
+                    }
                 } else {
                     debug!("bad upgrade handshake!");
                     // reset request body buf and mark as done
@@ -496,9 +524,13 @@ impl HttpSession {
     /// `Some(true)` if the this is a successful upgrade
     /// `Some(false)` if the request is an upgrade but the response refuses it
     /// `None` if the request is not an upgrade.
+    ///
+    /// A `CONNECT` request is upgraded to a tunnel by a 2xx response.
     pub fn is_upgrade(&self, header: &ResponseHeader) -> Option<bool> {
         if self.is_upgrade_req() {
             Some(is_upgrade_resp(header))
+        } else if self.is_connect_req() {
+            Some(header.status.is_success())
         } else {
             None
         }
@@ -872,6 +904,14 @@ impl HttpSession {
         self.ignore_info_resp = ignore;
     }
 
+    /// Sets the Alt-Svc header value to advertise alternative services, e.g., HTTP/3, to the
+    /// client.
+    ///
+    /// The header is added to the final response headers unless they already have one.
+    pub fn set_alt_svc(&mut self, alt_svc: Option<HeaderValue>) {
+        self.alt_svc = alt_svc;
+    }
+
     /// Return the [Digest] of the connection.
     pub fn digest(&self) -> &Digest {
         &self.digest
@@ -1462,6 +1502,55 @@ mod tests_stream {
         assert!(!http_stream.is_body_done());
     }
 
+    #[tokio::test]
+    async fn connect_tunnel() {
+        let input = b"CONNECT pingora.org:443 HTTP/1.1\r\nHost: pingora.org:443\r\n\r\nhello";
+        let mock_io = Builder::new()
+            .read(&input[..])
+            .write(b"HTTP/1.1 200 OK\r\n\r\n")
+            .read(b" world")
+            .write(b"pong")
+            .build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        http_stream.read_request().await.unwrap();
+        assert!(http_stream.is_connect_req());
+        http_stream.update_resp_headers = false;
+        let mut response = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        response.insert_header("Content-Length", "0").unwrap();
+        http_stream
+            .write_response_header(Box::new(response))
+            .await
+            .unwrap();
+        assert!(!http_stream.will_keepalive());
+        // the data after the request header is tunnelled
+        let body = http_stream.read_body_bytes().await.unwrap().unwrap();
+        assert_eq!(body.as_ref(), b"hello");
+        let body = http_stream.read_body_bytes().await.unwrap().unwrap();
+        assert_eq!(body.as_ref(), b" world");
+        http_stream.write_body(b"pong").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_refused() {
+        let input = b"CONNECT pingora.org:443 HTTP/1.1\r\nHost: pingora.org:443\r\n\r\n";
+        let mock_io = Builder::new()
+            .read(&input[..])
+            .write(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+            .build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        http_stream.read_request().await.unwrap();
+        http_stream.update_resp_headers = false;
+        let mut response = ResponseHeader::build(StatusCode::FORBIDDEN, None).unwrap();
+        response.insert_header("Content-Length", "0").unwrap();
+        http_stream
+            .write_response_header(Box::new(response))
+            .await
+            .unwrap();
+        // no tunnel, the connection can serve the next request
+        assert!(http_stream.is_body_done());
+        assert!(!http_stream.upgraded);
+    }
+
     #[tokio::test]
     async fn set_server_keepalive() {
         // close
@@ -1573,6 +1662,45 @@ mod tests_stream {
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn write_alt_svc() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut http_stream = HttpSession::new(Box::new(server));
+        http_stream.set_alt_svc(Some(HeaderValue::from_static("h3=\":443\"; ma=86400")));
+        let response_200 = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        http_stream
+            .write_response_header_ref(&response_200)
+            .await
+            .unwrap();
+        drop(http_stream);
+
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        let resp = str::from_utf8(&buf).unwrap();
+        assert!(
+            resp.contains("alt-svc: h3=\":443\"; ma=86400\r\n"),
+            "{resp}"
+        );
+
+        // the header set by the application is kept
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut http_stream = HttpSession::new(Box::new(server));
+        http_stream.set_alt_svc(Some(HeaderValue::from_static("h3=\":443\"; ma=86400")));
+        let mut response_200 = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        response_200.insert_header("Alt-Svc", "clear").unwrap();
+        http_stream
+            .write_response_header_ref(&response_200)
+            .await
+            .unwrap();
+        drop(http_stream);
+
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        let resp = str::from_utf8(&buf).unwrap();
+        assert!(resp.contains("Alt-Svc: clear\r\n"), "{resp}");
+        assert!(!resp.contains("ma=86400"), "{resp}");
+    }
+
     #[tokio::test]
     async fn write_informational_100_not_ignored_if_expect_continue() {
         let input = b"GET / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n";
@@ -18,6 +18,7 @@
 use bytes::Bytes;
 use futures::FutureExt;
 use h2::client::{self, ResponseFuture, SendRequest};
+use h2::ext::Protocol;
 use h2::{Reason, RecvStream, SendStream};
 use http::HeaderMap;
 use log::{debug, error, warn};
@@ -98,14 +99,41 @@ impl Http2Session {
         }
     }
 
+    /// Wait until the SETTINGS of the server are received
+    ///
+    /// Whether the server enabled extended CONNECT (RFC 8441) is only known then, so this should
+    /// be awaited before sending one on a new connection. It returns right away once known, and
+    /// gives up after a few seconds or when the connection is closed.
+    pub async fn wait_for_settings(&self) {
+        if self.send_req.is_extended_connect_protocol_enabled() {
+            return;
+        }
+        let mut settings = self.conn.settings();
+        let _ = tokio::time::timeout(PING_TIMEOUT, settings.wait_for(|received| *received)).await;
+    }
+
     /// Write the request header to the server
+    ///
+    /// An extended CONNECT request (RFC 8441) is sent when the request has a
+    /// [`h2::ext::Protocol`] extension, which fails if the server has not enabled it. Await
+    /// [Self::wait_for_settings()] first on a new connection.
     pub fn write_request_header(&mut self, mut req: Box<RequestHeader>, end: bool) -> Result<()> {
         if self.req_sent.is_some() {
             // cannot send again, TODO: warn
             return Ok(());
         }
         Self::sanitize_request_header(&mut req)?;
-        let parts = req.as_owned_parts();
+        let mut parts = req.as_owned_parts();
+        // the :protocol of extended CONNECT is not kept by as_owned_parts()
+        if let Some(protocol) = req.extensions.get::<Protocol>() {
+            if !self.send_req.is_extended_connect_protocol_enabled() {
+                return Error::e_explain(
+                    H2Error,
+                    "extended CONNECT is not enabled by the h2 server",
+                );
+            }
+            parts.extensions.insert(protocol.clone());
+        }
         let request = http::Request::from_parts(parts, ());
         // There is no write timeout for h2 because the actual write happens async from this fn
         let (resp_fut, send_body) = self
@@ -457,14 +485,38 @@ fn handle_read_header_error(e: h2::Error) -> Box<Error> {
 use tokio::sync::oneshot;
 
 pub async fn drive_connection<S>(
+    c: client::Connection<S>,
+    id: UniqueIDType,
+    closed: watch::Sender<bool>,
+    ping_interval: Option<Duration>,
+    ping_timeout_occurred: Arc<AtomicBool>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let (settings, _) = watch::channel(false);
+    drive_connection_notify_settings(
+        c,
+        id,
+        closed,
+        settings,
+        ping_interval,
+        ping_timeout_occurred,
+    )
+    .await
+}
+
+// Same as drive_connection(), and `settings` is set once the SETTINGS of the server are received.
+pub(crate) async fn drive_connection_notify_settings<S>(
     mut c: client::Connection<S>,
     id: UniqueIDType,
     closed: watch::Sender<bool>,
+    settings: watch::Sender<bool>,
     ping_interval: Option<Duration>,
     ping_timeout_occurred: Arc<AtomicBool>,
 ) where
     S: AsyncRead + AsyncWrite + Send + Unpin,
 {
+    let ping_pong = c.ping_pong();
     let interval = ping_interval.unwrap_or(Duration::ZERO);
     if !interval.is_zero() {
         // for ping to inform this fn to drop the connection
@@ -473,8 +525,9 @@ pub async fn drive_connection<S>(
         let dropped = Arc::new(AtomicBool::new(false));
         let dropped2 = dropped.clone();
 
-        if let Some(ping_pong) = c.ping_pong() {
+        if let Some(mut ping_pong) = ping_pong {
             pingora_runtime::current_handle().spawn(async move {
+                notify_settings(&mut ping_pong, settings, id).await;
                 do_ping_pong(ping_pong, interval, tx, dropped2, id).await;
             });
         } else {
@@ -497,6 +550,11 @@ pub async fn drive_connection<S>(
 
         dropped.store(true, Ordering::Relaxed);
     } else {
+        if let Some(mut ping_pong) = ping_pong {
+            pingora_runtime::current_handle().spawn(async move {
+                notify_settings(&mut ping_pong, settings, id).await;
+            });
+        }
         match c.await {
             Ok(_) => debug!("H2 connection finished fd: {id}"),
             Err(e) => debug!("H2 connection fd: {id} errored: {e:?}"),
@@ -507,6 +565,19 @@ pub async fn drive_connection<S>(
 
 const PING_TIMEOUT: Duration = Duration::from_secs(5);
 
+// The SETTINGS of the server are the first frame it sends, so they are applied once the first
+// PING is answered. The PING fails when the connection is gone, which drops `settings`.
+async fn notify_settings(
+    ping_pong: &mut h2::PingPong,
+    settings: watch::Sender<bool>,
+    id: UniqueIDType,
+) {
+    if ping_pong.ping(h2::Ping::opaque()).await.is_ok() {
+        debug!("H2 fd: {id} settings received");
+        let _ = settings.send(true);
+    }
+}
+
 async fn do_ping_pong(
     mut ping_pong: h2::PingPong,
     interval: Duration,
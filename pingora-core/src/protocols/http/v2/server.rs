@@ -16,15 +16,22 @@
 
 use bytes::Bytes;
 use futures::Future;
+use h2::ext::Protocol;
 use h2::server;
 use h2::server::SendResponse;
 use h2::{RecvStream, SendStream};
 use http::header::HeaderName;
 use http::uri::PathAndQuery;
-use http::{header, HeaderMap, Response};
+use http::{header, HeaderMap, HeaderValue, Response};
 use log::{debug, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use pingora_http::{RequestHeader, ResponseHeader};
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
 use crate::protocols::http::body_buffer::FixedBuffer;
 use crate::protocols::http::date::get_cached_date;
@@ -39,12 +46,196 @@ type H2Connection<S> = server::Connection<S, Bytes>;
 
 pub use h2::server::Builder as H2Options;
 
+static RESET_STREAMS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pingora_h2_reset_streams_total",
+        "number of downstream h2 streams reset by the clients"
+    )
+    .unwrap()
+});
+
+static STREAM_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pingora_h2_stream_errors_total",
+        "number of downstream h2 streams failed with a protocol error or reset by the clients"
+    )
+    .unwrap()
+});
+
+static ENHANCE_YOUR_CALM: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pingora_h2_enhance_your_calm_total",
+        "number of downstream h2 connections closed with GOAWAY(ENHANCE_YOUR_CALM) for exceeding a limit",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+/// The HTTP/2 settings and the abuse limits of the downstream connections
+///
+/// `None` leaves the default of the h2 library, or no limit for the limits enforced by Pingora.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct H2Settings {
+    /// SETTINGS_MAX_CONCURRENT_STREAMS: how many streams a client can open concurrently.
+    pub max_concurrent_streams: Option<u32>,
+    /// SETTINGS_INITIAL_WINDOW_SIZE: the initial flow control window of each stream.
+    pub initial_window_size: Option<u32>,
+    /// The initial flow control window of the whole connection.
+    pub initial_connection_window_size: Option<u32>,
+    /// SETTINGS_MAX_FRAME_SIZE: the largest frame payload the client can send.
+    pub max_frame_size: Option<u32>,
+    /// SETTINGS_MAX_HEADER_LIST_SIZE: the largest header list the client can send. This also
+    /// bounds the CONTINUATION frames of a header block. Default 64 KiB.
+    pub max_header_list_size: Option<u32>,
+    /// How many streams reset by the client can wait to be accepted, after which the connection
+    /// is closed with GOAWAY(ENHANCE_YOUR_CALM). This mitigates rapid-reset floods.
+    pub max_pending_accept_reset_streams: Option<usize>,
+    /// The budget of accepted streams a client can reset over the lifetime of a connection,
+    /// after which the connection is closed with GOAWAY(ENHANCE_YOUR_CALM). Default `None`.
+    ///
+    /// The budget never replenishes, so a long-lived connection with legitimate cancellations,
+    /// e.g. a browser aborting the downloads of a page it navigates away from, eventually
+    /// exhausts any value. Floods of resets are caught by `max_stream_error_rate` instead.
+    pub max_reset_streams: Option<usize>,
+    /// How many streams can fail with a protocol error or be reset by the client per second,
+    /// after which the connection is closed with GOAWAY(ENHANCE_YOUR_CALM). Default 100.
+    pub max_stream_error_rate: Option<u32>,
+    /// Whether to advertise SETTINGS_ENABLE_CONNECT_PROTOCOL to allow extended CONNECT
+    /// (RFC 8441), e.g., WebSockets over HTTP/2. Default true.
+    pub enable_connect_protocol: bool,
+}
+
+impl Default for H2Settings {
+    fn default() -> Self {
+        H2Settings {
+            max_concurrent_streams: None,
+            initial_window_size: None,
+            initial_connection_window_size: None,
+            max_frame_size: None,
+            max_header_list_size: Some(64 * 1024),
+            max_pending_accept_reset_streams: None,
+            max_reset_streams: None,
+            max_stream_error_rate: Some(100),
+            enable_connect_protocol: true,
+        }
+    }
+}
+
+impl H2Settings {
+    /// Build the [H2Options] to [handshake()] with.
+    pub fn h2_options(&self) -> H2Options {
+        let mut options = H2Options::new();
+        if let Some(max) = self.max_concurrent_streams {
+            options.max_concurrent_streams(max);
+        }
+        if let Some(size) = self.initial_window_size {
+            options.initial_window_size(size);
+        }
+        if let Some(size) = self.initial_connection_window_size {
+            options.initial_connection_window_size(size);
+        }
+        if let Some(size) = self.max_frame_size {
+            options.max_frame_size(size);
+        }
+        if let Some(size) = self.max_header_list_size {
+            options.max_header_list_size(size);
+        }
+        if let Some(max) = self.max_pending_accept_reset_streams {
+            options.max_pending_accept_reset_streams(max);
+        }
+        if self.enable_connect_protocol {
+            options.enable_connect_protocol();
+        }
+        options
+    }
+}
+
+/// Enforce the stream error limits of [H2Settings] on a connection.
+///
+/// It is shared by the streams of the connection, see [HttpSession::set_error_budget()].
+pub(crate) struct StreamErrorBudget {
+    max_reset_streams: Option<usize>,
+    max_stream_error_rate: Option<u32>,
+    resets: AtomicUsize,
+    // the start of the current one second window and the errors in it
+    window: Mutex<(Instant, u32)>,
+    exceeded: AtomicBool,
+    notify: Notify,
+}
+
+impl StreamErrorBudget {
+    /// Create a new budget from the limits of the `settings`.
+    pub(crate) fn new(settings: &H2Settings) -> Self {
+        StreamErrorBudget {
+            max_reset_streams: settings.max_reset_streams,
+            max_stream_error_rate: settings.max_stream_error_rate,
+            resets: AtomicUsize::new(0),
+            window: Mutex::new((Instant::now(), 0)),
+            exceeded: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    // Record a failed stream, `reset` if it is reset by the client.
+    fn record(&self, reset: bool) {
+        STREAM_ERRORS.inc();
+        if reset {
+            RESET_STREAMS.inc();
+            let resets = self.resets.fetch_add(1, Ordering::Relaxed) + 1;
+            if self.max_reset_streams.is_some_and(|max| resets > max) {
+                self.exceed("reset_streams");
+            }
+        }
+        if let Some(max) = self.max_stream_error_rate {
+            let mut window = self.window.lock();
+            let now = Instant::now();
+            if now.duration_since(window.0) >= Duration::from_secs(1) {
+                *window = (now, 0);
+            }
+            window.1 += 1;
+            if window.1 > max {
+                self.exceed("stream_error_rate");
+            }
+        }
+    }
+
+    fn exceed(&self, limit: &str) {
+        if !self.exceeded.swap(true, Ordering::Relaxed) {
+            ENHANCE_YOUR_CALM.with_label_values(&[limit]).inc();
+            self.notify.notify_one();
+        }
+    }
+
+    /// Wait until one of the limits is exceeded. The connection should then be closed with
+    /// GOAWAY(ENHANCE_YOUR_CALM).
+    pub(crate) async fn exceeded(&self) {
+        self.notify.notified().await
+    }
+
+    /// Count the connections closed by the h2 library for being abusive, e.g., when exceeding
+    /// [H2Settings::max_pending_accept_reset_streams].
+    pub(crate) fn record_connection_error(e: &Error) {
+        if let Some(e) = e.root_cause().downcast_ref::<h2::Error>() {
+            if e.is_go_away() && e.is_library() && e.reason() == Some(h2::Reason::ENHANCE_YOUR_CALM)
+            {
+                ENHANCE_YOUR_CALM.with_label_values(&["h2"]).inc();
+            }
+        }
+    }
+}
+
 /// Perform HTTP/2 connection handshake with an established (TLS) connection.
 ///
 /// The optional `options` allow to adjust certain HTTP/2 parameters and settings.
 /// See [`H2Options`] for more details.
+///
+/// The default options are the ones of the default [H2Settings], which advertise
+/// `SETTINGS_ENABLE_CONNECT_PROTOCOL` so that clients can open WebSockets with extended CONNECT
+/// (RFC 8441). Custom `options` need to call [`H2Options::enable_connect_protocol()`] to do the
+/// same.
 pub async fn handshake(io: Stream, options: Option<H2Options>) -> Result<H2Connection<Stream>> {
-    let options = options.unwrap_or_default();
+    let options = options.unwrap_or_else(|| H2Settings::default().h2_options());
     let res = options.handshake(io).await;
     match res {
         Ok(connection) => {
@@ -71,12 +262,23 @@ impl<'a> Future for Idle<'a> {
     type Output = Result<h2::Reason>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if let Some(body_writer) = self.0.send_response_body.as_mut() {
+        let res = if let Some(body_writer) = self.0.send_response_body.as_mut() {
             body_writer.poll_reset(cx)
         } else {
             self.0.send_response.poll_reset(cx)
+        };
+        match res {
+            Poll::Ready(Ok(reason)) => {
+                self.0.record_stream_error(true);
+                Poll::Ready(Ok(reason))
+            }
+            Poll::Ready(Err(e)) => {
+                let e = Error::because(ErrorType::H2Error, "downstream error while idling", e);
+                self.0.record_error(&e);
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
         }
-        .map_err(|e| Error::because(ErrorType::H2Error, "downstream error while idling", e))
     }
 }
 
@@ -99,6 +301,14 @@ pub struct HttpSession {
     retry_buffer: Option<FixedBuffer>,
     // digest to record underlying connection info
     digest: Arc<Digest>,
+    // the Alt-Svc header to add to the response header if it doesn't have one
+    alt_svc: Option<HeaderValue>,
+    // the :protocol pseudo header of an extended CONNECT request
+    connect_protocol: Option<Protocol>,
+    // the stream error limits of the connection
+    error_budget: Option<Arc<StreamErrorBudget>>,
+    // whether the failure of this stream is already recorded
+    error_recorded: bool,
 }
 
 impl HttpSession {
@@ -127,6 +337,7 @@ impl HttpSession {
 
         Ok(res.map(|(req, send_response)| {
             let (request_header, request_body_reader) = req.into_parts();
+            let connect_protocol = request_header.extensions.get::<Protocol>().cloned();
             HttpSession {
                 request_header: request_header.into(),
                 request_body_reader,
@@ -138,6 +349,10 @@ impl HttpSession {
                 body_sent: 0,
                 retry_buffer: None,
                 digest,
+                alt_svc: None,
+                connect_protocol,
+                error_budget: None,
+                error_recorded: false,
             }
         }))
     }
@@ -158,13 +373,52 @@ impl HttpSession {
         &mut self.request_header
     }
 
+    /// Set the [StreamErrorBudget] of the connection, which the failure of this stream counts
+    /// towards.
+    pub(crate) fn set_error_budget(&mut self, budget: Arc<StreamErrorBudget>) {
+        self.error_budget = Some(budget);
+    }
+
+    fn record_stream_error(&mut self, reset: bool) {
+        if self.error_recorded {
+            return;
+        }
+        self.error_recorded = true;
+        if let Some(budget) = self.error_budget.as_ref() {
+            budget.record(reset);
+        }
+    }
+
+    // Record the stream errors, but not the connection ones such as the client disconnecting
+    fn record_error(&mut self, e: &Error) {
+        let Some(e) = e.root_cause().downcast_ref::<h2::Error>() else {
+            return;
+        };
+        if e.is_io() || e.is_go_away() {
+            return;
+        }
+        let reset = e.is_reset() && e.is_remote();
+        self.record_stream_error(reset);
+    }
+
+    /// The `:protocol` of an extended CONNECT request (RFC 8441), e.g. `websocket`
+    ///
+    /// `None` for all other requests.
+    pub fn connect_protocol(&self) -> Option<&str> {
+        self.connect_protocol.as_ref().map(|p| p.as_str())
+    }
+
     /// Read request body bytes. `None` when there is no more body to read.
     pub async fn read_body_bytes(&mut self) -> Result<Option<Bytes>> {
         // TODO: timeout
         let data = self.request_body_reader.data().await.transpose().or_err(
             ErrorType::ReadError,
             "while reading downstream request body",
-        )?;
+        );
+        if let Err(e) = &data {
+            self.record_error(e);
+        }
+        let data = data?;
         if let Some(data) = data.as_ref() {
             self.body_read += data.len();
             if let Some(buffer) = self.retry_buffer.as_mut() {
@@ -195,6 +449,14 @@ impl HttpSession {
             return Ok(());
         }
 
+        if self.connect_protocol.is_some() && header.status == 101 {
+            // an HTTP/1.1 upgrade accepted by the upstream: the extended CONNECT succeeds with
+            // a 2xx, and the handshake headers of the upgrade are meaningless here
+            // https://www.rfc-editor.org/rfc/rfc8441#section-5
+            header.set_status(200)?;
+            header.remove_header(&header::SEC_WEBSOCKET_ACCEPT);
+        }
+
         if header.status.is_informational() {
             // ignore informational response 1xx header because send_response() can only be called once
             // https://github.com/hyperium/h2/issues/167
@@ -209,6 +471,11 @@ impl HttpSession {
 
         /* update headers */
         header.insert_header(header::DATE, get_cached_date())?;
+        if let Some(alt_svc) = self.alt_svc.as_ref() {
+            if header.headers.get(header::ALT_SVC).is_none() {
+                header.insert_header(header::ALT_SVC, alt_svc)?;
+            }
+        }
 
         // remove other h1 hop headers that cannot be present in H2
         // https://httpwg.org/specs/rfc7540.html#n-connection-specific-header-fields
@@ -223,7 +490,11 @@ impl HttpSession {
         let body_writer = self.send_response.send_response(resp, end).or_err(
             ErrorType::WriteError,
             "while writing h2 response to downstream",
-        )?;
+        );
+        if let Err(e) = &body_writer {
+            self.record_error(e);
+        }
+        let body_writer = body_writer?;
 
         self.response_written = Some(header);
         self.send_response_body = Some(body_writer);
@@ -246,9 +517,10 @@ impl HttpSession {
             ));
         };
         let data_len = data.len();
-        super::write_body(writer, data, end)
-            .await
-            .map_err(|e| e.into_down())?;
+        if let Err(e) = super::write_body(writer, data, end).await {
+            self.record_error(&e);
+            return Err(e.into_down());
+        }
         self.body_sent += data_len;
         self.ended = self.ended || end;
         Ok(())
@@ -266,10 +538,13 @@ impl HttpSession {
                 "try to send trailers before header is sent",
             ));
         };
-        writer.send_trailers(trailers).or_err(
+        if let Err(e) = writer.send_trailers(trailers).or_err(
             ErrorType::WriteError,
             "while writing h2 response trailers to downstream",
-        )?;
+        ) {
+            self.record_error(&e);
+            return Err(e);
+        }
         // sending trailers closes the stream
         self.ended = true;
         Ok(())
@@ -294,10 +569,13 @@ impl HttpSession {
         }
         if let Some(writer) = self.send_response_body.as_mut() {
             // use an empty data frame to signal the end
-            writer.send_data("".into(), true).or_err(
+            if let Err(e) = writer.send_data("".into(), true).or_err(
                 ErrorType::WriteError,
                 "while writing h2 response body to downstream",
-            )?;
+            ) {
+                self.record_error(&e);
+                return Err(e);
+            }
             self.ended = true;
         };
         // else: the response header is not sent, do nothing now.
@@ -367,6 +645,14 @@ impl HttpSession {
         self.response_written.as_deref()
     }
 
+    /// Sets the Alt-Svc header value to advertise alternative services, e.g., HTTP/3, to the
+    /// client.
+    ///
+    /// The header is added to the response header unless it already has one.
+    pub fn set_alt_svc(&mut self, alt_svc: Option<HeaderValue>) {
+        self.alt_svc = alt_svc;
+    }
+
     /// Give up the stream abruptly.
     ///
     /// This will send a `INTERNAL_ERROR` stream error to the client
@@ -722,4 +1008,116 @@ mod test {
             assert!(handle.await.is_ok());
         }
     }
+
+    #[tokio::test]
+    async fn test_extended_connect() {
+        let (client, server) = duplex(65536);
+        let client_body = "websocket frame";
+
+        let mut handles = vec![];
+        handles.push(tokio::spawn(async move {
+            let (h2, connection) = h2::client::handshake(client).await.unwrap();
+            tokio::spawn(async move {
+                connection.await.unwrap();
+            });
+
+            let mut h2 = h2.ready().await.unwrap();
+            // the SETTINGS of the server are received asynchronously
+            while !h2.is_extended_connect_protocol_enabled() {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+
+            let mut request = Request::builder()
+                .method(Method::CONNECT)
+                .uri("https://www.example.com/chat")
+                .header("sec-websocket-version", "13")
+                .body(())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(Protocol::from_static("websocket"));
+
+            let (response, mut req_body) = h2.send_request(request, false).unwrap();
+            let (head, mut body) = response.await.unwrap().into_parts();
+            // the 101 of the upgrade is turned into a 200
+            assert_eq!(head.status, 200);
+            assert!(head.headers.get(header::SEC_WEBSOCKET_ACCEPT).is_none());
+
+            req_body.send_data(client_body.into(), false).unwrap();
+            let data = body.data().await.unwrap().unwrap();
+            assert_eq!(data, client_body);
+            req_body.send_data("".into(), true).unwrap();
+            while let Some(data) = body.data().await {
+                assert!(data.unwrap().is_empty());
+            }
+        }));
+
+        let mut connection = handshake(Box::new(server), None).await.unwrap();
+        let digest = Arc::new(Digest::default());
+
+        while let Some(mut http) = HttpSession::from_h2_conn(&mut connection, digest.clone())
+            .await
+            .unwrap()
+        {
+            handles.push(tokio::spawn(async move {
+                let req = http.req_header();
+                assert_eq!(req.method, Method::CONNECT);
+                assert_eq!(req.uri, "https://www.example.com/chat");
+                assert_eq!(http.connect_protocol(), Some("websocket"));
+
+                let mut response_header = ResponseHeader::build(101, None).unwrap();
+                response_header
+                    .insert_header(header::UPGRADE, "websocket")
+                    .unwrap();
+                response_header
+                    .insert_header(header::SEC_WEBSOCKET_ACCEPT, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+                    .unwrap();
+                http.write_response_header(Box::new(response_header), false)
+                    .unwrap();
+                assert_eq!(http.response_written().unwrap().status, 200);
+
+                // echo the data of the tunnel
+                let data = http.read_body_bytes().await.unwrap().unwrap();
+                http.write_body(data, false).await.unwrap();
+                while let Some(data) = http.read_body_bytes().await.unwrap() {
+                    assert!(data.is_empty());
+                }
+                assert!(http.is_body_done());
+                http.finish().unwrap();
+            }));
+        }
+
+        for handle in handles {
+            // ensure no panics
+            assert!(handle.await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_budget() {
+        let settings = H2Settings {
+            max_reset_streams: Some(2),
+            ..Default::default()
+        };
+        let budget = StreamErrorBudget::new(&settings);
+        let exceeded = tokio::time::timeout(Duration::from_millis(10), budget.exceeded());
+
+        budget.record(false);
+        budget.record(true);
+        budget.record(true);
+        assert!(exceeded.await.is_err());
+
+        budget.record(true);
+        budget.exceeded().await;
+
+        let settings = H2Settings {
+            max_stream_error_rate: Some(2),
+            ..Default::default()
+        };
+        let budget = StreamErrorBudget::new(&settings);
+        for _ in 0..3 {
+            budget.record(false);
+        }
+        budget.exceeded().await;
+    }
 }
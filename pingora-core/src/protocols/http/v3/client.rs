@@ -0,0 +1,339 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP/3 client session
+
+use bytes::{Buf, Bytes};
+use h3::client::{RequestStream, SendRequest};
+use h3::error::{Code, StreamError};
+use http::HeaderMap;
+use log::warn;
+use pingora_error::{Error, ErrorType::*, OrErr, Result, RetryType};
+use pingora_http::{RequestHeader, ResponseHeader};
+use pingora_timeout::timeout;
+use std::time::Duration;
+
+use super::{write_body, H3BodyWriter};
+use crate::connectors::http::v3::ConnectionRef;
+use crate::protocols::{Digest, SocketAddr, UniqueIDType};
+
+type H3ResponseReader = RequestStream<h3_quinn::RecvStream, Bytes>;
+
+/// An HTTP/3 request stream to the server
+pub struct Http3Session {
+    send_req: SendRequest<h3_quinn::OpenStreams, Bytes>,
+    send_body: Option<H3BodyWriter>,
+    response_body_reader: Option<H3ResponseReader>,
+    req_sent: Option<Box<RequestHeader>>,
+    response_header: Option<ResponseHeader>,
+    /// The read timeout, which will be applied to both reading the header and the body.
+    /// The timeout is reset on every read. This is not a timeout on the overall duration of the
+    /// response.
+    pub read_timeout: Option<Duration>,
+    pub(crate) conn: ConnectionRef,
+    // Indicate that whether the request side of the stream is already finished
+    ended: bool,
+    // Indicate that whether the whole response body is read
+    response_ended: bool,
+}
+
+impl Drop for Http3Session {
+    fn drop(&mut self) {
+        self.conn.release_stream();
+    }
+}
+
+impl Http3Session {
+    pub(crate) fn new(
+        send_req: SendRequest<h3_quinn::OpenStreams, Bytes>,
+        conn: ConnectionRef,
+    ) -> Self {
+        Http3Session {
+            send_req,
+            send_body: None,
+            response_body_reader: None,
+            req_sent: None,
+            response_header: None,
+            read_timeout: None,
+            conn,
+            ended: false,
+            response_ended: false,
+        }
+    }
+
+    fn sanitize_request_header(req: &mut RequestHeader) -> Result<()> {
+        req.set_version(http::Version::HTTP_3);
+        if req.uri.authority().is_some() {
+            return Ok(());
+        }
+        // use host header to populate :authority field
+        let Some(authority) = req.headers.get(http::header::HOST).map(|v| v.as_bytes()) else {
+            return Error::e_explain(InvalidHTTPHeader, "no authority header for h3");
+        };
+        let uri = http::uri::Builder::new()
+            .scheme("https") // h3 is always over TLS
+            .authority(authority)
+            .path_and_query(req.uri.path_and_query().as_ref().unwrap().as_str())
+            .build();
+        match uri {
+            Ok(uri) => {
+                req.set_uri(uri);
+                Ok(())
+            }
+            Err(_) => Error::e_explain(
+                InvalidHTTPHeader,
+                format!("invalid authority from host {authority:?}"),
+            ),
+        }
+    }
+
+    /// Write the request header to the server
+    pub async fn write_request_header(
+        &mut self,
+        mut req: Box<RequestHeader>,
+        end: bool,
+    ) -> Result<()> {
+        if self.req_sent.is_some() {
+            // cannot send again
+            warn!("Request header is already sent, cannot send again");
+            return Ok(());
+        }
+        Self::sanitize_request_header(&mut req)?;
+        // the Host header is conveyed by :authority
+        req.remove_header(&http::header::HOST);
+        let parts = req.as_owned_parts();
+        let request = http::Request::from_parts(parts, ());
+        let stream = self
+            .send_req
+            .send_request(request)
+            .await
+            .map_err(|e| self.handle_err(e, "while sending request"))?;
+        let (mut send_body, response_body_reader) = stream.split();
+        if end {
+            send_body
+                .finish()
+                .await
+                .or_err(WriteError, "while finishing h3 request")?;
+        }
+        self.req_sent = Some(req);
+        self.send_body = Some(send_body);
+        self.response_body_reader = Some(response_body_reader);
+        self.ended = self.ended || end;
+
+        Ok(())
+    }
+
+    /// Write a request body chunk
+    pub async fn write_request_body(&mut self, data: Bytes, end: bool) -> Result<()> {
+        if self.ended {
+            warn!("Try to write request body after end of stream, dropping the extra data");
+            return Ok(());
+        }
+
+        let body_writer = self
+            .send_body
+            .as_mut()
+            .expect("Try to write request body before sending request header");
+
+        write_body(body_writer, data, end).await?;
+        self.ended = self.ended || end;
+        Ok(())
+    }
+
+    /// Signal that the request body has ended
+    pub async fn finish_request_body(&mut self) -> Result<()> {
+        if self.ended {
+            return Ok(());
+        }
+
+        let body_writer = self
+            .send_body
+            .as_mut()
+            .expect("Try to finish request stream before sending request header");
+
+        body_writer
+            .finish()
+            .await
+            .or_err(WriteError, "while finishing h3 request body")?;
+        self.ended = true;
+        Ok(())
+    }
+
+    /// Read the response header
+    pub async fn read_response_header(&mut self) -> Result<()> {
+        if self.response_header.is_some() {
+            panic!("H3 response header is already read")
+        }
+
+        let Some(reader) = self.response_body_reader.as_mut() else {
+            panic!("Try to read response header before sending request header")
+        };
+
+        let fut = reader.recv_response();
+        let res = match self.read_timeout {
+            Some(t) => timeout(t, fut)
+                .await
+                .map_err(|_| Error::explain(ReadTimedout, "while reading h3 response header"))?,
+            None => fut.await,
+        };
+        let resp = res.map_err(|e| self.handle_err(e, "while reading h3 response header"))?;
+        // TODO: 1xx responses are not surfaced by the h3 crate
+        let (resp, _) = resp.into_parts();
+        self.response_header = Some(resp.into());
+
+        Ok(())
+    }
+
+    /// Read the response body
+    ///
+    /// `None` means, no more body to read
+    pub async fn read_response_body(&mut self) -> Result<Option<Bytes>> {
+        if self.response_ended || self.response_header.is_none() {
+            // req is not sent or response is already read
+            return Ok(None);
+        }
+        let Some(reader) = self.response_body_reader.as_mut() else {
+            return Ok(None);
+        };
+
+        let fut = reader.recv_data();
+        let res = match self.read_timeout {
+            Some(t) => timeout(t, fut)
+                .await
+                .map_err(|_| Error::explain(ReadTimedout, "while reading h3 response body"))?,
+            None => fut.await,
+        };
+        let body = res
+            .or_err(ReadError, "while reading h3 response body")?
+            .map(|mut data| data.copy_to_bytes(data.remaining()));
+        if body.is_none() {
+            self.response_ended = true;
+        }
+        Ok(body)
+    }
+
+    /// Whether the response has ended
+    ///
+    /// Unlike h2, the end of an h3 response is only known after [Self::read_response_body()]
+    /// returns `None`.
+    pub fn response_finished(&self) -> bool {
+        self.response_ended
+    }
+
+    /// Read the optional trailer headers
+    pub async fn read_trailers(&mut self) -> Result<Option<HeaderMap>> {
+        let Some(reader) = self.response_body_reader.as_mut() else {
+            // response is not even read
+            return Ok(None);
+        };
+        let fut = reader.recv_trailers();
+
+        let res = match self.read_timeout {
+            Some(t) => timeout(t, fut)
+                .await
+                .map_err(|_| Error::explain(ReadTimedout, "while reading h3 trailer"))?,
+            None => fut.await,
+        };
+        res.or_err(ReadError, "while reading h3 trailers")
+    }
+
+    /// The request header if it is already sent
+    pub fn request_header(&self) -> Option<&RequestHeader> {
+        self.req_sent.as_deref()
+    }
+
+    /// The response header if it is already read
+    pub fn response_header(&self) -> Option<&ResponseHeader> {
+        self.response_header.as_ref()
+    }
+
+    /// Give up the http session abruptly.
+    pub fn shutdown(&mut self) {
+        if !self.ended {
+            if let Some(send_body) = self.send_body.as_mut() {
+                send_body.stop_stream(Code::H3_REQUEST_CANCELLED);
+            }
+        }
+        if !self.response_ended {
+            if let Some(reader) = self.response_body_reader.as_mut() {
+                reader.stop_sending(Code::H3_REQUEST_CANCELLED);
+            }
+        }
+    }
+
+    /// Return the connection ref of this stream.
+    pub(crate) fn conn(&self) -> ConnectionRef {
+        self.conn.clone()
+    }
+
+    /// Return the [Digest] of the connection
+    ///
+    /// For reused connection, the timing in the digest will reflect its initial handshakes
+    /// The caller should check if the connection is reused to avoid misuse the timing field.
+    pub fn digest(&self) -> Option<&Digest> {
+        Some(self.conn.digest())
+    }
+
+    /// Return a mutable [Digest] reference for the connection
+    ///
+    /// Will return `None` if multiple H3 streams are open.
+    pub fn digest_mut(&mut self) -> Option<&mut Digest> {
+        self.conn.digest_mut()
+    }
+
+    /// Return the server (peer) address recorded in the connection digest.
+    pub fn server_addr(&self) -> Option<&SocketAddr> {
+        self.conn
+            .digest()
+            .socket_digest
+            .as_ref()
+            .map(|d| d.peer_addr())?
+    }
+
+    /// Return the client (local) address recorded in the connection digest.
+    pub fn client_addr(&self) -> Option<&SocketAddr> {
+        self.conn
+            .digest()
+            .socket_digest
+            .as_ref()
+            .map(|d| d.local_addr())?
+    }
+
+    /// the FD of the UDP socket of the connection
+    pub fn fd(&self) -> UniqueIDType {
+        self.conn.id()
+    }
+
+    /// take the body sender to another task to perform duplex read and write
+    pub fn take_request_body_writer(&mut self) -> Option<H3BodyWriter> {
+        self.send_body.take()
+    }
+
+    fn handle_err(&self, e: StreamError, context: &'static str) -> Box<Error> {
+        let retry = match &e {
+            // GOAWAY: this connection is being teardown, retry via another connection
+            StreamError::RemoteClosing { .. } => {
+                self.conn.set_shutting_down();
+                RetryType::Decided(true)
+            }
+            // the connection went away before any response: only retry if the connection was
+            // reused, as a new connection failing this way is unlikely to do better next time
+            StreamError::ConnectionError { .. } => RetryType::ReusedOnly,
+            _ => RetryType::Decided(false),
+        };
+        let mut err = Error::because(H3Error, context, e);
+        err.retry = retry;
+        err
+    }
+}
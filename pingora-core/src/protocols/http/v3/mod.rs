@@ -0,0 +1,104 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP/3 implementation
+
+use bytes::Bytes;
+use h3::client::RequestStream;
+use http::header::{self, HeaderName};
+use pingora_error::{ErrorType::*, OrErr, Result};
+use pingora_http::ResponseHeader;
+use pingora_rustls::{hash_certificate, CertificateDer};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::protocols::l4::socket::SocketAddr;
+use crate::protocols::tls::{PeerCertificatesDer, SslDigest};
+use crate::protocols::{Digest, SocketDigest, TimingDigest};
+
+pub mod client;
+pub mod server;
+
+/// The ALPN of HTTP/3 over QUIC
+pub const ALPN_H3: &[u8] = b"h3";
+
+// Connection-specific header fields are malformed in HTTP/3
+// https://www.rfc-editor.org/rfc/rfc9114.html#section-4.2
+fn remove_connection_headers(header: &mut ResponseHeader) {
+    header.remove_header(&header::TRANSFER_ENCODING);
+    header.remove_header(&header::CONNECTION);
+    header.remove_header(&header::UPGRADE);
+    header.remove_header(&HeaderName::from_static("keep-alive"));
+    header.remove_header(&HeaderName::from_static("proxy-connection"));
+}
+
+/// The sending half of an HTTP/3 request stream, which carries the request body
+pub type H3BodyWriter = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+
+/// A helper function to write the body of h3 request streams.
+pub async fn write_body(writer: &mut H3BodyWriter, data: Bytes, end: bool) -> Result<()> {
+    if !data.is_empty() {
+        writer
+            .send_data(data)
+            .await
+            .or_err(WriteError, "while writing h3 request body")?;
+    }
+    if end {
+        writer
+            .finish()
+            .await
+            .or_err(WriteError, "while finishing h3 request body")?;
+    }
+    Ok(())
+}
+
+// Build the digest of an established QUIC connection. The addresses of the connection are
+// recorded in the given socket digest as the socket can be shared by many connections.
+pub(crate) fn connection_digest(
+    connection: &quinn::Connection,
+    socket_digest: SocketDigest,
+    local_addr: Option<std::net::SocketAddr>,
+) -> Digest {
+    let _ = socket_digest
+        .peer_addr
+        .set(Some(SocketAddr::Inet(connection.remote_address())));
+    let _ = socket_digest
+        .local_addr
+        .set(local_addr.map(SocketAddr::Inet));
+
+    let peer_certs = connection
+        .peer_identity()
+        .and_then(|id| id.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .filter(|certs| !certs.is_empty());
+    let cert_digest = peer_certs
+        .as_ref()
+        .and_then(|certs| certs.first())
+        .map(|cert| hash_certificate(cert))
+        .unwrap_or_default();
+    // the cipher is not exposed by the QUIC stack
+    let mut ssl_digest = SslDigest::new("", "TLSv1_3", None, None, cert_digest);
+    if let Some(certs) = peer_certs {
+        ssl_digest.set_peer_certificates(PeerCertificatesDer(*certs));
+    }
+
+    Digest {
+        ssl_digest: Some(Arc::new(ssl_digest)),
+        timing_digest: vec![Some(TimingDigest {
+            established_ts: SystemTime::now(),
+            connect_attempts: vec![],
+        })],
+        proxy_digest: None,
+        socket_digest: Some(Arc::new(socket_digest)),
+    }
+}
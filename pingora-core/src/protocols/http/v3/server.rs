@@ -0,0 +1,651 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP/3 server session
+
+use bytes::{Buf, Bytes};
+use futures::FutureExt;
+use h3::error::Code;
+use h3::server::{RequestResolver, RequestStream};
+use http::uri::PathAndQuery;
+use http::{header, HeaderMap, Response};
+use log::{debug, warn};
+use pingora_http::{RequestHeader, ResponseHeader};
+use std::sync::Arc;
+
+use super::remove_connection_headers;
+use crate::protocols::http::body_buffer::FixedBuffer;
+use crate::protocols::http::date::get_cached_date;
+use crate::protocols::http::v1::client::http_req_header_to_wire;
+use crate::protocols::http::HttpTask;
+use crate::protocols::{Digest, SocketAddr};
+use crate::{Error, ErrorType, OrErr, Result};
+
+const BODY_BUF_LIMIT: usize = 1024 * 64;
+
+type H3Connection = h3::server::Connection<h3_quinn::Connection, Bytes>;
+type H3Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+pub use h3::server::Builder as H3Options;
+
+/// Perform HTTP/3 connection handshake with an established QUIC connection.
+///
+/// The optional `options` allow to adjust certain HTTP/3 settings. See [`H3Options`].
+pub async fn handshake(
+    conn: quinn::Connection,
+    options: Option<H3Options>,
+) -> Result<H3Connection> {
+    let options = options.unwrap_or_else(h3::server::builder);
+    match options.build(h3_quinn::Connection::new(conn)).await {
+        Ok(connection) => {
+            debug!("H3 handshake done.");
+            Ok(connection)
+        }
+        Err(e) => Error::e_because(
+            ErrorType::HandshakeError,
+            "while h3 handshaking with client",
+            e,
+        ),
+    }
+}
+
+/// A new request stream of an HTTP/3 connection whose request header is not read yet
+pub struct PendingRequest {
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    digest: Arc<Digest>,
+}
+
+impl PendingRequest {
+    /// Read the request header and create the [`HttpSession`] of this request.
+    ///
+    /// Unlike HTTP/2, the request header arrives on its own stream, so this should be called
+    /// outside of the loop accepting the new streams to not block the other requests.
+    pub async fn read_request(self) -> Result<HttpSession> {
+        let (request, stream) = self.resolver.resolve_request().await.or_err(
+            ErrorType::H3Error,
+            "while reading h3 request header from downstream",
+        )?;
+        let (request_header, _) = request.into_parts();
+        let mut session = HttpSession {
+            request_header: request_header.into(),
+            stream,
+            body_preread: None,
+            body_done: false,
+            response_written: None,
+            ended: false,
+            body_read: 0,
+            body_sent: 0,
+            retry_buffer: None,
+            digest: self.digest,
+        };
+        // Tell whether the request has a body if the client already closed its side of the
+        // stream, which is usually the case for the requests without body.
+        match session.stream.recv_data().now_or_never() {
+            Some(Ok(Some(mut data))) => {
+                session.body_preread = Some(data.copy_to_bytes(data.remaining()))
+            }
+            Some(Ok(None)) => session.body_done = true,
+            Some(Err(e)) => {
+                return Error::e_because(
+                    ErrorType::ReadError,
+                    "while reading downstream request body",
+                    e,
+                )
+            }
+            None => {}
+        }
+        Ok(session)
+    }
+}
+
+/// Accept the next request stream of the HTTP/3 connection.
+///
+/// `accept()` drives the connection, so the server must call it in a loop until the client
+/// decides to close the connection.
+///
+/// `None` will be returned when the connection is closing so that the loop can exit.
+pub async fn accept(
+    conn: &mut H3Connection,
+    digest: Arc<Digest>,
+) -> Result<Option<PendingRequest>> {
+    let resolver = conn.accept().await.or_err(
+        ErrorType::H3Error,
+        "while accepting new downstream requests",
+    )?;
+    Ok(resolver.map(|resolver| PendingRequest { resolver, digest }))
+}
+
+/// HTTP/3 server session
+pub struct HttpSession {
+    request_header: RequestHeader,
+    stream: H3Stream,
+    // the first chunk of the request body, read when the session was created
+    body_preread: Option<Bytes>,
+    // whether the client closed its side of the stream
+    body_done: bool,
+    // Remember what has been written
+    response_written: Option<Box<ResponseHeader>>,
+    // whether the stream is already finished
+    ended: bool,
+    // How many (application, not wire) request body bytes have been read so far.
+    body_read: usize,
+    // How many (application, not wire) response body bytes have been sent so far.
+    body_sent: usize,
+    // buffered request body for retry logic
+    retry_buffer: Option<FixedBuffer>,
+    // digest to record underlying connection info
+    digest: Arc<Digest>,
+}
+
+impl HttpSession {
+    /// The request sent from the client
+    pub fn req_header(&self) -> &RequestHeader {
+        &self.request_header
+    }
+
+    /// A mutable reference to request sent from the client
+    pub fn req_header_mut(&mut self) -> &mut RequestHeader {
+        &mut self.request_header
+    }
+
+    /// Read request body bytes. `None` when there is no more body to read.
+    pub async fn read_body_bytes(&mut self) -> Result<Option<Bytes>> {
+        let data = if let Some(data) = self.body_preread.take() {
+            Some(data)
+        } else if self.body_done {
+            None
+        } else {
+            // TODO: timeout
+            self.stream
+                .recv_data()
+                .await
+                .or_err(
+                    ErrorType::ReadError,
+                    "while reading downstream request body",
+                )?
+                .map(|mut data| data.copy_to_bytes(data.remaining()))
+        };
+        match data.as_ref() {
+            Some(data) => {
+                self.body_read += data.len();
+                if let Some(buffer) = self.retry_buffer.as_mut() {
+                    buffer.write_to_buffer(data);
+                }
+            }
+            None => self.body_done = true,
+        }
+        Ok(data)
+    }
+
+    /// Write the response header to the client.
+    /// # the `end` flag
+    /// `end` marks the end of this session.
+    /// If the `end` flag is set, no more header or body can be sent to the client.
+    pub async fn write_response_header(
+        &mut self,
+        mut header: Box<ResponseHeader>,
+        end: bool,
+    ) -> Result<()> {
+        if self.ended {
+            return Ok(());
+        }
+
+        if header.status.is_informational() {
+            // like h2, informational responses are not forwarded
+            debug!("ignoring informational headers");
+            return Ok(());
+        }
+
+        if self.response_written.as_ref().is_some() {
+            warn!("Response header is already sent, cannot send again");
+            return Ok(());
+        }
+
+        /* update headers */
+        header.insert_header(header::DATE, get_cached_date())?;
+        remove_connection_headers(&mut header);
+
+        let resp = Response::from_parts(header.as_owned_parts(), ());
+        self.stream.send_response(resp).await.or_err(
+            ErrorType::WriteError,
+            "while writing h3 response to downstream",
+        )?;
+        self.response_written = Some(header);
+
+        if end {
+            self.finish().await?;
+        }
+        Ok(())
+    }
+
+    /// Similar to [Self::write_response_header], this function takes a reference instead
+    pub async fn write_response_header_ref(
+        &mut self,
+        header: &ResponseHeader,
+        end: bool,
+    ) -> Result<()> {
+        self.write_response_header(Box::new(header.clone()), end)
+            .await
+    }
+
+    /// Write response body to the client. See [Self::write_response_header] for how to use `end`.
+    pub async fn write_body(&mut self, data: Bytes, end: bool) -> Result<()> {
+        if self.ended {
+            warn!("Try to write body after end of stream, dropping the extra data");
+            return Ok(());
+        }
+        if self.response_written.is_none() {
+            return Err(Error::explain(
+                ErrorType::H3Error,
+                "try to send body before header is sent",
+            ));
+        }
+        let data_len = data.len();
+        if data_len > 0 {
+            self.stream.send_data(data).await.or_err(
+                ErrorType::WriteError,
+                "while writing h3 response body to downstream",
+            )?;
+        }
+        self.body_sent += data_len;
+        if end {
+            self.finish().await?;
+        }
+        Ok(())
+    }
+
+    /// Write response trailers to the client, this also closes the stream.
+    pub async fn write_trailers(&mut self, trailers: HeaderMap) -> Result<()> {
+        if self.ended {
+            warn!("Tried to write trailers after end of stream, dropping them");
+            return Ok(());
+        }
+        if self.response_written.is_none() {
+            return Err(Error::explain(
+                ErrorType::H3Error,
+                "try to send trailers before header is sent",
+            ));
+        }
+        self.stream.send_trailers(trailers).await.or_err(
+            ErrorType::WriteError,
+            "while writing h3 response trailers to downstream",
+        )?;
+        self.finish().await
+    }
+
+    /// Mark the session end. If no `end` flag is already set before this call, this call will
+    /// signal the client. Otherwise this call does nothing.
+    ///
+    /// Dropping this object without sending `end` will cause an error to the client, which will cause
+    /// the client to treat this session as bad or incomplete.
+    pub async fn finish(&mut self) -> Result<()> {
+        if self.ended || self.response_written.is_none() {
+            // already ended, or nothing to end: the stream is reset when dropped
+            return Ok(());
+        }
+        self.ended = true;
+        self.stream.finish().await.or_err(
+            ErrorType::WriteError,
+            "while finishing h3 response to downstream",
+        )
+    }
+
+    pub async fn response_duplex_vec(&mut self, tasks: Vec<HttpTask>) -> Result<bool> {
+        let mut end_stream = false;
+        for task in tasks.into_iter() {
+            end_stream = match task {
+                HttpTask::Header(header, end) => {
+                    self.write_response_header(header, end)
+                        .await
+                        .map_err(|e| e.into_down())?;
+                    end
+                }
+                HttpTask::Body(data, end) => match data {
+                    Some(d) => {
+                        if !d.is_empty() {
+                            self.write_body(d, end).await.map_err(|e| e.into_down())?;
+                        }
+                        end
+                    }
+                    None => end,
+                },
+                HttpTask::Trailer(Some(trailers)) => {
+                    self.write_trailers(*trailers)
+                        .await
+                        .map_err(|e| e.into_down())?;
+                    true
+                }
+                HttpTask::Trailer(None) => true,
+                HttpTask::Done => true,
+                HttpTask::Failed(e) => {
+                    return Err(e);
+                }
+            } || end_stream // safe guard in case `end` in tasks flips from true to false
+        }
+        if end_stream {
+            // no-op if finished already
+            self.finish().await.map_err(|e| e.into_down())?;
+        }
+        Ok(end_stream)
+    }
+
+    /// Return a string `$METHOD $PATH, Host: $HOST`. Mostly for logging and debug purpose
+    pub fn request_summary(&self) -> String {
+        format!(
+            "{} {}, Host: {}:{}",
+            self.request_header.method,
+            self.request_header
+                .uri
+                .path_and_query()
+                .map(PathAndQuery::as_str)
+                .unwrap_or_default(),
+            self.request_header.uri.host().unwrap_or_default(),
+            self.req_header()
+                .uri
+                .port()
+                .as_ref()
+                .map(|port| port.as_str())
+                .unwrap_or_default()
+        )
+    }
+
+    /// Return the written response header. `None` if it is not written yet.
+    pub fn response_written(&self) -> Option<&ResponseHeader> {
+        self.response_written.as_deref()
+    }
+
+    /// Give up the stream abruptly.
+    ///
+    /// This will send a `H3_INTERNAL_ERROR` stream error to the client
+    pub fn shutdown(&mut self) {
+        if !self.ended {
+            self.stream.stop_stream(Code::H3_INTERNAL_ERROR);
+            self.ended = true;
+        }
+    }
+
+    // Like its h2 counterpart, this is for pingora-proxy to create subrequests
+    pub fn pseudo_raw_h1_request_header(&self) -> Bytes {
+        let buf = http_req_header_to_wire(&self.request_header).unwrap(); // safe, None only when version unknown
+        buf.freeze()
+    }
+
+    /// Whether there is no more body to read
+    pub fn is_body_done(&self) -> bool {
+        self.is_body_empty() || (self.body_done && self.body_preread.is_none())
+    }
+
+    /// Whether there is any body to read. true means there no body in request.
+    pub fn is_body_empty(&self) -> bool {
+        self.body_read == 0
+            && self.body_preread.is_none()
+            && (self.body_done
+                || self
+                    .request_header
+                    .headers
+                    .get(header::CONTENT_LENGTH)
+                    .is_some_and(|cl| cl.as_bytes() == b"0"))
+    }
+
+    pub fn retry_buffer_truncated(&self) -> bool {
+        self.retry_buffer
+            .as_ref()
+            .map_or_else(|| false, |r| r.is_truncated())
+    }
+
+    pub fn enable_retry_buffering(&mut self) {
+        if self.retry_buffer.is_none() {
+            self.retry_buffer = Some(FixedBuffer::new(BODY_BUF_LIMIT))
+        }
+    }
+
+    pub fn get_retry_buffer(&self) -> Option<Bytes> {
+        self.retry_buffer.as_ref().and_then(|b| {
+            if b.is_truncated() {
+                None
+            } else {
+                b.get_buffer()
+            }
+        })
+    }
+
+    /// Similar to `read_body_bytes()` but will be pending after Ok(None) is returned
+    ///
+    /// Unlike HTTP/2, the client resetting the stream cannot be observed once its request body
+    /// is done. The failure shows up when writing the response instead.
+    pub async fn read_body_or_idle(&mut self, no_body_expected: bool) -> Result<Option<Bytes>> {
+        if no_body_expected || self.is_body_done() {
+            std::future::pending().await
+        } else {
+            self.read_body_bytes().await
+        }
+    }
+
+    /// Return how many response body bytes (application, not wire) already sent downstream
+    pub fn body_bytes_sent(&self) -> usize {
+        self.body_sent
+    }
+
+    /// Return how many request body bytes (application, not wire) already read from downstream
+    pub fn body_bytes_read(&self) -> usize {
+        self.body_read
+    }
+
+    /// Return the [Digest] of the connection.
+    pub fn digest(&self) -> Option<&Digest> {
+        Some(&self.digest)
+    }
+
+    /// Return a mutable [Digest] reference for the connection.
+    pub fn digest_mut(&mut self) -> Option<&mut Digest> {
+        Arc::get_mut(&mut self.digest)
+    }
+
+    /// Return the server (local) address recorded in the connection digest.
+    pub fn server_addr(&self) -> Option<&SocketAddr> {
+        self.digest.socket_digest.as_ref().map(|d| d.local_addr())?
+    }
+
+    /// Return the client (peer) address recorded in the connection digest.
+    pub fn client_addr(&self) -> Option<&SocketAddr> {
+        self.digest.socket_digest.as_ref().map(|d| d.peer_addr())?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::listeners::{QuicListenerEndpoint, QuicSettings};
+    use http::{Method, Request};
+    use pingora_rustls::{load_ca_file_into_store, ClientConfig, RootCertStore};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use std::future::poll_fn;
+
+    type H3Client = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+    fn client_endpoint() -> quinn::Endpoint {
+        let keys = format!("{}/tests/keys", env!("CARGO_MANIFEST_DIR"));
+        let mut roots = RootCertStore::empty();
+        load_ca_file_into_store(format!("{keys}/server_rustls.crt"), &mut roots).unwrap();
+        let mut tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![super::super::ALPN_H3.to_vec()];
+        let config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls).unwrap()));
+
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(config);
+        client
+    }
+
+    async fn connect(addr: std::net::SocketAddr) -> H3Client {
+        let conn = client_endpoint()
+            .connect(addr, "openrusty.org")
+            .unwrap()
+            .await
+            .unwrap();
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(conn))
+            .await
+            .unwrap();
+        tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+        send_request
+    }
+
+    async fn bind() -> QuicListenerEndpoint {
+        let keys = format!("{}/tests/keys", env!("CARGO_MANIFEST_DIR"));
+        let settings = QuicSettings::new(
+            &format!("{keys}/server_rustls.crt"),
+            &format!("{keys}/key.pem"),
+        )
+        .unwrap();
+        QuicListenerEndpoint::bind(
+            "127.0.0.1:0",
+            None,
+            &settings,
+            #[cfg(unix)]
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_h3_request_response() {
+        let endpoint = bind().await;
+        let addr = endpoint.local_addr().unwrap();
+
+        let client_task = tokio::spawn(async move {
+            let mut client = connect(addr).await;
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("https://openrusty.org/upload")
+                .header(header::CONTENT_LENGTH, "5")
+                .body(())
+                .unwrap();
+            let mut stream = client.send_request(req).await.unwrap();
+            stream
+                .send_data(Bytes::from_static(b"hello"))
+                .await
+                .unwrap();
+            stream.finish().await.unwrap();
+
+            let resp = stream.recv_response().await.unwrap();
+            assert_eq!(resp.status(), 200);
+            assert!(resp.headers().get(header::DATE).is_some());
+            assert!(resp.headers().get(header::CONNECTION).is_none());
+            let mut body = vec![];
+            while let Some(mut data) = stream.recv_data().await.unwrap() {
+                body.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+            }
+            assert_eq!(body, b"world");
+
+            // a request without body on the same connection
+            let req = Request::builder()
+                .uri("https://openrusty.org/get")
+                .body(())
+                .unwrap();
+            let mut stream = client.send_request(req).await.unwrap();
+            stream.finish().await.unwrap();
+            let resp = stream.recv_response().await.unwrap();
+            assert_eq!(resp.status(), 204);
+            assert!(stream.recv_data().await.unwrap().is_none());
+        });
+
+        let conn = endpoint.accept().await.unwrap().handshake().await.unwrap();
+        let digest = Arc::new(conn.digest);
+        assert_eq!(
+            digest.ssl_digest.as_ref().unwrap().version,
+            "TLSv1_3".to_string()
+        );
+        let mut h3_conn = handshake(conn.connection, None).await.unwrap();
+
+        let pending = accept(&mut h3_conn, digest.clone()).await.unwrap().unwrap();
+        let mut session = pending.read_request().await.unwrap();
+        assert_eq!(session.req_header().method, Method::POST);
+        assert_eq!(session.req_header().uri.path(), "/upload");
+        assert_eq!(session.req_header().version, http::Version::HTTP_3);
+        assert!(session.client_addr().is_some());
+        assert!(!session.is_body_empty());
+        let mut body = vec![];
+        while let Some(data) = session.read_body_bytes().await.unwrap() {
+            body.extend_from_slice(&data);
+        }
+        assert_eq!(body, b"hello");
+        assert!(session.is_body_done());
+        assert_eq!(session.body_bytes_read(), 5);
+
+        // body cannot be sent before the header
+        assert!(session
+            .write_body(Bytes::from_static(b"world"), false)
+            .await
+            .is_err());
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(header::CONNECTION, "keep-alive")
+            .unwrap();
+        session
+            .write_response_header(Box::new(resp), false)
+            .await
+            .unwrap();
+        session
+            .write_body(Bytes::from_static(b"world"), true)
+            .await
+            .unwrap();
+        assert_eq!(session.body_bytes_sent(), 5);
+
+        let pending = accept(&mut h3_conn, digest.clone()).await.unwrap().unwrap();
+        let mut session = pending.read_request().await.unwrap();
+        assert_eq!(session.req_header().method, Method::GET);
+        // not known until the client closes its side of the stream
+        if !session.is_body_done() {
+            assert!(session.read_body_bytes().await.unwrap().is_none());
+        }
+        assert!(session.is_body_empty());
+        let resp = ResponseHeader::build(204, None).unwrap();
+        session
+            .write_response_header(Box::new(resp), true)
+            .await
+            .unwrap();
+
+        client_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stopped_endpoint_ignores_connections() {
+        let endpoint = bind().await;
+        let addr = endpoint.local_addr().unwrap();
+        endpoint.stop();
+
+        // relay the packets of the client to see what the endpoint answers
+        let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let _connecting = client_endpoint()
+            .connect(relay_addr, "openrusty.org")
+            .unwrap();
+        let answered = async {
+            let mut buf = vec![0; 2048];
+            loop {
+                let (n, from) = relay.recv_from(&mut buf).await.unwrap();
+                if from == addr {
+                    return n;
+                }
+                relay.send_to(&buf[..n], addr).await.unwrap();
+            }
+        };
+
+        // neither refused nor reset, so that the client retransmits to whichever process reads
+        // the socket
+        let res = tokio::time::timeout(std::time::Duration::from_millis(300), answered).await;
+        assert!(res.is_err(), "{res:?}");
+    }
+}
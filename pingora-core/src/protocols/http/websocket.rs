@@ -0,0 +1,511 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! WebSocket frames and messages, see RFC 6455
+//!
+//! This is used to inspect the messages of upgraded connections. Extensions such as
+//! permessage-deflate are not supported: frames with any of the RSV bits set are rejected.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use pingora_error::{Error, ErrorType, Result};
+
+/// The frames violate the WebSocket protocol.
+pub const WS_PROTOCOL_ERROR: ErrorType = ErrorType::new("WebSocketProtocolError");
+/// A message is larger than the allowed size.
+pub const WS_MESSAGE_TOO_BIG: ErrorType = ErrorType::new("WebSocketMessageTooBig");
+/// A text message or the reason of a close frame is not valid UTF-8.
+pub const WS_INVALID_DATA: ErrorType = ErrorType::new("WebSocketInvalidData");
+
+/// The close status code for protocol errors
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// The close status code for messages whose data is inconsistent with their type
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+/// The close status code for messages that are too big to process
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+// the max payload size of control frames
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// The opcode of a WebSocket frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0x0 => Some(OpCode::Continuation),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    /// Whether this is the opcode of a control frame: close, ping or pong
+    pub fn is_control(self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+/// A WebSocket message
+///
+/// Either a text or binary message reassembled from all its fragments, or a control frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The opcode of the message, never [OpCode::Continuation]
+    pub opcode: OpCode,
+    /// The unmasked payload of the message
+    pub payload: Bytes,
+}
+
+impl Message {
+    /// Create a text message.
+    pub fn text(text: impl Into<String>) -> Self {
+        Message {
+            opcode: OpCode::Text,
+            payload: Bytes::from(text.into()),
+        }
+    }
+
+    /// Create a binary message.
+    pub fn binary(data: impl Into<Bytes>) -> Self {
+        Message {
+            opcode: OpCode::Binary,
+            payload: data.into(),
+        }
+    }
+
+    /// Create a close frame with the given status code and reason.
+    pub fn close(code: u16, reason: &str) -> Self {
+        let mut payload = BytesMut::with_capacity(2 + reason.len());
+        payload.put_u16(code);
+        payload.put_slice(reason.as_bytes());
+        Message {
+            opcode: OpCode::Close,
+            payload: payload.freeze(),
+        }
+    }
+
+    /// Create a ping frame.
+    pub fn ping(data: impl Into<Bytes>) -> Self {
+        Message {
+            opcode: OpCode::Ping,
+            payload: data.into(),
+        }
+    }
+
+    /// Create a pong frame.
+    pub fn pong(data: impl Into<Bytes>) -> Self {
+        Message {
+            opcode: OpCode::Pong,
+            payload: data.into(),
+        }
+    }
+
+    /// The text of a text message, `None` for other messages or invalid UTF-8
+    pub fn as_text(&self) -> Option<&str> {
+        if self.opcode != OpCode::Text {
+            return None;
+        }
+        std::str::from_utf8(&self.payload).ok()
+    }
+
+    /// The status code of a close frame, `None` for other messages or a close without code
+    pub fn close_code(&self) -> Option<u16> {
+        if self.opcode != OpCode::Close || self.payload.len() < 2 {
+            return None;
+        }
+        Some(u16::from_be_bytes([self.payload[0], self.payload[1]]))
+    }
+
+    /// Encode the message as a single frame into `buf`.
+    ///
+    /// Frames sent by clients must be masked, in which case a random masking key is used.
+    pub fn encode(&self, mask: bool, buf: &mut BytesMut) {
+        let len = self.payload.len();
+        buf.reserve(14 + len);
+        // FIN is always set as the message is not fragmented
+        buf.put_u8(0x80 | self.opcode.as_u8());
+        let mask_bit = if mask { 0x80 } else { 0 };
+        if len < 126 {
+            buf.put_u8(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            buf.put_u8(mask_bit | 126);
+            buf.put_u16(len as u16);
+        } else {
+            buf.put_u8(mask_bit | 127);
+            buf.put_u64(len as u64);
+        }
+        if mask {
+            let key: [u8; 4] = rand::random();
+            buf.put_slice(&key);
+            let start = buf.len();
+            buf.put_slice(&self.payload);
+            apply_mask(&mut buf[start..], key);
+        } else {
+            buf.put_slice(&self.payload);
+        }
+    }
+}
+
+fn apply_mask(data: &mut [u8], key: [u8; 4]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+}
+
+// A single frame read from the wire, with its payload unmasked
+struct Frame {
+    fin: bool,
+    opcode: OpCode,
+    payload: Bytes,
+}
+
+// Check the payload of a complete message against its type.
+fn validate(message: Message) -> Result<Message> {
+    match message.opcode {
+        OpCode::Text if std::str::from_utf8(&message.payload).is_err() => {
+            return Error::e_explain(WS_INVALID_DATA, "text message is not valid UTF-8");
+        }
+        OpCode::Close => {
+            // either empty or a status code followed by a UTF-8 reason, see RFC 6455 5.5.1
+            if message.payload.len() == 1 {
+                return Error::e_explain(WS_PROTOCOL_ERROR, "close frame with a 1 byte payload");
+            }
+            if message.payload.len() > 2 && std::str::from_utf8(&message.payload[2..]).is_err() {
+                return Error::e_explain(WS_INVALID_DATA, "close reason is not valid UTF-8");
+            }
+        }
+        _ => {}
+    }
+    Ok(message)
+}
+
+/// Decode the frames of one direction of a WebSocket connection into [Message]s
+///
+/// Partial frames are buffered until the rest of them is received. The fragments of text and
+/// binary messages are reassembled, while control frames are returned as they are received,
+/// even in the middle of a fragmented message.
+pub struct MessageDecoder {
+    buf: BytesMut,
+    // the opcode and the payload so far of the fragmented message being reassembled
+    fragments: Option<(OpCode, BytesMut)>,
+    masked: bool,
+    max_message_size: usize,
+}
+
+impl MessageDecoder {
+    /// Create a new [MessageDecoder] which rejects the messages larger than `max_message_size`.
+    ///
+    /// `masked` tells whether the frames must be masked, which is the case of the frames sent by
+    /// clients and only them. The frames that don't follow it are rejected.
+    pub fn new(masked: bool, max_message_size: usize) -> Self {
+        MessageDecoder {
+            buf: BytesMut::new(),
+            fragments: None,
+            masked,
+            max_message_size,
+        }
+    }
+
+    /// Buffer the `data` received from the wire.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Return the next complete message from the buffered data, `None` if more data is needed.
+    ///
+    /// After an error, the state of the decoder is undefined and it should not be used anymore.
+    pub fn next_message(&mut self) -> Result<Option<Message>> {
+        while let Some(frame) = self.decode_frame()? {
+            if frame.opcode.is_control() {
+                return validate(Message {
+                    opcode: frame.opcode,
+                    payload: frame.payload,
+                })
+                .map(Some);
+            }
+            match (frame.opcode, self.fragments.as_mut()) {
+                (OpCode::Continuation, None) => {
+                    return Error::e_explain(
+                        WS_PROTOCOL_ERROR,
+                        "continuation frame without message",
+                    )
+                }
+                (OpCode::Continuation, Some((_, payload))) => {
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let (opcode, payload) = self.fragments.take().unwrap(); // just checked
+                        return validate(Message {
+                            opcode,
+                            payload: payload.freeze(),
+                        })
+                        .map(Some);
+                    }
+                }
+                (_, Some(_)) => {
+                    return Error::e_explain(
+                        WS_PROTOCOL_ERROR,
+                        "new message before the end of a fragmented message",
+                    )
+                }
+                (opcode, None) => {
+                    if frame.fin {
+                        return validate(Message {
+                            opcode,
+                            payload: frame.payload,
+                        })
+                        .map(Some);
+                    }
+                    self.fragments = Some((opcode, BytesMut::from(&frame.payload[..])));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Read one complete frame from the buffer, None if more data is needed
+    fn decode_frame(&mut self) -> Result<Option<Frame>> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let (b0, b1) = (self.buf[0], self.buf[1]);
+        let fin = b0 & 0x80 != 0;
+        if b0 & 0x70 != 0 {
+            return Error::e_explain(WS_PROTOCOL_ERROR, "RSV bits set without extension");
+        }
+        let Some(opcode) = OpCode::from_u8(b0 & 0x0F) else {
+            return Error::e_explain(
+                WS_PROTOCOL_ERROR,
+                format!("reserved opcode {:#x}", b0 & 0x0F),
+            );
+        };
+        let masked = b1 & 0x80 != 0;
+        if masked != self.masked {
+            let explain = if self.masked {
+                "unmasked frame from a client"
+            } else {
+                "masked frame from a server"
+            };
+            return Error::e_explain(WS_PROTOCOL_ERROR, explain);
+        }
+        let (len, len_size) = match b1 & 0x7F {
+            126 => {
+                if self.buf.len() < 4 {
+                    return Ok(None);
+                }
+                (u16::from_be_bytes([self.buf[2], self.buf[3]]) as u64, 2)
+            }
+            127 => {
+                if self.buf.len() < 10 {
+                    return Ok(None);
+                }
+                let len = u64::from_be_bytes(self.buf[2..10].try_into().unwrap());
+                if len >> 63 != 0 {
+                    return Error::e_explain(WS_PROTOCOL_ERROR, "invalid frame length");
+                }
+                (len, 8)
+            }
+            len => (len as u64, 0),
+        };
+
+        if opcode.is_control() {
+            if !fin || len > MAX_CONTROL_PAYLOAD as u64 {
+                return Error::e_explain(WS_PROTOCOL_ERROR, "invalid control frame");
+            }
+        } else {
+            // check the size before buffering the whole frame
+            let buffered = self.fragments.as_ref().map_or(0, |(_, p)| p.len()) as u64;
+            if buffered + len > self.max_message_size as u64 {
+                return Error::e_explain(
+                    WS_MESSAGE_TOO_BIG,
+                    format!("message larger than {} bytes", self.max_message_size),
+                );
+            }
+        }
+
+        let header_len = 2 + len_size + if masked { 4 } else { 0 };
+        let len = len as usize; // no larger than max_message_size
+        if self.buf.len() < header_len + len {
+            return Ok(None);
+        }
+        let mask_key = masked.then(|| {
+            let k = &self.buf[header_len - 4..header_len];
+            [k[0], k[1], k[2], k[3]]
+        });
+        self.buf.advance(header_len);
+        let mut payload = self.buf.split_to(len);
+        if let Some(key) = mask_key {
+            apply_mask(&mut payload, key);
+        }
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload: payload.freeze(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // push the data and return all the messages completed by it
+    fn decode(decoder: &mut MessageDecoder, data: &[u8]) -> Result<Vec<Message>> {
+        decoder.push(data);
+        let mut messages = vec![];
+        while let Some(message) = decoder.next_message()? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    fn encode(message: &Message, mask: bool) -> BytesMut {
+        let mut buf = BytesMut::new();
+        message.encode(mask, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for mask in [true, false] {
+            let mut decoder = MessageDecoder::new(mask, 1 << 20);
+            for message in [
+                Message::text("hello"),
+                Message::binary(vec![7u8; 300]),
+                Message::binary(vec![1u8; 70000]),
+                Message::ping("p"),
+                Message::close(1000, "bye"),
+            ] {
+                let buf = encode(&message, mask);
+                assert_eq!(decode(&mut decoder, &buf).unwrap(), vec![message]);
+            }
+        }
+        assert_eq!(Message::close(1001, "").close_code(), Some(1001));
+        assert_eq!(Message::text("hi").as_text(), Some("hi"));
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let mut decoder = MessageDecoder::new(true, 1 << 20);
+        let mut buf = encode(&Message::text("hello world"), true);
+        buf.extend_from_slice(&encode(&Message::binary("abc"), true));
+        let mut messages = vec![];
+        for b in buf.chunks(3) {
+            messages.extend(decode(&mut decoder, b).unwrap());
+        }
+        assert_eq!(
+            messages,
+            vec![Message::text("hello world"), Message::binary("abc")]
+        );
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut decoder = MessageDecoder::new(false, 1 << 20);
+        // "hel" + ping + "lo" in a fragmented text message, unmasked
+        let data = b"\x01\x03hel\x89\x01p\x80\x02lo";
+        assert_eq!(
+            decode(&mut decoder, data).unwrap(),
+            vec![Message::ping("p"), Message::text("hello")]
+        );
+
+        let mut decoder = MessageDecoder::new(false, 1 << 20);
+        let e = decode(&mut decoder, b"\x80\x02lo").unwrap_err();
+        assert_eq!(e.etype(), &WS_PROTOCOL_ERROR);
+
+        let mut decoder = MessageDecoder::new(false, 1 << 20);
+        let e = decode(&mut decoder, b"\x01\x03hel\x81\x02lo").unwrap_err();
+        assert_eq!(e.etype(), &WS_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        for data in [
+            &b"\xC1\x02hi"[..],       // RSV1
+            &b"\x83\x00"[..],         // reserved opcode
+            &b"\x09\x01p"[..],        // fragmented ping
+            &[0x89, 126, 0, 126][..], // ping larger than 125 bytes
+            &b"\x88\x01\x03"[..],     // close with a 1 byte payload
+        ] {
+            let mut decoder = MessageDecoder::new(false, 1 << 20);
+            let e = decode(&mut decoder, data).unwrap_err();
+            assert_eq!(e.etype(), &WS_PROTOCOL_ERROR);
+        }
+    }
+
+    #[test]
+    fn test_masking() {
+        // frames from clients must be masked, frames from servers must not
+        for mask in [true, false] {
+            let mut decoder = MessageDecoder::new(!mask, 1 << 20);
+            let e = decode(&mut decoder, &encode(&Message::text("hi"), mask)).unwrap_err();
+            assert_eq!(e.etype(), &WS_PROTOCOL_ERROR);
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        for data in [
+            &b"\x81\x02\xC3\x28"[..],         // text
+            &b"\x01\x01\xC3\x80\x01\x28"[..], // fragmented text
+            &b"\x88\x04\x03\xE8\xC3\x28"[..], // close reason
+        ] {
+            let mut decoder = MessageDecoder::new(false, 1 << 20);
+            let e = decode(&mut decoder, data).unwrap_err();
+            assert_eq!(e.etype(), &WS_INVALID_DATA);
+        }
+
+        // a character split across fragments is fine
+        let mut decoder = MessageDecoder::new(false, 1 << 20);
+        let messages = decode(&mut decoder, b"\x01\x01\xC3\x80\x01\xA9").unwrap();
+        assert_eq!(messages, vec![Message::text("é")]);
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let mut decoder = MessageDecoder::new(false, 10);
+        let buf = encode(&Message::text("0123456789"), false);
+        assert!(decode(&mut decoder, &buf).is_ok());
+        // rejected by the length in the header before the payload is received
+        let buf = encode(&Message::binary(vec![0u8; 11]), false);
+        let e = decode(&mut decoder, &buf[..2]).unwrap_err();
+        assert_eq!(e.etype(), &WS_MESSAGE_TOO_BIG);
+
+        // the fragments add up
+        let mut decoder = MessageDecoder::new(false, 10);
+        let e = decode(&mut decoder, b"\x02\x06012345\x80\x06012345").unwrap_err();
+        assert_eq!(e.etype(), &WS_MESSAGE_TOO_BIG);
+    }
+}
@@ -36,11 +36,12 @@ use tokio::net::TcpStream;
 #[cfg(unix)]
 use tokio::net::UnixStream;
 
+use crate::listeners::ConnectionPermit;
 use crate::protocols::l4::ext::{set_tcp_keepalive, TcpKeepalive};
 use crate::protocols::raw_connect::ProxyDigest;
 use crate::protocols::{
-    GetProxyDigest, GetSocketDigest, GetTimingDigest, Peek, Shutdown, SocketDigest, Ssl,
-    TimingDigest, UniqueID, UniqueIDType,
+    ConnectAttempt, GetProxyDigest, GetSocketDigest, GetTimingDigest, Peek, Shutdown, SocketDigest,
+    Ssl, TimingDigest, UniqueID, UniqueIDType,
 };
 use crate::upstreams::peer::Tracer;
 
@@ -365,9 +366,20 @@ pub struct Stream {
     write_pending_time: AccumulatedDuration,
     /// Last rx timestamp associated with the last recvmsg call.
     pub rx_ts: Option<SystemTime>,
+    // the connection limits this accepted connection counts toward until it is dropped
+    connection_permit: Option<ConnectionPermit>,
+    connect_attempts: Vec<ConnectAttempt>,
 }
 
 impl Stream {
+    pub(crate) fn set_connection_permit(&mut self, permit: ConnectionPermit) {
+        self.connection_permit = Some(permit);
+    }
+
+    pub(crate) fn set_connect_attempts(&mut self, attempts: Vec<ConnectAttempt>) {
+        self.connect_attempts = attempts;
+    }
+
     /// set TCP nodelay for this connection if `self` is TCP
     pub fn set_nodelay(&mut self) -> Result<()> {
         if let RawStream::Tcp(s) = &self.stream.get_mut().stream {
@@ -431,6 +443,8 @@ impl From<TcpStream> for Stream {
             read_pending_time: AccumulatedDuration::new(),
             write_pending_time: AccumulatedDuration::new(),
             rx_ts: None,
+            connection_permit: None,
+            connect_attempts: vec![],
         }
     }
 }
@@ -453,6 +467,8 @@ impl From<UnixStream> for Stream {
             read_pending_time: AccumulatedDuration::new(),
             write_pending_time: AccumulatedDuration::new(),
             rx_ts: None,
+            connection_permit: None,
+            connect_attempts: vec![],
         }
     }
 }
@@ -512,6 +528,7 @@ impl GetTimingDigest for Stream {
         let mut digest = Vec::with_capacity(2); // expect to have both L4 stream and TLS layer
         digest.push(Some(TimingDigest {
             established_ts: self.established_ts,
+            connect_attempts: self.connect_attempts.clone(),
         }));
         digest
     }
@@ -17,14 +17,16 @@
 mod digest;
 pub mod http;
 pub mod l4;
+pub mod proxy_protocol;
 pub mod raw_connect;
+pub mod socks5;
 pub mod tls;
 #[cfg(windows)]
 mod windows;
 
 pub use digest::{
-    Digest, GetProxyDigest, GetSocketDigest, GetTimingDigest, ProtoDigest, SocketDigest,
-    TimingDigest,
+    ConnectAttempt, ConnectOutcome, Digest, GetProxyDigest, GetSocketDigest, GetTimingDigest,
+    ProtoDigest, SocketDigest, TimingDigest,
 };
 pub use l4::ext::TcpKeepalive;
 pub use tls::ALPN;
@@ -331,6 +333,29 @@ impl ConnFdReusable for InetSocketAddr {
     }
 }
 
+// A connection to any of the addresses
+#[cfg(unix)]
+impl ConnFdReusable for [InetSocketAddr] {
+    fn check_fd_match<V: AsRawFd>(&self, fd: V) -> bool {
+        let fd = fd.as_raw_fd();
+        match getpeername::<SockaddrStorage>(fd) {
+            Ok(peer) => {
+                if self.iter().any(|addr| SockaddrStorage::from(*addr) == peer) {
+                    debug!("Inet FD to: {peer} is reusable");
+                    true
+                } else {
+                    error!("Crit: FD mismatch: fd: {fd:?}, addrs: {self:?}, peer: {peer}",);
+                    false
+                }
+            }
+            Err(e) => {
+                debug!("Idle connection is broken: {e:?}");
+                false
+            }
+        }
+    }
+}
+
 #[cfg(windows)]
 impl ConnSockReusable for InetSocketAddr {
     fn check_sock_match<V: AsRawSocket>(&self, sock: V) -> bool {
@@ -0,0 +1,853 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HAProxy PROXY protocol
+//!
+//! This mod implements the decoder and the encoder of both the human readable v1 and the binary
+//! v2 format of the [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt),
+//! which L4 load balancers use to pass the original client and destination addresses of a
+//! connection.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use pingora_error::{Error, ErrorType::*, OrErr, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr as InetSocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::l4::socket::SocketAddr;
+use super::l4::stream::Stream;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// "PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n"
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+const V2_INET_ADDRS_LEN: usize = 12;
+const V2_INET6_ADDRS_LEN: usize = 36;
+const V2_UNIX_PATH_LEN: usize = 108;
+const V2_UNIX_ADDRS_LEN: usize = V2_UNIX_PATH_LEN * 2;
+
+/// TLV type of the application-layer protocol negotiated by the client
+pub const PP2_TYPE_ALPN: u8 = 0x01;
+/// TLV type of the host name (e.g. TLS SNI) the client requested
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+/// TLV type of the CRC32c checksum of the header
+pub const PP2_TYPE_CRC32C: u8 = 0x03;
+/// TLV type that should be ignored, used for padding
+pub const PP2_TYPE_NOOP: u8 = 0x04;
+/// TLV type of an opaque identifier of the connection
+pub const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+/// TLV type of the TLS information of the client connection
+pub const PP2_TYPE_SSL: u8 = 0x20;
+/// TLV type of the network namespace the connection was accepted in
+pub const PP2_TYPE_NETNS: u8 = 0x30;
+
+/// The version of the PROXY protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyProtocolVersion {
+    /// The human readable format
+    V1,
+    /// The binary format
+    V2,
+}
+
+/// The command of a PROXY protocol header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyCommand {
+    /// The connection was established by the proxy itself (e.g. health checks). The addresses of
+    /// the underlying connection should be used.
+    Local,
+    /// The connection was relayed on behalf of another node.
+    Proxy,
+}
+
+/// The transport protocol of the proxied connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyTransport {
+    Unspecified,
+    Stream,
+    Datagram,
+}
+
+/// A Type-Length-Value extension of a PROXY protocol v2 header
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProxyTlv {
+    /// The type of this TLV, see the `PP2_TYPE_*` constants
+    pub kind: u8,
+    /// The raw value of this TLV
+    pub value: Bytes,
+}
+
+/// A decoded PROXY protocol header
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProxyHeader {
+    pub version: ProxyProtocolVersion,
+    pub command: ProxyCommand,
+    pub transport: ProxyTransport,
+    /// The address of the original client, if known
+    pub source: Option<SocketAddr>,
+    /// The address the original client connected to, if known
+    pub destination: Option<SocketAddr>,
+    /// The TLVs of the header. Always empty for v1.
+    pub tlvs: Vec<ProxyTlv>,
+}
+
+impl ProxyHeader {
+    /// Create a header of the given `version` for a stream connection from `source` to
+    /// `destination`.
+    pub fn new(version: ProxyProtocolVersion, source: SocketAddr, destination: SocketAddr) -> Self {
+        ProxyHeader {
+            version,
+            command: ProxyCommand::Proxy,
+            transport: ProxyTransport::Stream,
+            source: Some(source),
+            destination: Some(destination),
+            tlvs: vec![],
+        }
+    }
+
+    /// Whether the addresses in this header should be used instead of the ones of the
+    /// underlying connection.
+    pub fn is_proxied(&self) -> bool {
+        self.command == ProxyCommand::Proxy && self.source.is_some()
+    }
+
+    /// Return the value of the first TLV of the given type, if any.
+    pub fn tlv(&self, kind: u8) -> Option<&Bytes> {
+        self.tlvs.iter().find(|t| t.kind == kind).map(|t| &t.value)
+    }
+
+    /// Return the authority (host name) TLV, if any.
+    pub fn authority(&self) -> Option<&str> {
+        self.tlv(PP2_TYPE_AUTHORITY)
+            .and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    /// Return the ALPN TLV, if any.
+    pub fn alpn(&self) -> Option<&[u8]> {
+        self.tlv(PP2_TYPE_ALPN).map(|v| v.as_ref())
+    }
+}
+
+/// Try to decode a PROXY protocol header (v1 or v2) from the beginning of `buf`.
+///
+/// Return `Ok(None)` if `buf` doesn't contain the complete header yet. Otherwise return the
+/// header and the number of bytes it occupies in `buf`.
+pub fn parse_proxy_header(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>> {
+    let Some(first) = buf.first() else {
+        return Ok(None);
+    };
+    match first {
+        b'P' => parse_v1(buf),
+        b'\r' => parse_v2(buf),
+        _ => Error::e_explain(InvalidProxyProtocol, "no PROXY protocol signature"),
+    }
+}
+
+fn check_prefix(buf: &[u8], prefix: &[u8]) -> Result<bool> {
+    let len = buf.len().min(prefix.len());
+    if buf[..len] != prefix[..len] {
+        return Error::e_explain(InvalidProxyProtocol, "no PROXY protocol signature");
+    }
+    Ok(len == prefix.len())
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>> {
+    if !check_prefix(buf, V1_PREFIX)? {
+        return Ok(None);
+    }
+    let search_len = buf.len().min(V1_MAX_LEN);
+    let Some(end) = buf[..search_len].windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Error::e_explain(InvalidProxyProtocol, "v1 header too long");
+        }
+        return Ok(None);
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .or_err(InvalidProxyProtocol, "v1 header is not valid ASCII")?;
+    let mut parts = line.split(' ');
+
+    let transport_family = parts.next().unwrap_or_default();
+    let (source, destination) = match transport_family {
+        // the rest of the line should be ignored
+        "UNKNOWN" => (None, None),
+        "TCP4" | "TCP6" => {
+            let fields: Vec<&str> = parts.collect();
+            let [src_ip, dst_ip, src_port, dst_port] = fields[..] else {
+                return Error::e_explain(
+                    InvalidProxyProtocol,
+                    format!("invalid v1 address line: {line}"),
+                );
+            };
+            let (src_ip, dst_ip) = if transport_family == "TCP4" {
+                (
+                    src_ip.parse::<Ipv4Addr>().map(Into::into),
+                    dst_ip.parse::<Ipv4Addr>().map(Into::into),
+                )
+            } else {
+                (
+                    src_ip.parse::<Ipv6Addr>().map(Into::into),
+                    dst_ip.parse::<Ipv6Addr>().map(Into::into),
+                )
+            };
+            let src_ip = src_ip.or_err(InvalidProxyProtocol, "invalid v1 source address")?;
+            let dst_ip = dst_ip.or_err(InvalidProxyProtocol, "invalid v1 destination address")?;
+            let src_port = src_port
+                .parse::<u16>()
+                .or_err(InvalidProxyProtocol, "invalid v1 source port")?;
+            let dst_port = dst_port
+                .parse::<u16>()
+                .or_err(InvalidProxyProtocol, "invalid v1 destination port")?;
+            (
+                Some(InetSocketAddr::new(src_ip, src_port).into()),
+                Some(InetSocketAddr::new(dst_ip, dst_port).into()),
+            )
+        }
+        other => {
+            return Error::e_explain(
+                InvalidProxyProtocol,
+                format!("unknown v1 transport family: {other}"),
+            );
+        }
+    };
+
+    let header = ProxyHeader {
+        version: ProxyProtocolVersion::V1,
+        command: ProxyCommand::Proxy,
+        transport: if source.is_some() {
+            ProxyTransport::Stream
+        } else {
+            ProxyTransport::Unspecified
+        },
+        source,
+        destination,
+        tlvs: vec![],
+    };
+    Ok(Some((header, end + 2)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>> {
+    if !check_prefix(buf, V2_SIGNATURE)? || buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version_command = buf[12];
+    if version_command >> 4 != 2 {
+        return Error::e_explain(
+            InvalidProxyProtocol,
+            format!("unsupported v2 version: {}", version_command >> 4),
+        );
+    }
+    let command = match version_command & 0x0F {
+        0 => ProxyCommand::Local,
+        1 => ProxyCommand::Proxy,
+        c => {
+            return Error::e_explain(InvalidProxyProtocol, format!("unknown v2 command: {c}"));
+        }
+    };
+    let family_transport = buf[13];
+    let transport = match family_transport & 0x0F {
+        0 => ProxyTransport::Unspecified,
+        1 => ProxyTransport::Stream,
+        2 => ProxyTransport::Datagram,
+        t => {
+            return Error::e_explain(InvalidProxyProtocol, format!("unknown v2 transport: {t}"));
+        }
+    };
+    let addrs_len = match family_transport >> 4 {
+        0 => 0,
+        1 => V2_INET_ADDRS_LEN,
+        2 => V2_INET6_ADDRS_LEN,
+        3 => V2_UNIX_ADDRS_LEN,
+        f => {
+            return Error::e_explain(InvalidProxyProtocol, format!("unknown v2 family: {f}"));
+        }
+    };
+
+    let payload_len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let total_len = V2_HEADER_LEN + payload_len;
+    if buf.len() < total_len {
+        return Ok(None);
+    }
+    if payload_len < addrs_len {
+        return Error::e_explain(
+            InvalidProxyProtocol,
+            format!("v2 payload of {payload_len} bytes is too short for its address family"),
+        );
+    }
+
+    let payload = &buf[V2_HEADER_LEN..total_len];
+    let addrs = &payload[..addrs_len];
+    let (source, destination) = match (command, family_transport >> 4) {
+        // the receiver must ignore the address information of LOCAL connections
+        (ProxyCommand::Local, _) | (_, 0) => (None, None),
+        (_, 1) => {
+            let src_ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let dst_ip = Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+            let src_port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            let dst_port = u16::from_be_bytes([addrs[10], addrs[11]]);
+            (
+                Some(InetSocketAddr::new(src_ip.into(), src_port).into()),
+                Some(InetSocketAddr::new(dst_ip.into(), dst_port).into()),
+            )
+        }
+        (_, 2) => {
+            let mut src_ip = [0u8; 16];
+            let mut dst_ip = [0u8; 16];
+            src_ip.copy_from_slice(&addrs[..16]);
+            dst_ip.copy_from_slice(&addrs[16..32]);
+            let src_port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            let dst_port = u16::from_be_bytes([addrs[34], addrs[35]]);
+            (
+                Some(InetSocketAddr::new(Ipv6Addr::from(src_ip).into(), src_port).into()),
+                Some(InetSocketAddr::new(Ipv6Addr::from(dst_ip).into(), dst_port).into()),
+            )
+        }
+        _ => (
+            unix_addr(&addrs[..V2_UNIX_PATH_LEN]),
+            unix_addr(&addrs[V2_UNIX_PATH_LEN..]),
+        ),
+    };
+
+    let mut tlvs = vec![];
+    let mut rest = &payload[addrs_len..];
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            return Error::e_explain(InvalidProxyProtocol, "truncated v2 TLV");
+        }
+        let kind = rest[0];
+        let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        if rest.len() < 3 + len {
+            return Error::e_explain(InvalidProxyProtocol, "truncated v2 TLV value");
+        }
+        tlvs.push(ProxyTlv {
+            kind,
+            value: Bytes::copy_from_slice(&rest[3..3 + len]),
+        });
+        rest = &rest[3 + len..];
+    }
+
+    let header = ProxyHeader {
+        version: ProxyProtocolVersion::V2,
+        command,
+        transport,
+        source,
+        destination,
+        tlvs,
+    };
+    Ok(Some((header, total_len)))
+}
+
+#[cfg(unix)]
+fn unix_addr(raw: &[u8]) -> Option<SocketAddr> {
+    use std::os::unix::ffi::OsStrExt;
+    // the path is NUL padded
+    let len = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+    if len == 0 {
+        return None;
+    }
+    let path = std::ffi::OsStr::from_bytes(&raw[..len]);
+    std::os::unix::net::SocketAddr::from_pathname(path)
+        .ok()
+        .map(SocketAddr::Unix)
+}
+
+#[cfg(windows)]
+fn unix_addr(_raw: &[u8]) -> Option<SocketAddr> {
+    None
+}
+
+enum EncodedAddrs {
+    None,
+    Inet(InetSocketAddr, InetSocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf, std::path::PathBuf),
+}
+
+impl EncodedAddrs {
+    fn new(header: &ProxyHeader) -> Self {
+        if header.command == ProxyCommand::Local {
+            return EncodedAddrs::None;
+        }
+        match (&header.source, &header.destination) {
+            (Some(SocketAddr::Inet(src)), Some(SocketAddr::Inet(dst))) => {
+                if src.is_ipv4() == dst.is_ipv4() {
+                    EncodedAddrs::Inet(*src, *dst)
+                } else {
+                    // both addresses need to be of the same family
+                    EncodedAddrs::Inet(to_ipv6(src), to_ipv6(dst))
+                }
+            }
+            #[cfg(unix)]
+            (Some(SocketAddr::Unix(src)), Some(SocketAddr::Unix(dst))) => {
+                match (src.as_pathname(), dst.as_pathname()) {
+                    (Some(src), Some(dst)) => EncodedAddrs::Unix(src.into(), dst.into()),
+                    _ => EncodedAddrs::None,
+                }
+            }
+            _ => EncodedAddrs::None,
+        }
+    }
+}
+
+fn to_ipv6(addr: &InetSocketAddr) -> InetSocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => InetSocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => *addr,
+    }
+}
+
+impl ProxyHeader {
+    /// Encode this header in the wire format of its version.
+    ///
+    /// v1 headers can only carry TCP over IPv4/IPv6 addresses; other headers are encoded as
+    /// `UNKNOWN`. TLVs are only encoded in v2 headers.
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        match self.version {
+            ProxyProtocolVersion::V1 => Ok(self.to_v1_bytes()),
+            ProxyProtocolVersion::V2 => self.to_v2_bytes(),
+        }
+    }
+
+    fn to_v1_bytes(&self) -> Bytes {
+        let line = match EncodedAddrs::new(self) {
+            EncodedAddrs::Inet(src, dst) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if src.is_ipv4() { "TCP4" } else { "TCP6" },
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            ),
+            _ => "PROXY UNKNOWN\r\n".to_string(),
+        };
+        line.into()
+    }
+
+    fn to_v2_bytes(&self) -> Result<Bytes> {
+        let mut payload = BytesMut::new();
+        let family = match EncodedAddrs::new(self) {
+            EncodedAddrs::None => 0x00,
+            EncodedAddrs::Inet(src, dst) => {
+                match (src.ip(), dst.ip()) {
+                    (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                        payload.put_slice(&src_ip.octets());
+                        payload.put_slice(&dst_ip.octets());
+                    }
+                    (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+                        payload.put_slice(&src_ip.octets());
+                        payload.put_slice(&dst_ip.octets());
+                    }
+                    _ => unreachable!("addresses are converted to the same family"),
+                }
+                payload.put_u16(src.port());
+                payload.put_u16(dst.port());
+                if src.is_ipv4() {
+                    0x10
+                } else {
+                    0x20
+                }
+            }
+            #[cfg(unix)]
+            EncodedAddrs::Unix(src, dst) => {
+                use std::os::unix::ffi::OsStrExt;
+                for path in [src, dst] {
+                    let path = path.as_os_str().as_bytes();
+                    if path.len() > V2_UNIX_PATH_LEN {
+                        return Error::e_explain(InternalError, "unix path too long for PROXY v2");
+                    }
+                    payload.put_slice(path);
+                    payload.put_bytes(0, V2_UNIX_PATH_LEN - path.len());
+                }
+                0x30
+            }
+        };
+        for tlv in self.tlvs.iter() {
+            let len =
+                u16::try_from(tlv.value.len()).or_err(InternalError, "PROXY v2 TLV too long")?;
+            payload.put_u8(tlv.kind);
+            payload.put_u16(len);
+            payload.put_slice(&tlv.value);
+        }
+        let payload_len =
+            u16::try_from(payload.len()).or_err(InternalError, "PROXY v2 header too long")?;
+
+        let command = match self.command {
+            ProxyCommand::Local => 0x0,
+            ProxyCommand::Proxy => 0x1,
+        };
+        let transport = match self.transport {
+            ProxyTransport::Unspecified => 0x0,
+            ProxyTransport::Stream => 0x1,
+            ProxyTransport::Datagram => 0x2,
+        };
+        let mut buf = BytesMut::with_capacity(V2_HEADER_LEN + payload.len());
+        buf.put_slice(V2_SIGNATURE);
+        buf.put_u8(0x20 | command);
+        buf.put_u8(family | transport);
+        buf.put_u16(payload_len);
+        buf.put_slice(&payload);
+        Ok(buf.freeze())
+    }
+}
+
+/// Write the given PROXY protocol `header` to the beginning of the given `stream`.
+pub async fn write_proxy_header(stream: &mut Stream, header: &ProxyHeader) -> Result<()> {
+    let buf = header.to_bytes()?;
+    stream
+        .write_all(&buf)
+        .await
+        .or_err(WriteError, "while writing PROXY protocol header")?;
+    stream
+        .flush()
+        .await
+        .or_err(WriteError, "while flushing PROXY protocol header")
+}
+
+/// Read the PROXY protocol header (v1 or v2) at the beginning of the given `stream`.
+///
+/// Only the header is consumed. Any data read after it is put back into the `stream`.
+pub async fn read_proxy_header(stream: &mut Stream) -> Result<ProxyHeader> {
+    let mut buf = BytesMut::with_capacity(V1_MAX_LEN.max(V2_HEADER_LEN));
+    loop {
+        let n = stream
+            .read_buf(&mut buf)
+            .await
+            .or_err(ReadError, "while reading PROXY protocol header")?;
+        if n == 0 {
+            return Error::e_explain(ConnectionClosed, "while reading PROXY protocol header");
+        }
+        if let Some((header, len)) = parse_proxy_header(&buf)? {
+            stream.rewind(&buf[len..]);
+            return Ok(header);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn v2_header(version_command: u8, family_transport: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(version_command);
+        buf.push(family_transport);
+        buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn test_parse_v1_tcp4() {
+        let raw = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (header, len) = parse_proxy_header(raw).unwrap().unwrap();
+        assert_eq!(len, 47);
+        assert_eq!(header.version, ProxyProtocolVersion::V1);
+        assert_eq!(header.command, ProxyCommand::Proxy);
+        assert_eq!(header.transport, ProxyTransport::Stream);
+        assert_eq!(
+            header.source.unwrap(),
+            "192.168.0.1:56324".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            header.destination.unwrap(),
+            "192.168.0.11:443".parse::<SocketAddr>().unwrap()
+        );
+        assert!(header.tlvs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_v1_tcp6() {
+        let raw = b"PROXY TCP6 2001:db8::1 ::1 56324 443\r\n";
+        let (header, len) = parse_proxy_header(raw).unwrap().unwrap();
+        assert_eq!(len, raw.len());
+        assert_eq!(
+            header.source.unwrap(),
+            "[2001:db8::1]:56324".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            header.destination.unwrap(),
+            "[::1]:443".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_v1_unknown() {
+        let raw = b"PROXY UNKNOWN whatever\r\n";
+        let (header, len) = parse_proxy_header(raw).unwrap().unwrap();
+        assert_eq!(len, raw.len());
+        assert!(!header.is_proxied());
+        assert_eq!(header.transport, ProxyTransport::Unspecified);
+    }
+
+    #[test]
+    fn test_parse_v1_partial() {
+        let raw = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n";
+        for i in 0..raw.len() {
+            assert!(parse_proxy_header(&raw[..i]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_parse_v1_invalid() {
+        let cases: &[&[u8]] = &[
+            b"GET / HTTP/1.1\r\n",
+            b"PROXY TCP5 192.168.0.1 192.168.0.11 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            b"PROXY TCP4 192.168.0.1 ::1 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 65536\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443 extra\r\n",
+            b"PROXYTCP4",
+        ];
+        for raw in cases {
+            let e = parse_proxy_header(raw).unwrap_err();
+            assert_eq!(e.etype(), &InvalidProxyProtocol);
+        }
+        let too_long = [b"PROXY UNKNOWN ".as_ref(), &[b'a'; 200]].concat();
+        let e = parse_proxy_header(&too_long).unwrap_err();
+        assert_eq!(e.etype(), &InvalidProxyProtocol);
+    }
+
+    #[test]
+    fn test_parse_v2_inet_with_tlvs() {
+        let mut payload = vec![10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x01, 0xBB];
+        payload.extend_from_slice(&[PP2_TYPE_AUTHORITY, 0, 11]);
+        payload.extend_from_slice(b"example.com");
+        payload.extend_from_slice(&[PP2_TYPE_ALPN, 0, 2]);
+        payload.extend_from_slice(b"h2");
+        let mut raw = v2_header(0x21, 0x11, &payload);
+        let header_len = raw.len();
+        raw.extend_from_slice(b"trailing data");
+
+        let (header, len) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(len, header_len);
+        assert_eq!(header.version, ProxyProtocolVersion::V2);
+        assert_eq!(header.command, ProxyCommand::Proxy);
+        assert_eq!(header.transport, ProxyTransport::Stream);
+        assert!(header.is_proxied());
+        assert_eq!(
+            header.source,
+            Some("10.0.0.1:8080".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(
+            header.destination,
+            Some("10.0.0.2:443".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(header.tlvs.len(), 2);
+        assert_eq!(header.authority(), Some("example.com"));
+        assert_eq!(header.alpn(), Some(b"h2".as_ref()));
+    }
+
+    #[test]
+    fn test_parse_v2_inet6() {
+        let mut payload = vec![0u8; 36];
+        payload[15] = 1; // ::1
+        payload[16] = 0x20;
+        payload[17] = 0x01; // 2001::
+        payload[32..34].copy_from_slice(&1234u16.to_be_bytes());
+        payload[34..36].copy_from_slice(&443u16.to_be_bytes());
+        let raw = v2_header(0x21, 0x21, &payload);
+        let (header, _) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(
+            header.source.unwrap(),
+            "[::1]:1234".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            header.destination.unwrap(),
+            "[2001::]:443".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_v2_unix() {
+        let mut payload = vec![0u8; V2_UNIX_ADDRS_LEN];
+        payload[..9].copy_from_slice(b"/tmp/src1");
+        payload[V2_UNIX_PATH_LEN..V2_UNIX_PATH_LEN + 9].copy_from_slice(b"/tmp/dst1");
+        let raw = v2_header(0x21, 0x31, &payload);
+        let (header, _) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(
+            header.source.unwrap(),
+            "unix:/tmp/src1".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            header.destination.unwrap(),
+            "unix:/tmp/dst1".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_v2_local() {
+        let payload = [10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x01, 0xBB];
+        let raw = v2_header(0x20, 0x11, &payload);
+        let (header, len) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(len, raw.len());
+        assert_eq!(header.command, ProxyCommand::Local);
+        assert!(!header.is_proxied());
+        assert!(header.source.is_none());
+
+        let raw = v2_header(0x20, 0x00, &[]);
+        let (header, len) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(len, V2_HEADER_LEN);
+        assert_eq!(header.transport, ProxyTransport::Unspecified);
+    }
+
+    #[test]
+    fn test_parse_v2_partial() {
+        let payload = [
+            10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x01, 0xBB, 0x04, 0, 1, 0,
+        ];
+        let raw = v2_header(0x21, 0x11, &payload);
+        for i in 0..raw.len() {
+            assert!(parse_proxy_header(&raw[..i]).unwrap().is_none());
+        }
+        assert!(parse_proxy_header(&raw).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_parse_v2_invalid() {
+        let addrs = [10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x01, 0xBB];
+        let cases = [
+            // bad signature
+            [b"\r\n\r\n\0\r\nQUIX\n".as_ref(), &[0x21, 0x11, 0, 0]].concat(),
+            // bad version
+            v2_header(0x11, 0x11, &addrs),
+            // bad command
+            v2_header(0x22, 0x11, &addrs),
+            // bad family
+            v2_header(0x21, 0x41, &addrs),
+            // bad transport
+            v2_header(0x21, 0x13, &addrs),
+            // too short for the address family
+            v2_header(0x21, 0x21, &addrs),
+            // truncated TLV
+            v2_header(0x21, 0x11, &[addrs.as_ref(), &[0x04, 0]].concat()),
+            // TLV longer than the payload
+            v2_header(0x21, 0x11, &[addrs.as_ref(), &[0x04, 0, 5, 0]].concat()),
+        ];
+        for raw in cases.iter() {
+            let e = parse_proxy_header(raw).unwrap_err();
+            assert_eq!(e.etype(), &InvalidProxyProtocol);
+        }
+    }
+
+    #[test]
+    fn test_encode_v1() {
+        let header = ProxyHeader::new(
+            ProxyProtocolVersion::V1,
+            "192.168.0.1:56324".parse().unwrap(),
+            "192.168.0.11:443".parse().unwrap(),
+        );
+        let raw = header.to_bytes().unwrap();
+        assert_eq!(
+            raw.as_ref(),
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n"
+        );
+
+        // mixed families are encoded as IPv6
+        let header = ProxyHeader::new(
+            ProxyProtocolVersion::V1,
+            "192.168.0.1:56324".parse().unwrap(),
+            "[::1]:443".parse().unwrap(),
+        );
+        let raw = header.to_bytes().unwrap();
+        assert_eq!(
+            raw.as_ref(),
+            b"PROXY TCP6 ::ffff:192.168.0.1 ::1 56324 443\r\n"
+        );
+        let (decoded, _) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert!(decoded.is_proxied());
+
+        let mut header = header;
+        header.command = ProxyCommand::Local;
+        assert_eq!(header.to_bytes().unwrap().as_ref(), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn test_encode_v2_roundtrip() {
+        let mut header = ProxyHeader::new(
+            ProxyProtocolVersion::V2,
+            "10.0.0.1:8080".parse().unwrap(),
+            "10.0.0.2:443".parse().unwrap(),
+        );
+        header.tlvs.push(ProxyTlv {
+            kind: PP2_TYPE_AUTHORITY,
+            value: Bytes::from_static(b"example.com"),
+        });
+        let raw = header.to_bytes().unwrap();
+        assert_eq!(raw.len(), V2_HEADER_LEN + 12 + 3 + 11);
+        let (decoded, len) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(len, raw.len());
+        assert_eq!(decoded, header);
+
+        header.source = Some("[2001:db8::1]:1234".parse().unwrap());
+        header.destination = Some("[::1]:443".parse().unwrap());
+        header.transport = ProxyTransport::Datagram;
+        let raw = header.to_bytes().unwrap();
+        let (decoded, _) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(decoded, header);
+
+        header.command = ProxyCommand::Local;
+        let raw = header.to_bytes().unwrap();
+        assert_eq!(raw.len(), V2_HEADER_LEN + 3 + 11);
+        let (decoded, _) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(decoded.command, ProxyCommand::Local);
+        assert!(decoded.source.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_encode_v2_unix() {
+        let header = ProxyHeader::new(
+            ProxyProtocolVersion::V2,
+            "unix:/tmp/src1".parse().unwrap(),
+            "unix:/tmp/dst1".parse().unwrap(),
+        );
+        let raw = header.to_bytes().unwrap();
+        assert_eq!(raw.len(), V2_HEADER_LEN + V2_UNIX_ADDRS_LEN);
+        let (decoded, _) = parse_proxy_header(&raw).unwrap().unwrap();
+        assert_eq!(decoded, header);
+    }
+
+    #[tokio::test]
+    async fn test_read_proxy_header() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        // the header arrives in pieces, followed by the application data
+        tokio::spawn(async move {
+            client.write_all(b"PROXY TCP4 1.2.3.4 ").await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            client
+                .write_all(b"5.6.7.8 1111 2222\r\nhello")
+                .await
+                .unwrap();
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = tokio::spawn(async move {
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            let mut server = server;
+            tokio::io::copy(&mut server, &mut accepted).await.unwrap();
+        });
+        let mut stream: Stream = tokio::net::TcpStream::connect(addr).await.unwrap().into();
+        let header = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(
+            header.source.unwrap(),
+            "1.2.3.4:1111".parse::<SocketAddr>().unwrap()
+        );
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"hello");
+        relay.await.unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SOCKS5 CONNECT client, see RFC 1928 and RFC 1929
+//!
+//! Like [super::raw_connect], the stream is yielded as is once the proxy has connected it to the
+//! destination so that the protocols on top can use it directly.
+
+use pingora_error::{Error, ErrorType, ErrorType::*, OrErr, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+// the version of the username/password subnegotiation
+const AUTH_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Ask the SOCKS5 proxy at the other end of `stream` to connect to `host` and `port`.
+///
+/// Host names are resolved by the proxy. The username/password authentication is offered when
+/// `credentials` are given.
+///
+/// Return the address the proxy bound to connect to the destination. The errors of the proxy
+/// connecting to the destination are mapped to [ConnectRefused], [ConnectNoRoute],
+/// [ConnectTimedout] and [ConnectProxyFailure].
+pub async fn connect<S>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    credentials: Option<(&str, &str)>,
+) -> Result<SocketAddr>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // validate the request before sending anything
+    let request = connect_request(host, port)?;
+
+    let greeting: &[u8] = if credentials.is_some() {
+        &[VERSION, 2, NO_AUTH, USERNAME_PASSWORD]
+    } else {
+        &[VERSION, 1, NO_AUTH]
+    };
+    write(stream, greeting).await?;
+    let mut choice = [0; 2];
+    read(stream, &mut choice).await?;
+    if choice[0] != VERSION {
+        return Error::e_explain(
+            Socks5ProtocolError,
+            format!("unexpected SOCKS version {}", choice[0]),
+        );
+    }
+    match (choice[1], credentials) {
+        (NO_AUTH, _) => {}
+        (USERNAME_PASSWORD, Some((username, password))) => {
+            authenticate(stream, username, password).await?
+        }
+        (NO_ACCEPTABLE_METHOD, _) => {
+            return Error::e_explain(Socks5AuthError, "no acceptable authentication method")
+        }
+        (method, _) => {
+            return Error::e_explain(
+                Socks5ProtocolError,
+                format!("unexpected authentication method {method}"),
+            )
+        }
+    }
+
+    write(stream, &request).await?;
+    let mut reply = [0; 4];
+    read(stream, &mut reply).await?;
+    if reply[0] != VERSION {
+        return Error::e_explain(
+            Socks5ProtocolError,
+            format!("unexpected SOCKS version {}", reply[0]),
+        );
+    }
+    if reply[1] != 0 {
+        let (etype, reason) = reply_error(reply[1]);
+        return Error::e_explain(etype, format!("SOCKS5 proxy reply: {reason}"));
+    }
+    let ip = match reply[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            read(stream, &mut octets).await?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            read(stream, &mut octets).await?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        ATYP_DOMAIN => {
+            // not meaningful to the client, skip the name
+            let mut len = [0; 1];
+            read(stream, &mut len).await?;
+            let mut name = vec![0; len[0] as usize];
+            read(stream, &mut name).await?;
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        atyp => {
+            return Error::e_explain(
+                Socks5ProtocolError,
+                format!("unexpected address type {atyp}"),
+            )
+        }
+    };
+    let mut bound_port = [0; 2];
+    read(stream, &mut bound_port).await?;
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(bound_port)))
+}
+
+fn connect_request(host: &str, port: u16) -> Result<Vec<u8>> {
+    let mut request = vec![VERSION, CMD_CONNECT, 0];
+    let literal = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    match literal.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.is_empty() || host.len() > 255 {
+                return Error::e_explain(
+                    ConnectProxyFailure,
+                    format!("invalid SOCKS5 destination host {host:?}"),
+                );
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+async fn authenticate<S>(stream: &mut S, username: &str, password: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if username.is_empty() || username.len() > 255 || password.len() > 255 {
+        return Error::e_explain(Socks5AuthError, "invalid SOCKS5 username or password");
+    }
+    let mut request = vec![AUTH_VERSION, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    write(stream, &request).await?;
+    let mut reply = [0; 2];
+    read(stream, &mut reply).await?;
+    if reply[1] != 0 {
+        return Error::e_explain(Socks5AuthError, "username/password rejected");
+    }
+    Ok(())
+}
+
+fn reply_error(code: u8) -> (ErrorType, &'static str) {
+    match code {
+        1 => (ConnectProxyFailure, "general SOCKS server failure"),
+        2 => (ConnectProxyFailure, "connection not allowed by ruleset"),
+        3 => (ConnectNoRoute, "network unreachable"),
+        4 => (ConnectNoRoute, "host unreachable"),
+        5 => (ConnectRefused, "connection refused"),
+        6 => (ConnectTimedout, "TTL expired"),
+        7 => (Socks5ProtocolError, "command not supported"),
+        8 => (Socks5ProtocolError, "address type not supported"),
+        _ => (Socks5ProtocolError, "unknown reply code"),
+    }
+}
+
+async fn write<S: AsyncWrite + Unpin>(stream: &mut S, buf: &[u8]) -> Result<()> {
+    stream
+        .write_all(buf)
+        .await
+        .or_err(WriteError, "while writing to SOCKS5 proxy")?;
+    stream
+        .flush()
+        .await
+        .or_err(WriteError, "while flushing to SOCKS5 proxy")
+}
+
+async fn read<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut [u8]) -> Result<()> {
+    stream
+        .read_exact(buf)
+        .await
+        .or_err(ReadError, "while reading from SOCKS5 proxy")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_test::io::Builder;
+
+    #[tokio::test]
+    async fn test_connect_domain() {
+        let mut mock = Builder::new()
+            .write(&[5, 1, 0])
+            .read(&[5, 0])
+            .write(b"\x05\x01\x00\x03\x0bpingora.org\x01\xbb")
+            .read(&[5, 0, 0, 1, 192, 0, 2, 1, 0x1f, 0x90])
+            .build();
+        let bound = connect(&mut mock, "pingora.org", 443, None).await.unwrap();
+        assert_eq!(bound, "192.0.2.1:8080".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_connect_auth() {
+        let mut mock = Builder::new()
+            .write(&[5, 2, 0, 2])
+            .read(&[5, 2])
+            .write(b"\x01\x04user\x04pass")
+            .read(&[1, 0])
+            .write(&[
+                5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80,
+            ])
+            .read(&[5, 0, 0, 3, 1, b'a', 0, 80])
+            .build();
+        connect(&mut mock, "[::1]", 80, Some(("user", "pass")))
+            .await
+            .unwrap();
+
+        let mut mock = Builder::new()
+            .write(&[5, 2, 0, 2])
+            .read(&[5, 2])
+            .write(b"\x01\x04user\x05wrong")
+            .read(&[1, 1])
+            .build();
+        let e = connect(&mut mock, "127.0.0.1", 80, Some(("user", "wrong")))
+            .await
+            .unwrap_err();
+        assert_eq!(e.etype(), &Socks5AuthError);
+
+        let mut mock = Builder::new().write(&[5, 1, 0]).read(&[5, 0xff]).build();
+        let e = connect(&mut mock, "127.0.0.1", 80, None).await.unwrap_err();
+        assert_eq!(e.etype(), &Socks5AuthError);
+    }
+
+    #[tokio::test]
+    async fn test_connect_errors() {
+        for (code, etype) in [
+            (1, ConnectProxyFailure),
+            (4, ConnectNoRoute),
+            (5, ConnectRefused),
+            (6, ConnectTimedout),
+            (8, Socks5ProtocolError),
+        ] {
+            let mut mock = Builder::new()
+                .write(&[5, 1, 0])
+                .read(&[5, 0])
+                .write(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+                .read(&[5, code, 0, 1])
+                .build();
+            let e = connect(&mut mock, "127.0.0.1", 80, None).await.unwrap_err();
+            assert_eq!(e.etype(), &etype);
+        }
+
+        // not a SOCKS5 proxy
+        let mut mock = Builder::new().write(&[5, 1, 0]).read(b"HT").build();
+        let e = connect(&mut mock, "127.0.0.1", 80, None).await.unwrap_err();
+        assert_eq!(e.etype(), &Socks5ProtocolError);
+
+        let mut mock = Builder::new().build();
+        let e = connect(&mut mock, "", 80, None).await.unwrap_err();
+        assert_eq!(e.etype(), &ConnectProxyFailure);
+    }
+}
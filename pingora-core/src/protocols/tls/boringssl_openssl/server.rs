@@ -183,3 +183,101 @@ async fn test_async_cert() {
         .await
         .unwrap();
 }
+
+#[cfg(test)]
+async fn client_cert_handshake(
+    mode: crate::listeners::tls::ClientCertMode,
+    check_crl: bool,
+    client_cert: Option<&str>,
+) -> Result<SslStream<tokio::io::DuplexStream>> {
+    use crate::listeners::tls::TlsSettings;
+    use tokio::io::AsyncReadExt;
+
+    let keys = format!("{}/tests/keys", env!("CARGO_MANIFEST_DIR"));
+    let mut settings =
+        TlsSettings::intermediate(&format!("{keys}/server.crt"), &format!("{keys}/key.pem"))
+            .unwrap();
+    let crl = format!("{keys}/client_ca.crl");
+    settings
+        .set_client_cert_verification(
+            &format!("{keys}/client_ca.crt"),
+            check_crl.then_some(crl.as_str()),
+            mode,
+        )
+        .unwrap();
+    let acceptor = settings.build();
+
+    let client_cert = client_cert.map(|name| {
+        (
+            format!("{keys}/{name}.crt"),
+            format!("{keys}/{name}.key.pem"),
+        )
+    });
+    let (client, server) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let mut ssl_context = ssl::SslContext::builder(ssl::SslMethod::tls()).unwrap();
+        if let Some((cert, key)) = client_cert {
+            ssl_context
+                .set_certificate_file(cert, ssl::SslFiletype::PEM)
+                .unwrap();
+            ssl_context
+                .set_private_key_file(key, ssl::SslFiletype::PEM)
+                .unwrap();
+        }
+        let mut ssl = ssl::Ssl::new(&ssl_context.build()).unwrap();
+        ssl.set_verify(ssl::SslVerifyMode::NONE);
+        let mut stream = SslStream::new(ssl, client).unwrap();
+        let _ = Pin::new(&mut stream).connect().await;
+        let mut buf = [0; 1];
+        let _ = stream.read(&mut buf).await;
+    });
+
+    acceptor.tls_handshake(server).await
+}
+
+#[tokio::test]
+async fn test_client_cert() {
+    use crate::listeners::tls::ClientCertMode;
+
+    let stream = client_cert_handshake(ClientCertMode::Required, false, Some("client"))
+        .await
+        .unwrap();
+    let digest = stream.ssl_digest().unwrap();
+    assert_eq!(
+        digest.subject(),
+        Some("O=Pingora Test, CN=client.pingora.org")
+    );
+    assert_eq!(
+        digest.subject_alt_names(),
+        ["client.pingora.org", "spiffe://pingora.org/client"]
+    );
+    assert_eq!(digest.peer_cert_chain().len(), 1);
+    assert_eq!(digest.cert_digest.len(), 32);
+
+    assert!(client_cert_handshake(ClientCertMode::Required, false, None)
+        .await
+        .is_err());
+
+    let stream = client_cert_handshake(ClientCertMode::Optional, false, None)
+        .await
+        .unwrap();
+    let digest = stream.ssl_digest().unwrap();
+    assert!(digest.subject().is_none());
+    assert!(digest.peer_cert_chain().is_empty());
+}
+
+#[tokio::test]
+async fn test_client_cert_crl() {
+    use crate::listeners::tls::ClientCertMode;
+
+    assert!(
+        client_cert_handshake(ClientCertMode::Required, true, Some("client"))
+            .await
+            .is_ok()
+    );
+    assert!(
+        client_cert_handshake(ClientCertMode::Optional, true, Some("client_revoked"))
+            .await
+            .is_err()
+    );
+}
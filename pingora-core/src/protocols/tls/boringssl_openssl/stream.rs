@@ -13,10 +13,12 @@
 // limitations under the License.
 
 use crate::protocols::digest::TimingDigest;
-use crate::protocols::tls::{SslDigest, ALPN};
+use crate::protocols::tls::{
+    fingerprint::ClientHelloFingerprint, PeerCertificates, SslDigest, ALPN,
+};
 use crate::protocols::{Peek, Ssl, UniqueID, UniqueIDType};
 use crate::tls::{self, ssl, tokio_ssl::SslStream as InnerSsl};
-use crate::utils::tls::{get_organization, get_serial};
+use crate::utils::tls::{get_organization, get_serial, get_subject, get_subject_alt_names};
 use log::warn;
 use pingora_error::{ErrorType::*, OrErr, Result};
 use std::pin::Pin;
@@ -31,7 +33,7 @@ use pingora_boringssl as ssl_lib;
 #[cfg(feature = "openssl")]
 use pingora_openssl as ssl_lib;
 
-use ssl_lib::{hash::MessageDigest, ssl::SslRef};
+use ssl_lib::{hash::MessageDigest, ssl::SslRef, x509::X509};
 
 /// The TLS connection
 #[derive(Debug)]
@@ -91,6 +93,15 @@ impl<T> SslStream<T> {
     pub fn ssl_digest(&self) -> Option<Arc<SslDigest>> {
         self.digest.clone()
     }
+
+    /// Set the fingerprints of the ClientHello of this connection in its [SslDigest]
+    pub(crate) fn set_client_hello_fingerprint(&mut self, fingerprint: ClientHelloFingerprint) {
+        if let Some(digest) = self.digest.as_mut() {
+            let digest = Arc::make_mut(digest);
+            digest.ja3 = Some(fingerprint.ja3);
+            digest.ja4 = Some(fingerprint.ja4);
+        }
+    }
 }
 
 use std::ops::{Deref, DerefMut};
@@ -203,13 +214,42 @@ impl SslDigest {
             None => (Vec::new(), None, None),
         };
 
-        SslDigest {
-            cipher,
-            version: ssl.version_str(),
-            organization: org,
-            serial_number: sn,
-            cert_digest,
+        let mut digest = SslDigest::new(cipher, ssl.version_str(), org, sn, cert_digest);
+        digest.resumed = ssl.session_reused();
+        if let Some(leaf) = ssl.peer_certificate() {
+            let chain = ssl
+                .peer_cert_chain()
+                .map(|chain| chain.iter().map(|c| c.to_owned()).collect())
+                .unwrap_or_default();
+            digest.set_peer_certificates(PeerX509 { leaf, chain });
         }
+        digest
+    }
+}
+
+// The certificates of the peer, which are only encoded and parsed when asked for
+struct PeerX509 {
+    leaf: X509,
+    // includes the leaf on the client side but not on the server side
+    chain: Vec<X509>,
+}
+
+impl PeerCertificates for PeerX509 {
+    fn chain(&self) -> Vec<Vec<u8>> {
+        let mut chain = Vec::with_capacity(self.chain.len() + 1);
+        chain.extend(self.leaf.to_der().ok());
+        let leaf_der = chain.first().cloned();
+        chain.extend(
+            self.chain
+                .iter()
+                .filter_map(|c| c.to_der().ok())
+                .filter(|der| Some(der) != leaf_der.as_ref()),
+        );
+        chain
+    }
+
+    fn names(&self) -> (Option<String>, Vec<String>) {
+        (get_subject(&self.leaf), get_subject_alt_names(&self.leaf))
     }
 }
 
@@ -0,0 +1,396 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parsing the TLS ClientHello without terminating TLS
+//!
+//! This allows routing TLS connections by SNI and ALPN while the raw bytes are left in the
+//! [`Stream`] to be forwarded untouched.
+
+use crate::protocols::{Peek, Stream};
+use pingora_error::{Error, ErrorType::*, OrErr, Result};
+
+const RECORD_HEADER_LEN: usize = 5;
+const HANDSHAKE_HEADER_LEN: usize = 4;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+pub(super) const EXTENSION_SERVER_NAME: u16 = 0x0000;
+pub(super) const EXTENSION_ALPN: u16 = 0x0010;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0x00;
+// a TLS record carries at most 2^14 bytes of plaintext
+const MAX_RECORD_LEN: usize = 1 << 14;
+// ClientHellos with large post-quantum key shares are still well below this
+const MAX_CLIENT_HELLO_LEN: usize = 64 * 1024;
+
+/// The routing related information of a TLS ClientHello
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// The host name in the server_name extension, if any
+    pub sni: Option<String>,
+    /// The protocols offered in the ALPN extension, in the client's order of preference
+    pub alpn: Vec<Vec<u8>>,
+}
+
+impl ClientHello {
+    /// Whether the client offers the given ALPN protocol, e.g. `b"h2"`.
+    pub fn offers_alpn(&self, proto: &[u8]) -> bool {
+        self.alpn.iter().any(|p| p == proto)
+    }
+}
+
+enum Parsed {
+    NotTls,
+    // the total number of bytes needed to make progress
+    Incomplete(usize),
+    // the body of the ClientHello handshake message
+    Complete(Vec<u8>),
+}
+
+fn malformed(context: &'static str) -> Box<Error> {
+    Error::explain(HandshakeError, context)
+}
+
+// A minimal cursor over the ClientHello body
+pub(super) struct Reader<'a> {
+    pub(super) buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(super) fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(malformed("truncated ClientHello"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub(super) fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(super) fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub(super) fn vec_u8(&mut self) -> Result<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    pub(super) fn vec_u16(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+fn parse_server_name(data: &[u8]) -> Result<Option<String>> {
+    let mut list = Reader {
+        buf: Reader { buf: data }.vec_u16()?,
+    };
+    while !list.buf.is_empty() {
+        let name_type = list.u8()?;
+        let name = list.vec_u16()?;
+        if name_type == SERVER_NAME_TYPE_HOST_NAME {
+            let name = std::str::from_utf8(name).or_err(HandshakeError, "invalid SNI")?;
+            return Ok(Some(name.to_ascii_lowercase()));
+        }
+    }
+    Ok(None)
+}
+
+pub(super) fn parse_alpn(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut list = Reader {
+        buf: Reader { buf: data }.vec_u16()?,
+    };
+    let mut protos = vec![];
+    while !list.buf.is_empty() {
+        protos.push(list.vec_u8()?.to_vec());
+    }
+    Ok(protos)
+}
+
+fn parse_client_hello_body(body: &[u8]) -> Result<ClientHello> {
+    let mut r = Reader { buf: body };
+    r.take(2)?; // legacy_version
+    r.take(32)?; // random
+    r.vec_u8()?; // legacy_session_id
+    r.vec_u16()?; // cipher_suites
+    r.vec_u8()?; // legacy_compression_methods
+
+    let mut hello = ClientHello::default();
+    if r.buf.is_empty() {
+        // no extensions at all
+        return Ok(hello);
+    }
+    let mut extensions = Reader { buf: r.vec_u16()? };
+    while !extensions.buf.is_empty() {
+        let ext_type = extensions.u16()?;
+        let data = extensions.vec_u16()?;
+        match ext_type {
+            EXTENSION_SERVER_NAME => hello.sni = parse_server_name(data)?,
+            EXTENSION_ALPN => hello.alpn = parse_alpn(data)?,
+            _ => {}
+        }
+    }
+    Ok(hello)
+}
+
+fn parse_records(buf: &[u8]) -> Result<Parsed> {
+    let mut offset = 0;
+    let mut handshake = Vec::new();
+    loop {
+        if buf.len() < offset + RECORD_HEADER_LEN {
+            return Ok(Parsed::Incomplete(offset + RECORD_HEADER_LEN));
+        }
+        let header = &buf[offset..offset + RECORD_HEADER_LEN];
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            if offset == 0 {
+                return Ok(Parsed::NotTls);
+            }
+            return Err(malformed("unexpected record type in ClientHello"));
+        }
+        let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if record_len == 0 || record_len > MAX_RECORD_LEN {
+            return Err(malformed("invalid record length in ClientHello"));
+        }
+        let record_end = offset + RECORD_HEADER_LEN + record_len;
+        if buf.len() < record_end {
+            return Ok(Parsed::Incomplete(record_end));
+        }
+        handshake.extend_from_slice(&buf[offset + RECORD_HEADER_LEN..record_end]);
+        offset = record_end;
+
+        if handshake.len() < HANDSHAKE_HEADER_LEN {
+            continue;
+        }
+        if handshake[0] != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return Err(malformed("first handshake message is not ClientHello"));
+        }
+        let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if len > MAX_CLIENT_HELLO_LEN {
+            return Err(malformed("ClientHello too large"));
+        }
+        if handshake.len() >= HANDSHAKE_HEADER_LEN + len {
+            handshake.truncate(HANDSHAKE_HEADER_LEN + len);
+            handshake.drain(..HANDSHAKE_HEADER_LEN);
+            return Ok(Parsed::Complete(handshake));
+        }
+    }
+}
+
+/// Parse the ClientHello from the beginning of a TLS connection.
+///
+/// Return `Ok(None)` if `buf` doesn't start with a TLS handshake record or if more data is
+/// needed to parse the whole ClientHello.
+pub fn parse_client_hello(buf: &[u8]) -> Result<Option<ClientHello>> {
+    match parse_client_hello_records(buf)? {
+        Some(body) => parse_client_hello_body(&body).map(Some),
+        None => Ok(None),
+    }
+}
+
+// Return the body of the ClientHello handshake message in `buf`, if complete
+pub(super) fn parse_client_hello_records(buf: &[u8]) -> Result<Option<Vec<u8>>> {
+    match parse_records(buf)? {
+        Parsed::Complete(body) => Ok(Some(body)),
+        Parsed::NotTls | Parsed::Incomplete(_) => Ok(None),
+    }
+}
+
+/// Peek the ClientHello of the given [`Stream`] without consuming any data from it.
+///
+/// Return `Ok(None)` if the stream doesn't start with a TLS handshake record or if peeking is not
+/// supported by the stream. This function waits until the whole ClientHello is received, so the
+/// caller should apply a timeout.
+pub async fn peek_client_hello(stream: &mut Stream) -> Result<Option<ClientHello>> {
+    peek_client_hello_from(stream.as_mut()).await
+}
+
+// peek_client_hello() for any stream type, e.g. before the TLS handshake of a listener
+pub(crate) async fn peek_client_hello_from<S: Peek + Send + ?Sized>(
+    stream: &mut S,
+) -> Result<Option<ClientHello>> {
+    match peek_client_hello_body(stream).await? {
+        Some(body) => parse_client_hello_body(&body).map(Some),
+        None => Ok(None),
+    }
+}
+
+// Peek the body of the ClientHello handshake message, see peek_client_hello()
+pub(super) async fn peek_client_hello_body<S: Peek + Send + ?Sized>(
+    stream: &mut S,
+) -> Result<Option<Vec<u8>>> {
+    let mut want = RECORD_HEADER_LEN;
+    loop {
+        let mut buf = vec![0; want];
+        let peeked = stream
+            .try_peek(&mut buf)
+            .await
+            .or_err(ReadError, "while peeking ClientHello")?;
+        if !peeked {
+            return Ok(None);
+        }
+        match parse_records(&buf)? {
+            Parsed::NotTls => return Ok(None),
+            Parsed::Complete(body) => return Ok(Some(body)),
+            Parsed::Incomplete(n) => want = n,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn vec_u16(data: &[u8]) -> Vec<u8> {
+        let mut v = (data.len() as u16).to_be_bytes().to_vec();
+        v.extend_from_slice(data);
+        v
+    }
+
+    fn extension(ext_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut v = ext_type.to_be_bytes().to_vec();
+        v.extend(vec_u16(data));
+        v
+    }
+
+    fn client_hello(sni: Option<&str>, alpn: &[&[u8]], max_record_len: usize) -> Vec<u8> {
+        let mut extensions = vec![];
+        if let Some(sni) = sni {
+            let mut entry = vec![SERVER_NAME_TYPE_HOST_NAME];
+            entry.extend(vec_u16(sni.as_bytes()));
+            extensions.extend(extension(EXTENSION_SERVER_NAME, &vec_u16(&entry)));
+        }
+        if !alpn.is_empty() {
+            let mut list = vec![];
+            for p in alpn {
+                list.push(p.len() as u8);
+                list.extend_from_slice(p);
+            }
+            extensions.extend(extension(EXTENSION_ALPN, &vec_u16(&list)));
+        }
+        // an extension to be skipped: supported_versions
+        extensions.extend(extension(0x002b, &[0x02, 0x03, 0x04]));
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([7; 32]); // random
+        body.extend([32]);
+        body.extend([1; 32]); // session id
+        body.extend(vec_u16(&[0x13, 0x01, 0x13, 0x02])); // cipher suites
+        body.extend([1, 0]); // compression
+        body.extend(vec_u16(&extensions));
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut records = vec![];
+        for chunk in handshake.chunks(max_record_len) {
+            records.extend([CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+            records.extend(vec_u16(chunk));
+        }
+        records
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        let buf = client_hello(Some("Example.COM"), &[b"h2", b"http/1.1"], MAX_RECORD_LEN);
+        let hello = parse_client_hello(&buf).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert!(hello.offers_alpn(b"h2"));
+        assert!(!hello.offers_alpn(b"h3"));
+
+        let hello = parse_client_hello(&client_hello(None, &[], MAX_RECORD_LEN))
+            .unwrap()
+            .unwrap();
+        assert_eq!(hello, ClientHello::default());
+    }
+
+    #[test]
+    fn test_parse_client_hello_fragmented() {
+        let buf = client_hello(Some("example.com"), &[b"h2"], 16);
+        let hello = parse_client_hello(&buf).unwrap().unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, vec![b"h2".to_vec()]);
+    }
+
+    #[test]
+    fn test_parse_client_hello_incomplete() {
+        let buf = client_hello(Some("example.com"), &[b"h2"], 16);
+        for i in 0..buf.len() {
+            assert!(parse_client_hello(&buf[..i]).unwrap().is_none());
+        }
+        assert!(matches!(
+            parse_records(&buf[..3]).unwrap(),
+            Parsed::Incomplete(RECORD_HEADER_LEN)
+        ));
+    }
+
+    #[test]
+    fn test_parse_client_hello_invalid() {
+        assert!(matches!(
+            parse_records(b"GET / HTTP/1.1\r\n").unwrap(),
+            Parsed::NotTls
+        ));
+        // not a ClientHello
+        assert!(parse_client_hello(&[0x16, 0x03, 0x01, 0x00, 0x04, 0x02, 0, 0, 0]).is_err());
+        // bad record length
+        assert!(parse_client_hello(&[0x16, 0x03, 0x01, 0xff, 0xff]).is_err());
+        // invalid SNI
+        let mut buf = client_hello(Some("example.com"), &[], MAX_RECORD_LEN);
+        let len = buf.len();
+        buf[len - 9] = 0xff;
+        assert!(parse_client_hello(&buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_peek_client_hello() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hello = client_hello(Some("example.com"), &[b"h2"], 64);
+        let mut sent = hello.clone();
+        sent.extend_from_slice(b"more data");
+
+        let to_send = sent.clone();
+        tokio::spawn(async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            // send in pieces so that the records arrive separately
+            for chunk in to_send.chunks(50) {
+                client.write_all(chunk).await.unwrap();
+                client.flush().await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        });
+
+        let (io, _) = listener.accept().await.unwrap();
+        let mut stream: Stream = Box::new(crate::protocols::l4::stream::Stream::from(io));
+        let parsed = peek_client_hello(&mut stream).await.unwrap().unwrap();
+        assert_eq!(parsed.sni.as_deref(), Some("example.com"));
+
+        // nothing is consumed
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn test_peek_unsupported() {
+        let mut stream: Stream = Box::new(tokio_test::io::Builder::new().build());
+        // the mock doesn't support peeking
+        assert!(peek_client_hello(&mut stream).await.unwrap().is_none());
+    }
+}
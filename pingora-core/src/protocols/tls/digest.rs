@@ -14,7 +14,13 @@
 
 //! TLS information from the TLS connection
 
+use once_cell::sync::OnceCell;
+use std::sync::Arc;
+
 /// The TLS connection information
+///
+/// Use [SslDigest::new()] to create one: more fields may be added in the future.
+#[non_exhaustive]
 #[derive(Clone, Debug)]
 pub struct SslDigest {
     /// The cipher used
@@ -26,5 +32,109 @@ pub struct SslDigest {
     /// The serial number of the peer's certificate
     pub serial_number: Option<String>,
     /// The digest of the peer's certificate
+    ///
+    /// This is the SHA-256 fingerprint of the DER encoded certificate.
     pub cert_digest: Vec<u8>,
+    /// Whether the handshake resumed a previous TLS session instead of a full handshake
+    pub resumed: bool,
+    /// The JA3 fingerprint of the ClientHello of a downstream connection
+    ///
+    /// Only set when fingerprinting is enabled on the listener, see
+    /// [ClientHelloFingerprint](super::fingerprint::ClientHelloFingerprint).
+    pub ja3: Option<String>,
+    /// The JA4 fingerprint of the ClientHello of a downstream connection
+    ///
+    /// Only set when fingerprinting is enabled on the listener.
+    pub ja4: Option<String>,
+    peer_certificates: Option<Arc<PeerCertDetails>>,
+}
+
+impl SslDigest {
+    /// Create a new [SslDigest] without the details of the peer's certificate chain
+    ///
+    /// The other public fields, e.g. [Self::resumed], are unset and can be set afterwards.
+    pub fn new(
+        cipher: &'static str,
+        version: &'static str,
+        organization: Option<String>,
+        serial_number: Option<String>,
+        cert_digest: Vec<u8>,
+    ) -> Self {
+        SslDigest {
+            cipher,
+            version,
+            organization,
+            serial_number,
+            cert_digest,
+            resumed: false,
+            ja3: None,
+            ja4: None,
+            peer_certificates: None,
+        }
+    }
+
+    // Attach the certificates of the peer, whose details are extracted when first asked for
+    #[cfg(feature = "any_tls")]
+    pub(crate) fn set_peer_certificates(&mut self, certs: impl PeerCertificates + 'static) {
+        self.peer_certificates = Some(Arc::new(PeerCertDetails {
+            certs: Box::new(certs),
+            chain: OnceCell::new(),
+            names: OnceCell::new(),
+        }));
+    }
+
+    /// The subject of the peer's certificate, e.g. `O=Org, CN=name`
+    pub fn subject(&self) -> Option<&str> {
+        self.peer_certificates.as_ref()?.names().0.as_deref()
+    }
+
+    /// The DNS names, IP addresses, emails and URIs in the subject alternative names of the
+    /// peer's certificate
+    pub fn subject_alt_names(&self) -> &[String] {
+        self.peer_certificates
+            .as_ref()
+            .map_or(&[], |certs| &certs.names().1)
+    }
+
+    /// The DER encoded certificate chain of the peer, leaf first
+    ///
+    /// For downstream connections with client certificate verification enabled, this is the
+    /// chain that was verified during the handshake.
+    pub fn peer_cert_chain(&self) -> &[Vec<u8>] {
+        self.peer_certificates
+            .as_ref()
+            .map_or(&[], |certs| certs.chain())
+    }
+}
+
+/// The certificates presented by the peer of a TLS connection
+pub(crate) trait PeerCertificates: Send + Sync {
+    /// The DER encoded certificate chain, leaf first
+    fn chain(&self) -> Vec<Vec<u8>>;
+    /// The subject and the subject alternative names of the leaf certificate
+    fn names(&self) -> (Option<String>, Vec<String>);
+}
+
+// The details of the peer certificates, which are only extracted once asked for as most
+// connections never look at them
+struct PeerCertDetails {
+    certs: Box<dyn PeerCertificates>,
+    chain: OnceCell<Vec<Vec<u8>>>,
+    names: OnceCell<(Option<String>, Vec<String>)>,
+}
+
+impl PeerCertDetails {
+    fn chain(&self) -> &[Vec<u8>] {
+        self.chain.get_or_init(|| self.certs.chain())
+    }
+
+    fn names(&self) -> &(Option<String>, Vec<String>) {
+        self.names.get_or_init(|| self.certs.names())
+    }
+}
+
+impl std::fmt::Debug for PeerCertDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerCertDetails").finish_non_exhaustive()
+    }
 }
@@ -0,0 +1,378 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! JA3 and JA4 fingerprints of the TLS ClientHello
+//!
+//! The fingerprints identify the TLS implementation of a client by the parameters it offers in
+//! its ClientHello, see <https://github.com/salesforce/ja3> and
+//! <https://github.com/FoxIO-LLC/ja4>.
+
+use super::client_hello::{
+    parse_alpn, parse_client_hello_records, peek_client_hello_body, Reader, EXTENSION_ALPN,
+    EXTENSION_SERVER_NAME,
+};
+use crate::protocols::Peek;
+use md5::Md5;
+use pingora_error::Result;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+const EXTENSION_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXTENSION_EC_POINT_FORMATS: u16 = 0x000b;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// The JA3 and JA4 fingerprints of a TLS ClientHello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientHelloFingerprint {
+    /// The JA3 fingerprint: the MD5 hash of the JA3 string in hex
+    pub ja3: String,
+    /// The JA4 fingerprint, e.g. `t13d1516h2_8daaf6152771_e5627efa2ab1`
+    pub ja4: String,
+}
+
+impl ClientHelloFingerprint {
+    /// Compute the fingerprints of the ClientHello at the beginning of a TLS connection.
+    ///
+    /// Return `Ok(None)` if `buf` doesn't start with a TLS handshake record or if more data is
+    /// needed to parse the whole ClientHello.
+    pub fn parse(buf: &[u8]) -> Result<Option<Self>> {
+        match parse_client_hello_records(buf)? {
+            Some(body) => Self::from_client_hello_body(&body).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn from_client_hello_body(body: &[u8]) -> Result<Self> {
+        let fields = Fields::parse(body)?;
+        Ok(ClientHelloFingerprint {
+            ja3: fields.ja3(),
+            ja4: fields.ja4(),
+        })
+    }
+}
+
+/// Peek the ClientHello of the given stream and compute its fingerprints without consuming any
+/// data from the stream.
+///
+/// Return `Ok(None)` if the stream doesn't start with a TLS handshake record or if peeking is not
+/// supported by the stream. This function waits until the whole ClientHello is received.
+pub async fn peek_client_hello_fingerprint<S: Peek + Send + ?Sized>(
+    stream: &mut S,
+) -> Result<Option<ClientHelloFingerprint>> {
+    match peek_client_hello_body(stream).await? {
+        Some(body) => ClientHelloFingerprint::from_client_hello_body(&body).map(Some),
+        None => Ok(None),
+    }
+}
+
+// GREASE values (RFC 8701) are random, so both fingerprints ignore them
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn u16_list(data: &[u8]) -> Result<Vec<u16>> {
+    let mut r = Reader { buf: data };
+    let mut values = vec![];
+    while !r.buf.is_empty() {
+        let value = r.u16()?;
+        if !is_grease(value) {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn join<T: ToString>(values: impl Iterator<Item = T>, sep: &str) -> String {
+    values.map(|v| v.to_string()).collect::<Vec<_>>().join(sep)
+}
+
+// the truncated SHA-256 hash of a JA4 section
+fn ja4_hash(section: &str) -> String {
+    if section.is_empty() {
+        return "000000000000".to_string();
+    }
+    let mut hash = to_hex(&Sha256::digest(section.as_bytes()));
+    hash.truncate(12);
+    hash
+}
+
+// The fingerprinted fields of a ClientHello, without GREASE values
+#[derive(Default)]
+struct Fields<'a> {
+    version: u16,
+    ciphers: Vec<u16>,
+    // in the order they are sent
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: &'a [u8],
+    signature_algorithms: Vec<u16>,
+    supported_versions: Vec<u16>,
+    first_alpn: Option<Vec<u8>>,
+}
+
+impl<'a> Fields<'a> {
+    fn parse(body: &'a [u8]) -> Result<Self> {
+        let mut r = Reader { buf: body };
+        let version = r.u16()?; // legacy_version
+        r.take(32)?; // random
+        r.vec_u8()?; // legacy_session_id
+        let ciphers = u16_list(r.vec_u16()?)?;
+        r.vec_u8()?; // legacy_compression_methods
+
+        let mut fields = Fields {
+            version,
+            ciphers,
+            ..Default::default()
+        };
+        if r.buf.is_empty() {
+            // no extensions at all
+            return Ok(fields);
+        }
+        let mut extensions = Reader { buf: r.vec_u16()? };
+        while !extensions.buf.is_empty() {
+            let ext_type = extensions.u16()?;
+            let data = extensions.vec_u16()?;
+            if is_grease(ext_type) {
+                continue;
+            }
+            fields.extensions.push(ext_type);
+            let mut ext = Reader { buf: data };
+            match ext_type {
+                EXTENSION_SUPPORTED_GROUPS => fields.groups = u16_list(ext.vec_u16()?)?,
+                EXTENSION_EC_POINT_FORMATS => fields.point_formats = ext.vec_u8()?,
+                EXTENSION_SIGNATURE_ALGORITHMS => {
+                    fields.signature_algorithms = u16_list(ext.vec_u16()?)?
+                }
+                EXTENSION_SUPPORTED_VERSIONS => {
+                    fields.supported_versions = u16_list(ext.vec_u8()?)?
+                }
+                EXTENSION_ALPN => fields.first_alpn = parse_alpn(data)?.into_iter().next(),
+                _ => {}
+            }
+        }
+        Ok(fields)
+    }
+
+    fn ja3(&self) -> String {
+        let ja3 = format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(self.ciphers.iter(), "-"),
+            join(self.extensions.iter(), "-"),
+            join(self.groups.iter(), "-"),
+            join(self.point_formats.iter(), "-"),
+        );
+        to_hex(&Md5::digest(ja3.as_bytes()))
+    }
+
+    fn ja4(&self) -> String {
+        let version = self
+            .supported_versions
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            0x0002 => "s2",
+            0xfeff => "d1",
+            0xfefd => "d2",
+            0xfefc => "d3",
+            _ => "00",
+        };
+        let sni = if self.extensions.contains(&EXTENSION_SERVER_NAME) {
+            'd'
+        } else {
+            'i'
+        };
+        let alpn = match self.first_alpn.as_deref() {
+            Some(proto) if !proto.is_empty() => {
+                let (first, last) = (proto[0], proto[proto.len() - 1]);
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                } else {
+                    // the first and last characters of the hex representation instead
+                    let hex = format!("{first:02x}{last:02x}");
+                    format!("{}{}", &hex[..1], &hex[3..])
+                }
+            }
+            _ => "00".to_string(),
+        };
+
+        let mut ciphers = self.ciphers.clone();
+        ciphers.sort_unstable();
+        let ciphers = join(ciphers.iter().map(|c| format!("{c:04x}")), ",");
+
+        let mut extensions: Vec<_> = self
+            .extensions
+            .iter()
+            .filter(|e| **e != EXTENSION_SERVER_NAME && **e != EXTENSION_ALPN)
+            .collect();
+        extensions.sort_unstable();
+        let mut extensions = join(extensions.iter().map(|e| format!("{e:04x}")), ",");
+        if !extensions.is_empty() && !self.signature_algorithms.is_empty() {
+            extensions.push('_');
+            extensions.push_str(&join(
+                self.signature_algorithms.iter().map(|s| format!("{s:04x}")),
+                ",",
+            ));
+        }
+
+        format!(
+            "t{version}{sni}{:02}{:02}{alpn}_{}_{}",
+            self.ciphers.len().min(99),
+            self.extensions.len().min(99),
+            ja4_hash(&ciphers),
+            ja4_hash(&extensions)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec_u16(data: &[u8]) -> Vec<u8> {
+        let mut v = (data.len() as u16).to_be_bytes().to_vec();
+        v.extend_from_slice(data);
+        v
+    }
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    fn client_hello(version: u16, ciphers: &[u16], extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = version.to_be_bytes().to_vec();
+        body.extend([7; 32]); // random
+        body.extend([0]); // session id
+        body.extend(vec_u16(&u16s(ciphers)));
+        body.extend([1, 0]); // compression
+        let mut ext_bytes = vec![];
+        for (ext_type, data) in extensions {
+            ext_bytes.extend(ext_type.to_be_bytes());
+            ext_bytes.extend(vec_u16(data));
+        }
+        body.extend(vec_u16(&ext_bytes));
+
+        let mut handshake = vec![0x01];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend(vec_u16(&handshake));
+        record
+    }
+
+    fn server_name(name: &str) -> Vec<u8> {
+        let mut entry = vec![0];
+        entry.extend(vec_u16(name.as_bytes()));
+        vec_u16(&entry)
+    }
+
+    #[test]
+    fn test_ja3() {
+        // the example from the JA3 README:
+        // 769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0
+        let hello = client_hello(
+            0x0301,
+            &[
+                0x0a0a, 47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4,
+            ],
+            &[
+                (0x0000, server_name("example.com")),
+                (0x1a1a, vec![]),
+                (0x000a, vec_u16(&u16s(&[0x2a2a, 23, 24, 25]))),
+                (0x000b, vec![1, 0]),
+            ],
+        );
+        let fingerprint = ClientHelloFingerprint::parse(&hello).unwrap().unwrap();
+        assert_eq!(fingerprint.ja3, "ada70206e40642a3e4461f35503241d5");
+        assert_eq!(fingerprint.ja4, "t10d120300_d94e65cdb899_33a13ba74d1c");
+    }
+
+    #[test]
+    fn test_ja4() {
+        // the example from the JA4 specification
+        let ciphers = [
+            0x0a0a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+            0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+        ];
+        let mut alpn = vec![2];
+        alpn.extend(b"h2");
+        alpn.push(8);
+        alpn.extend(b"http/1.1");
+        let extensions = [
+            (0x0a0a, vec![]),
+            (0x0000, server_name("example.com")),
+            (0x0017, vec![]),
+            (0xff01, vec![0]),
+            (0x000a, vec_u16(&u16s(&[0x0a0a, 0x001d, 0x0017, 0x0018]))),
+            (0x000b, vec![1, 0]),
+            (0x0023, vec![]),
+            (0x0010, vec_u16(&alpn)),
+            (0x0005, vec![1, 0, 0, 0, 0]),
+            (
+                0x000d,
+                vec_u16(&u16s(&[
+                    0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+                ])),
+            ),
+            (0x0012, vec![]),
+            (0x0033, vec![]),
+            (0x002d, vec![1, 1]),
+            (0x002b, {
+                let mut v = vec![6];
+                v.extend(u16s(&[0x0a0a, 0x0304, 0x0303]));
+                v
+            }),
+            (0x001b, vec![]),
+            (0x4469, vec![]),
+            (0x0015, vec![]),
+        ];
+        let hello = client_hello(0x0303, &ciphers, &extensions);
+        let fingerprint = ClientHelloFingerprint::parse(&hello).unwrap().unwrap();
+        assert_eq!(fingerprint.ja4, "t13d1516h2_8daaf6152771_e5627efa2ab1");
+
+        // no SNI, ALPN or signature algorithms
+        let hello = client_hello(0x0303, &[0x1301], &[(0x002d, vec![1, 1])]);
+        let fingerprint = ClientHelloFingerprint::parse(&hello).unwrap().unwrap();
+        assert!(fingerprint.ja4.starts_with("t12i010100_"));
+
+        // non alphanumeric ALPN
+        let hello = client_hello(0x0303, &[], &[(0x0010, vec_u16(&[2, 0xab, 0x01]))]);
+        let fingerprint = ClientHelloFingerprint::parse(&hello).unwrap().unwrap();
+        assert_eq!(fingerprint.ja4, "t12i0001a1_000000000000_000000000000");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(ClientHelloFingerprint::parse(b"GET / HTTP/1.1\r\n")
+            .unwrap()
+            .is_none());
+        let hello = client_hello(0x0303, &[0x1301], &[(0x000a, vec![0, 5, 0])]);
+        assert!(ClientHelloFingerprint::parse(&hello).is_err());
+    }
+}
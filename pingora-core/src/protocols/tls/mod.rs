@@ -14,7 +14,9 @@
 
 //! The TLS layer implementations
 
+pub mod client_hello;
 pub mod digest;
+pub mod fingerprint;
 pub use digest::*;
 
 #[cfg(feature = "openssl_derived")]
@@ -44,6 +46,9 @@ pub enum ALPN {
     H2,
     /// Prefer HTTP/2 over HTTP/1.1
     H2H1,
+    /// HTTP/3 over QUIC only
+    #[cfg(feature = "quic")]
+    H3,
 }
 
 impl std::fmt::Display for ALPN {
@@ -52,13 +57,21 @@ impl std::fmt::Display for ALPN {
             ALPN::H1 => write!(f, "H1"),
             ALPN::H2 => write!(f, "H2"),
             ALPN::H2H1 => write!(f, "H2H1"),
+            #[cfg(feature = "quic")]
+            ALPN::H3 => write!(f, "H3"),
         }
     }
 }
 
 impl ALPN {
     /// Create a new ALPN according to the `max` and `min` version constraints
+    ///
+    /// HTTP/3 is only selected when `min` is 3, as it cannot be negotiated with the other versions.
     pub fn new(max: u8, min: u8) -> Self {
+        #[cfg(feature = "quic")]
+        if min == 3 {
+            return ALPN::H3;
+        }
         if max == 1 {
             ALPN::H1
         } else if min == 2 {
@@ -72,6 +85,8 @@ impl ALPN {
     pub fn get_max_http_version(&self) -> u8 {
         match self {
             ALPN::H1 => 1,
+            #[cfg(feature = "quic")]
+            ALPN::H3 => 3,
             _ => 2,
         }
     }
@@ -80,6 +95,8 @@ impl ALPN {
     pub fn get_min_http_version(&self) -> u8 {
         match self {
             ALPN::H2 => 2,
+            #[cfg(feature = "quic")]
+            ALPN::H3 => 3,
             _ => 1,
         }
     }
@@ -110,6 +127,8 @@ impl ALPN {
             ALPN::H1 => vec![b"http/1.1".to_vec()],
             ALPN::H2 => vec![b"h2".to_vec()],
             ALPN::H2H1 => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            #[cfg(feature = "quic")]
+            ALPN::H3 => vec![b"h3".to_vec()],
         }
     }
 }
@@ -19,7 +19,61 @@ mod stream;
 pub use stream::*;
 
 use crate::utils::tls::WrappedX509;
+use pingora_rustls::{CertifiedKey, ClientHello, SignatureScheme};
+use std::sync::Arc;
 
 pub type CaType = [WrappedX509];
 
-pub struct TlsRef;
+/// The state of a TLS handshake which is passed to [TlsAccept](crate::listeners::TlsAccept)
+/// callbacks
+///
+/// With rustls the callback runs once the ClientHello is received, so the information the client
+/// offers is available here and the certificate to serve can be set via
+/// [TlsRef::set_certified_key()].
+pub struct TlsRef {
+    server_name: Option<String>,
+    alpn: Vec<Vec<u8>>,
+    signature_schemes: Vec<SignatureScheme>,
+    certified_key: Option<Arc<CertifiedKey>>,
+}
+
+impl TlsRef {
+    pub(crate) fn from_client_hello(hello: &ClientHello) -> Self {
+        TlsRef {
+            server_name: hello.server_name().map(|s| s.to_string()),
+            alpn: hello
+                .alpn()
+                .map(|protos| protos.map(|p| p.to_vec()).collect())
+                .unwrap_or_default(),
+            signature_schemes: hello.signature_schemes().to_vec(),
+            certified_key: None,
+        }
+    }
+
+    /// The SNI sent by the client, if any
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// The ALPN protocols offered by the client, in the client's order of preference
+    pub fn alpn(&self) -> &[Vec<u8>] {
+        &self.alpn
+    }
+
+    /// The signature schemes the client supports, which can be used to choose between e.g. an
+    /// ECDSA and an RSA certificate
+    pub fn signature_schemes(&self) -> &[SignatureScheme] {
+        &self.signature_schemes
+    }
+
+    /// Set the certificate and key to serve for this connection
+    ///
+    /// The handshake fails if no certificate is set.
+    pub fn set_certified_key(&mut self, key: Arc<CertifiedKey>) {
+        self.certified_key = Some(key);
+    }
+
+    pub(crate) fn take_certified_key(&mut self) -> Option<Arc<CertifiedKey>> {
+        self.certified_key.take()
+    }
+}
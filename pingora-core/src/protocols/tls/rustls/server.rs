@@ -15,38 +15,17 @@
 //! Rustls TLS server specific implementation
 
 use crate::listeners::TlsAcceptCallbacks;
-use crate::protocols::tls::rustls::TlsStream;
+use crate::protocols::tls::rustls::{TlsRef, TlsStream};
 use crate::protocols::IO;
 use crate::{listeners::tls::Acceptor, protocols::Shutdown};
 use async_trait::async_trait;
 use log::warn;
 use pingora_error::{ErrorType::*, OrErr, Result};
-use std::pin::Pin;
+use pingora_rustls::LazyConfigAcceptor;
+#[cfg(test)]
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-impl<S: AsyncRead + AsyncWrite + Send + Unpin> TlsStream<S> {
-    async fn start_accept(mut self: Pin<&mut Self>) -> Result<bool> {
-        // TODO: suspend cert callback
-        let res = self.accept().await;
-
-        match res {
-            Ok(()) => Ok(true),
-            Err(e) => {
-                if e.etype == TLSWantX509Lookup {
-                    Ok(false)
-                } else {
-                    Err(e)
-                }
-            }
-        }
-    }
-
-    async fn resume_accept(mut self: Pin<&mut Self>) -> Result<()> {
-        // TODO: unblock cert callback
-        self.accept().await
-    }
-}
-
 async fn prepare_tls_stream<S: IO>(acceptor: &Acceptor, io: S) -> Result<TlsStream<S>> {
     TlsStream::from_acceptor(acceptor, io)
         .await
@@ -64,24 +43,29 @@ pub async fn handshake<S: IO>(acceptor: &Acceptor, io: S) -> Result<TlsStream<S>
 }
 
 /// Perform TLS handshake for the given connection with the given configuration and callbacks
-/// callbacks are currently not supported within pingora Rustls and are ignored
+///
+/// The callbacks are invoked once the ClientHello is received to decide which certificate to serve.
 pub async fn handshake_with_callback<S: IO>(
     acceptor: &Acceptor,
     io: S,
-    _callbacks: &TlsAcceptCallbacks,
+    callbacks: &TlsAcceptCallbacks,
 ) -> Result<TlsStream<S>> {
-    let mut tls_stream = prepare_tls_stream(acceptor, io).await?;
-    let done = Pin::new(&mut tls_stream).start_accept().await?;
-    if !done {
-        // TODO: verify if/how callback in handshake can be done using Rustls
-        warn!("Callacks are not supported with feature \"rustls\".");
-
-        Pin::new(&mut tls_stream)
-            .resume_accept()
-            .await
-            .explain_err(TLSHandshakeFailure, |e| format!("TLS accept() failed: {e}"))?;
-    }
+    let start = LazyConfigAcceptor::new(Default::default(), io)
+        .await
+        .explain_err(TLSHandshakeFailure, |e| {
+            format!("TLS ClientHello error: {e}")
+        })?;
+    let mut tls_ref = TlsRef::from_client_hello(&start.client_hello());
+    callbacks.certificate_callback(&mut tls_ref).await;
+    let accept = acceptor.accept_with_cert(tls_ref.take_certified_key(), |config| {
+        start.into_stream(config)
+    });
 
+    let mut tls_stream = TlsStream::from_accept(accept);
+    tls_stream
+        .accept()
+        .await
+        .explain_err(TLSHandshakeFailure, |e| format!("TLS accept() failed: {e}"))?;
     Ok(tls_stream)
 }
 
@@ -100,8 +84,149 @@ where
     }
 }
 
-#[ignore]
 #[tokio::test]
 async fn test_async_cert() {
-    todo!("callback support and test for Rustls")
+    use crate::listeners::{tls::TlsSettings, TlsAccept};
+    use pingora_rustls::{load_certified_key, ClientConfig, RootCertStore, TlsConnector};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct Callback(Arc<AtomicBool>);
+    #[async_trait]
+    impl TlsAccept for Callback {
+        async fn certificate_callback(&self, ssl: &mut TlsRef) -> () {
+            assert_eq!(ssl.server_name().unwrap(), "openrusty.org");
+            assert_eq!(ssl.alpn(), [b"h2".to_vec()]);
+            assert!(!ssl.signature_schemes().is_empty());
+            let cert = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+            let key = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+            ssl.set_certified_key(Arc::new(load_certified_key(&cert, &key).unwrap()));
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let called = Arc::new(AtomicBool::new(false));
+    let mut settings = TlsSettings::with_callbacks(Box::new(Callback(called.clone()))).unwrap();
+    settings.enable_h2();
+    let acceptor = settings.build();
+
+    let (client, server) = tokio::io::duplex(4096);
+
+    let client = tokio::spawn(async move {
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = TlsConnector::from(Arc::new(config));
+        let server_name = "openrusty.org".try_into().unwrap();
+        connector.connect(server_name, client).await
+    });
+
+    // the client does not trust the test certificate so the handshake fails after the
+    // certificate selected by the callback was presented
+    assert!(acceptor.tls_handshake(server).await.is_err());
+    let err = client.await.unwrap().unwrap_err();
+    assert!(
+        err.to_string().contains("invalid peer certificate"),
+        "{err}"
+    );
+    assert!(called.load(Ordering::SeqCst));
+}
+
+#[cfg(test)]
+async fn client_cert_handshake(
+    mode: crate::listeners::tls::ClientCertMode,
+    check_crl: bool,
+    client_cert: Option<&str>,
+) -> Result<TlsStream<tokio::io::DuplexStream>> {
+    use crate::listeners::tls::TlsSettings;
+    use pingora_rustls::{load_certs_and_key_files, ClientConfig, RootCertStore, TlsConnector};
+    use tokio::io::AsyncReadExt;
+
+    let keys = format!("{}/tests/keys", env!("CARGO_MANIFEST_DIR"));
+    let server_cert = format!("{keys}/server_rustls.crt");
+    let mut settings = TlsSettings::intermediate(&server_cert, &format!("{keys}/key.pem")).unwrap();
+    let crl = format!("{keys}/client_ca.crl");
+    settings
+        .set_client_cert_verification(
+            &format!("{keys}/client_ca.crt"),
+            check_crl.then_some(crl.as_str()),
+            mode,
+        )
+        .unwrap();
+    let acceptor = settings.build();
+
+    let mut roots = RootCertStore::empty();
+    pingora_rustls::load_ca_file_into_store(&server_cert, &mut roots).unwrap();
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match client_cert {
+        Some(name) => {
+            let (certs, key) = load_certs_and_key_files(
+                &format!("{keys}/{name}.crt"),
+                &format!("{keys}/{name}.key.pem"),
+            )
+            .unwrap()
+            .unwrap();
+            builder.with_client_auth_cert(certs, key).unwrap()
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let (client, server) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let connector = TlsConnector::from(Arc::new(config));
+        let server_name = "openrusty.org".try_into().unwrap();
+        if let Ok(mut stream) = connector.connect(server_name, client).await {
+            let mut buf = [0; 1];
+            let _ = stream.read(&mut buf).await;
+        }
+    });
+
+    acceptor.tls_handshake(server).await
+}
+
+#[tokio::test]
+async fn test_client_cert() {
+    use crate::listeners::tls::ClientCertMode;
+
+    let stream = client_cert_handshake(ClientCertMode::Required, false, Some("client"))
+        .await
+        .unwrap();
+    let digest = stream.ssl_digest().unwrap();
+    assert_eq!(
+        digest.subject(),
+        Some("O=Pingora Test, CN=client.pingora.org")
+    );
+    assert_eq!(
+        digest.subject_alt_names(),
+        ["client.pingora.org", "spiffe://pingora.org/client"]
+    );
+    assert_eq!(digest.peer_cert_chain().len(), 1);
+    assert_eq!(digest.cert_digest.len(), 32);
+
+    assert!(client_cert_handshake(ClientCertMode::Required, false, None)
+        .await
+        .is_err());
+
+    let stream = client_cert_handshake(ClientCertMode::Optional, false, None)
+        .await
+        .unwrap();
+    let digest = stream.ssl_digest().unwrap();
+    assert!(digest.subject().is_none());
+    assert!(digest.peer_cert_chain().is_empty());
+}
+
+#[tokio::test]
+async fn test_client_cert_crl() {
+    use crate::listeners::tls::ClientCertMode;
+
+    assert!(
+        client_cert_handshake(ClientCertMode::Required, true, Some("client"))
+            .await
+            .is_ok()
+    );
+    assert!(
+        client_cert_handshake(ClientCertMode::Optional, true, Some("client_revoked"))
+            .await
+            .is_err()
+    );
 }
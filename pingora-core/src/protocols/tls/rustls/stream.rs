@@ -21,18 +21,22 @@ use std::time::{Duration, SystemTime};
 
 use crate::listeners::tls::Acceptor;
 use crate::protocols::raw_connect::ProxyDigest;
-use crate::protocols::{tls::SslDigest, Peek, TimingDigest, UniqueIDType};
+use crate::protocols::tls::{fingerprint::ClientHelloFingerprint, PeerCertificates, SslDigest};
 use crate::protocols::{
     GetProxyDigest, GetSocketDigest, GetTimingDigest, SocketDigest, Ssl, UniqueID, ALPN,
 };
-use crate::utils::tls::get_organization_serial_bytes;
+use crate::protocols::{Peek, TimingDigest, UniqueIDType};
+use crate::utils::tls::{
+    get_organization_serial_bytes, get_subject_alt_names_x509, get_subject_x509,
+};
 use pingora_error::ErrorType::{AcceptError, ConnectError, InternalError, TLSHandshakeFailure};
 use pingora_error::{OkOrErr, OrErr, Result};
 use pingora_rustls::TlsStream as RusTlsStream;
-use pingora_rustls::{hash_certificate, NoDebug};
+use pingora_rustls::{hash_certificate, CertificateDer, HandshakeKind, NoDebug};
 use pingora_rustls::{Accept, Connect, ServerName, TlsConnector};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use x509_parser::nom::AsBytes;
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 #[derive(Debug)]
 pub struct InnerStream<T> {
@@ -88,6 +92,21 @@ where
             timing: Default::default(),
         })
     }
+
+    /// Create a new TLS connection from a handshake already started by a lazy acceptor
+    ///
+    /// The caller needs to perform [`Self::accept()`] to finish the handshake.
+    pub(crate) fn from_accept(accept: Accept<T>) -> Self {
+        TlsStream {
+            tls: InnerStream {
+                accept: Some(accept).into(),
+                connect: None.into(),
+                stream: None,
+            },
+            digest: None,
+            timing: Default::default(),
+        }
+    }
 }
 
 impl<S> GetSocketDigest for TlsStream<S>
@@ -133,6 +152,15 @@ impl<T> TlsStream<T> {
     pub fn ssl_digest(&self) -> Option<Arc<SslDigest>> {
         self.digest.clone()
     }
+
+    /// Set the fingerprints of the ClientHello of this connection in its [SslDigest]
+    pub(crate) fn set_client_hello_fingerprint(&mut self, fingerprint: ClientHelloFingerprint) {
+        if let Some(digest) = self.digest.as_mut() {
+            let digest = Arc::make_mut(digest);
+            digest.ja3 = Some(fingerprint.ja3);
+            digest.ja4 = Some(fingerprint.ja4);
+        }
+    }
 }
 
 impl<T> Deref for TlsStream<T> {
@@ -384,13 +412,29 @@ impl SslDigest {
             .map(|(organization, serial)| (organization, Some(serial)))
             .unwrap_or_default();
 
-        SslDigest {
-            cipher,
-            version,
-            organization,
-            serial_number,
-            cert_digest,
+        let mut digest = SslDigest::new(cipher, version, organization, serial_number, cert_digest);
+        digest.resumed = session.handshake_kind() == Some(HandshakeKind::Resumed);
+        if let Some(certs) = peer_certificates.filter(|certs| !certs.is_empty()) {
+            digest.set_peer_certificates(PeerCertificatesDer(certs.to_vec()));
         }
+        digest
+    }
+}
+
+/// The DER encoded certificates of the peer, which are only parsed when asked for
+pub(crate) struct PeerCertificatesDer(pub(crate) Vec<CertificateDer<'static>>);
+
+impl PeerCertificates for PeerCertificatesDer {
+    fn chain(&self) -> Vec<Vec<u8>> {
+        self.0.iter().map(|cert| cert.to_vec()).collect()
+    }
+
+    fn names(&self) -> (Option<String>, Vec<String>) {
+        self.0
+            .first()
+            .and_then(|cert| X509Certificate::from_der(cert.as_bytes()).ok())
+            .map(|(_, cert)| (get_subject_x509(&cert), get_subject_alt_names_x509(&cert)))
+            .unwrap_or_default()
     }
 }
 
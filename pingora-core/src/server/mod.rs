@@ -38,7 +38,7 @@ use tokio::time::{sleep, Duration};
 use crate::services::Service;
 use configuration::{Opt, ServerConf};
 #[cfg(unix)]
-pub use transfer_fd::Fds;
+pub use transfer_fd::{Fds, SystemdListenEnv};
 
 use pingora_error::{Error, ErrorType, Result};
 
@@ -141,6 +141,8 @@ pub struct Server {
     services: Vec<Box<dyn Service>>,
     #[cfg(unix)]
     listen_fds: Option<ListenFds>,
+    #[cfg(unix)]
+    systemd_env: SystemdListenEnv,
     shutdown_watch: watch::Sender<bool>,
     // TODO: we many want to drop this copy to let sender call closed()
     shutdown_recv: ShutdownWatch,
@@ -252,6 +254,10 @@ impl Server {
     #[cfg(unix)]
     fn load_fds(&mut self, upgrade: bool) -> Result<(), nix::Error> {
         let mut fds = Fds::new();
+        let activated = fds.get_from_systemd(std::mem::take(&mut self.systemd_env))?;
+        if activated > 0 {
+            info!("Took {activated} sockets from systemd");
+        }
         if upgrade {
             debug!("Trying to receive socks");
             fds.get_from_sock(self.configuration.as_ref().upgrade_sock.as_str())?
@@ -282,6 +288,8 @@ impl Server {
             services: vec![],
             #[cfg(unix)]
             listen_fds: None,
+            #[cfg(unix)]
+            systemd_env: SystemdListenEnv::take(),
             shutdown_watch: tx,
             shutdown_recv: rx,
             configuration: Arc::new(conf),
@@ -298,6 +306,9 @@ impl Server {
     ///
     /// Command line options can either be passed by parsing the command line arguments via
     /// `Opt::parse_args()`, or be generated by other means.
+    ///
+    /// The variables of systemd socket activation are taken out of the environment here, so the
+    /// server is best created before any other thread is started, see [`SystemdListenEnv`].
     pub fn new(opt: impl Into<Option<Opt>>) -> Result<Server> {
         let opt = opt.into();
         let (tx, rx) = watch::channel(false);
@@ -324,6 +335,8 @@ impl Server {
             services: vec![],
             #[cfg(unix)]
             listen_fds: None,
+            #[cfg(unix)]
+            systemd_env: SystemdListenEnv::take(),
             shutdown_watch: tx,
             shutdown_recv: rx,
             configuration: Arc::new(conf),
@@ -349,6 +362,9 @@ impl Server {
     ///
     /// When trying to zero downtime upgrade from an older version of the server which is already
     /// running, this function will try to get all its listening sockets in order to take them over.
+    ///
+    /// The listening sockets passed by systemd socket activation are taken over as well, see
+    /// [`Fds::get_from_systemd()`].
     pub fn bootstrap(&mut self) {
         info!("Bootstrap starting");
         debug!("{:#?}", self.options);
@@ -15,12 +15,15 @@
 #[cfg(target_os = "linux")]
 use log::{debug, error, warn};
 use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
 #[cfg(target_os = "linux")]
-use nix::sys::socket::{self, AddressFamily, RecvMsg, SockFlag, SockType, UnixAddr};
+use nix::sys::socket::{self, AddressFamily, RecvMsg, SockFlag, UnixAddr};
+use nix::sys::socket::{getsockname, getsockopt, sockopt, SockType, SockaddrStorage};
 #[cfg(target_os = "linux")]
 use nix::sys::stat;
 use nix::{Error, NixPath};
 use std::collections::HashMap;
+use std::env;
 use std::io::Write;
 #[cfg(target_os = "linux")]
 use std::io::{IoSlice, IoSliceMut};
@@ -28,6 +31,8 @@ use std::os::unix::io::RawFd;
 #[cfg(target_os = "linux")]
 use std::{thread, time};
 
+use crate::listeners::udp_fd_key;
+
 // Utilities to transfer file descriptors between sockets, e.g. during graceful upgrades.
 
 /// Container for open file descriptors and their associated bind addresses.
@@ -81,6 +86,114 @@ impl Fds {
         self.deserialize(keys, fds);
         Ok(())
     }
+
+    /// Take the listening sockets passed via systemd socket activation, see `sd_listen_fds(3)`.
+    ///
+    /// Each socket is added under its `FileDescriptorName=` if one is set, otherwise under its
+    /// local address, e.g. `0.0.0.0:443` or the path of a UDS.
+    ///
+    /// Return the number of sockets taken, which is 0 if the process is not socket activated.
+    pub fn get_from_systemd(&mut self, env: SystemdListenEnv) -> Result<usize, Error> {
+        let listen_fds = parse_listen_fds(
+            env.pid.as_deref(),
+            env.fds.as_deref(),
+            env.names.as_deref(),
+            std::process::id(),
+        )?;
+        for (fd, name) in listen_fds.iter() {
+            self.add_listen_fd(*fd, name.as_deref())?;
+        }
+        Ok(listen_fds.len())
+    }
+
+    fn add_listen_fd(&mut self, fd: RawFd, name: Option<&str>) -> Result<(), Error> {
+        // keep the socket from leaking into the processes we spawn
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        // systemd passes blocking sockets unless NonBlocking= is set
+        let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+        fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+        let name = match name {
+            // the serialization above can't handle whitespace
+            Some(name) if name.contains(char::is_whitespace) => {
+                log::warn!("Ignoring the name {name:?} of the systemd socket {fd}");
+                None
+            }
+            name => name,
+        };
+        let bind = match name {
+            Some(name) => name.to_string(),
+            None => {
+                let addr: SockaddrStorage = getsockname(fd)?;
+                match addr.as_unix_addr().and_then(|addr| addr.path()) {
+                    Some(path) => path.to_str().ok_or(Errno::EINVAL)?.to_string(),
+                    None => addr.to_string(),
+                }
+            }
+        };
+        // UDP sockets are recorded apart from the stream listeners of the same address
+        let key = match getsockopt(fd, sockopt::SockType)? {
+            SockType::Datagram => udp_fd_key(&bind),
+            _ => bind,
+        };
+        self.add(key, fd);
+        Ok(())
+    }
+}
+
+/// The `LISTEN_*` variables of systemd socket activation, see `sd_listen_fds(3)`
+#[derive(Debug, Default)]
+pub struct SystemdListenEnv {
+    pid: Option<String>,
+    fds: Option<String>,
+    names: Option<String>,
+}
+
+impl SystemdListenEnv {
+    /// Take the variables out of the environment so that they are not inherited by the processes
+    /// we spawn
+    ///
+    /// Modifying the environment races with any other thread reading it, so this is called
+    /// before any thread or runtime is started, see [`Server::new()`](crate::server::Server::new).
+    pub fn take() -> Self {
+        let vars = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].map(|var| {
+            let value = env::var(var).ok();
+            env::remove_var(var);
+            value
+        });
+        let [pid, fds, names] = vars;
+        SystemdListenEnv { pid, fds, names }
+    }
+}
+
+/// The first file descriptor passed by systemd, see `sd_listen_fds(3)`
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// The file descriptors passed to the process `pid` along with their names if any
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(RawFd, Option<String>)>, Error> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(vec![]);
+    };
+    // the variables are meant for another process, e.g. our parent
+    if listen_pid.parse::<u32>().map_err(|_| Error::EINVAL)? != pid {
+        return Ok(vec![]);
+    }
+    let count: RawFd = listen_fds.parse().map_err(|_| Error::EINVAL)?;
+    let mut names = listen_fdnames.map(|names| names.split(':'));
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            let name = names
+                .as_mut()
+                .and_then(|names| names.next())
+                .filter(|name| !name.is_empty())
+                .map(String::from);
+            (fd, name)
+        })
+        .collect())
 }
 
 fn serialize_vec_string(vec_string: &[String], mut buf: &mut [u8]) -> usize {
@@ -372,6 +485,65 @@ mod tests {
         assert_eq!(de_vec_string[1], "bbb");
     }
 
+    #[test]
+    fn test_parse_listen_fds() {
+        assert!(parse_listen_fds(None, None, None, 42).unwrap().is_empty());
+        assert!(parse_listen_fds(Some("42"), None, None, 42)
+            .unwrap()
+            .is_empty());
+        // for another process
+        assert!(parse_listen_fds(Some("41"), Some("2"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(parse_listen_fds(Some("42"), Some("two"), None, 42).is_err());
+
+        let fds = parse_listen_fds(Some("42"), Some("2"), None, 42).unwrap();
+        assert_eq!(fds, vec![(3, None), (4, None)]);
+        let fds = parse_listen_fds(Some("42"), Some("3"), Some("http::https"), 42).unwrap();
+        assert_eq!(
+            fds,
+            vec![
+                (3, Some("http".to_string())),
+                (4, None),
+                (5, Some("https".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_listen_fd() {
+        use std::os::unix::io::AsRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap().to_string();
+        let path = "/tmp/pingora_systemd_fd.sock";
+        let _ = std::fs::remove_file(path);
+        let uds = std::os::unix::net::UnixListener::bind(path).unwrap();
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_addr = udp.local_addr().unwrap().to_string();
+        let udp2 = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp2_addr = udp2.local_addr().unwrap().to_string();
+
+        let mut fds = Fds::new();
+        fds.add_listen_fd(tcp.as_raw_fd(), Some("web")).unwrap();
+        fds.add_listen_fd(uds.as_raw_fd(), None).unwrap();
+        fds.add_listen_fd(udp.as_raw_fd(), Some("quic")).unwrap();
+        fds.add_listen_fd(udp2.as_raw_fd(), Some("bad name"))
+            .unwrap();
+        // a socket is only known by its name if it has one
+        assert_eq!(*fds.get("web").unwrap(), tcp.as_raw_fd());
+        assert!(fds.get(&tcp_addr).is_none());
+        assert_eq!(*fds.get(path).unwrap(), uds.as_raw_fd());
+        assert_eq!(*fds.get("udp://quic").unwrap(), udp.as_raw_fd());
+        assert!(fds.get(&udp_fd_key(&udp_addr)).is_none());
+        assert!(fds.get(&udp_addr).is_none());
+        assert_eq!(*fds.get(&udp_fd_key(&udp2_addr)).unwrap(), udp2.as_raw_fd());
+        assert_eq!(fds.serialize().0.len(), 4);
+        let flags = OFlag::from_bits_truncate(fcntl(tcp.as_raw_fd(), FcntlArg::F_GETFL).unwrap());
+        assert!(flags.contains(OFlag::O_NONBLOCK));
+    }
+
     #[test]
     fn test_send_receive_fds() {
         init_log();
@@ -20,7 +20,11 @@
 
 use crate::apps::ServerApp;
 use crate::listeners::tls::TlsSettings;
-use crate::listeners::{Listeners, ServerAddress, TcpSocketOptions, TransportStack};
+use crate::listeners::{
+    ConnectionLimits, Listeners, ServerAddress, TcpSocketOptions, TransportStack,
+};
+#[cfg(feature = "quic")]
+use crate::listeners::{QuicListenerEndpoint, QuicSettings, UdpSocketOptions};
 use crate::protocols::Stream;
 #[cfg(unix)]
 use crate::server::ListenFds;
@@ -107,11 +111,40 @@ impl<A> Service<A> {
             .add_tls_with_settings(addr, sock_opt, settings)
     }
 
+    /// Add a QUIC listening endpoint serving HTTP/3 with the given certificate and key paths.
+    ///
+    /// The clients usually discover this endpoint via the `Alt-Svc` header of the responses
+    /// sent over the TCP endpoints, see [`crate::apps::HttpServerOptions`].
+    #[cfg(feature = "quic")]
+    pub fn add_quic(&mut self, addr: &str, cert_path: &str, key_path: &str) -> Result<()> {
+        self.listeners.add_quic(addr, cert_path, key_path)
+    }
+
+    /// Add a QUIC listening endpoint with the given [`QuicSettings`] and [`UdpSocketOptions`].
+    #[cfg(feature = "quic")]
+    pub fn add_quic_with_settings(
+        &mut self,
+        addr: &str,
+        sock_opt: Option<UdpSocketOptions>,
+        settings: QuicSettings,
+    ) {
+        self.listeners
+            .add_quic_with_settings(addr, sock_opt, settings)
+    }
+
     /// Add an endpoint according to the given [`ServerAddress`]
     pub fn add_address(&mut self, addr: ServerAddress) {
         self.listeners.add_address(addr);
     }
 
+    /// Limit the connections of all the endpoints of this service combined.
+    ///
+    /// The metrics of these limits are labeled with the name of the service.
+    /// See [`Listeners::set_connection_limits()`].
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.listeners.set_connection_limits(&self.name, limits);
+    }
+
     /// Get a reference to the application inside this service
     pub fn app_logic(&self) -> Option<&A> {
         self.app_logic.as_ref()
@@ -169,6 +202,11 @@ impl<A: ServerApp + Send + Sync + 'static> Service<A> {
                         match io.handshake().await {
                             Ok(io) => Self::handle_event(io, app, shutdown).await,
                             Err(e) => {
+                                #[cfg(feature = "acme")]
+                                if e.etype() == &crate::listeners::tls::acme::CHALLENGE_ANSWERED {
+                                    debug!("{e}");
+                                    return;
+                                }
                                 // TODO: Maybe IOApp trait needs a fn to handle/filter our this error
                                 error!("Downstream handshake error {e}");
                             }
@@ -196,6 +234,51 @@ impl<A: ServerApp + Send + Sync + 'static> Service<A> {
 
         stack.cleanup();
     }
+
+    #[cfg(feature = "quic")]
+    async fn run_quic_endpoint(
+        app_logic: Arc<A>,
+        endpoint: QuicListenerEndpoint,
+        mut shutdown: ShutdownWatch,
+    ) {
+        // the accept loop, until the system is shutting down
+        loop {
+            let new_conn = tokio::select! {
+                new_conn = endpoint.accept() => new_conn,
+                shutdown_signal = shutdown.changed() => {
+                    match shutdown_signal {
+                        Ok(()) => {
+                            if !*shutdown.borrow() {
+                                // happen in the initial read
+                                continue;
+                            }
+                            info!("Shutting down {}", endpoint.as_str());
+                            break;
+                        }
+                        Err(e) => {
+                            error!("shutdown_signal error {e}");
+                            break;
+                        }
+                    }
+                }
+            };
+            let Some(conn) = new_conn else {
+                // the endpoint is closed
+                break;
+            };
+            let app = app_logic.clone();
+            let shutdown = shutdown.clone();
+            current_handle().spawn(async move {
+                match conn.handshake().await {
+                    Ok(conn) => app.process_new_quic(conn, &shutdown).await,
+                    Err(e) => error!("Downstream QUIC handshake error {e}"),
+                }
+            });
+        }
+
+        // the established connections are left to finish their requests
+        endpoint.stop();
+    }
 }
 
 #[async_trait]
@@ -206,6 +289,21 @@ impl<A: ServerApp + Send + Sync + 'static> ServiceTrait for Service<A> {
         shutdown: ShutdownWatch,
     ) {
         let runtime = current_handle();
+        #[cfg(feature = "quic")]
+        let quic_endpoints = match self
+            .listeners
+            .build_quic(
+                #[cfg(unix)]
+                fds.clone(),
+            )
+            .await
+        {
+            Ok(endpoints) => endpoints,
+            Err(e) => {
+                error!("Failed to build QUIC listeners of {}: {e}", self.name);
+                return;
+            }
+        };
         let endpoints = self
             .listeners
             .build(
@@ -221,13 +319,25 @@ impl<A: ServerApp + Send + Sync + 'static> ServiceTrait for Service<A> {
             .expect("can only start_service() once");
         let app_logic = Arc::new(app_logic);
 
-        let handlers = endpoints.into_iter().map(|endpoint| {
+        #[allow(unused_mut)] // only extended with the QUIC endpoints
+        let mut handlers: Vec<_> = endpoints
+            .into_iter()
+            .map(|endpoint| {
+                let shutdown = shutdown.clone();
+                let my_app_logic = app_logic.clone();
+                runtime.spawn(async move {
+                    Self::run_endpoint(my_app_logic, endpoint, shutdown).await;
+                })
+            })
+            .collect();
+        #[cfg(feature = "quic")]
+        handlers.extend(quic_endpoints.into_iter().map(|endpoint| {
             let shutdown = shutdown.clone();
             let my_app_logic = app_logic.clone();
             runtime.spawn(async move {
-                Self::run_endpoint(my_app_logic, endpoint, shutdown).await;
+                Self::run_quic_endpoint(my_app_logic, endpoint, shutdown).await;
             })
-        });
+        }));
 
         futures::future::join_all(handlers).await;
         self.listeners.cleanup();
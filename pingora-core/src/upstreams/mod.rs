@@ -15,3 +15,4 @@
 //! The interface to connect to a remote server
 
 pub mod peer;
+pub mod resolver;
@@ -16,10 +16,12 @@
 
 use crate::connectors::{l4::BindTo, L4Connect};
 use crate::protocols::l4::socket::SocketAddr;
+use crate::protocols::proxy_protocol::ProxyHeader;
 use crate::protocols::tls::CaType;
 #[cfg(unix)]
 use crate::protocols::ConnFdReusable;
 use crate::protocols::TcpKeepalive;
+use crate::upstreams::resolver::Resolver;
 use crate::utils::tls::{get_organization_unit, CertKey};
 use ahash::AHasher;
 use derivative::Derivative;
@@ -42,6 +44,9 @@ use tokio::net::TcpSocket;
 
 pub use crate::protocols::tls::ALPN;
 
+/// The default Connection Attempt Delay of Happy Eyeballs, see RFC 8305 8
+pub const DEFAULT_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
 /// The interface to trace the connection
 pub trait Tracing: Send + Sync + std::fmt::Debug {
     /// This method is called when successfully connected to a remote server
@@ -80,6 +85,10 @@ pub trait Peer: Display + Clone {
     fn get_proxy(&self) -> Option<&Proxy> {
         None
     }
+    /// Get the SOCKS5 proxy setting to connect to the remote server
+    fn get_socks5_proxy(&self) -> Option<&Socks5Proxy> {
+        None
+    }
     /// Get the additional options to connect to the peer.
     ///
     /// See [`PeerOptions`] for more details
@@ -114,6 +123,34 @@ pub trait Peer: Display + Clone {
             None => None,
         }
     }
+    /// The SHA-256 digests of the SubjectPublicKeyInfo that the certificate chain of the server
+    /// must contain one of.
+    ///
+    /// See [`PeerOptions::spki_pins`].
+    fn spki_pins(&self) -> &[[u8; 32]] {
+        match self.get_peer_options() {
+            Some(opt) => &opt.spki_pins,
+            None => &[],
+        }
+    }
+    /// The other addresses of the remote server, e.g. of the other IP family, to race against
+    /// [`Self::address()`] when establishing a new connection.
+    ///
+    /// See [`PeerOptions::alternative_addresses`].
+    fn alternative_addresses(&self) -> &[InetSocketAddr] {
+        match self.get_peer_options() {
+            Some(opt) => &opt.alternative_addresses,
+            None => &[],
+        }
+    }
+    /// How long to wait for a connection attempt before starting the next one when racing
+    /// several addresses.
+    fn connection_attempt_delay(&self) -> Duration {
+        match self.get_peer_options() {
+            Some(opt) => opt.connection_attempt_delay,
+            None => DEFAULT_CONNECTION_ATTEMPT_DELAY,
+        }
+    }
     /// Information about the local source address this connection should be bound to.
     fn bind_to(&self) -> Option<&BindTo> {
         match self.get_peer_options() {
@@ -190,9 +227,26 @@ pub trait Peer: Display + Clone {
             .unwrap_or_default()
     }
 
+    /// The PROXY protocol header to send right after the connection is established, if any.
+    fn proxy_protocol(&self) -> Option<&ProxyHeader> {
+        self.get_peer_options()
+            .and_then(|o| o.proxy_protocol.as_ref())
+    }
+
     #[cfg(unix)]
     fn matches_fd<V: AsRawFd>(&self, fd: V) -> bool {
-        self.address().check_fd_match(fd)
+        if self.alternative_addresses().is_empty() {
+            return self.address().check_fd_match(fd);
+        }
+        // the connection may be to any of the addresses
+        let addrs: Vec<_> = self
+            .address()
+            .as_inet()
+            .into_iter()
+            .chain(self.alternative_addresses())
+            .copied()
+            .collect();
+        addrs[..].check_fd_match(fd)
     }
 
     #[cfg(windows)]
@@ -278,6 +332,8 @@ impl Peer for BasicPeer {
     fn reuse_hash(&self) -> u64 {
         let mut hasher = AHasher::default();
         self._address.hash(&mut hasher);
+        self.spki_pins().hash(&mut hasher);
+        self.proxy_protocol().hash(&mut hasher);
         hasher.finish()
     }
 
@@ -329,13 +385,29 @@ pub struct PeerOptions {
     pub verify_hostname: bool,
     /* accept the cert if it's CN matches the SNI or this name */
     pub alternative_cn: Option<String>,
+    /// The other addresses of the server besides the address of the peer, e.g. the IPv4 ones of
+    /// a server with an IPv6 address.
+    ///
+    /// When set, new connections race all the addresses with the Happy Eyeballs algorithm of
+    /// RFC 8305: the attempts alternate between the IP families, starting with the family of the
+    /// address of the peer, and each one starts once the previous one failed or
+    /// `connection_attempt_delay` passed. The first established connection is used.
+    pub alternative_addresses: Vec<InetSocketAddr>,
+    /// The Connection Attempt Delay of RFC 8305, 250ms by default.
+    pub connection_attempt_delay: Duration,
+    /// The SHA-256 digests of the DER encoded SubjectPublicKeyInfo to pin the server to.
+    ///
+    /// When set, the certificate chain presented by the server must contain a certificate with
+    /// one of these public keys on top of the CA validation, otherwise the connection fails with
+    /// [`CertPinMismatch`](pingora_error::ErrorType::CertPinMismatch). Empty disables pinning.
+    pub spki_pins: Vec<[u8; 32]>,
     pub alpn: ALPN,
     pub ca: Option<Arc<CaType>>,
     pub tcp_keepalive: Option<TcpKeepalive>,
     pub tcp_recv_buf: Option<usize>,
     pub dscp: Option<u8>,
     pub h2_ping_interval: Option<Duration>,
-    // how many concurrent h2 stream are allowed in the same connection
+    // how many concurrent h2 (or h3) stream are allowed in the same connection
     pub max_h2_streams: usize,
     pub extra_proxy_headers: BTreeMap<String, Vec<u8>>,
     // The list of curve the tls connection should advertise
@@ -347,6 +419,9 @@ pub struct PeerOptions {
     pub tcp_fast_open: bool,
     // use Arc because Clone is required but not allowed in trait object
     pub tracer: Option<Tracer>,
+    // The PROXY protocol header to send to the server before anything else.
+    // Connections carrying different headers are never reused for each other.
+    pub proxy_protocol: Option<ProxyHeader>,
     // A custom L4 connector to use to establish new L4 connections
     pub custom_l4: Option<Arc<dyn L4Connect + Send + Sync>>,
     #[derivative(Debug = "ignore")]
@@ -367,6 +442,9 @@ impl PeerOptions {
             verify_cert: true,
             verify_hostname: true,
             alternative_cn: None,
+            alternative_addresses: vec![],
+            connection_attempt_delay: DEFAULT_CONNECTION_ATTEMPT_DELAY,
+            spki_pins: vec![],
             alpn: ALPN::H1,
             ca: None,
             tcp_keepalive: None,
@@ -379,6 +457,7 @@ impl PeerOptions {
             second_keyshare: true, // default true and noop when not using PQ curves
             tcp_fast_open: false,
             tracer: None,
+            proxy_protocol: None,
             custom_l4: None,
             upstream_tcp_sock_tweak_hook: None,
         }
@@ -410,6 +489,12 @@ impl Display for PeerOptions {
         if let Some(cn) = &self.alternative_cn {
             write!(f, "alt_cn: {},", cn)?;
         }
+        if !self.alternative_addresses.is_empty() {
+            write!(f, "alt_addrs: {:?},", self.alternative_addresses)?;
+        }
+        if !self.spki_pins.is_empty() {
+            write!(f, "spki_pins: {},", self.spki_pins.len())?;
+        }
         write!(f, "alpn: {},", self.alpn)?;
         if let Some(cas) = &self.ca {
             for ca in cas.iter() {
@@ -427,6 +512,9 @@ impl Display for PeerOptions {
         if let Some(h2_ping_interval) = self.h2_ping_interval {
             write!(f, "h2_ping_interval: {:?},", h2_ping_interval)?;
         }
+        if let Some(header) = &self.proxy_protocol {
+            write!(f, "proxy_protocol: {:?},", header.version)?;
+        }
         Ok(())
     }
 }
@@ -438,6 +526,7 @@ pub struct HttpPeer {
     pub scheme: Scheme,
     pub sni: String,
     pub proxy: Option<Proxy>,
+    pub socks5_proxy: Option<Socks5Proxy>,
     pub client_cert_key: Option<Arc<CertKey>>,
     /// a custom field to isolate connection reuse. Requests with different group keys
     /// cannot share connections with each other.
@@ -460,6 +549,7 @@ impl HttpPeer {
             scheme: Scheme::from_tls_bool(tls),
             sni,
             proxy: None,
+            socks5_proxy: None,
             client_cert_key: None,
             group_key: 0,
             options: PeerOptions::new(),
@@ -473,6 +563,32 @@ impl HttpPeer {
         Self::new_from_sockaddr(SocketAddr::Inet(addr), tls, sni)
     }
 
+    /// Create a new [`HttpPeer`] for a server with several addresses, e.g. both IPv6 and IPv4
+    /// ones, which new connections race with Happy Eyeballs starting with the first address.
+    ///
+    /// See [`PeerOptions::alternative_addresses`]. Panics if `addresses` is empty.
+    pub fn new_with_addresses(addresses: &[InetSocketAddr], tls: bool, sni: String) -> Self {
+        let (addr, alternatives) = addresses
+            .split_first()
+            .expect("at least one address is required");
+        let mut peer = Self::new_from_sockaddr(SocketAddr::Inet(*addr), tls, sni);
+        peer.options.alternative_addresses = alternatives.to_vec();
+        peer
+    }
+
+    /// Create a new [`HttpPeer`] for all the addresses of `host` found by the given [`Resolver`],
+    /// see [`Self::new_with_addresses()`].
+    pub async fn resolve(
+        resolver: &Resolver,
+        host: &str,
+        port: u16,
+        tls: bool,
+        sni: String,
+    ) -> Result<Self> {
+        let addresses = resolver.lookup(host, port).await?;
+        Ok(Self::new_with_addresses(&addresses, tls, sni))
+    }
+
     /// Create a new [`HttpPeer`] with the given path to Unix domain socket and TLS settings.
     #[cfg(unix)]
     pub fn new_uds(path: &str, tls: bool, sni: String) -> Result<Self> {
@@ -502,12 +618,33 @@ impl HttpPeer {
                 port,
                 headers,
             }),
+            socks5_proxy: None,
             client_cert_key: None,
             group_key: 0,
             options: PeerOptions::new(),
         }
     }
 
+    /// Create a new [`HttpPeer`] that connects to `host` and `port` through the SOCKS5 proxy at
+    /// `next_hop`, which resolves `host` if it is not an IP address.
+    pub fn new_socks5_proxy(
+        next_hop: SocketAddr,
+        host: &str,
+        port: u16,
+        tls: bool,
+        sni: String,
+        auth: Option<Socks5Auth>,
+    ) -> Self {
+        let mut peer = Self::new_from_sockaddr(next_hop.clone(), tls, sni);
+        peer.socks5_proxy = Some(Socks5Proxy {
+            next_hop,
+            host: host.to_string(),
+            port,
+            auth,
+        });
+        peer
+    }
+
     fn peer_hash(&self) -> u64 {
         let mut hasher = AHasher::default();
         self.hash(&mut hasher);
@@ -520,6 +657,7 @@ impl Hash for HttpPeer {
         self._address.hash(state);
         self.scheme.hash(state);
         self.proxy.hash(state);
+        self.socks5_proxy.hash(state);
         self.sni.hash(state);
         // client cert serial
         self.client_cert_key.hash(state);
@@ -527,7 +665,11 @@ impl Hash for HttpPeer {
         self.verify_cert().hash(state);
         self.verify_hostname().hash(state);
         self.alternative_cn().hash(state);
+        self.spki_pins().hash(state);
+        self.alternative_addresses().hash(state);
         self.group_key.hash(state);
+        // the PROXY header is bound to the connection
+        self.proxy_protocol().hash(state);
     }
 }
 
@@ -540,6 +682,9 @@ impl Display for HttpPeer {
         if let Some(p) = self.proxy.as_ref() {
             write!(f, "proxy: {p},")?;
         }
+        if let Some(p) = self.socks5_proxy.as_ref() {
+            write!(f, "socks5 proxy: {p},")?;
+        }
         if let Some(cert) = &self.client_cert_key {
             write!(f, "client cert: {},", cert)?;
         }
@@ -577,10 +722,26 @@ impl Peer for HttpPeer {
         self.proxy.as_ref()
     }
 
+    fn get_socks5_proxy(&self) -> Option<&Socks5Proxy> {
+        self.socks5_proxy.as_ref()
+    }
+
     #[cfg(unix)]
     fn matches_fd<V: AsRawFd>(&self, fd: V) -> bool {
         if let Some(proxy) = self.get_proxy() {
             proxy.next_hop.check_fd_match(fd)
+        } else if let Some(proxy) = self.get_socks5_proxy() {
+            proxy.next_hop.check_fd_match(fd)
+        } else if !self.alternative_addresses().is_empty() {
+            // the connection may be to any of the addresses
+            let addrs: Vec<_> = self
+                .address()
+                .as_inet()
+                .into_iter()
+                .chain(self.alternative_addresses())
+                .copied()
+                .collect();
+            addrs[..].check_fd_match(fd)
         } else {
             self.address().check_fd_match(fd)
         }
@@ -592,6 +753,8 @@ impl Peer for HttpPeer {
 
         if let Some(proxy) = self.get_proxy() {
             panic!("windows do not support peers with proxy")
+        } else if let Some(proxy) = self.get_socks5_proxy() {
+            proxy.next_hop.check_sock_match(sock)
         } else {
             self.address().check_sock_match(sock)
         }
@@ -626,3 +789,55 @@ impl Display for Proxy {
         )
     }
 }
+
+/// The settings to connect to the remote server through a SOCKS5 proxy
+#[derive(Debug, Hash, Clone)]
+pub struct Socks5Proxy {
+    /// The address of the SOCKS5 proxy
+    pub next_hop: SocketAddr,
+    /// The host to connect to, resolved by the proxy if it is not an IP address
+    pub host: String,
+    /// The port to connect to
+    pub port: u16,
+    /// The username/password to authenticate to the proxy with, if any
+    pub auth: Option<Socks5Auth>,
+}
+
+impl Display for Socks5Proxy {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(
+            f,
+            "next_hop: {}, host: {}, port: {}",
+            self.next_hop, self.host, self.port
+        )?;
+        if let Some(auth) = &self.auth {
+            write!(f, ", username: {}", auth.username)?;
+        }
+        Ok(())
+    }
+}
+
+/// The username/password credentials of a SOCKS5 proxy, see RFC 1929
+#[derive(Hash, Clone)]
+pub struct Socks5Auth {
+    pub username: String,
+    pub password: String,
+}
+
+impl Socks5Auth {
+    pub fn new(username: &str, password: &str) -> Self {
+        Socks5Auth {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+// keep the password out of the logs
+impl std::fmt::Debug for Socks5Auth {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Socks5Auth")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
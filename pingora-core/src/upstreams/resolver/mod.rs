@@ -0,0 +1,585 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Asynchronous resolution of the host names of upstream servers, with caching
+//!
+//! A [Resolver] either asks the resolver of the system or queries the DNS nameservers it is
+//! configured with. The addresses are cached for the TTL of their records, and kept for a while
+//! after that to be used in case the host can't be resolved again. Concurrent lookups of the same
+//! host share a single resolution.
+//!
+//! ```no_run
+//! use pingora_core::upstreams::peer::HttpPeer;
+//! use pingora_core::upstreams::resolver::Resolver;
+//!
+//! # async fn example() -> pingora_error::Result<()> {
+//! let resolver = Resolver::new();
+//! let peer = HttpPeer::resolve(&resolver, "example.com", 443, true, "example.com".into()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod wire;
+
+use log::{debug, warn};
+use lru::LruCache;
+use parking_lot::Mutex;
+use pingora_error::{BError, Error, ErrorType, ErrorType::Custom, OrErr, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::OnceCell;
+
+use wire::{Answer, RecordType};
+
+const DNS_ERR: ErrorType = Custom("DNSError");
+
+/// The default TTL of the addresses found by the resolver of the system, which doesn't tell it
+pub const DEFAULT_SYSTEM_TTL: Duration = Duration::from_secs(60);
+/// The default lowest TTL to cache addresses for
+pub const DEFAULT_MIN_TTL: Duration = Duration::from_secs(1);
+/// The default highest TTL to cache addresses for
+pub const DEFAULT_MAX_TTL: Duration = Duration::from_secs(24 * 3600);
+/// The default of how long expired addresses are still used when the host can't be resolved
+pub const DEFAULT_MAX_STALE: Duration = Duration::from_secs(3600);
+/// The default time to wait for the response of a nameserver
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+/// The default number of hosts whose addresses are cached
+pub const DEFAULT_CACHE_SIZE: usize = 4096;
+
+// large enough for any response without EDNS
+const MAX_UDP_RESPONSE_LEN: usize = 1500;
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+// The outcome of a resolution, shared by the lookups waiting for it
+type Flight = Arc<OnceCell<std::result::Result<Vec<IpAddr>, Arc<BError>>>>;
+
+/// A DNS resolver caching the addresses it finds
+pub struct Resolver {
+    // the resolver of the system is used when empty
+    nameservers: Vec<SocketAddr>,
+    cache: Mutex<LruCache<String, CacheEntry>>,
+    // the resolutions in progress by host
+    inflight: Mutex<HashMap<String, Flight>>,
+    /// The lowest TTL to cache addresses for
+    pub min_ttl: Duration,
+    /// The highest TTL to cache addresses for
+    pub max_ttl: Duration,
+    /// How long expired addresses are still returned for when resolving their host fails
+    pub max_stale: Duration,
+    /// The TTL of the addresses found by the resolver of the system
+    pub system_ttl: Duration,
+    /// How long to wait for the response of a nameserver
+    pub timeout: Duration,
+    /// How many times each nameserver is tried
+    pub attempts: usize,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    /// Create a [Resolver] using the resolver of the system, i.e., `getaddrinfo(3)`, which also
+    /// reads `/etc/hosts`.
+    pub fn new() -> Self {
+        Self::with_nameservers(vec![])
+    }
+
+    /// Create a [Resolver] querying the given DNS nameservers in order, e.g. a local stand-in
+    /// server in tests.
+    pub fn with_nameservers(nameservers: Vec<SocketAddr>) -> Self {
+        Resolver {
+            nameservers,
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(DEFAULT_CACHE_SIZE).unwrap(),
+            )),
+            inflight: Mutex::new(HashMap::new()),
+            min_ttl: DEFAULT_MIN_TTL,
+            max_ttl: DEFAULT_MAX_TTL,
+            max_stale: DEFAULT_MAX_STALE,
+            system_ttl: DEFAULT_SYSTEM_TTL,
+            timeout: DEFAULT_TIMEOUT,
+            attempts: 2,
+        }
+    }
+
+    /// Set how many hosts the addresses are cached for, [DEFAULT_CACHE_SIZE] by default. The
+    /// least recently used ones are evicted beyond that.
+    pub fn set_cache_size(&mut self, size: usize) {
+        self.cache
+            .get_mut()
+            .resize(NonZeroUsize::new(size).unwrap_or(NonZeroUsize::MIN));
+    }
+
+    /// Look up the IP addresses of `host`, IPv6 ones first.
+    ///
+    /// The cached addresses are returned until they expire. If resolving the host fails after
+    /// that, they are still returned for up to [Self::max_stale].
+    pub async fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let literal = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        if let Ok(ip) = literal.parse() {
+            return Ok(vec![ip]);
+        }
+
+        let key = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addrs) = self.cached(&key, Duration::ZERO) {
+            return Ok(addrs);
+        }
+
+        let flight = self.inflight.lock().entry(key.clone()).or_default().clone();
+        let resolved = flight
+            .get_or_init(|| self.resolve_and_cache(&key))
+            .await
+            .clone();
+        {
+            // the next lookups after this one resolve the host again
+            let mut inflight = self.inflight.lock();
+            if inflight.get(&key).is_some_and(|f| Arc::ptr_eq(f, &flight)) {
+                inflight.remove(&key);
+            }
+        }
+
+        resolved.or_else(|e| match self.cached(&key, self.max_stale) {
+            Some(addrs) => {
+                warn!("Fail to resolve {key}, using stale addresses: {e}");
+                Ok(addrs)
+            }
+            None => Err(Error::because(
+                e.etype().clone(),
+                format!("fail to resolve {key}"),
+                e,
+            )),
+        })
+    }
+
+    // The cached addresses of `key` if they expired less than `stale` ago. The addresses that
+    // expired more than `max_stale` ago are evicted.
+    fn cached(&self, key: &str, stale: Duration) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.lock();
+        let entry = cache.get(key)?;
+        let now = Instant::now();
+        if now < entry.expires + stale {
+            return Some(entry.addrs.clone());
+        }
+        if now >= entry.expires + self.max_stale {
+            cache.pop(key);
+        }
+        None
+    }
+
+    async fn resolve_and_cache(&self, key: &str) -> std::result::Result<Vec<IpAddr>, Arc<BError>> {
+        let (addrs, ttl) = self.resolve(key).await.map_err(Arc::new)?;
+        let ttl = ttl.max(self.min_ttl).min(self.max_ttl);
+        debug!("resolved {key} to {addrs:?} for {ttl:?}");
+        self.cache.lock().put(
+            key.to_string(),
+            CacheEntry {
+                addrs: addrs.clone(),
+                expires: Instant::now() + ttl,
+            },
+        );
+        Ok(addrs)
+    }
+
+    /// Look up the socket addresses of `host` with the given port, IPv6 ones first.
+    pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok(self
+            .lookup_ip(host)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Forget all the cached addresses.
+    pub fn clear(&self) {
+        self.cache.lock().clear();
+    }
+
+    // the non-empty list of addresses of the host and their TTL
+    async fn resolve(&self, host: &str) -> Result<(Vec<IpAddr>, Duration)> {
+        if self.nameservers.is_empty() {
+            return self.resolve_system(host).await;
+        }
+        let (v6, v4) = futures::join!(
+            self.query(host, RecordType::Aaaa),
+            self.query(host, RecordType::A)
+        );
+        let (mut addrs, mut ttl) = (vec![], u32::MAX);
+        let mut error = None;
+        for answer in [v6, v4] {
+            match answer {
+                Ok((found, found_ttl)) if !found.is_empty() => {
+                    addrs.extend(found);
+                    ttl = ttl.min(found_ttl);
+                }
+                Ok(_) => {}
+                Err(e) => error = Some(e),
+            }
+        }
+        match (addrs.is_empty(), error) {
+            (false, _) => Ok((addrs, Duration::from_secs(ttl.into()))),
+            (true, Some(e)) => Err(e),
+            (true, None) => Error::e_explain(DNS_ERR, format!("no address for {host}")),
+        }
+    }
+
+    async fn resolve_system(&self, host: &str) -> Result<(Vec<IpAddr>, Duration)> {
+        let mut addrs: Vec<IpAddr> = vec![];
+        let found = tokio::net::lookup_host((host, 0))
+            .await
+            .or_err_with(DNS_ERR, || format!("fail to resolve {host}"))?;
+        for addr in found {
+            if !addrs.contains(&addr.ip()) {
+                addrs.push(addr.ip());
+            }
+        }
+        if addrs.is_empty() {
+            return Error::e_explain(DNS_ERR, format!("no address for {host}"));
+        }
+        // stable so that the order of the system is kept within each family
+        addrs.sort_by_key(|ip| ip.is_ipv4());
+        Ok((addrs, self.system_ttl))
+    }
+
+    // ask the nameservers in order until one answers
+    async fn query(&self, host: &str, rtype: RecordType) -> Result<(Vec<IpAddr>, u32)> {
+        let mut error = None;
+        for _ in 0..self.attempts.max(1) {
+            for nameserver in self.nameservers.iter() {
+                match self.query_nameserver(nameserver, host, rtype).await {
+                    Ok(answer) => return Ok(answer),
+                    Err(e) => {
+                        debug!("{rtype:?} query of {host} to {nameserver} failed: {e}");
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(error.expect("at least one nameserver"))
+    }
+
+    async fn query_nameserver(
+        &self,
+        nameserver: &SocketAddr,
+        host: &str,
+        rtype: RecordType,
+    ) -> Result<(Vec<IpAddr>, u32)> {
+        let query = wire::query(rand::random(), host, rtype)?;
+        let mut answer =
+            pingora_timeout::timeout(self.timeout, udp_exchange(nameserver, &query, rtype))
+                .await
+                .or_err_with(DNS_ERR, || format!("timeout querying {nameserver}"))??;
+        if answer == Answer::Truncated {
+            answer =
+                pingora_timeout::timeout(self.timeout, tcp_exchange(nameserver, &query, rtype))
+                    .await
+                    .or_err_with(DNS_ERR, || {
+                        format!("timeout querying {nameserver} over TCP")
+                    })??;
+        }
+        match answer {
+            Answer::Addrs(addrs, ttl) => Ok((addrs, ttl)),
+            Answer::Truncated => Error::e_explain(
+                DNS_ERR,
+                format!("truncated response over TCP from {nameserver}"),
+            ),
+        }
+    }
+}
+
+async fn udp_exchange(nameserver: &SocketAddr, query: &[u8], rtype: RecordType) -> Result<Answer> {
+    let local: SocketAddr = if nameserver.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .or_err(DNS_ERR, "fail to bind UDP socket")?;
+    socket
+        .connect(nameserver)
+        .await
+        .or_err_with(DNS_ERR, || format!("fail to connect to {nameserver}"))?;
+    socket
+        .send(query)
+        .await
+        .or_err_with(DNS_ERR, || format!("fail to send query to {nameserver}"))?;
+    let mut buf = [0; MAX_UDP_RESPONSE_LEN];
+    loop {
+        let len = socket
+            .recv(&mut buf)
+            .await
+            .or_err_with(DNS_ERR, || format!("fail to receive from {nameserver}"))?;
+        // ignore the responses to other queries
+        if len >= 2 && buf[..2] == query[..2] {
+            return wire::parse_response(&buf[..len], query, rtype);
+        }
+    }
+}
+
+async fn tcp_exchange(nameserver: &SocketAddr, query: &[u8], rtype: RecordType) -> Result<Answer> {
+    let mut stream = TcpStream::connect(nameserver)
+        .await
+        .or_err_with(DNS_ERR, || format!("fail to connect to {nameserver}"))?;
+    // messages are prefixed with their length over TCP, see RFC 1035 4.2.2
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    stream
+        .write_all(&message)
+        .await
+        .or_err_with(DNS_ERR, || format!("fail to send query to {nameserver}"))?;
+    let len = stream
+        .read_u16()
+        .await
+        .or_err_with(DNS_ERR, || format!("fail to receive from {nameserver}"))?;
+    let mut buf = vec![0; len as usize];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .or_err_with(DNS_ERR, || format!("fail to receive from {nameserver}"))?;
+    wire::parse_response(&buf, query, rtype)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use parking_lot::RwLock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A stand-in DNS server answering with the records of its hosts
+    pub(crate) struct MockDns {
+        pub addr: SocketAddr,
+        pub records: Arc<RwLock<HashMap<String, Vec<(IpAddr, u32)>>>>,
+        pub queries: Arc<AtomicUsize>,
+    }
+
+    impl MockDns {
+        // truncate the UDP responses when `truncate` so that the queries are retried over TCP
+        pub async fn start(truncate: bool) -> Self {
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = udp.local_addr().unwrap();
+            let tcp = tokio::net::TcpListener::bind(addr).await.unwrap();
+            let records: Arc<RwLock<HashMap<String, Vec<(IpAddr, u32)>>>> = Default::default();
+            let queries = Arc::new(AtomicUsize::new(0));
+
+            let answer = {
+                let records = records.clone();
+                let queries = queries.clone();
+                move |query: &[u8], truncated: bool| {
+                    queries.fetch_add(1, Ordering::SeqCst);
+                    // the name of the question, up to the first label for simplicity
+                    let len = query[12] as usize;
+                    let host = std::str::from_utf8(&query[13..13 + len]).unwrap();
+                    let records = records.read().get(host).cloned();
+                    match records {
+                        Some(records) => wire::response(query, &records, truncated),
+                        None => {
+                            // SERVFAIL
+                            let mut resp = wire::response(query, &[], false);
+                            resp[3] |= 2;
+                            resp
+                        }
+                    }
+                }
+            };
+
+            let udp_answer = answer.clone();
+            tokio::spawn(async move {
+                let mut buf = [0; 512];
+                loop {
+                    let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+                    let resp = udp_answer(&buf[..len], truncate);
+                    udp.send_to(&resp, from).await.unwrap();
+                }
+            });
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = tcp.accept().await.unwrap();
+                    let len = stream.read_u16().await.unwrap();
+                    let mut query = vec![0; len as usize];
+                    stream.read_exact(&mut query).await.unwrap();
+                    let resp = answer(&query, false);
+                    stream.write_u16(resp.len() as u16).await.unwrap();
+                    stream.write_all(&resp).await.unwrap();
+                }
+            });
+
+            MockDns {
+                addr,
+                records,
+                queries,
+            }
+        }
+
+        pub fn set(&self, host: &str, records: &[(&str, u32)]) {
+            let records = records
+                .iter()
+                .map(|(ip, ttl)| (ip.parse().unwrap(), *ttl))
+                .collect();
+            self.records.write().insert(host.to_string(), records);
+        }
+
+        pub fn remove(&self, host: &str) {
+            self.records.write().remove(host);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let dns = MockDns::start(false).await;
+        dns.set("host", &[("192.0.2.1", 300), ("2001:db8::1", 300)]);
+        let resolver = Resolver::with_nameservers(vec![dns.addr]);
+
+        let addrs = resolver.lookup("host", 80).await.unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "[2001:db8::1]:80".parse().unwrap(),
+                "192.0.2.1:80".parse().unwrap()
+            ]
+        );
+        assert_eq!(dns.queries.load(Ordering::SeqCst), 2);
+        // cached, case insensitive
+        resolver.lookup("HOST.", 80).await.unwrap();
+        assert_eq!(dns.queries.load(Ordering::SeqCst), 2);
+
+        assert!(resolver.lookup("unknown", 80).await.is_err());
+
+        // IP literals are not resolved
+        assert_eq!(
+            resolver.lookup_ip("[::1]").await.unwrap(),
+            vec![IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 1])]
+        );
+        assert_eq!(
+            resolver.lookup_ip("127.0.0.1").await.unwrap(),
+            vec![IpAddr::from([127, 0, 0, 1])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ttl_and_stale() {
+        let dns = MockDns::start(false).await;
+        dns.set("host", &[("192.0.2.1", 0)]);
+        let mut resolver = Resolver::with_nameservers(vec![dns.addr]);
+        resolver.min_ttl = Duration::from_millis(50);
+        resolver.max_stale = Duration::from_millis(200);
+        resolver.timeout = Duration::from_millis(100);
+
+        let ip1: IpAddr = "192.0.2.1".parse().unwrap();
+        let ip2: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(resolver.lookup_ip("host").await.unwrap(), vec![ip1]);
+        dns.set("host", &[("192.0.2.2", 0)]);
+        // cached for the min TTL
+        assert_eq!(resolver.lookup_ip("host").await.unwrap(), vec![ip1]);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(resolver.lookup_ip("host").await.unwrap(), vec![ip2]);
+
+        // stale on error
+        dns.remove("host");
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(resolver.lookup_ip("host").await.unwrap(), vec![ip2]);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(resolver.lookup_ip("host").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let dns = MockDns::start(false).await;
+        dns.set("host", &[("192.0.2.1", 300)]);
+        let resolver = Resolver::with_nameservers(vec![dns.addr]);
+
+        let lookups = (0..10).map(|_| resolver.lookup_ip("host"));
+        for addrs in futures::future::join_all(lookups).await {
+            assert_eq!(addrs.unwrap(), vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        }
+        // one A and one AAAA query
+        assert_eq!(dns.queries.load(Ordering::SeqCst), 2);
+        assert!(resolver.inflight.lock().is_empty());
+
+        // failures are shared as well
+        let lookups = (0..10).map(|_| resolver.lookup_ip("unknown"));
+        for addrs in futures::future::join_all(lookups).await {
+            assert!(addrs.is_err());
+        }
+        assert_eq!(
+            dns.queries.load(Ordering::SeqCst),
+            2 + 2 * resolver.attempts
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_eviction() {
+        let dns = MockDns::start(false).await;
+        dns.set("host1", &[("192.0.2.1", 0)]);
+        dns.set("host2", &[("192.0.2.2", 300)]);
+        let mut resolver = Resolver::with_nameservers(vec![dns.addr]);
+        resolver.min_ttl = Duration::from_millis(10);
+        resolver.max_stale = Duration::from_millis(10);
+        resolver.set_cache_size(1);
+
+        resolver.lookup_ip("host1").await.unwrap();
+        resolver.lookup_ip("host2").await.unwrap();
+        // the least recently used host is evicted
+        assert_eq!(resolver.cache.lock().len(), 1);
+        assert!(resolver.cache.lock().contains("host2"));
+
+        resolver.set_cache_size(2);
+        resolver.lookup_ip("host1").await.unwrap();
+        assert_eq!(resolver.cache.lock().len(), 2);
+        // the addresses are evicted once they can't be used as stale ones either
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(resolver.cached("host1", Duration::ZERO).is_none());
+        assert!(!resolver.cache.lock().contains("host1"));
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_fallback() {
+        let dns = MockDns::start(true).await;
+        dns.set("host", &[("192.0.2.1", 300)]);
+        // nothing listens to the port of a dropped socket
+        let dead = {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        };
+        let mut resolver = Resolver::with_nameservers(vec![dead, dns.addr]);
+        resolver.timeout = Duration::from_millis(100);
+        // over TCP as the UDP response is truncated
+        assert_eq!(
+            resolver.lookup_ip("host").await.unwrap(),
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_system_resolver() {
+        let resolver = Resolver::new();
+        let addrs = resolver.lookup_ip("localhost").await.unwrap();
+        assert!(addrs.iter().all(|ip| ip.is_loopback()));
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The DNS messages to look up the addresses of a host, see RFC 1035 4
+
+use pingora_error::{Error, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::DNS_ERR;
+
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+// QR in the flags
+const RESPONSE: u16 = 0x8000;
+// TC in the flags
+const TRUNCATED: u16 = 0x0200;
+// RD in the flags
+const RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const NO_ERROR: u16 = 0;
+const NAME_ERROR: u16 = 3;
+
+/// The types of the records with addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum RecordType {
+    A = 1,
+    Aaaa = 28,
+}
+
+/// The addresses in the answer to a query
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Answer {
+    /// The addresses and the lowest TTL of their records, empty if the host has no address of
+    /// the type
+    Addrs(Vec<IpAddr>, u32),
+    /// The answer is too large for UDP and should be retried over TCP
+    Truncated,
+}
+
+/// Encode the query for the records of `rtype` of `host` with the given ID
+pub(super) fn query(id: u16, host: &str, rtype: RecordType) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + host.len() + 6);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&RECURSION_DESIRED.to_be_bytes());
+    // 1 question, no answer, authority or additional records
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut buf, host)?;
+    buf.extend_from_slice(&(rtype as u16).to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+fn encode_name(buf: &mut Vec<u8>, host: &str) -> Result<()> {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    // the labels, their lengths and the root
+    if host.is_empty() || host.len() + 2 > 255 {
+        return Error::e_explain(DNS_ERR, format!("invalid host name {host:?}"));
+    }
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Error::e_explain(DNS_ERR, format!("invalid host name {host:?}"));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    Ok(())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos + len;
+        if end > self.buf.len() {
+            return Error::e_explain(DNS_ERR, "truncated DNS message");
+        }
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // names are not compared, the records of the type in the answer section are the addresses of
+    // the host or of the target of its CNAME
+    fn skip_name(&mut self) -> Result<()> {
+        loop {
+            let len = self.take(1)?[0];
+            match len {
+                0 => return Ok(()),
+                // a pointer ends the name
+                len if len & 0xc0 == 0xc0 => return self.take(1).map(|_| ()),
+                len if len & 0xc0 == 0 => {
+                    self.take(len as usize)?;
+                }
+                _ => return Error::e_explain(DNS_ERR, "invalid label in DNS message"),
+            }
+        }
+    }
+}
+
+/// Parse the response to `query` for the records of `rtype`
+///
+/// The ID and the question of the response must be the ones of the query, see RFC 5452 4.
+pub(super) fn parse_response(buf: &[u8], query: &[u8], rtype: RecordType) -> Result<Answer> {
+    let mut reader = Reader { buf, pos: 0 };
+    if reader.take(2)? != &query[..2] {
+        return Error::e_explain(DNS_ERR, "DNS response ID mismatch");
+    }
+    let flags = reader.u16()?;
+    if flags & RESPONSE == 0 {
+        return Error::e_explain(DNS_ERR, "not a DNS response");
+    }
+    if flags & TRUNCATED != 0 {
+        return Ok(Answer::Truncated);
+    }
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    // authority and additional records are not needed
+    reader.take(4)?;
+    // the name, type and class of the single question of the query, with the name compared case
+    // insensitively
+    let question = &query[HEADER_LEN..];
+    if questions != 1 || !reader.take(question.len())?.eq_ignore_ascii_case(question) {
+        return Error::e_explain(DNS_ERR, "DNS response question mismatch");
+    }
+    match flags & RCODE_MASK {
+        NO_ERROR => {}
+        // the host doesn't exist
+        NAME_ERROR => return Ok(Answer::Addrs(vec![], 0)),
+        rcode => return Error::e_explain(DNS_ERR, format!("DNS response code {rcode}")),
+    }
+
+    let mut addrs = vec![];
+    let mut min_ttl = u32::MAX;
+    for _ in 0..answers {
+        reader.skip_name()?;
+        let record_type = reader.u16()?;
+        let class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let len = reader.u16()? as usize;
+        let data = reader.take(len)?;
+        if class != CLASS_IN || record_type != rtype as u16 {
+            continue;
+        }
+        let addr = match (rtype, data.len()) {
+            (RecordType::A, 4) => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            (RecordType::Aaaa, 16) => {
+                let octets: [u8; 16] = data.try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Error::e_explain(DNS_ERR, "invalid address record"),
+        };
+        addrs.push(addr);
+        min_ttl = min_ttl.min(ttl);
+    }
+    if addrs.is_empty() {
+        min_ttl = 0;
+    }
+    Ok(Answer::Addrs(addrs, min_ttl))
+}
+
+/// Encode the response to `query` with the given records of (address, TTL), for the stand-in
+/// DNS servers of the tests
+#[cfg(test)]
+pub(super) fn response(query: &[u8], records: &[(IpAddr, u32)], truncated: bool) -> Vec<u8> {
+    let mut reader = Reader {
+        buf: query,
+        pos: HEADER_LEN,
+    };
+    reader.skip_name().unwrap();
+    let question_end = reader.pos + 4;
+    let rtype = u16::from_be_bytes([query[reader.pos], query[reader.pos + 1]]);
+    let records: Vec<_> = records
+        .iter()
+        .filter(|(addr, _)| match addr {
+            IpAddr::V4(_) => rtype == RecordType::A as u16,
+            IpAddr::V6(_) => rtype == RecordType::Aaaa as u16,
+        })
+        .collect();
+
+    let mut buf = query[..2].to_vec();
+    let mut flags = RESPONSE | RECURSION_DESIRED;
+    if truncated {
+        flags |= TRUNCATED;
+    }
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&[0, 1]);
+    buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(&query[HEADER_LEN..question_end]);
+    for (addr, ttl) in records {
+        // a pointer to the name in the question
+        buf.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        match addr {
+            IpAddr::V4(ip) => {
+                buf.extend_from_slice(&4u16.to_be_bytes());
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.extend_from_slice(&16u16.to_be_bytes());
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let query = query(0x1234, "www.example.com.", RecordType::Aaaa).unwrap();
+        assert_eq!(
+            query,
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+              \x03www\x07example\x03com\x00\x00\x1c\x00\x01"
+        );
+        assert!(super::query(1, "", RecordType::A).is_err());
+        assert!(super::query(1, "a..b", RecordType::A).is_err());
+        assert!(super::query(1, &"a".repeat(64), RecordType::A).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let q = query(7, "example.com", RecordType::A).unwrap();
+        let records = [
+            ("192.0.2.1".parse().unwrap(), 300),
+            ("192.0.2.2".parse().unwrap(), 60),
+            ("2001:db8::1".parse().unwrap(), 10),
+        ];
+        let resp = response(&q, &records, false);
+        assert_eq!(
+            parse_response(&resp, &q, RecordType::A).unwrap(),
+            Answer::Addrs(
+                vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()],
+                60
+            )
+        );
+        let other_id = query(8, "example.com", RecordType::A).unwrap();
+        assert!(parse_response(&resp, &other_id, RecordType::A).is_err());
+        // the query is not a response
+        assert!(parse_response(&q, &q, RecordType::A).is_err());
+        assert!(parse_response(&resp[..resp.len() - 1], &q, RecordType::A).is_err());
+
+        // the question must be the one of the query, the case of the name aside
+        let upper = query(7, "EXAMPLE.com", RecordType::A).unwrap();
+        assert!(parse_response(&resp, &upper, RecordType::A).is_ok());
+        let other_name = query(7, "example.org", RecordType::A).unwrap();
+        assert!(parse_response(&resp, &other_name, RecordType::A).is_err());
+        let other_type = query(7, "example.com", RecordType::Aaaa).unwrap();
+        assert!(parse_response(&resp, &other_type, RecordType::Aaaa).is_err());
+        let mut other_class = resp.clone();
+        other_class[HEADER_LEN + 15] = 3;
+        assert!(parse_response(&other_class, &q, RecordType::A).is_err());
+        let mut no_question = resp.clone();
+        no_question[5] = 0;
+        assert!(parse_response(&no_question, &q, RecordType::A).is_err());
+
+        let q = query(7, "example.com", RecordType::Aaaa).unwrap();
+        let resp = response(&q, &records, false);
+        assert_eq!(
+            parse_response(&resp, &q, RecordType::Aaaa).unwrap(),
+            Answer::Addrs(vec!["2001:db8::1".parse().unwrap()], 10)
+        );
+
+        let resp = response(&q, &[], true);
+        assert_eq!(
+            parse_response(&resp, &q, RecordType::Aaaa).unwrap(),
+            Answer::Truncated
+        );
+        let resp = response(&q, &[], false);
+        assert_eq!(
+            parse_response(&resp, &q, RecordType::Aaaa).unwrap(),
+            Answer::Addrs(vec![], 0)
+        );
+    }
+
+    #[test]
+    fn test_parse_response_cname() {
+        // www.example.com CNAME example.com, example.com A 192.0.2.1
+        let q = query(7, "www.example.com", RecordType::A).unwrap();
+        let mut resp = b"\x00\x07\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00".to_vec();
+        resp.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        resp.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x0e\x10\x00\x02\xc0\x10");
+        resp.extend_from_slice(b"\xc0\x10\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\xc0\x00\x02\x01");
+        assert_eq!(
+            parse_response(&resp, &q, RecordType::A).unwrap(),
+            Answer::Addrs(vec!["192.0.2.1".parse().unwrap()], 60)
+        );
+
+        // NXDOMAIN
+        resp[3] = 0x83;
+        assert_eq!(
+            parse_response(&resp, &q, RecordType::A).unwrap(),
+            Answer::Addrs(vec![], 0)
+        );
+        // SERVFAIL
+        resp[3] = 0x82;
+        assert!(parse_response(&resp, &q, RecordType::A).is_err());
+    }
+}
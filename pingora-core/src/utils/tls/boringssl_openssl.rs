@@ -15,6 +15,7 @@
 use crate::tls::{nid::Nid, pkey::PKey, pkey::Private, x509::X509};
 use crate::Result;
 use pingora_error::{ErrorType::*, OrErr};
+use sha2::{Digest, Sha256};
 use std::hash::{Hash, Hasher};
 
 fn get_subject_name(cert: &X509, name_type: Nid) -> Option<String> {
@@ -29,6 +30,17 @@ fn get_subject_name(cert: &X509, name_type: Nid) -> Option<String> {
         })
 }
 
+/// Return the SHA-256 digest of the SubjectPublicKeyInfo of the DER encoded certificate.
+pub fn get_spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    let spki = X509::from_der(cert)
+        .ok()?
+        .public_key()
+        .ok()?
+        .public_key_to_der()
+        .ok()?;
+    Some(Sha256::digest(spki).into())
+}
+
 /// Return the organization associated with the X509 certificate.
 pub fn get_organization(cert: &X509) -> Option<String> {
     get_subject_name(cert, Nid::ORGANIZATIONNAME)
@@ -44,6 +56,42 @@ pub fn get_organization_unit(cert: &X509) -> Option<String> {
     get_subject_name(cert, Nid::ORGANIZATIONALUNITNAME)
 }
 
+/// Return the subject of the X509 certificate in the form of `O=Org, CN=name`.
+pub fn get_subject(cert: &X509) -> Option<String> {
+    let entries = cert
+        .subject_name()
+        .entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            let value = String::from_utf8_lossy(entry.data().as_slice());
+            Some(format!("{key}={value}"))
+        })
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        None
+    } else {
+        Some(entries.join(", "))
+    }
+}
+
+/// Return the DNS names, IP addresses, emails and URIs of the subject alternative names of the
+/// X509 certificate.
+pub fn get_subject_alt_names(cert: &X509) -> Vec<String> {
+    let Some(names) = cert.subject_alt_names() else {
+        return vec![];
+    };
+    names
+        .iter()
+        .filter_map(|name| {
+            name.dnsname()
+                .or_else(|| name.email())
+                .or_else(|| name.uri())
+                .map(|n| n.to_string())
+                .or_else(|| name.ipaddress().and_then(super::ip_to_string))
+        })
+        .collect()
+}
+
 /// Return the serial number associated with the X509 certificate as a hexadecimal value.
 pub fn get_serial(cert: &X509) -> Result<String> {
     let bn = cert
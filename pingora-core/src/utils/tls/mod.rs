@@ -23,3 +23,14 @@ mod rustls;
 
 #[cfg(feature = "rustls")]
 pub use rustls::*;
+
+// Format the raw IP address of a subject alternative name
+#[cfg(feature = "any_tls")]
+fn ip_to_string(ip: &[u8]) -> Option<String> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    match ip.len() {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).to_string()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).to_string()),
+        _ => None,
+    }
+}
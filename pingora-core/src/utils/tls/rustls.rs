@@ -15,8 +15,15 @@
 use ouroboros::self_referencing;
 use pingora_error::Result;
 use pingora_rustls::CertificateDer;
+use sha2::{Digest, Sha256};
 use std::hash::{Hash, Hasher};
-use x509_parser::prelude::{FromDer, X509Certificate};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// Return the SHA-256 digest of the SubjectPublicKeyInfo of the DER encoded certificate.
+pub fn get_spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    Some(Sha256::digest(cert.tbs_certificate.subject_pki.raw).into())
+}
 
 /// Get the organization and serial number associated with the given certificate
 /// see https://en.wikipedia.org/wiki/X.509#Structure_of_a_certificate
@@ -88,6 +95,47 @@ pub fn get_common_name(x509cert: &WrappedX509) -> Option<String> {
         .reduce(|cur, next| cur + &next)
 }
 
+/// Return the subject of the X509 certificate in the form of `O=Org, CN=name`.
+pub fn get_subject(x509cert: &WrappedX509) -> Option<String> {
+    get_subject_x509(x509cert.borrow_cert())
+}
+
+/// Return the subject of the X509 certificate in the form of `O=Org, CN=name`.
+pub fn get_subject_x509(x509cert: &X509Certificate<'_>) -> Option<String> {
+    let subject = x509cert.subject.to_string();
+    if subject.is_empty() {
+        None
+    } else {
+        Some(subject)
+    }
+}
+
+/// Return the DNS names, IP addresses, emails and URIs of the subject alternative names of the
+/// X509 certificate.
+pub fn get_subject_alt_names(x509cert: &WrappedX509) -> Vec<String> {
+    get_subject_alt_names_x509(x509cert.borrow_cert())
+}
+
+/// Return the DNS names, IP addresses, emails and URIs of the subject alternative names of the
+/// X509 certificate.
+pub fn get_subject_alt_names_x509(x509cert: &X509Certificate<'_>) -> Vec<String> {
+    let Ok(Some(names)) = x509cert.subject_alternative_name() else {
+        return vec![];
+    };
+    names
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(n) | GeneralName::RFC822Name(n) | GeneralName::URI(n) => {
+                Some(n.to_string())
+            }
+            GeneralName::IPAddress(ip) => super::ip_to_string(ip),
+            _ => None,
+        })
+        .collect()
+}
+
 /// Get the `not_after` field for the valid time period for the given cert
 /// see https://en.wikipedia.org/wiki/X.509#Structure_of_a_certificate
 pub fn get_not_after(x509cert: &WrappedX509) -> String {
@@ -145,6 +145,12 @@ pub enum ErrorType {
     /// Custom error with static string and code.
     /// this field allows users to extend error further with error codes.
     CustomCode(&'static str, u16),
+    // new variants are appended to keep the order of the existing ones
+    CertPinMismatch, // cert does not match the SPKI pins
+    InvalidProxyProtocol,
+    H3Error,             // catch all
+    Socks5AuthError,     // no acceptable auth method or the credentials are rejected
+    Socks5ProtocolError, // not SOCKS5 or malformed replies
 }
 
 impl ErrorType {
@@ -193,6 +199,11 @@ impl ErrorType {
             ErrorType::UnknownError => "UnknownError",
             ErrorType::Custom(s) => s,
             ErrorType::CustomCode(s, _) => s,
+            ErrorType::CertPinMismatch => "CertPinMismatch",
+            ErrorType::InvalidProxyProtocol => "InvalidProxyProtocol",
+            ErrorType::H3Error => "H3Error",
+            ErrorType::Socks5AuthError => "Socks5AuthError",
+            ErrorType::Socks5ProtocolError => "Socks5ProtocolError",
         }
     }
 }
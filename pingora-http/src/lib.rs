@@ -192,6 +192,13 @@ impl RequestHeader {
         self.base.method = method;
     }
 
+    /// Return a mutable reference to the extensions of the request
+    ///
+    /// The extensions are not kept when the header is cloned.
+    pub fn extensions_mut(&mut self) -> &mut http::Extensions {
+        &mut self.base.extensions
+    }
+
     /// Set the request URI
     pub fn set_uri(&mut self, uri: http::Uri) {
         self.base.uri = uri;
@@ -203,13 +210,25 @@ impl RequestHeader {
     ///
     /// Generally prefer [Self::set_uri()] to modify the header's URI if able.
     ///
+    /// Besides paths, the absolute form (`http://example.com/path`) of the requests to proxies and
+    /// the authority form (`example.com:443`) of `CONNECT` requests are accepted, see RFC 9112 3.2.
+    /// The Host header is left as is: servers that are not proxies should use the authority of an
+    /// absolute-form URI instead of it, see RFC 9112 3.2.2.
+    ///
     /// This API is to allow supporting non UTF-8 cases.
     pub fn set_raw_path(&mut self, path: &[u8]) -> Result<()> {
         if let Ok(p) = std::str::from_utf8(path) {
-            let uri = Uri::builder()
-                .path_and_query(p)
-                .build()
-                .explain_err(InvalidHTTPHeader, |_| format!("invalid uri {}", p))?;
+            let uri = match Uri::builder().path_and_query(p).build() {
+                Ok(uri) => Ok(uri),
+                Err(e) => match p.parse::<Uri>() {
+                    Ok(uri) if uri.scheme().is_some() && uri.authority().is_some() => Ok(uri),
+                    Ok(uri) if self.base.method == Method::CONNECT && uri.path().is_empty() => {
+                        Ok(uri)
+                    }
+                    _ => Err(e),
+                },
+            }
+            .explain_err(InvalidHTTPHeader, |_| format!("invalid uri {}", p))?;
             self.base.uri = uri;
             // keep raw_path empty, no need to store twice
         } else {
@@ -245,15 +264,14 @@ impl RequestHeader {
     pub fn raw_path(&self) -> &[u8] {
         if !self.raw_path_fallback.is_empty() {
             &self.raw_path_fallback
+        } else if let Some(path) = self.base.uri.path_and_query() {
+            path.as_str().as_bytes()
         } else {
-            // Url should always be set
+            // the authority form of CONNECT requests
             self.base
                 .uri
-                .path_and_query()
-                .as_ref()
-                .unwrap()
-                .as_str()
-                .as_bytes()
+                .authority()
+                .map_or(b"", |a| a.as_str().as_bytes())
         }
     }
 
@@ -657,6 +675,22 @@ mod tests {
         assert_eq!(4096, http_header_map_upper_bound(Some(7777)));
     }
 
+    #[test]
+    fn test_request_target_forms() {
+        let req = RequestHeader::build("GET", b"http://pingora.org/a?b", None).unwrap();
+        assert_eq!(req.uri.scheme_str(), Some("http"));
+        assert_eq!(req.uri.host(), Some("pingora.org"));
+        assert_eq!(req.raw_path(), b"/a?b");
+
+        let req = RequestHeader::build("CONNECT", b"pingora.org:443", None).unwrap();
+        assert_eq!(req.uri.host(), Some("pingora.org"));
+        assert_eq!(req.uri.port_u16(), Some(443));
+        assert_eq!(req.raw_path(), b"pingora.org:443");
+
+        // the authority form is only for CONNECT
+        assert!(RequestHeader::build("GET", b"pingora.org:443", None).is_err());
+    }
+
     #[test]
     fn test_single_header() {
         let mut req = RequestHeader::build("GET", b"\\", None).unwrap();
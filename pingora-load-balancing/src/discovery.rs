@@ -16,8 +16,10 @@
 
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
+use futures::future::try_join_all;
 use http::Extensions;
 use pingora_core::protocols::l4::socket::SocketAddr;
+use pingora_core::upstreams::resolver::Resolver;
 use pingora_error::Result;
 use std::io::Result as IoResult;
 use std::net::ToSocketAddrs;
@@ -37,7 +39,51 @@ pub trait ServiceDiscovery {
     async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)>;
 }
 
-// TODO: add DNS base discovery
+/// Discover the [Backend]s at all the addresses of a list of hosts, looked up with a [Resolver].
+///
+/// The discovery fails, so that the current backends are kept, if any of the hosts can't be
+/// resolved even with the stale addresses cached by the [Resolver].
+pub struct Dns {
+    resolver: Arc<Resolver>,
+    hosts: Vec<(String, u16)>,
+}
+
+impl Dns {
+    /// Create a new boxed [Dns] service discovery for the given (host, port) pairs.
+    pub fn new<H, T>(resolver: Arc<Resolver>, hosts: T) -> Box<Self>
+    where
+        H: Into<String>,
+        T: IntoIterator<Item = (H, u16)>,
+    {
+        Box::new(Dns {
+            resolver,
+            hosts: hosts
+                .into_iter()
+                .map(|(host, port)| (host.into(), port))
+                .collect(),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for Dns {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let lookups = self
+            .hosts
+            .iter()
+            .map(|(host, port)| self.resolver.lookup(host, *port));
+        let mut backends = BTreeSet::new();
+        for addrs in try_join_all(lookups).await? {
+            backends.extend(addrs.into_iter().map(|addr| Backend {
+                addr: SocketAddr::Inet(addr),
+                weight: 1,
+                ext: Extensions::new(),
+            }));
+        }
+        // no readiness
+        Ok((backends, HashMap::new()))
+    }
+}
 
 /// A static collection of [Backend]s for service discovery.
 #[derive(Default)]
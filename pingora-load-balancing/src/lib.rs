@@ -435,6 +435,26 @@ mod test {
         assert!(backend.contains(&backend2));
     }
 
+    #[tokio::test]
+    async fn test_dns_backends() {
+        use pingora_core::upstreams::resolver::Resolver;
+
+        let resolver = Arc::new(Resolver::new());
+        let discovery =
+            discovery::Dns::new(resolver.clone(), [("localhost", 80), ("127.0.0.2", 81)]);
+        let backends = Backends::new(discovery);
+        backends.update(|_| {}).await.unwrap();
+        let backends = backends.get_backend();
+        assert!(backends.contains(&Backend::new("127.0.0.2:81").unwrap()));
+        assert!(backends.iter().any(|b| b
+            .addr
+            .as_inet()
+            .is_some_and(|a| a.ip().is_loopback() && a.port() == 80)));
+
+        let discovery = discovery::Dns::new(resolver, [("localhost", 80), ("invalid..", 80)]);
+        assert!(Backends::new(discovery).update(|_| {}).await.is_err());
+    }
+
     #[tokio::test]
     async fn test_backends() {
         let discovery = discovery::Static::default();
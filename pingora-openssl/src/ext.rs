@@ -15,17 +15,19 @@
 use foreign_types::ForeignTypeRef;
 use libc::*;
 use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
 use openssl::pkey::{HasPrivate, PKeyRef};
-use openssl::ssl::{Ssl, SslAcceptor, SslRef};
-use openssl::x509::store::X509StoreRef;
-use openssl::x509::verify::X509VerifyParamRef;
+use openssl::ssl::{Ssl, SslAcceptor, SslContext, SslContextBuilder, SslFiletype, SslRef};
+use openssl::x509::store::{X509Lookup, X509StoreBuilderRef, X509StoreRef};
+use openssl::x509::verify::{X509VerifyFlags, X509VerifyParamRef};
 use openssl::x509::X509Ref;
 use openssl_sys::{
-    SSL_ctrl, EVP_PKEY, SSL, SSL_CTRL_SET_GROUPS_LIST, SSL_CTRL_SET_VERIFY_CERT_STORE, X509,
-    X509_VERIFY_PARAM,
+    SSL_ctrl, EVP_CIPHER_CTX, EVP_PKEY, HMAC_CTX, SSL, SSL_CTRL_SET_GROUPS_LIST,
+    SSL_CTRL_SET_VERIFY_CERT_STORE, SSL_CTX, X509, X509_VERIFY_PARAM,
 };
 use std::ffi::CString;
 use std::os::raw;
+use std::sync::OnceLock;
 
 fn cvt(r: c_long) -> Result<c_long, ErrorStack> {
     if r != 1 {
@@ -52,6 +54,8 @@ extern "C" {
         >,
         arg: *mut raw::c_void,
     );
+
+    pub fn SSL_CTX_set_timeout(ctx: *mut SSL_CTX, t: c_long) -> c_long;
 }
 
 /// Add name as an additional reference identifier that can match the peer's certificate
@@ -89,6 +93,20 @@ pub fn ssl_set_verify_cert_store(
     Ok(())
 }
 
+/// Load the CRLs in the PEM file at `path` into `store` and check the leaf certificates
+/// verified with `store` against them
+///
+/// See [X509_load_crl_file](https://www.openssl.org/docs/man1.1.1/man3/X509_load_crl_file.html).
+pub fn x509_store_add_crl_file(
+    store: &mut X509StoreBuilderRef,
+    path: &str,
+) -> Result<(), ErrorStack> {
+    store
+        .add_lookup(X509Lookup::file())?
+        .load_crl_file(path, SslFiletype::PEM)?;
+    store.set_flags(X509VerifyFlags::CRL_CHECK)
+}
+
 /// Load the certificate into `ssl`
 ///
 /// See [SSL_use_certificate](https://www.openssl.org/docs/man1.1.1/man3/SSL_use_certificate.html).
@@ -157,6 +175,127 @@ pub fn ssl_set_groups_list(ssl: &mut SslRef, groups: &str) -> Result<(), ErrorSt
 /// This function is specific to BoringSSL. This function is noop for OpenSSL.
 pub fn ssl_use_second_key_share(_ssl: &mut SslRef, _enabled: bool) {}
 
+/// The length of a session ticket key: a 16-byte key name, a 32-byte HMAC-SHA256 secret and a
+/// 32-byte AES-256 key, in this order
+pub const TICKET_KEY_LEN: usize = 80;
+
+type TicketKeyLookup = Box<dyn Fn(Option<&[u8]>) -> Option<[u8; TICKET_KEY_LEN]> + Send + Sync>;
+
+static TICKET_KEY_INDEX: OnceLock<Index<SslContext, TicketKeyLookup>> = OnceLock::new();
+
+/// Encrypt and decrypt the session tickets of `ctx` with the keys given by `lookup`
+///
+/// `lookup(None)` returns the key to encrypt new tickets with, `lookup(Some(name))` the key with
+/// the given name to decrypt a ticket with. Tickets decrypted with a key other than the current
+/// one are renewed, tickets of unknown keys fall back to full handshakes. Clients are told that
+/// tickets are valid for `lifetime` seconds, which is also the session timeout of `ctx`.
+///
+/// See [SSL_CTX_set_tlsext_ticket_key_cb](https://www.openssl.org/docs/man3.1/man3/SSL_CTX_set_tlsext_ticket_key_cb.html).
+pub fn ssl_ctx_set_ticket_key_callback<F>(
+    ctx: &mut SslContextBuilder,
+    lookup: F,
+    lifetime: u32,
+) -> Result<(), ErrorStack>
+where
+    F: Fn(Option<&[u8]>) -> Option<[u8; TICKET_KEY_LEN]> + Send + Sync + 'static,
+{
+    const SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB: c_int = 72;
+    let index = *TICKET_KEY_INDEX.get_or_init(|| SslContext::new_ex_index().unwrap());
+    let lookup: TicketKeyLookup = Box::new(lookup);
+    ctx.set_ex_data(index, lookup);
+    type Callback = unsafe extern "C" fn(
+        *mut SSL,
+        *mut c_uchar,
+        *mut c_uchar,
+        *mut EVP_CIPHER_CTX,
+        *mut HMAC_CTX,
+        c_int,
+    ) -> c_int;
+    unsafe {
+        SSL_CTX_set_timeout(ctx.as_ptr(), lifetime as c_long);
+        // safety: OpenSSL calls back with the signature of SSL_CTX_set_tlsext_ticket_key_cb
+        let callback = std::mem::transmute::<Callback, unsafe extern "C" fn()>(raw_ticket_key);
+        cvt(openssl_sys::SSL_CTX_callback_ctrl__fixed_rust(
+            ctx.as_ptr(),
+            SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB,
+            Some(callback),
+        ))
+        .map(|_| ())
+    }
+}
+
+unsafe extern "C" fn raw_ticket_key(
+    ssl: *mut SSL,
+    key_name: *mut c_uchar,
+    iv: *mut c_uchar,
+    cipher_ctx: *mut EVP_CIPHER_CTX,
+    hmac_ctx: *mut HMAC_CTX,
+    enc: c_int,
+) -> c_int {
+    let ssl = SslRef::from_ptr(ssl);
+    let Some(lookup) = TICKET_KEY_INDEX
+        .get()
+        .and_then(|index| ssl.ssl_context().ex_data(*index))
+    else {
+        return -1;
+    };
+    let name = std::slice::from_raw_parts_mut(key_name, 16);
+
+    if enc == 1 {
+        let Some(key) = lookup(None) else {
+            // no ticket is issued
+            return 0;
+        };
+        name.copy_from_slice(&key[..16]);
+        if openssl_sys::RAND_bytes(iv, 16) != 1
+            || openssl_sys::EVP_EncryptInit_ex(
+                cipher_ctx,
+                openssl_sys::EVP_aes_256_cbc(),
+                std::ptr::null_mut(),
+                key[48..].as_ptr(),
+                iv,
+            ) != 1
+            || openssl_sys::HMAC_Init_ex(
+                hmac_ctx,
+                key[16..48].as_ptr() as *const c_void,
+                32,
+                openssl_sys::EVP_sha256(),
+                std::ptr::null_mut(),
+            ) != 1
+        {
+            return -1;
+        }
+        1
+    } else {
+        let Some(key) = lookup(Some(name)) else {
+            // unknown key, fall back to a full handshake
+            return 0;
+        };
+        if openssl_sys::HMAC_Init_ex(
+            hmac_ctx,
+            key[16..48].as_ptr() as *const c_void,
+            32,
+            openssl_sys::EVP_sha256(),
+            std::ptr::null_mut(),
+        ) != 1
+            || openssl_sys::EVP_DecryptInit_ex(
+                cipher_ctx,
+                openssl_sys::EVP_aes_256_cbc(),
+                std::ptr::null_mut(),
+                key[48..].as_ptr(),
+                iv,
+            ) != 1
+        {
+            return -1;
+        }
+        match lookup(None) {
+            Some(current) if current[..16] == *name => 1,
+            // renew the tickets of the previous keys
+            _ => 2,
+        }
+    }
+}
+
 /// Clear the error stack
 ///
 /// SSL calls should check and clear the OpenSSL error stack. But some calls fail to do so.
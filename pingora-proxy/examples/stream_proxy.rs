@@ -0,0 +1,72 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use clap::Parser;
+use log::info;
+use std::time::Duration;
+
+use pingora_core::server::configuration::Opt;
+use pingora_core::server::Server;
+use pingora_core::upstreams::peer::BasicPeer;
+use pingora_core::Result;
+use pingora_error::Error;
+use pingora_proxy::{ProxyStream, StreamSession};
+
+pub struct MyStreamProxy;
+
+#[async_trait]
+impl ProxyStream for MyStreamProxy {
+    type CTX = ();
+    fn new_ctx(&self) -> Self::CTX {}
+
+    async fn upstream_peer(
+        &self,
+        session: &mut StreamSession,
+        _ctx: &mut Self::CTX,
+    ) -> Result<Box<BasicPeer>> {
+        // close connections that stay silent for too long
+        session.idle_timeout = Some(Duration::from_secs(60));
+        Ok(Box::new(BasicPeer::new("1.1.1.1:80")))
+    }
+
+    async fn logging(&self, session: &mut StreamSession, e: Option<&Error>, _ctx: &mut Self::CTX) {
+        let digest = session.digest();
+        info!(
+            "{:?} closed after {:?}, sent: {}, received: {}, error: {:?}",
+            session.client_addr(),
+            session.elapsed(),
+            digest.downstream_bytes,
+            digest.upstream_bytes,
+            e
+        );
+    }
+}
+
+// RUST_LOG=INFO cargo run --example stream_proxy
+// curl 127.0.0.1:6196 -H "Host: one.one.one.one"
+fn main() {
+    env_logger::init();
+
+    // read command line arguments
+    let opt = Opt::parse();
+    let mut my_server = Server::new(Some(opt)).unwrap();
+    my_server.bootstrap();
+
+    let mut my_proxy = pingora_proxy::stream_proxy_service(&my_server.configuration, MyStreamProxy);
+    my_proxy.add_tcp("0.0.0.0:6196");
+
+    my_server.add_service(my_proxy);
+    my_server.run_forever();
+}
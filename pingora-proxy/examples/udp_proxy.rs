@@ -0,0 +1,46 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::Parser;
+use pingora_core::services::background::background_service;
+use std::time::Duration;
+
+use pingora_core::server::configuration::Opt;
+use pingora_core::server::Server;
+use pingora_load_balancing::{selection::RoundRobin, LoadBalancer};
+use pingora_proxy::UdpProxy;
+
+// RUST_LOG=INFO cargo run --example udp_proxy
+// dig @127.0.0.1 -p 6197 one.one.one.one
+fn main() {
+    env_logger::init();
+
+    // read command line arguments
+    let opt = Opt::parse();
+    let mut my_server = Server::new(Some(opt)).unwrap();
+    my_server.bootstrap();
+
+    let upstreams: LoadBalancer<RoundRobin> =
+        LoadBalancer::try_from_iter(["1.1.1.1:53", "1.0.0.1:53"]).unwrap();
+    let background = background_service("udp discovery", upstreams);
+
+    let mut dns = UdpProxy::new("DNS proxy", background.task());
+    // DNS queries are short lived
+    dns.idle_timeout = Duration::from_secs(5);
+    dns.add_udp("0.0.0.0:6197");
+
+    my_server.add_service(dns);
+    my_server.add_service(background);
+    my_server.run_forever();
+}
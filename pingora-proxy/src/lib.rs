@@ -24,6 +24,9 @@
 //! - Dynamic upstream selection
 //! - Configurable retry and failover
 //! - Fully programmable and customizable at any stage of a HTTP request
+//! - Generic L4 (TCP/UDS) stream proxying via [ProxyStream]
+//! - UDP datagram proxying via [UdpProxy]
+//! - Forward proxying of absolute-form requests and `CONNECT` tunnels, see [ProxyHttp::is_forward_proxy()]
 //!
 //! # How to use
 //!
@@ -51,6 +54,8 @@ use tokio::time;
 use pingora_cache::NoCacheReason;
 use pingora_core::apps::{HttpServerApp, HttpServerOptions};
 use pingora_core::connectors::{http::Connector, ConnectorOptions};
+#[cfg(feature = "acme")]
+use pingora_core::listeners::tls::AcmeChallenges;
 use pingora_core::modules::http::compression::ResponseCompressionBuilder;
 use pingora_core::modules::http::{HttpModuleCtx, HttpModules};
 use pingora_core::protocols::http::client::HttpSession as ClientSession;
@@ -69,20 +74,35 @@ const TASK_BUFFER_SIZE: usize = 4;
 
 mod proxy_cache;
 mod proxy_common;
+mod proxy_forward;
 mod proxy_h1;
 mod proxy_h2;
+#[cfg(feature = "quic")]
+mod proxy_h3;
 mod proxy_purge;
+mod proxy_stream;
 mod proxy_trait;
+mod proxy_udp;
+mod proxy_websocket;
 mod subrequest;
 
 use subrequest::Ctx as SubReqCtx;
 
 pub use proxy_cache::range_filter::{range_header_filter, RangeType};
+pub use proxy_forward::ForwardDestination;
 pub use proxy_purge::PurgeStatus;
+pub use proxy_stream::{
+    stream_proxy_service, stream_proxy_service_with_name, ProxyStream, StreamDigest, StreamProxy,
+    StreamProxyOptions, StreamSession,
+};
 pub use proxy_trait::ProxyHttp;
+pub use proxy_udp::UdpProxy;
+pub use proxy_websocket::WebSocketDirection;
 
 pub mod prelude {
-    pub use crate::{http_proxy_service, ProxyHttp, Session};
+    pub use crate::{
+        http_proxy_service, stream_proxy_service, ProxyHttp, ProxyStream, Session, StreamSession,
+    };
 }
 
 /// The concrete type that holds the user defined HTTP proxy.
@@ -94,6 +114,14 @@ pub struct HttpProxy<SV> {
     shutdown: Notify,
     pub server_options: Option<HttpServerOptions>,
     pub downstream_modules: HttpModules,
+    /// The idle and total timeouts of the `CONNECT` tunnels of a forward proxy, see
+    /// [ProxyHttp::is_forward_proxy()]. No timeout by default. The ClientHello timeout is not
+    /// used by tunnels.
+    pub tunnel_options: StreamProxyOptions,
+    /// The pending ACME challenges whose HTTP-01 requests this proxy answers before any filter
+    /// runs. See [AcmeChallenges].
+    #[cfg(feature = "acme")]
+    pub acme_challenges: Option<Arc<AcmeChallenges>>,
     max_retries: usize,
 }
 
@@ -105,6 +133,9 @@ impl<SV> HttpProxy<SV> {
             shutdown: Notify::new(),
             server_options: None,
             downstream_modules: HttpModules::new(),
+            tunnel_options: StreamProxyOptions::default(),
+            #[cfg(feature = "acme")]
+            acme_challenges: None,
             max_retries: conf.max_retries,
         }
     }
@@ -225,6 +256,27 @@ impl<SV> HttpProxy<SV> {
 
                         (server_reused, error)
                     }
+                    #[cfg(feature = "quic")]
+                    ClientSession::H3(mut h3) => {
+                        let (server_reused, error) = self
+                            .proxy_to_h3_upstream(session, &mut h3, client_reused, &peer, ctx)
+                            .await;
+                        let session = ClientSession::H3(h3);
+                        self.client_upstream
+                            .release_http_session(session, &*peer, peer.idle_timeout())
+                            .await;
+                        (server_reused, error)
+                    }
+                    // pingora-core is built with quic but this crate is not
+                    #[cfg(not(feature = "quic"))]
+                    #[allow(unreachable_patterns)]
+                    _ => (
+                        false,
+                        Some(Error::explain(
+                            InternalError,
+                            "h3 upstreams require the quic feature of pingora-proxy",
+                        )),
+                    ),
                 };
                 (
                     server_reused,
@@ -288,6 +340,43 @@ impl<SV> HttpProxy<SV> {
             None
         }
     }
+
+    // Answer an ACME HTTP-01 challenge request with its key authorization, see RFC 8555 8.3
+    #[cfg(feature = "acme")]
+    async fn respond_acme_challenge(
+        &self,
+        mut session: Session,
+        mut ctx: SV::CTX,
+        key_auth: String,
+    ) -> Option<Stream>
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let mut resp = ResponseHeader::build(200, Some(3)).unwrap();
+        resp.insert_header(header::SERVER, &SERVER_NAME[..])
+            .unwrap();
+        resp.insert_header(header::CONTENT_TYPE, "application/octet-stream")
+            .unwrap();
+        resp.insert_header(header::CONTENT_LENGTH, key_auth.len())
+            .unwrap();
+        let res = match session
+            .downstream_session
+            .write_response_header(Box::new(resp))
+            .await
+        {
+            Ok(()) => {
+                session
+                    .downstream_session
+                    .write_response_body(key_auth.into(), true)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let error = res.err();
+        self.finish(session, &mut ctx, error.is_none(), error.as_deref())
+            .await
+    }
 }
 
 use pingora_cache::HttpCache;
@@ -310,6 +399,12 @@ pub struct Session {
     subrequest_ctx: Option<Box<SubReqCtx>>,
     // Downstream filter modules
     pub downstream_modules_ctx: HttpModuleCtx,
+    // the destination of a forward proxy request
+    forward_destination: Option<ForwardDestination>,
+    // the byte counters of a CONNECT tunnel
+    tunnel_digest: Option<StreamDigest>,
+    // the message inspection of a WebSocket session
+    websocket: Option<proxy_websocket::WebSocketCtx>,
 }
 
 impl Session {
@@ -325,6 +420,9 @@ impl Session {
             ignore_downstream_range: false,
             subrequest_ctx: None,
             downstream_modules_ctx: downstream_modules.build_ctx(),
+            forward_destination: None,
+            tunnel_digest: None,
+            websocket: None,
         }
     }
 
@@ -351,6 +449,16 @@ impl Session {
         &self.downstream_session
     }
 
+    /// The destination of this forward proxy request, see [ProxyHttp::is_forward_proxy()]
+    pub fn forward_destination(&self) -> Option<&ForwardDestination> {
+        self.forward_destination.as_ref()
+    }
+
+    /// The byte counters and timing of the `CONNECT` tunnel of this request, if one was established
+    pub fn tunnel_digest(&self) -> Option<&StreamDigest> {
+        self.tunnel_digest.as_ref()
+    }
+
     /// Write HTTP response with the given error code to the downstream.
     pub async fn respond_error(&mut self, error: u16) -> Result<()> {
         self.as_downstream_mut().respond_error(error).await
@@ -482,6 +590,15 @@ impl<SV> HttpProxy<SV> {
         SV: ProxyHttp + Send + Sync + 'static,
         <SV as ProxyHttp>::CTX: Send + Sync,
     {
+        #[cfg(feature = "acme")]
+        if let Some(key_auth) = self
+            .acme_challenges
+            .as_ref()
+            .and_then(|challenges| challenges.http_01_response(session.req_header().uri.path()))
+        {
+            return self.respond_acme_challenge(session, ctx, key_auth).await;
+        }
+
         if let Err(e) = self
             .inner
             .early_request_filter(&mut session, &mut ctx)
@@ -526,6 +643,17 @@ impl<SV> HttpProxy<SV> {
             }
         }
 
+        if self.inner.is_forward_proxy(&session, &ctx) {
+            if let Some((reuse, err)) = self.proxy_forward(&mut session, &mut ctx).await {
+                // refused or tunnelled
+                return self.finish(session, &mut ctx, reuse, err.as_deref()).await;
+            }
+        } else if let Err(e) = proxy_forward::reverse_proxy_form(session.req_header_mut()) {
+            self.handle_error(&mut session, &mut ctx, e, "Fail to filter request:")
+                .await;
+            return None;
+        }
+
         if let Some((reuse, err)) = self.proxy_cache(&mut session, &mut ctx).await {
             // cache hit
             return self.finish(session, &mut ctx, reuse, err.as_deref()).await;
@@ -0,0 +1,736 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Forward proxying: requests with an absolute-form URI and `CONNECT` tunnels
+
+use super::*;
+use http::{Method, Uri};
+use proxy_stream::{duplex, split, TunnelRead, TunnelWrite};
+use std::time::{Duration, Instant};
+
+/// The destination of a forward proxy request, see [ProxyHttp::is_forward_proxy()]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardDestination {
+    /// The host name or IP address, without the brackets of IPv6 addresses
+    pub host: String,
+    /// The port, the default one of the scheme if the URI has none
+    pub port: u16,
+    /// Whether the request is for an `https` URI, which should be sent over TLS. Always false
+    /// for `CONNECT` requests, whose tunnelled data is forwarded as is.
+    pub tls: bool,
+}
+
+impl ForwardDestination {
+    // The destination of a request with an absolute-form or an authority-form (CONNECT) URI.
+    // None for the requests to the proxy itself.
+    fn from_request(req: &RequestHeader) -> Option<Result<Self>> {
+        let uri = &req.uri;
+        let host = uri
+            .host()
+            .map(|h| h.trim_start_matches('[').trim_end_matches(']'));
+        if req.method == Method::CONNECT {
+            return Some(match (host, uri.port_u16()) {
+                (Some(host), Some(port)) => Ok(ForwardDestination {
+                    host: host.to_string(),
+                    port,
+                    tls: false,
+                }),
+                _ => Error::e_explain(InvalidHTTPHeader, "CONNECT request without host and port"),
+            });
+        }
+        let host = host?;
+        let tls = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            _ => {
+                return Some(Error::e_explain(
+                    InvalidHTTPHeader,
+                    format!("unsupported scheme in {uri}"),
+                ))
+            }
+        };
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        Some(Ok(ForwardDestination {
+            host: host.to_string(),
+            port,
+            tls,
+        }))
+    }
+}
+
+// Send the request in origin form, with the Host of its URI, see RFC 9112 3.2.2
+fn to_origin_form(req: &mut RequestHeader) -> Result<()> {
+    let authority = req
+        .uri
+        .authority()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    req.insert_header(header::HOST, authority)?;
+    let path = req
+        .uri
+        .path_and_query()
+        .map_or("/", |p| p.as_str())
+        .to_string();
+    let uri = Uri::builder()
+        .path_and_query(path)
+        .build()
+        .explain_err(InvalidHTTPHeader, |_| "invalid path")?;
+    req.set_uri(uri);
+    Ok(())
+}
+
+// A server that is not a forward proxy uses the authority of an absolute-form URI instead of the
+// Host header, see RFC 9112 3.2.2. The request is rewritten so that the filters, the cache key
+// and the upstream request all agree on the Host.
+pub(crate) fn reverse_proxy_form(req: &mut RequestHeader) -> Result<()> {
+    // the URIs of h2 requests always have an authority, from their :authority pseudo header
+    if req.version >= http::Version::HTTP_2
+        || req.method == Method::CONNECT
+        || req.uri.authority().is_none()
+    {
+        return Ok(());
+    }
+    to_origin_form(req)
+}
+
+impl<SV> HttpProxy<SV> {
+    // Handle a forward proxy request. Return Some when the request is finished here, because it
+    // is refused or tunnelled, along with whether the downstream connection can be reused.
+    pub(crate) async fn proxy_forward(
+        &self,
+        session: &mut Session,
+        ctx: &mut SV::CTX,
+    ) -> Option<(bool, Option<Box<Error>>)>
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        // an extended CONNECT (e.g. WebSocket over h2) is for the origin, not a tunnel
+        if session.connect_protocol().is_some() {
+            return None;
+        }
+        let destination = match ForwardDestination::from_request(session.req_header())? {
+            Ok(destination) => destination,
+            Err(e) => return Some(self.fail_forward(session, ctx, e.into_down()).await),
+        };
+        session.forward_destination = Some(destination.clone());
+
+        match self
+            .inner
+            .forward_destination_filter(session, &destination, ctx)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Forward proxy destination refused: {destination:?}");
+                if session.response_written().is_some() {
+                    return Some((true, None));
+                }
+                let res = session.respond_error(403).await;
+                return Some((res.is_ok(), res.err().map(|e| e.into_down())));
+            }
+            Err(e) => return Some(self.fail_forward(session, ctx, e).await),
+        }
+
+        if session.req_header().method == Method::CONNECT {
+            return Some(self.proxy_tunnel(session, ctx).await);
+        }
+        if let Err(e) = to_origin_form(session.req_header_mut()) {
+            return Some(self.fail_forward(session, ctx, e).await);
+        }
+        // proxied like any other request from now on
+        None
+    }
+
+    async fn fail_forward(
+        &self,
+        session: &mut Session,
+        ctx: &mut SV::CTX,
+        e: Box<Error>,
+    ) -> (bool, Option<Box<Error>>)
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let status = self.inner.fail_to_proxy(session, &e, ctx).await;
+        if !self.inner.suppress_error_log(session, ctx, &e) {
+            error!(
+                "Fail to proxy: {}, status: {}, {}",
+                e,
+                status,
+                self.inner.request_summary(session, ctx)
+            );
+        }
+        (false, Some(e))
+    }
+
+    async fn connect_tunnel(
+        &self,
+        session: &mut Session,
+        ctx: &mut SV::CTX,
+    ) -> Result<(Stream, Box<HttpPeer>)>
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let mut retries: usize = 0;
+        loop {
+            retries += 1;
+            let peer = self.inner.upstream_peer(session, ctx).await?;
+            match self.client_upstream.transport().new_stream(&*peer).await {
+                Ok(stream) => return Ok((stream, peer)),
+                Err(mut e) => {
+                    e.as_up();
+                    let e = self.inner.fail_to_connect(session, &peer, ctx, e).into_up();
+                    if !e.retry() || retries >= self.max_retries {
+                        return Err(e);
+                    }
+                    warn!(
+                        "Fail to connect: {}, tries: {}, {}",
+                        e,
+                        retries,
+                        self.inner.request_summary(session, ctx)
+                    );
+                }
+            }
+        }
+    }
+
+    // Connect to the upstream, answer the CONNECT request with 200 and forward the data in both
+    // directions
+    async fn proxy_tunnel(
+        &self,
+        session: &mut Session,
+        ctx: &mut SV::CTX,
+    ) -> (bool, Option<Box<Error>>)
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let (mut upstream, peer) = match self.connect_tunnel(session, ctx).await {
+            Ok(connected) => connected,
+            Err(e) => return self.fail_forward(session, ctx, e).await,
+        };
+        let digest = Digest {
+            ssl_digest: upstream.get_ssl_digest(),
+            timing_digest: upstream.get_timing_digest(),
+            proxy_digest: upstream.get_proxy_digest(),
+            socket_digest: upstream.get_socket_digest(),
+        };
+        #[cfg(windows)]
+        let raw = upstream.id() as std::os::windows::io::RawSocket;
+        #[cfg(unix)]
+        let raw = upstream.id();
+        if let Err(e) = self
+            .inner
+            .connected_to_upstream(session, false, &peer, raw, Some(&digest), ctx)
+            .await
+        {
+            return self.fail_forward(session, ctx, e).await;
+        }
+        session.tunnel_digest = Some(StreamDigest {
+            upstream_connected: Some(Instant::now()),
+            ..Default::default()
+        });
+
+        let resp = ResponseHeader::build(200, Some(1)).unwrap();
+        let mut res = session
+            .write_response_header(Box::new(resp), false)
+            .await
+            .map_err(|e| e.into_down());
+        if res.is_ok() {
+            res = tunnel(session, &mut upstream, &self.tunnel_options).await;
+        }
+        if let Err(e) = res.as_ref() {
+            // the tunnel is established, no error response can be sent anymore
+            if !self.inner.suppress_error_log(session, ctx, e) {
+                error!(
+                    "Fail to tunnel: {}, {}",
+                    e,
+                    self.inner.request_summary(session, ctx)
+                );
+            }
+        }
+        (false, res.err())
+    }
+}
+
+// Forward data in both directions until both sides are done, an error occurs or a timeout of
+// the options is reached.
+async fn tunnel(
+    session: &mut Session,
+    upstream: &mut Stream,
+    options: &StreamProxyOptions,
+) -> Result<()> {
+    let tunnel = tunnel_data(session, upstream, options.idle_timeout);
+    match options.total_timeout {
+        Some(t) => match time::timeout(t, tunnel).await {
+            Ok(res) => res,
+            Err(_) => Error::e_explain(ReadTimedout, format!("total timeout of {t:?} reached")),
+        },
+        None => tunnel.await,
+    }
+}
+
+async fn tunnel_data(
+    session: &mut Session,
+    upstream: &mut Stream,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    // unlike a stream, the downstream HTTP session can't be split into halves, so it is driven on
+    // its own and exchanges the tunnelled data with duplex() over channels
+    let (to_upstream, from_downstream) = mpsc::channel(1);
+    let (to_downstream, from_upstream) = mpsc::channel(1);
+    let Session {
+        downstream_session,
+        tunnel_digest,
+        ..
+    } = session;
+    futures::future::try_join(
+        drive_downstream(downstream_session, to_upstream, from_upstream),
+        duplex(
+            (from_downstream, to_downstream),
+            split(upstream, true),
+            tunnel_digest.get_or_insert_with(Default::default),
+            idle_timeout,
+        ),
+    )
+    .await
+    .map(|_| ())
+}
+
+// Read the tunnelled data from the downstream session into `to_upstream` and write the data of
+// `from_upstream` to it, until both directions are done. A read only starts once the previous
+// data is queued so that the writes to downstream never wait for the upstream.
+async fn drive_downstream(
+    downstream: &mut HttpSession,
+    to_upstream: mpsc::Sender<Bytes>,
+    mut from_upstream: mpsc::Receiver<Bytes>,
+) -> Result<()> {
+    let mut to_upstream = Some(to_upstream);
+    let mut pending = None;
+    let mut downstream_done = false;
+    let mut upstream_done = false;
+
+    while to_upstream.is_some() || !upstream_done {
+        tokio::select! {
+            body = downstream.read_request_body(), if !downstream_done && pending.is_none() => {
+                match body.map_err(|e| e.into_down())? {
+                    Some(data) => pending = Some(data),
+                    None => downstream_done = true,
+                }
+            }
+            permit = async { to_upstream.as_ref().unwrap().reserve().await }, if pending.is_some() => {
+                permit
+                    .or_err(WriteError, "while forwarding to upstream")
+                    .map_err(|e| e.into_up())?
+                    .send(pending.take().unwrap());
+            }
+            data = from_upstream.recv(), if !upstream_done => {
+                match data {
+                    Some(data) => downstream
+                        .write_response_body(data, false)
+                        .await
+                        .map_err(|e| e.into_down())?,
+                    None => {
+                        upstream_done = true;
+                        // this also stops reading from an HTTP/1 downstream
+                        downstream
+                            .write_response_body(Bytes::new(), true)
+                            .await
+                            .map_err(|e| e.into_down())?;
+                        if downstream.is_body_done() {
+                            downstream_done = true;
+                        }
+                    }
+                }
+            }
+        }
+        if downstream_done && pending.is_none() {
+            // propagate the half close
+            to_upstream = None;
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl TunnelRead for mpsc::Receiver<Bytes> {
+    async fn read(&mut self) -> Result<Option<Bytes>> {
+        Ok(self.recv().await)
+    }
+}
+
+#[async_trait]
+impl TunnelWrite for mpsc::Sender<Bytes> {
+    async fn write(&mut self, data: Bytes) -> Result<()> {
+        self.send(data)
+            .await
+            .or_err(WriteError, "while forwarding to downstream")
+            .map_err(|e| e.into_down())
+    }
+
+    async fn shutdown(&mut self) {
+        // the channel is closed once the sender is dropped at the end of the forwarding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_core::apps::HttpServerApp;
+    use pingora_error::ErrorType;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
+
+    fn destination(method: &str, uri: &[u8]) -> Option<Result<ForwardDestination>> {
+        let req = RequestHeader::build(method, uri, None).unwrap();
+        ForwardDestination::from_request(&req)
+    }
+
+    #[test]
+    fn test_forward_destination() {
+        let dest = |host: &str, port, tls| ForwardDestination {
+            host: host.to_string(),
+            port,
+            tls,
+        };
+        assert_eq!(
+            destination("CONNECT", b"pingora.org:443").unwrap().unwrap(),
+            dest("pingora.org", 443, false)
+        );
+        assert_eq!(
+            destination("CONNECT", b"[::1]:8443").unwrap().unwrap(),
+            dest("::1", 8443, false)
+        );
+        assert!(destination("CONNECT", b"pingora.org").unwrap().is_err());
+        assert_eq!(
+            destination("GET", b"http://pingora.org/a")
+                .unwrap()
+                .unwrap(),
+            dest("pingora.org", 80, false)
+        );
+        assert_eq!(
+            destination("GET", b"https://pingora.org:8443/a")
+                .unwrap()
+                .unwrap(),
+            dest("pingora.org", 8443, true)
+        );
+        assert!(destination("GET", b"ftp://pingora.org/a").unwrap().is_err());
+        assert!(destination("GET", b"/a").is_none());
+    }
+
+    #[test]
+    fn test_to_origin_form() {
+        let mut req = RequestHeader::build("GET", b"http://pingora.org:8080/a?b", None).unwrap();
+        req.insert_header("Host", "other.org").unwrap();
+        to_origin_form(&mut req).unwrap();
+        assert_eq!(req.uri, "/a?b");
+        assert_eq!(req.headers.get("Host").unwrap(), "pingora.org:8080");
+    }
+
+    #[test]
+    fn test_reverse_proxy_form() {
+        let mut req = RequestHeader::build("GET", b"http://pingora.org/a?b", None).unwrap();
+        req.insert_header("Host", "other.org").unwrap();
+        reverse_proxy_form(&mut req).unwrap();
+        assert_eq!(req.uri, "/a?b");
+        assert_eq!(req.headers.get("Host").unwrap(), "pingora.org");
+
+        // origin form is left as is
+        let mut req = RequestHeader::build("GET", b"/a", None).unwrap();
+        req.insert_header("Host", "other.org").unwrap();
+        reverse_proxy_form(&mut req).unwrap();
+        assert_eq!(req.uri, "/a");
+        assert_eq!(req.headers.get("Host").unwrap(), "other.org");
+    }
+
+    type Logged = Arc<Mutex<Option<(Option<StreamDigest>, Option<ErrorType>)>>>;
+
+    struct ForwardProxy {
+        denied_port: u16,
+        logged: Logged,
+    }
+
+    #[async_trait]
+    impl ProxyHttp for ForwardProxy {
+        type CTX = ();
+        fn new_ctx(&self) {}
+
+        fn is_forward_proxy(&self, _session: &Session, _ctx: &()) -> bool {
+            true
+        }
+
+        async fn forward_destination_filter(
+            &self,
+            _session: &mut Session,
+            destination: &ForwardDestination,
+            _ctx: &mut (),
+        ) -> Result<bool> {
+            Ok(destination.port != self.denied_port)
+        }
+
+        async fn upstream_peer(
+            &self,
+            session: &mut Session,
+            _ctx: &mut (),
+        ) -> Result<Box<HttpPeer>> {
+            let dest = session.forward_destination().unwrap();
+            let addr = (dest.host.as_str(), dest.port);
+            Ok(Box::new(HttpPeer::new(addr, false, String::new())))
+        }
+
+        async fn logging(&self, session: &mut Session, e: Option<&Error>, _ctx: &mut ()) {
+            *self.logged.lock().unwrap() = Some((
+                session.tunnel_digest().cloned(),
+                e.map(|e| e.etype().clone()),
+            ));
+        }
+    }
+
+    async fn start_proxy(
+        tunnel_options: StreamProxyOptions,
+    ) -> (TcpStream, tokio::task::JoinHandle<()>, Logged) {
+        let logged = Arc::new(Mutex::new(None));
+        let conf = Arc::new(ServerConf::default());
+        let proxy = ForwardProxy {
+            denied_port: 25,
+            logged: logged.clone(),
+        };
+        let mut proxy = HttpProxy::new(proxy, conf);
+        proxy.tunnel_options = tunnel_options;
+        let proxy = Arc::new(proxy);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (io, _) = listener.accept().await.unwrap();
+            let stream: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(io));
+            let (_tx, shutdown) = watch::channel(false);
+            let mut session = HttpSession::new_http1(stream);
+            // serve requests on the connection until it can't be reused
+            loop {
+                let Some(stream) = proxy.process_new_http(session, &shutdown).await else {
+                    break;
+                };
+                session = HttpSession::new_http1(stream);
+            }
+        });
+        let client = TcpStream::connect(addr).await.unwrap();
+        (client, handle, logged)
+    }
+
+    async fn read_header(client: &mut TcpStream) -> String {
+        let mut header = vec![];
+        while !header.ends_with(b"\r\n\r\n") {
+            header.push(client.read_u8().await.unwrap());
+        }
+        String::from_utf8(header).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnel() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut io, _) = upstream.accept().await.unwrap();
+            let (mut r, mut w) = io.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+
+        // denied, error responses close the connection
+        let (mut client, handle, logged) = start_proxy(StreamProxyOptions::default()).await;
+        client
+            .write_all(b"CONNECT 127.0.0.1:25 HTTP/1.1\r\nHost: 127.0.0.1:25\r\n\r\n")
+            .await
+            .unwrap();
+        let header = read_header(&mut client).await;
+        assert!(header.starts_with("HTTP/1.1 403"), "{header}");
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, None);
+        assert!(digest.is_none());
+
+        let (mut client, handle, logged) = start_proxy(StreamProxyOptions::default()).await;
+        let req = format!("CONNECT 127.0.0.1:{upstream_port} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        client.write_all(req.as_bytes()).await.unwrap();
+        let header = read_header(&mut client).await;
+        assert!(header.starts_with("HTTP/1.1 200"), "{header}");
+        assert!(!header.to_ascii_lowercase().contains("content-length"));
+
+        client.write_all(b"hello tunnel").await.unwrap();
+        let mut echoed = [0; 12];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello tunnel");
+        client.shutdown().await.unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, None);
+        let digest = digest.unwrap();
+        assert_eq!(digest.downstream_bytes, 12);
+        assert_eq!(digest.upstream_bytes, 12);
+        assert!(digest.upstream_connected.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnel_both_directions() {
+        // more than the socket buffers can hold
+        const LEN: usize = 32 * 1024 * 1024;
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            // only read once everything is written
+            let (mut io, _) = upstream.accept().await.unwrap();
+            io.write_all(&vec![b'u'; LEN]).await.unwrap();
+            io.shutdown().await.unwrap();
+            let mut received = vec![];
+            io.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received.len(), LEN);
+        });
+
+        let (mut client, handle, logged) = start_proxy(StreamProxyOptions::default()).await;
+        let req = format!("CONNECT 127.0.0.1:{upstream_port} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        client.write_all(req.as_bytes()).await.unwrap();
+        let header = read_header(&mut client).await;
+        assert!(header.starts_with("HTTP/1.1 200"), "{header}");
+
+        let (mut r, mut w) = client.into_split();
+        let write = async {
+            w.write_all(&vec![b'd'; LEN]).await.unwrap();
+            w.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut received = vec![];
+            r.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received.len(), LEN);
+        };
+        time::timeout(Duration::from_secs(10), async { tokio::join!(write, read) })
+            .await
+            .unwrap();
+
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, None);
+        let digest = digest.unwrap();
+        assert_eq!(digest.downstream_bytes, LEN as u64);
+        assert_eq!(digest.upstream_bytes, LEN as u64);
+    }
+
+    #[tokio::test]
+    async fn test_absolute_form() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut io, _) = upstream.accept().await.unwrap();
+            let header = read_header(&mut io).await;
+            assert!(header.starts_with("GET /path?q HTTP/1.1\r\n"), "{header}");
+            assert!(header.contains(&format!("127.0.0.1:{upstream_port}")));
+            io.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+        });
+
+        let (mut client, _handle, logged) = start_proxy(StreamProxyOptions::default()).await;
+        let req = format!(
+            "GET http://127.0.0.1:{upstream_port}/path?q HTTP/1.1\r\nHost: other.org\r\n\r\n"
+        );
+        client.write_all(req.as_bytes()).await.unwrap();
+        let header = read_header(&mut client).await;
+        assert!(header.starts_with("HTTP/1.1 200"), "{header}");
+        let mut body = [0; 2];
+        client.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"ok");
+
+        // not a tunnel
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, None);
+        assert!(digest.is_none());
+    }
+
+    async fn tunnel_upstream(respond: bool) -> u16 {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut io, _) = upstream.accept().await.unwrap();
+            if !respond {
+                // read but never respond
+                let mut buf = [0; 64];
+                while io.read(&mut buf).await.unwrap_or(0) > 0 {}
+                return;
+            }
+            // keep the tunnel busy
+            loop {
+                if io.write_all(b".").await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        upstream_port
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnel_idle_timeout() {
+        let upstream_port = tunnel_upstream(false).await;
+        let options = StreamProxyOptions {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let (mut client, handle, logged) = start_proxy(options).await;
+        let req = format!("CONNECT 127.0.0.1:{upstream_port} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        client.write_all(req.as_bytes()).await.unwrap();
+        let header = read_header(&mut client).await;
+        assert!(header.starts_with("HTTP/1.1 200"), "{header}");
+        client.write_all(b"ping").await.unwrap();
+        let mut rest = vec![];
+        // the proxy closes the connection once idle
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, Some(ReadTimedout));
+        assert_eq!(digest.unwrap().downstream_bytes, 4);
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnel_total_timeout() {
+        let upstream_port = tunnel_upstream(true).await;
+        let options = StreamProxyOptions {
+            idle_timeout: Some(Duration::from_secs(1)),
+            total_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (mut client, handle, logged) = start_proxy(options).await;
+        let req = format!("CONNECT 127.0.0.1:{upstream_port} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+        client.write_all(req.as_bytes()).await.unwrap();
+        let header = read_header(&mut client).await;
+        assert!(header.starts_with("HTTP/1.1 200"), "{header}");
+        let mut data = vec![];
+        client.read_to_end(&mut data).await.unwrap();
+        assert!(!data.is_empty());
+
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, Some(ReadTimedout));
+        assert_eq!(digest.unwrap().upstream_bytes, data.len() as u64);
+    }
+}
@@ -15,6 +15,33 @@
 use super::*;
 use crate::proxy_cache::{range_filter::RangeBodyFilter, ServeFromCache};
 use crate::proxy_common::*;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+// Turn an extended CONNECT request (RFC 8441) into the HTTP/1.1 upgrade request it stands for.
+// The 101 response is turned back into a 200 by the h2 downstream session. Any other 2xx means
+// the upstream ignored the upgrade, see extended_connect_response_filter().
+fn extended_connect_to_upgrade(req: &mut RequestHeader, protocol: &str) -> Result<()> {
+    req.set_method(http::Method::GET);
+    req.insert_header(header::UPGRADE, protocol)?;
+    req.insert_header(header::CONNECTION, "Upgrade")?;
+    // h2 clients don't send the key as the handshake is the CONNECT itself, see RFC 8441 5.
+    if protocol.eq_ignore_ascii_case("websocket")
+        && req.headers.get(header::SEC_WEBSOCKET_KEY).is_none()
+    {
+        let key = STANDARD.encode(rand::random::<[u8; 16]>());
+        req.insert_header(header::SEC_WEBSOCKET_KEY, key)?;
+    }
+    Ok(())
+}
+
+// An extended CONNECT only succeeds with a 2xx (RFC 8441 5), which an H1 upstream answers with a
+// 101. A 2xx of an upstream that ignored the upgrade would open a tunnel to nowhere.
+fn extended_connect_response_filter(header: &mut ResponseHeader) -> Result<()> {
+    if header.status.is_success() {
+        header.set_status(502)?;
+    }
+    Ok(())
+}
 
 impl<SV> HttpProxy<SV> {
     pub(crate) async fn proxy_1to1(
@@ -35,18 +62,25 @@ impl<SV> HttpProxy<SV> {
 
         let mut req = session.req_header().clone();
 
-        // Convert HTTP2 headers to H1
-        if req.version == Version::HTTP_2 {
+        // Convert HTTP2 and HTTP3 headers to H1
+        if matches!(req.version, Version::HTTP_2 | Version::HTTP_3) {
             req.set_version(Version::HTTP_11);
-            // if client has body but has no content length, add chunked encoding
-            // https://datatracker.ietf.org/doc/html/rfc9112#name-message-body
-            // "The presence of a message body in a request is signaled by a Content-Length or Transfer-Encoding header field."
-            if !session.is_body_empty() && session.get_header(header::CONTENT_LENGTH).is_none() {
+            if let Some(protocol) = session.connect_protocol() {
+                // the tunnel of an extended CONNECT is an upgraded connection in H1
+                if let Err(e) = extended_connect_to_upgrade(&mut req, protocol) {
+                    return (false, true, Some(e));
+                }
+            } else if !session.is_body_empty()
+                && session.get_header(header::CONTENT_LENGTH).is_none()
+            {
+                // if client has body but has no content length, add chunked encoding
+                // https://datatracker.ietf.org/doc/html/rfc9112#name-message-body
+                // "The presence of a message body in a request is signaled by a Content-Length or Transfer-Encoding header field."
                 req.insert_header(header::TRANSFER_ENCODING, "chunked")
                     .unwrap();
             }
             if session.get_header(header::HOST).is_none() {
-                // H2 is required to set :authority, but no necessarily header
+                // H2 and H3 are required to set :authority, but no necessarily header
                 // most H1 server expect host header, so convert
                 let host = req.uri.authority().map_or("", |a| a.as_str()).to_owned();
                 req.insert_header(header::HOST, host).unwrap();
@@ -75,6 +109,10 @@ impl<SV> HttpProxy<SV> {
             }
         }
 
+        if session.is_upgrade_req() {
+            self.websocket_request_filter(session, &mut req, ctx);
+        }
+
         session.upstream_compression.request_filter(&req);
 
         debug!("Sending header to upstream {:?}", req);
@@ -450,6 +488,12 @@ impl<SV> HttpProxy<SV> {
         // skip caching if already served from cache
         if !from_cache {
             self.upstream_filter(session, &mut task, ctx)?;
+            if let HttpTask::Header(header, _) = &mut task {
+                self.websocket_response_filter(session, header);
+                if session.connect_protocol().is_some() {
+                    extended_connect_response_filter(header)?;
+                }
+            }
 
             // cache the original response before any downstream transformation
             // requests that bypassed cache still need to run filters to see if the response has become cacheable
@@ -521,7 +565,9 @@ impl<SV> HttpProxy<SV> {
                     Err(e) => Err(e),
                 }
             }
-            HttpTask::Body(data, end) => {
+            HttpTask::Body(mut data, end) => {
+                self.websocket_body_filter(session, &mut data, WebSocketDirection::Downstream, ctx)
+                    .await?;
                 let mut data = range_body_filter.filter_body(data);
                 if let Some(duration) = self
                     .inner
@@ -561,6 +607,9 @@ impl<SV> HttpProxy<SV> {
             .request_body_filter(&mut data, end_of_body)
             .await?;
 
+        self.websocket_body_filter(session, &mut data, WebSocketDirection::Upstream, ctx)
+            .await?;
+
         self.inner
             .request_body_filter(session, &mut data, end_of_body, ctx)
             .await?;
@@ -637,3 +686,42 @@ pub(crate) async fn send_body_to1(
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extended_connect_to_upgrade() {
+        let mut req = RequestHeader::build("CONNECT", b"/chat", None).unwrap();
+        req.insert_header("sec-websocket-version", "13").unwrap();
+        extended_connect_to_upgrade(&mut req, "websocket").unwrap();
+        assert_eq!(req.method, http::Method::GET);
+        assert_eq!(req.headers.get(header::UPGRADE).unwrap(), "websocket");
+        assert_eq!(req.headers.get(header::CONNECTION).unwrap(), "Upgrade");
+        let key = req.headers.get(header::SEC_WEBSOCKET_KEY).unwrap();
+        assert_eq!(STANDARD.decode(key.as_bytes()).unwrap().len(), 16);
+        assert_eq!(req.headers.get("sec-websocket-version").unwrap(), "13");
+
+        // only websocket has a key
+        let mut req = RequestHeader::build("CONNECT", b"/", None).unwrap();
+        extended_connect_to_upgrade(&mut req, "connect-udp").unwrap();
+        assert_eq!(req.headers.get(header::UPGRADE).unwrap(), "connect-udp");
+        assert!(req.headers.get(header::SEC_WEBSOCKET_KEY).is_none());
+    }
+
+    #[test]
+    fn test_extended_connect_response_filter() {
+        let mut resp = ResponseHeader::build(101, None).unwrap();
+        extended_connect_response_filter(&mut resp).unwrap();
+        assert_eq!(resp.status, 101);
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        extended_connect_response_filter(&mut resp).unwrap();
+        assert_eq!(resp.status, 502);
+
+        let mut resp = ResponseHeader::build(404, None).unwrap();
+        extended_connect_response_filter(&mut resp).unwrap();
+        assert_eq!(resp.status, 404);
+    }
+}
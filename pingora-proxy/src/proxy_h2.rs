@@ -16,7 +16,9 @@ use super::*;
 use crate::proxy_cache::{range_filter::RangeBodyFilter, ServeFromCache};
 use crate::proxy_common::*;
 use http::{header::CONTENT_LENGTH, Method, StatusCode};
-use pingora_core::protocols::http::v2::{client::Http2Session, write_body};
+use pingora_core::protocols::http::v2::{self, client::Http2Session};
+#[cfg(feature = "quic")]
+use pingora_core::protocols::http::v3::{self, H3BodyWriter};
 
 // add scheme and authority as required by h2 lib
 fn update_h2_scheme_authority(
@@ -66,6 +68,24 @@ fn update_h2_scheme_authority(
     }
 }
 
+/// The request body writer of a multiplexed upstream stream, which is written in duplex with the
+/// response being read.
+pub(crate) enum UpstreamBodyWriter {
+    H2(h2::SendStream<Bytes>),
+    #[cfg(feature = "quic")]
+    H3(Box<H3BodyWriter>),
+}
+
+impl UpstreamBodyWriter {
+    async fn write_body(&mut self, data: Bytes, end: bool) -> Result<()> {
+        match self {
+            Self::H2(writer) => v2::write_body(writer, data, end).await,
+            #[cfg(feature = "quic")]
+            Self::H3(writer) => v3::write_body(writer, data, end).await,
+        }
+    }
+}
+
 impl<SV> HttpProxy<SV> {
     pub(crate) async fn proxy_down_to_up(
         &self,
@@ -94,6 +114,12 @@ impl<SV> HttpProxy<SV> {
         /* turn it into h2 */
         req.set_version(Version::HTTP_2);
 
+        // the extension is lost when cloning the request, carry the extended CONNECT over
+        if let Some(protocol) = session.connect_protocol() {
+            req.extensions_mut()
+                .insert(h2::ext::Protocol::from(protocol));
+        }
+
         if session.cache.enabled() {
             if let Err(e) = pingora_cache::filters::upstream::request_filter(
                 &mut req,
@@ -144,6 +170,9 @@ impl<SV> HttpProxy<SV> {
         debug!("send END_STREAM on HEADERS: {send_end_stream}");
 
         let req = Box::new(RequestHeader::from(req));
+        if session.connect_protocol().is_some() {
+            client_session.wait_for_settings().await;
+        }
         if let Err(e) = client_session.write_request_header(req, send_header_eos) {
             return (false, Some(e.into_up()));
         }
@@ -161,9 +190,11 @@ impl<SV> HttpProxy<SV> {
         client_session.read_timeout = peer.options.read_timeout;
 
         // take the body writer out of the client for easy duplex
-        let mut client_body = client_session
-            .take_request_body_writer()
-            .expect("already send request header");
+        let mut client_body = UpstreamBodyWriter::H2(
+            client_session
+                .take_request_body_writer()
+                .expect("already send request header"),
+        );
 
         let (tx, rx) = mpsc::channel::<HttpTask>(TASK_BUFFER_SIZE);
 
@@ -215,10 +246,10 @@ impl<SV> HttpProxy<SV> {
     }
 
     // returns whether server (downstream) session can be reused
-    async fn bidirection_down_to_up(
+    pub(crate) async fn bidirection_down_to_up(
         &self,
         session: &mut Session,
-        client_body: &mut h2::SendStream<bytes::Bytes>,
+        client_body: &mut UpstreamBodyWriter,
         mut rx: mpsc::Receiver<HttpTask>,
         ctx: &mut SV::CTX,
     ) -> Result<bool>
@@ -253,6 +284,7 @@ impl<SV> HttpProxy<SV> {
         while !downstream_state.is_done() || !response_state.is_done() {
             // Similar logic in h1 need to reserve capacity first to avoid deadlock
             // But we don't need to do the same because the h2 client_body pipe is unbounded (never block)
+            // and the h3 one is only bounded by the QUIC flow control of the request stream
             tokio::select! {
                 // NOTE: cannot avoid this copy since h2 owns the buf
                 body = session.downstream_session.read_body_or_idle(downstream_state.is_done()), if downstream_state.can_poll() => {
@@ -523,7 +555,7 @@ impl<SV> HttpProxy<SV> {
         session: &mut Session,
         mut data: Option<Bytes>,
         end_of_body: bool,
-        client_body: &mut h2::SendStream<bytes::Bytes>,
+        client_body: &mut UpstreamBodyWriter,
         ctx: &mut SV::CTX,
     ) -> Result<bool>
     where
@@ -547,14 +579,16 @@ impl<SV> HttpProxy<SV> {
         }
 
         if let Some(data) = data {
-            debug!("Write {} bytes body to h2 upstream", data.len());
-            write_body(client_body, data, end_of_body)
+            debug!("Write {} bytes body to multiplexed upstream", data.len());
+            client_body
+                .write_body(data, end_of_body)
                 .await
                 .map_err(|e| e.into_up())?;
         } else {
             debug!("Read downstream body done");
             /* send a standalone END_STREAM flag */
-            write_body(client_body, Bytes::new(), true)
+            client_body
+                .write_body(Bytes::new(), true)
                 .await
                 .map_err(|e| e.into_up())?;
         }
@@ -711,3 +745,111 @@ fn test_update_authority() {
     update_h2_scheme_authority(&mut parts, b"example.com", false).unwrap();
     assert_eq!("http://example.com", parts.uri);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use h2::ext::Protocol;
+    use pingora_core::apps::ServerApp;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
+
+    struct H2cProxy {
+        upstream: std::net::SocketAddr,
+    }
+
+    #[async_trait]
+    impl ProxyHttp for H2cProxy {
+        type CTX = ();
+        fn new_ctx(&self) {}
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut (),
+        ) -> Result<Box<HttpPeer>> {
+            let mut peer = HttpPeer::new(self.upstream, false, String::new());
+            peer.options.set_http_version(2, 2);
+            Ok(Box::new(peer))
+        }
+    }
+
+    // An h2c upstream accepting extended CONNECT requests and echoing the data of their tunnels
+    async fn echo_upstream() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (io, _) = listener.accept().await.unwrap();
+            let mut conn = h2::server::Builder::new()
+                .enable_connect_protocol()
+                .handshake::<_, Bytes>(io)
+                .await
+                .unwrap();
+            let (req, mut respond) = conn.accept().await.unwrap().unwrap();
+            tokio::spawn(async move { while conn.accept().await.is_some() {} });
+            assert_eq!(req.method(), Method::CONNECT);
+            let protocol = req.extensions().get::<Protocol>().unwrap();
+            assert_eq!(protocol.as_str(), "websocket");
+            let resp = http::Response::builder().status(200).body(()).unwrap();
+            let mut send = respond.send_response(resp, false).unwrap();
+            let mut body = req.into_body();
+            while let Some(data) = body.data().await {
+                let data = data.unwrap();
+                let _ = body.flow_control().release_capacity(data.len());
+                send.send_data(data, false).unwrap();
+            }
+            send.send_data(Bytes::new(), true).unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_extended_connect_h2_to_h2() {
+        let upstream = echo_upstream().await;
+        let conf = Arc::new(ServerConf::default());
+        let mut proxy = HttpProxy::new(H2cProxy { upstream }, conf);
+        let mut server_options = HttpServerOptions::default();
+        server_options.h2c = true;
+        proxy.server_options = Some(server_options);
+        let proxy = Arc::new(proxy);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (io, _) = listener.accept().await.unwrap();
+            let stream: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(io));
+            let (_tx, shutdown) = watch::channel(false);
+            proxy.process_new(stream, &shutdown).await;
+        });
+
+        let io = TcpStream::connect(addr).await.unwrap();
+        let (h2, connection) = h2::client::handshake(io).await.unwrap();
+        tokio::spawn(connection);
+        let mut h2 = h2.ready().await.unwrap();
+        // the SETTINGS of the proxy are received asynchronously
+        while !h2.is_extended_connect_protocol_enabled() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut req = http::Request::builder()
+            .method(Method::CONNECT)
+            .uri("http://pingora.org/chat")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(Protocol::from_static("websocket"));
+        let (resp, mut send) = h2.send_request(req, false).unwrap();
+        let (head, mut body) = resp.await.unwrap().into_parts();
+        assert_eq!(head.status, 200);
+
+        send.send_data(Bytes::from_static(b"websocket frame"), false)
+            .unwrap();
+        let data = body.data().await.unwrap().unwrap();
+        assert_eq!(data, "websocket frame");
+        send.send_data(Bytes::new(), true).unwrap();
+        while let Some(data) = body.data().await {
+            assert!(data.unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::*;
+use crate::proxy_h2::UpstreamBodyWriter;
+use pingora_core::protocols::http::v3::client::Http3Session;
+
+impl<SV> HttpProxy<SV> {
+    async fn proxy_down_to_up_h3(
+        &self,
+        session: &mut Session,
+        client_session: &mut Http3Session,
+        peer: &HttpPeer,
+        ctx: &mut SV::CTX,
+    ) -> (bool, Option<Box<Error>>)
+    // (reuse_server, error)
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        if let Some(protocol) = session.connect_protocol() {
+            let e = Error::explain(
+                H3Error,
+                format!("extended CONNECT for {protocol} is not supported by h3 upstreams"),
+            );
+            return (false, Some(e));
+        }
+
+        let mut req = session.req_header().clone();
+
+        if req.version != Version::HTTP_2 && req.version != Version::HTTP_3 {
+            /* remove H1 specific headers, which are malformed in h3 as in h2 */
+            req.remove_header(&http::header::TRANSFER_ENCODING);
+            req.remove_header(&http::header::CONNECTION);
+            req.remove_header(&http::header::UPGRADE);
+            req.remove_header("keep-alive");
+            req.remove_header("proxy-connection");
+        }
+
+        /* turn it into h3 */
+        req.set_version(Version::HTTP_3);
+
+        if session.cache.enabled() {
+            if let Err(e) = pingora_cache::filters::upstream::request_filter(
+                &mut req,
+                session.cache.maybe_cache_meta(),
+            ) {
+                session.cache.disable(NoCacheReason::InternalError);
+                warn!("cache upstream filter error {}, disabling cache", e);
+            }
+        }
+
+        match self
+            .inner
+            .upstream_request_filter(session, &mut req, ctx)
+            .await
+        {
+            Ok(_) => { /* continue */ }
+            Err(e) => {
+                return (false, Some(e));
+            }
+        }
+
+        session.upstream_compression.request_filter(&req);
+        let body_empty = session.as_mut().is_body_empty();
+
+        // the client session moves the `Host` header to :authority
+        debug!("Request to h3: {req:?}");
+
+        if let Err(e) = client_session
+            .write_request_header(Box::new(req), body_empty)
+            .await
+        {
+            return (false, Some(e.into_up()));
+        }
+
+        client_session.read_timeout = peer.options.read_timeout;
+
+        // take the body writer out of the client for easy duplex
+        let mut client_body = UpstreamBodyWriter::H3(Box::new(
+            client_session
+                .take_request_body_writer()
+                .expect("already send request header"),
+        ));
+
+        let (tx, rx) = mpsc::channel::<HttpTask>(TASK_BUFFER_SIZE);
+
+        session.as_mut().enable_retry_buffering();
+
+        /* read downstream body and upstream response at the same time */
+
+        let ret = tokio::try_join!(
+            self.bidirection_down_to_up(session, &mut client_body, rx, ctx),
+            pipe_up_to_down_response(client_session, tx)
+        );
+
+        match ret {
+            Ok((downstream_can_reuse, _upstream)) => (downstream_can_reuse, None),
+            Err(e) => (false, Some(e)),
+        }
+    }
+
+    pub(crate) async fn proxy_to_h3_upstream(
+        &self,
+        session: &mut Session,
+        client_session: &mut Http3Session,
+        reused: bool,
+        peer: &HttpPeer,
+        ctx: &mut SV::CTX,
+    ) -> (bool, Option<Box<Error>>)
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        #[cfg(windows)]
+        let raw = client_session.fd() as std::os::windows::io::RawSocket;
+        #[cfg(unix)]
+        let raw = client_session.fd();
+
+        if let Err(e) = self
+            .inner
+            .connected_to_upstream(session, reused, peer, raw, client_session.digest(), ctx)
+            .await
+        {
+            return (false, Some(e));
+        }
+
+        self.proxy_down_to_up_h3(session, client_session, peer, ctx)
+            .await
+    }
+}
+
+/* Read response header, body and trailer from h3 upstream and send them to tx */
+async fn pipe_up_to_down_response(
+    client: &mut Http3Session,
+    tx: mpsc::Sender<HttpTask>,
+) -> Result<()> {
+    client
+        .read_response_header()
+        .await
+        .map_err(|e| e.into_up())?; // should we send the error as an HttpTask?
+
+    let resp_header = Box::new(client.response_header().expect("just read").clone());
+    // unlike h2, the end of the response is only known after reading the body
+    tx.send(HttpTask::Header(resp_header, false))
+        .await
+        .or_err(InternalError, "sending h3 headers to pipe")?;
+
+    loop {
+        let data = match client.read_response_body().await {
+            Ok(Some(d)) => d,
+            Ok(None) => break,
+            Err(e) => {
+                // Push the error to downstream and then quit
+                let _ = tx.send(HttpTask::Failed(e.into_up())).await;
+                // Downstream should consume all remaining data and handle the error
+                return Ok(());
+            }
+        };
+        if data.is_empty() {
+            continue;
+        }
+        tx.send(HttpTask::Body(Some(data), false))
+            .await
+            .or_err(InternalError, "sending h3 body to pipe")?;
+    }
+
+    // attempt to get trailers
+    let trailers = match client.read_trailers().await {
+        Ok(t) => t,
+        Err(e) => {
+            // Similar to above, push the error to downstream and then quit
+            let _ = tx.send(HttpTask::Failed(e.into_up())).await;
+            return Ok(());
+        }
+    };
+
+    let task = match trailers {
+        Some(trailers) => HttpTask::Trailer(Some(Box::new(trailers))),
+        None => HttpTask::Body(None, true),
+    };
+    // bidirection_down_to_up() could already be done if the response has a content-length
+    if tx.send(task).await.is_err() {
+        debug!("h3 to downstream channel closed!");
+        return Ok(());
+    }
+
+    tx.send(HttpTask::Done)
+        .await
+        .unwrap_or_else(|_| debug!("h3 to downstream channel closed!"));
+
+    Ok(())
+}
@@ -0,0 +1,915 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generic L4 (TCP/UDS) stream proxy
+//!
+//! Users define their stream proxy by implementing the [ProxyStream] trait and pass it to
+//! [`stream_proxy_service()`]. Every accepted downstream connection is forwarded, byte for byte,
+//! to the upstream [BasicPeer] returned by [ProxyStream::upstream_peer()].
+//!
+//! # TLS passthrough
+//!
+//! [StreamSession::peek_client_hello()] exposes the SNI and ALPN of a TLS connection without
+//! terminating it, so that [ProxyStream::upstream_peer()] can route on them. The ClientHello stays
+//! in the stream and is forwarded untouched along with the rest of the connection.
+//!
+//! The stream proxy does not terminate TLS itself: the connections are either forwarded as is or,
+//! to terminate them, looped back to a TLS endpoint of another service as described below.
+//!
+//! To serve both passthrough and terminated hosts on one listener, route the terminated hosts to
+//! a local TLS endpoint of another service, e.g. one created by [`http_proxy_service()`]. Setting
+//! [PeerOptions::proxy_protocol](pingora_core::upstreams::peer::PeerOptions) on that peer and
+//! enabling [TcpSocketOptions::proxy_protocol](pingora_core::listeners::TcpSocketOptions) on that
+//! endpoint preserves the client address.
+
+use super::*;
+use bytes::BytesMut;
+use pingora_core::apps::ServerApp;
+use pingora_core::connectors::TransportConnector;
+use pingora_core::protocols::l4::socket::SocketAddr;
+use pingora_core::protocols::tls::client_hello::{peek_client_hello, ClientHello};
+use pingora_core::upstreams::peer::BasicPeer;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+const BUF_SIZE: usize = 16 * 1024;
+const DEFAULT_CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The interface to control the L4 stream proxy
+///
+/// The methods in [ProxyStream] are callbacks which will be performed on every downstream
+/// connection at their particular stage.
+///
+/// If any of the callbacks returns [Result::Err], the connection will be closed, and the error
+/// will be logged.
+#[cfg_attr(not(doc_async_trait), async_trait)]
+pub trait ProxyStream {
+    /// The per connection object to share state across the different callbacks
+    type CTX;
+
+    /// Define how the `ctx` should be created.
+    fn new_ctx(&self) -> Self::CTX;
+
+    /// Define where the proxy should forward the connection to.
+    ///
+    /// The returned [BasicPeer] contains the information regarding where and how the upstream
+    /// connection should be established.
+    async fn upstream_peer(
+        &self,
+        session: &mut StreamSession,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<BasicPeer>>;
+
+    /// This filter is called when there is an error in the process of establishing a connection
+    /// to the upstream.
+    ///
+    /// In this filter the user can decide whether the error is retry-able by marking the error `e`.
+    ///
+    /// If the error can be retried, [Self::upstream_peer()] will be called again so that the user
+    /// can decide whether to connect to the same upstream or another upstream that is possibly
+    /// available.
+    fn fail_to_connect(
+        &self,
+        _session: &mut StreamSession,
+        _peer: &BasicPeer,
+        _ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        e
+    }
+
+    /// This filter is called when a connection to the upstream is established.
+    ///
+    /// Returning an error here closes both the downstream and the upstream connections before
+    /// any data is forwarded.
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut StreamSession,
+        _peer: &BasicPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        _digest: Option<&Digest>,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(())
+    }
+
+    /// This callback is invoked once the connection is closed, successfully or not.
+    ///
+    /// The byte counters of the connection are available via [StreamSession::digest()].
+    async fn logging(&self, _session: &mut StreamSession, _e: Option<&Error>, _ctx: &mut Self::CTX)
+    where
+        Self::CTX: Send + Sync,
+    {
+    }
+
+    /// A value of true means that the log message will be suppressed. The default value is false.
+    fn suppress_error_log(
+        &self,
+        _session: &StreamSession,
+        _ctx: &Self::CTX,
+        _error: &Error,
+    ) -> bool {
+        false
+    }
+
+    /// This callback is invoked every time connection related error log needs to be generated
+    ///
+    /// Users can define what is important to be written about this connection via the returned
+    /// string.
+    fn connection_summary(&self, session: &StreamSession, _ctx: &Self::CTX) -> String {
+        session.summary()
+    }
+}
+
+/// The byte counters and timing of a proxied stream
+#[derive(Debug, Clone, Default)]
+pub struct StreamDigest {
+    /// The number of bytes read from downstream and written to upstream
+    pub downstream_bytes: u64,
+    /// The number of bytes read from upstream and written to downstream
+    pub upstream_bytes: u64,
+    /// When the upstream connection was established
+    pub upstream_connected: Option<Instant>,
+}
+
+/// The downstream connection of the L4 stream proxy along with its state
+pub struct StreamSession {
+    downstream: Stream,
+    downstream_digest: Digest,
+    upstream_digest: Option<Digest>,
+    digest: StreamDigest,
+    // None: not peeked yet, Some(None): not TLS
+    client_hello: Option<Option<ClientHello>>,
+    start: Instant,
+    /// The maximum time to wait for data in either direction before closing the connection
+    ///
+    /// `None` means no idle timeout.
+    pub idle_timeout: Option<Duration>,
+    /// The maximum lifetime of the proxied connection, measured from when data starts to be
+    /// forwarded.
+    ///
+    /// `None` means no total timeout.
+    pub total_timeout: Option<Duration>,
+    /// The maximum time [Self::peek_client_hello()] waits for the ClientHello
+    ///
+    /// `None` means no timeout.
+    pub client_hello_timeout: Option<Duration>,
+}
+
+impl StreamSession {
+    fn new(downstream: Stream, options: &StreamProxyOptions) -> Self {
+        let downstream_digest = Digest {
+            ssl_digest: downstream.get_ssl_digest(),
+            timing_digest: downstream.get_timing_digest(),
+            proxy_digest: downstream.get_proxy_digest(),
+            socket_digest: downstream.get_socket_digest(),
+        };
+        StreamSession {
+            downstream,
+            downstream_digest,
+            upstream_digest: None,
+            digest: StreamDigest::default(),
+            client_hello: None,
+            start: Instant::now(),
+            idle_timeout: options.idle_timeout,
+            total_timeout: options.total_timeout,
+            client_hello_timeout: options.client_hello_timeout,
+        }
+    }
+
+    /// Return a reference to the downstream [Stream].
+    pub fn downstream(&self) -> &Stream {
+        &self.downstream
+    }
+
+    /// Return a mutable reference to the downstream [Stream].
+    ///
+    /// Any data read from it here will not be forwarded to the upstream.
+    pub fn downstream_mut(&mut self) -> &mut Stream {
+        &mut self.downstream
+    }
+
+    /// Return the [Digest] of the downstream connection.
+    pub fn downstream_digest(&self) -> &Digest {
+        &self.downstream_digest
+    }
+
+    /// Return the [Digest] of the upstream connection, if one is established.
+    pub fn upstream_digest(&self) -> Option<&Digest> {
+        self.upstream_digest.as_ref()
+    }
+
+    /// Return the byte counters of this connection.
+    pub fn digest(&self) -> &StreamDigest {
+        &self.digest
+    }
+
+    /// Return the client (peer) address of the downstream connection.
+    pub fn client_addr(&self) -> Option<&SocketAddr> {
+        self.downstream_digest
+            .socket_digest
+            .as_ref()
+            .map(|d| d.peer_addr())?
+    }
+
+    /// Return the server (local) address of the downstream connection.
+    pub fn server_addr(&self) -> Option<&SocketAddr> {
+        self.downstream_digest
+            .socket_digest
+            .as_ref()
+            .map(|d| d.local_addr())?
+    }
+
+    /// Peek the TLS ClientHello sent by the client without consuming it.
+    ///
+    /// This allows routing on the SNI and ALPN of a TLS connection in [ProxyStream::upstream_peer()]
+    /// while the TLS handshake itself is forwarded untouched to the upstream.
+    ///
+    /// Return `Ok(None)` if the connection doesn't start with a TLS handshake. The result is cached
+    /// so this function can be called multiple times.
+    ///
+    /// This waits for the client to send data. With a server-first protocol (e.g. SMTP or MySQL)
+    /// the client sends nothing until the upstream greets it, so this only returns an error once
+    /// [Self::client_hello_timeout] is reached.
+    pub async fn peek_client_hello(&mut self) -> Result<Option<&ClientHello>> {
+        if self.client_hello.is_none() {
+            let peek = peek_client_hello(&mut self.downstream);
+            let hello = match self.client_hello_timeout {
+                Some(t) => time::timeout(t, peek)
+                    .await
+                    .or_err(ReadTimedout, "while peeking ClientHello")
+                    .and_then(|r| r),
+                None => peek.await,
+            }
+            .map_err(|e| e.into_down())?;
+            self.client_hello = Some(hello);
+        }
+        Ok(self.client_hello.as_ref().and_then(|h| h.as_ref()))
+    }
+
+    /// Return the TLS ClientHello if it is already peeked by [Self::peek_client_hello()].
+    pub fn client_hello(&self) -> Option<&ClientHello> {
+        self.client_hello.as_ref().and_then(|h| h.as_ref())
+    }
+
+    /// Return how long this connection has been alive.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} -> {}, downstream bytes: {}, upstream bytes: {}",
+            self.client_addr()
+                .map_or_else(|| "-".to_string(), |a| a.to_string()),
+            self.server_addr()
+                .map_or_else(|| "-".to_string(), |a| a.to_string()),
+            self.digest.downstream_bytes,
+            self.digest.upstream_bytes
+        )
+    }
+}
+
+/// Options that control the timeouts of a [StreamProxy]
+///
+/// These are the defaults of every [StreamSession], which can be overridden per connection.
+#[derive(Debug, Clone)]
+pub struct StreamProxyOptions {
+    /// See [StreamSession::idle_timeout]. Default `None`.
+    pub idle_timeout: Option<Duration>,
+    /// See [StreamSession::total_timeout]. Default `None`.
+    pub total_timeout: Option<Duration>,
+    /// See [StreamSession::client_hello_timeout]. Default 10 seconds.
+    pub client_hello_timeout: Option<Duration>,
+}
+
+impl Default for StreamProxyOptions {
+    fn default() -> Self {
+        StreamProxyOptions {
+            idle_timeout: None,
+            total_timeout: None,
+            client_hello_timeout: Some(DEFAULT_CLIENT_HELLO_TIMEOUT),
+        }
+    }
+}
+
+/// The concrete type that holds the user defined L4 stream proxy.
+///
+/// Users don't need to interact with this object directly.
+pub struct StreamProxy<SV> {
+    inner: SV,
+    connector: TransportConnector,
+    pub options: StreamProxyOptions,
+    max_retries: usize,
+}
+
+impl<SV> StreamProxy<SV> {
+    /// Create a new [StreamProxy] from the user implemented [ProxyStream].
+    ///
+    /// This is useful to build a [Service] with custom listeners via [Service::with_listeners()].
+    /// Otherwise [`stream_proxy_service()`] is the simpler way to create the service.
+    pub fn new(inner: SV, conf: Arc<ServerConf>) -> Self {
+        StreamProxy {
+            inner,
+            connector: TransportConnector::new(Some(ConnectorOptions::from_server_conf(&conf))),
+            options: StreamProxyOptions::default(),
+            max_retries: conf.max_retries,
+        }
+    }
+
+    async fn connect_upstream(
+        &self,
+        session: &mut StreamSession,
+        ctx: &mut SV::CTX,
+    ) -> Result<(Stream, Box<BasicPeer>)>
+    where
+        SV: ProxyStream + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let mut retries: usize = 0;
+        loop {
+            retries += 1;
+            let peer = self.inner.upstream_peer(session, ctx).await?;
+            match self.connector.new_stream(&*peer).await {
+                Ok(stream) => return Ok((stream, peer)),
+                Err(e) => {
+                    let e = self.inner.fail_to_connect(session, &peer, ctx, e).into_up();
+                    if !e.retry() || retries >= self.max_retries {
+                        return Err(e);
+                    }
+                    warn!(
+                        "Fail to connect: {}, tries: {}, {}",
+                        e,
+                        retries,
+                        self.inner.connection_summary(session, ctx)
+                    );
+                }
+            }
+        }
+    }
+
+    async fn proxy_stream(&self, session: &mut StreamSession, ctx: &mut SV::CTX) -> Result<()>
+    where
+        SV: ProxyStream + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let (mut upstream, peer) = self.connect_upstream(session, ctx).await?;
+        session.digest.upstream_connected = Some(Instant::now());
+        session.upstream_digest = Some(Digest {
+            ssl_digest: upstream.get_ssl_digest(),
+            timing_digest: upstream.get_timing_digest(),
+            proxy_digest: upstream.get_proxy_digest(),
+            socket_digest: upstream.get_socket_digest(),
+        });
+
+        #[cfg(windows)]
+        let raw = upstream.id() as std::os::windows::io::RawSocket;
+        #[cfg(unix)]
+        let raw = upstream.id();
+
+        // clone to not hold a borrow of session across the callback
+        let digest = session.upstream_digest.clone();
+        self.inner
+            .connected_to_upstream(session, &peer, raw, digest.as_ref(), ctx)
+            .await?;
+
+        let StreamSession {
+            downstream,
+            digest,
+            idle_timeout,
+            total_timeout,
+            ..
+        } = session;
+        let duplex = duplex(
+            split(downstream, false),
+            split(&mut upstream, true),
+            digest,
+            *idle_timeout,
+        );
+        match total_timeout {
+            Some(t) => match time::timeout(*t, duplex).await {
+                Ok(res) => res,
+                Err(_) => Error::e_explain(ReadTimedout, format!("total timeout of {t:?} reached")),
+            },
+            None => duplex.await,
+        }
+    }
+}
+
+// The read half of one side of a connection forwarded by duplex()
+#[async_trait]
+pub(crate) trait TunnelRead: Send {
+    // None once the side closed its write half
+    async fn read(&mut self) -> Result<Option<Bytes>>;
+}
+
+// The write half of one side of a connection forwarded by duplex()
+#[async_trait]
+pub(crate) trait TunnelWrite: Send {
+    async fn write(&mut self, data: Bytes) -> Result<()>;
+    // propagate the half close of the other side
+    async fn shutdown(&mut self);
+}
+
+// A half of a [Stream], whose errors are attributed to the upstream or the downstream side
+pub(crate) struct StreamHalf<H> {
+    half: H,
+    upstream: bool,
+}
+
+impl<H> StreamHalf<H> {
+    fn peer(&self) -> &'static str {
+        if self.upstream {
+            "upstream"
+        } else {
+            "downstream"
+        }
+    }
+
+    fn side(&self, e: Box<Error>) -> Box<Error> {
+        if self.upstream {
+            e.into_up()
+        } else {
+            e.into_down()
+        }
+    }
+}
+
+// Split `stream` into halves which duplex() drives concurrently
+pub(crate) fn split(
+    stream: &mut Stream,
+    upstream: bool,
+) -> (
+    StreamHalf<ReadHalf<&mut Stream>>,
+    StreamHalf<WriteHalf<&mut Stream>>,
+) {
+    let (read, write) = tokio::io::split(stream);
+    (
+        StreamHalf {
+            half: read,
+            upstream,
+        },
+        StreamHalf {
+            half: write,
+            upstream,
+        },
+    )
+}
+
+#[async_trait]
+impl<S: AsyncRead + Send> TunnelRead for StreamHalf<ReadHalf<S>> {
+    async fn read(&mut self) -> Result<Option<Bytes>> {
+        let mut buf = BytesMut::with_capacity(BUF_SIZE);
+        let n = self
+            .half
+            .read_buf(&mut buf)
+            .await
+            .or_err_with(ReadError, || format!("while reading from {}", self.peer()))
+            .map_err(|e| self.side(e))?;
+        Ok((n > 0).then(|| buf.freeze()))
+    }
+}
+
+#[async_trait]
+impl<S: AsyncWrite + Send> TunnelWrite for StreamHalf<WriteHalf<S>> {
+    async fn write(&mut self, data: Bytes) -> Result<()> {
+        self.half
+            .write_all(&data)
+            .await
+            .or_err_with(WriteError, || format!("while writing to {}", self.peer()))
+            .map_err(|e| self.side(e))?;
+        self.half
+            .flush()
+            .await
+            .or_err_with(WriteError, || format!("while flushing to {}", self.peer()))
+            .map_err(|e| self.side(e))
+    }
+
+    async fn shutdown(&mut self) {
+        if let Err(e) = self.half.shutdown().await {
+            debug!("Fail to shutdown {} write: {e}", self.peer());
+        }
+    }
+}
+
+// Forward data in both directions until both sides close their write half or an error occurs.
+// The directions are forwarded concurrently so that a side which doesn't read doesn't stall the
+// other direction. The idle timeout is reached when no data is forwarded in either direction.
+pub(crate) async fn duplex(
+    downstream: (impl TunnelRead, impl TunnelWrite),
+    upstream: (impl TunnelRead, impl TunnelWrite),
+    digest: &mut StreamDigest,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    let (downstream_read, downstream_write) = downstream;
+    let (upstream_read, upstream_write) = upstream;
+    let last_active = Mutex::new(time::Instant::now());
+    let forwarding = futures::future::try_join(
+        forward(
+            downstream_read,
+            upstream_write,
+            &mut digest.downstream_bytes,
+            &last_active,
+        ),
+        forward(
+            upstream_read,
+            downstream_write,
+            &mut digest.upstream_bytes,
+            &last_active,
+        ),
+    );
+    let Some(t) = idle_timeout else {
+        return forwarding.await.map(|_| ());
+    };
+    tokio::select! {
+        res = forwarding => res.map(|_| ()),
+        _ = idle(t, &last_active) => {
+            Error::e_explain(ReadTimedout, format!("idle timeout of {t:?} reached"))
+        }
+    }
+}
+
+async fn forward(
+    mut reader: impl TunnelRead,
+    mut writer: impl TunnelWrite,
+    bytes: &mut u64,
+    last_active: &Mutex<time::Instant>,
+) -> Result<()> {
+    while let Some(data) = reader.read().await? {
+        let n = data.len();
+        writer.write(data).await?;
+        *bytes += n as u64;
+        *last_active.lock().unwrap() = time::Instant::now();
+    }
+    writer.shutdown().await;
+    Ok(())
+}
+
+// Return once no data is forwarded for `timeout`
+async fn idle(timeout: Duration, last_active: &Mutex<time::Instant>) {
+    loop {
+        let deadline = *last_active.lock().unwrap() + timeout;
+        if deadline <= time::Instant::now() {
+            return;
+        }
+        time::sleep_until(deadline).await;
+    }
+}
+
+#[cfg_attr(not(doc_async_trait), async_trait)]
+impl<SV> ServerApp for StreamProxy<SV>
+where
+    SV: ProxyStream + Send + Sync + 'static,
+    <SV as ProxyStream>::CTX: Send + Sync,
+{
+    async fn process_new(
+        self: &Arc<Self>,
+        io: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let mut session = StreamSession::new(io, &self.options);
+        let mut ctx = self.inner.new_ctx();
+
+        let res = self.proxy_stream(&mut session, &mut ctx).await;
+        let e = res.err();
+        if let Some(e) = e.as_ref() {
+            if !self.inner.suppress_error_log(&session, &ctx, e) {
+                error!(
+                    "Fail to proxy: {}, {}",
+                    e,
+                    self.inner.connection_summary(&session, &ctx)
+                );
+            }
+        }
+        self.inner
+            .logging(&mut session, e.as_deref(), &mut ctx)
+            .await;
+
+        // the stream is proxied until it is closed, never reuse it
+        None
+    }
+}
+
+/// Create a [Service] from the user implemented [ProxyStream].
+///
+/// The returned [Service] can be hosted by a [pingora_core::server::Server] directly.
+pub fn stream_proxy_service<SV>(conf: &Arc<ServerConf>, inner: SV) -> Service<StreamProxy<SV>> {
+    stream_proxy_service_with_name(conf, inner, "Pingora Stream Proxy Service")
+}
+
+/// Create a [Service] from the user implemented [ProxyStream].
+///
+/// The returned [Service] can be hosted by a [pingora_core::server::Server] directly.
+pub fn stream_proxy_service_with_name<SV>(
+    conf: &Arc<ServerConf>,
+    inner: SV,
+    name: &str,
+) -> Service<StreamProxy<SV>> {
+    Service::new(name.to_string(), StreamProxy::new(inner, conf.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_error::ErrorType;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
+
+    type Logged = Arc<Mutex<Option<(StreamDigest, Option<ErrorType>)>>>;
+
+    struct TestProxy {
+        upstream: String,
+        // route TLS connections by SNI
+        sni_upstreams: HashMap<String, String>,
+        logged: Logged,
+    }
+
+    #[async_trait]
+    impl ProxyStream for TestProxy {
+        type CTX = ();
+        fn new_ctx(&self) {}
+
+        async fn upstream_peer(
+            &self,
+            session: &mut StreamSession,
+            _ctx: &mut (),
+        ) -> Result<Box<BasicPeer>> {
+            if !self.sni_upstreams.is_empty() {
+                let sni = session
+                    .peek_client_hello()
+                    .await?
+                    .and_then(|h| h.sni.as_ref());
+                if let Some(upstream) = sni.and_then(|sni| self.sni_upstreams.get(sni)) {
+                    return Ok(Box::new(BasicPeer::new(upstream)));
+                }
+            }
+            Ok(Box::new(BasicPeer::new(&self.upstream)))
+        }
+
+        async fn logging(&self, session: &mut StreamSession, e: Option<&Error>, _ctx: &mut ()) {
+            *self.logged.lock().unwrap() =
+                Some((session.digest().clone(), e.map(|e| e.etype().clone())));
+        }
+    }
+
+    async fn start_proxy(
+        upstream: String,
+        options: StreamProxyOptions,
+    ) -> (TcpStream, tokio::task::JoinHandle<()>, Logged) {
+        start_sni_proxy(upstream, HashMap::new(), options).await
+    }
+
+    async fn start_sni_proxy(
+        upstream: String,
+        sni_upstreams: HashMap<String, String>,
+        options: StreamProxyOptions,
+    ) -> (TcpStream, tokio::task::JoinHandle<()>, Logged) {
+        let logged = Arc::new(Mutex::new(None));
+        let conf = Arc::new(ServerConf::default());
+        let mut proxy = StreamProxy::new(
+            TestProxy {
+                upstream,
+                sni_upstreams,
+                logged: logged.clone(),
+            },
+            conf,
+        );
+        proxy.options = options;
+        let proxy = Arc::new(proxy);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (io, _) = listener.accept().await.unwrap();
+            let stream: Stream = Box::new(pingora_core::protocols::l4::stream::Stream::from(io));
+            let (_tx, shutdown) = watch::channel(false);
+            assert!(proxy.process_new(stream, &shutdown).await.is_none());
+        });
+        let client = TcpStream::connect(addr).await.unwrap();
+        (client, handle, logged)
+    }
+
+    #[tokio::test]
+    async fn test_stream_proxy_echo() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut io, _) = upstream.accept().await.unwrap();
+            let (mut r, mut w) = io.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+
+        let (mut client, handle, logged) =
+            start_proxy(upstream_addr, StreamProxyOptions::default()).await;
+        client.write_all(b"hello stream").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut echoed = vec![];
+        client.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello stream");
+
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, None);
+        assert_eq!(digest.downstream_bytes, 12);
+        assert_eq!(digest.upstream_bytes, 12);
+        assert!(digest.upstream_connected.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_stream_proxy_both_directions() {
+        // more than the socket buffers can hold
+        const LEN: usize = 32 * 1024 * 1024;
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            // only read once everything is written
+            let (mut io, _) = upstream.accept().await.unwrap();
+            io.write_all(&vec![b'u'; LEN]).await.unwrap();
+            io.shutdown().await.unwrap();
+            let mut received = vec![];
+            io.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received.len(), LEN);
+        });
+
+        let (client, handle, logged) =
+            start_proxy(upstream_addr, StreamProxyOptions::default()).await;
+        let (mut r, mut w) = client.into_split();
+        let write = async {
+            w.write_all(&vec![b'd'; LEN]).await.unwrap();
+            w.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut received = vec![];
+            r.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received.len(), LEN);
+        };
+        time::timeout(Duration::from_secs(10), async { tokio::join!(write, read) })
+            .await
+            .unwrap();
+
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, None);
+        assert_eq!(digest.downstream_bytes, LEN as u64);
+        assert_eq!(digest.upstream_bytes, LEN as u64);
+    }
+
+    #[tokio::test]
+    async fn test_stream_proxy_idle_timeout() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut io, _) = upstream.accept().await.unwrap();
+            // read but never respond
+            let mut buf = [0; 64];
+            while io.read(&mut buf).await.unwrap_or(0) > 0 {}
+        });
+
+        let options = StreamProxyOptions {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let (mut client, handle, logged) = start_proxy(upstream_addr, options).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = vec![];
+        // the proxy closes the connection once idle
+        client.read_to_end(&mut buf).await.unwrap();
+
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, Some(ReadTimedout));
+        assert_eq!(digest.downstream_bytes, 4);
+        assert_eq!(digest.upstream_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_proxy_total_timeout() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut io, _) = upstream.accept().await.unwrap();
+            // keep the connection busy
+            loop {
+                if io.write_all(b".").await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        let options = StreamProxyOptions {
+            idle_timeout: Some(Duration::from_secs(1)),
+            total_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (mut client, handle, logged) = start_proxy(upstream_addr, options).await;
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(!buf.is_empty());
+
+        handle.await.unwrap();
+        let (digest, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, Some(ReadTimedout));
+        assert_eq!(digest.upstream_bytes, buf.len() as u64);
+    }
+
+    async fn named_upstream(name: &'static [u8]) -> String {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut io, _) = upstream.accept().await.unwrap();
+            let mut received = vec![];
+            io.read_to_end(&mut received).await.unwrap();
+            io.write_all(name).await.unwrap();
+            io.write_all(&received).await.unwrap();
+        });
+        addr
+    }
+
+    // A ClientHello record with only the server_name extension
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let mut server_name = vec![0]; // host_name
+        server_name.extend((sni.len() as u16).to_be_bytes());
+        server_name.extend(sni.as_bytes());
+        let mut extension = vec![0, 0]; // server_name
+        extension.extend((server_name.len() as u16 + 2).to_be_bytes());
+        extension.extend((server_name.len() as u16).to_be_bytes());
+        extension.extend(server_name);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0; 32]); // random
+        body.extend([0]); // session id
+        body.extend([0, 2, 0x13, 0x01]); // cipher suites
+        body.extend([1, 0]); // compression
+        body.extend((extension.len() as u16).to_be_bytes());
+        body.extend(extension);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((body.len() as u16 + 4).to_be_bytes());
+        record.extend([0x01, 0]);
+        record.extend((body.len() as u16).to_be_bytes());
+        record.extend(body);
+        record
+    }
+
+    #[tokio::test]
+    async fn test_stream_proxy_sni_passthrough() {
+        let default = named_upstream(b"default:").await;
+        let passthrough = named_upstream(b"passthrough:").await;
+        let routes = HashMap::from([("passthrough.example.com".to_string(), passthrough)]);
+
+        let (mut client, handle, _) =
+            start_sni_proxy(default, routes, StreamProxyOptions::default()).await;
+        let hello = client_hello("passthrough.example.com");
+        client.write_all(&hello).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        handle.await.unwrap();
+
+        // routed by SNI and the ClientHello is forwarded untouched
+        let mut expected = b"passthrough:".to_vec();
+        expected.extend(hello);
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_stream_proxy_client_hello_timeout() {
+        let default = named_upstream(b"default:").await;
+        let routes = HashMap::from([("passthrough.example.com".to_string(), default.clone())]);
+        let options = StreamProxyOptions {
+            client_hello_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+
+        // e.g. a server-first protocol, the client waits to be greeted
+        let (mut client, handle, logged) = start_sni_proxy(default, routes, options).await;
+        let mut received = vec![];
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        handle.await.unwrap();
+        let (_, e) = logged.lock().unwrap().take().unwrap();
+        assert_eq!(e, Some(ReadTimedout));
+    }
+}
@@ -18,6 +18,7 @@ use pingora_cache::{
     CacheKey, CacheMeta, ForcedInvalidationKind,
     RespCacheable::{self, *},
 };
+use pingora_core::protocols::http::websocket::Message as WebSocketMessage;
 use proxy_cache::range_filter::{self};
 use std::time::Duration;
 
@@ -72,6 +73,27 @@ pub trait ProxyHttp {
         Ok(false)
     }
 
+    /// Decide whether the destination of a forward proxy request may be accessed.
+    ///
+    /// This filter is called after [Self::request_filter()] for the requests with an absolute-form
+    /// URI or the `CONNECT` method when [Self::is_forward_proxy()] returns true. The destination is
+    /// also available via [Session::forward_destination()].
+    ///
+    /// When `Ok(false)` is returned, a 403 response is sent unless the filter already sent one.
+    ///
+    /// By default all destinations are allowed.
+    async fn forward_destination_filter(
+        &self,
+        _session: &mut Session,
+        _destination: &ForwardDestination,
+        _ctx: &mut Self::CTX,
+    ) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(true)
+    }
+
     /// Handle the incoming request before any downstream module is executed.
     ///
     /// This function is similar to [Self::request_filter()] but executes before any other logic,
@@ -340,7 +362,8 @@ pub trait ProxyHttp {
     /// there is a fatal error that terminate the request.
     ///
     /// An error log is already emitted if there is any error. This phase is used for collecting
-    /// metrics and sending access logs.
+    /// metrics and sending access logs. The bytes forwarded through a `CONNECT` tunnel are
+    /// available via [Session::tunnel_digest()].
     async fn logging(&self, _session: &mut Session, _e: Option<&Error>, _ctx: &mut Self::CTX)
     where
         Self::CTX: Send + Sync,
@@ -477,6 +500,57 @@ pub trait ProxyHttp {
         false
     }
 
+    /// Whether the proxy acts as a forward proxy for this request
+    ///
+    /// When true, requests with an absolute-form URI (`GET http://host/path`) are sent upstream in
+    /// origin form with the `Host` of the URI, and `CONNECT` requests open a tunnel to the
+    /// requested destination. [Self::upstream_peer()] should select the peer from
+    /// [Session::forward_destination()], e.g. via `HttpPeer::resolve()`. The peer of a tunnel
+    /// should not use TLS because the client runs its own TLS handshake through the tunnel.
+    ///
+    /// Requests in origin form are proxied as usual. The default value is false.
+    fn is_forward_proxy(&self, _session: &Session, _ctx: &Self::CTX) -> bool {
+        false
+    }
+
+    /// Whether to inspect the messages of this WebSocket upgrade request
+    ///
+    /// Returning the maximum size of a message in bytes enables the parsing of the frames once
+    /// the upstream accepts the upgrade, so that [Self::websocket_message_filter()] is called
+    /// on every message. The `Sec-WebSocket-Extensions` header is removed from the upgrade
+    /// request because compressed messages cannot be inspected.
+    ///
+    /// A message larger than the limit, or frames violating the protocol, are replaced with a close
+    /// frame (1009 or 1002) sent to the receiver on behalf of the sender. Nothing else is
+    /// forwarded in that direction afterwards, like after any close frame.
+    ///
+    /// Only HTTP/1.1 upstreams are supported. The default value `None` forwards the upgraded
+    /// connection as is.
+    fn websocket_max_message_size(&self, _session: &Session, _ctx: &Self::CTX) -> Option<usize> {
+        None
+    }
+
+    /// Inspect, modify, drop or inject the text and binary messages of a WebSocket session
+    ///
+    /// `messages` initially holds one complete message received in the given `direction`.
+    /// The messages left in it are forwarded in order: clear it to drop the message, push more
+    /// to inject messages. Pushing a close frame closes this direction of the session.
+    ///
+    /// Ping, pong and close frames are passed through without calling this filter.
+    /// See [Self::websocket_max_message_size()] to enable it.
+    async fn websocket_message_filter(
+        &self,
+        _session: &mut Session,
+        _direction: WebSocketDirection,
+        _messages: &mut Vec<WebSocketMessage>,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(())
+    }
+
     /// This filter is called after the proxy cache generates the downstream response to the purge
     /// request (to invalidate or delete from the HTTP cache), based on the purge status, which
     /// indicates whether the request succeeded or failed.
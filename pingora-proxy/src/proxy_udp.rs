@@ -0,0 +1,462 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! UDP datagram proxy
+//!
+//! Datagrams received on the listening sockets of a [UdpProxy] are forwarded to the backends of a
+//! [LoadBalancer], and the responses are relayed back to the clients that sent them.
+
+use super::*;
+use log::info;
+use lru::LruCache;
+use pingora_core::listeners::{UdpListenerEndpoint, UdpSocketOptions};
+#[cfg(unix)]
+use pingora_core::server::ListenFds;
+use pingora_core::services::Service as ServiceTrait;
+use pingora_error::OkOrErr;
+use pingora_load_balancing::selection::{BackendIter, BackendSelection};
+use pingora_load_balancing::LoadBalancer;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_SESSIONS: usize = 4096;
+// same as what the examples use for LoadBalancer::select()
+const MAX_SELECT_ITERATIONS: usize = 256;
+
+// A client session: all the datagrams of a client go through the same upstream socket so that
+// the responses can be matched back to the client.
+struct UdpSession {
+    upstream: UdpSocket,
+    created: Instant,
+    // milliseconds since `created`
+    last_active: AtomicU64,
+    // notified when the session is evicted to make room for a new one
+    evicted: Notify,
+}
+
+impl UdpSession {
+    fn touch(&self) {
+        self.last_active
+            .store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.created.elapsed().saturating_sub(Duration::from_millis(
+            self.last_active.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+// The sessions are kept in the order of their last activity, the idlest one first.
+type SessionTable = Arc<Mutex<LruCache<SocketAddr, Arc<UdpSession>>>>;
+
+/// A [Service](pingora_core::services::Service) that proxies UDP datagrams to the backends of a
+/// [LoadBalancer]
+///
+/// Every client address gets its own session with a dedicated upstream socket. A session expires
+/// once there has been no traffic in either direction for [Self::idle_timeout]; the next datagram
+/// from that client starts a new session, which may be assigned to a different backend.
+///
+/// Each listening endpoint keeps at most [Self::max_sessions] sessions (and upstream sockets).
+/// When the limit is reached, the session that has been idle the longest is evicted to make room
+/// for a new client.
+///
+/// The listening sockets take part in graceful upgrade: they are passed to the new process the
+/// same way TCP listeners are.
+pub struct UdpProxy<S> {
+    name: String,
+    addrs: Vec<(String, Option<UdpSocketOptions>)>,
+    lb: Arc<LoadBalancer<S>>,
+    /// How long a client session is kept without traffic in either direction. Default 30s.
+    pub idle_timeout: Duration,
+    /// The max number of client sessions of each listening endpoint. Default 4096.
+    pub max_sessions: usize,
+    /// The number of preferred threads. `None` to follow global setting.
+    pub threads: Option<usize>,
+}
+
+impl<S> UdpProxy<S> {
+    /// Create a new [UdpProxy] which forwards datagrams to the backends selected by `lb`.
+    ///
+    /// The [LoadBalancer] should be run as a background service separately if it needs service
+    /// discovery or health checks.
+    pub fn new(name: &str, lb: Arc<LoadBalancer<S>>) -> Self {
+        UdpProxy {
+            name: name.to_string(),
+            addrs: vec![],
+            lb,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            threads: None,
+        }
+    }
+
+    /// Add a UDP listening endpoint with the given address (e.g., `127.0.0.1:53`).
+    pub fn add_udp(&mut self, addr: &str) {
+        self.addrs.push((addr.to_string(), None));
+    }
+
+    /// Add a UDP listening endpoint with the given [`UdpSocketOptions`].
+    pub fn add_udp_with_settings(&mut self, addr: &str, sock_opt: UdpSocketOptions) {
+        self.addrs.push((addr.to_string(), Some(sock_opt)));
+    }
+}
+
+async fn new_session<S>(
+    lb: &LoadBalancer<S>,
+    client: &SocketAddr,
+) -> Result<(Arc<UdpSession>, SocketAddr)>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    let backend = lb
+        .select(client.to_string().as_bytes(), MAX_SELECT_ITERATIONS)
+        .or_err(ConnectNoRoute, "no backend available")?;
+    let peer = *backend.addr.as_inet().or_err_with(ConnectError, || {
+        format!("UDP backend {} is not an inet address", backend.addr)
+    })?;
+    let local: SocketAddr = match peer {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let upstream = UdpSocket::bind(local)
+        .await
+        .or_err(SocketError, "while binding upstream UDP socket")?;
+    upstream.connect(peer).await.or_err_with(ConnectError, || {
+        format!("while connecting to UDP backend {peer}")
+    })?;
+    let session = UdpSession {
+        upstream,
+        created: Instant::now(),
+        last_active: AtomicU64::new(0),
+        evicted: Notify::new(),
+    };
+    Ok((Arc::new(session), peer))
+}
+
+// Relay the responses of the upstream back to the client until the session expires.
+async fn relay_upstream(
+    downstream: Arc<UdpSocket>,
+    client: SocketAddr,
+    session: Arc<UdpSession>,
+    sessions: SessionTable,
+    idle_timeout: Duration,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let remaining = idle_timeout.saturating_sub(session.idle_for());
+        if remaining.is_zero() {
+            debug!("UDP session of {client} expired");
+            break;
+        }
+        let readable = tokio::select! {
+            _ = session.evicted.notified() => return,
+            res = time::timeout(remaining, session.upstream.readable()) => res,
+        };
+        match readable {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("Fail to wait for upstream of {client}: {e}");
+                break;
+            }
+            // the downstream may have been active in the meantime, check again
+            Err(_) => continue,
+        }
+        match session.upstream.try_recv(&mut buf) {
+            Ok(n) => {
+                session.touch();
+                sessions.lock().unwrap().promote(&client);
+                if let Err(e) = downstream.send_to(&buf[..n], client).await {
+                    warn!("Fail to send datagram to {client}: {e}");
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => {
+                // e.g. ICMP port unreachable, let the next datagram pick a backend again
+                warn!("Fail to receive datagram from upstream of {client}: {e}");
+                break;
+            }
+        }
+    }
+    let mut sessions = sessions.lock().unwrap();
+    if sessions
+        .peek(&client)
+        .is_some_and(|s| Arc::ptr_eq(s, &session))
+    {
+        sessions.pop(&client);
+    }
+}
+
+async fn run_endpoint<S>(
+    endpoint: UdpListenerEndpoint,
+    lb: Arc<LoadBalancer<S>>,
+    idle_timeout: Duration,
+    max_sessions: usize,
+    mut shutdown: ShutdownWatch,
+) where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    let name = endpoint.as_str().to_string();
+    let downstream = Arc::new(endpoint.into_socket());
+    let max_sessions = NonZeroUsize::new(max_sessions).unwrap_or(NonZeroUsize::MIN);
+    let sessions: SessionTable = Arc::new(Mutex::new(LruCache::new(max_sessions)));
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (n, client) = tokio::select! {
+            _ = shutdown.changed() => {
+                info!("Shutting down {name}");
+                break;
+            }
+            res = downstream.recv_from(&mut buf) => match res {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Fail to receive datagram on {name}: {e}");
+                    continue;
+                }
+            }
+        };
+
+        let existing = sessions.lock().unwrap().get(&client).cloned();
+        let session = match existing {
+            Some(s) => s,
+            None => match new_session(&lb, &client).await {
+                Ok((session, peer)) => {
+                    debug!("New UDP session {client} -> {peer}");
+                    // a full table evicts the session that has been idle the longest
+                    let evicted = sessions.lock().unwrap().push(client, session.clone());
+                    if let Some((evicted_client, evicted)) = evicted {
+                        debug!("UDP session of {evicted_client} evicted");
+                        evicted.evicted.notify_one();
+                    }
+                    tokio::spawn(relay_upstream(
+                        downstream.clone(),
+                        client,
+                        session.clone(),
+                        sessions.clone(),
+                        idle_timeout,
+                    ));
+                    session
+                }
+                Err(e) => {
+                    warn!("Fail to proxy datagram from {client}: {e}");
+                    continue;
+                }
+            },
+        };
+        session.touch();
+        if let Err(e) = session.upstream.send(&buf[..n]).await {
+            warn!("Fail to send datagram of {client} to upstream: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl<S> ServiceTrait for UdpProxy<S>
+where
+    S: BackendSelection + Send + Sync + 'static,
+    S::Iter: BackendIter,
+{
+    async fn start_service(
+        &mut self,
+        #[cfg(unix)] fds: Option<ListenFds>,
+        shutdown: ShutdownWatch,
+    ) {
+        let mut handlers = vec![];
+        for (addr, opt) in self.addrs.iter() {
+            let endpoint = UdpListenerEndpoint::bind(
+                addr,
+                opt.as_ref(),
+                #[cfg(unix)]
+                fds.clone(),
+            )
+            .await
+            .expect("Failed to bind UDP listeners");
+            handlers.push(tokio::spawn(run_endpoint(
+                endpoint,
+                self.lb.clone(),
+                self.idle_timeout,
+                self.max_sessions,
+                shutdown.clone(),
+            )));
+        }
+        futures::future::join_all(handlers).await;
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.threads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_load_balancing::selection::RoundRobin;
+    use tokio::sync::watch;
+
+    async fn echo_upstream() -> (SocketAddr, Arc<Mutex<Vec<SocketAddr>>>) {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let senders = Arc::new(Mutex::new(vec![]));
+        let senders_clone = senders.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                let (n, from) = upstream.recv_from(&mut buf).await.unwrap();
+                senders_clone.lock().unwrap().push(from);
+                upstream.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+        (addr, senders)
+    }
+
+    fn start_proxy(
+        listen: &str,
+        upstream: SocketAddr,
+        idle_timeout: Duration,
+        max_sessions: usize,
+    ) -> watch::Sender<bool> {
+        let lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_iter([upstream]).unwrap();
+        let mut proxy = UdpProxy::new("udp proxy", Arc::new(lb));
+        proxy.idle_timeout = idle_timeout;
+        proxy.max_sessions = max_sessions;
+        proxy.add_udp(listen);
+        let (tx, shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            proxy
+                .start_service(
+                    #[cfg(unix)]
+                    None,
+                    shutdown,
+                )
+                .await
+        });
+        tx
+    }
+
+    async fn roundtrip(client: &UdpSocket, proxy: &str, msg: &[u8]) {
+        let mut buf = [0; 1500];
+        // the proxy may not be listening yet
+        for _ in 0..50 {
+            client.send_to(msg, proxy).await.unwrap();
+            if let Ok(res) =
+                time::timeout(Duration::from_millis(100), client.recv_from(&mut buf)).await
+            {
+                let (n, _) = res.unwrap();
+                assert_eq!(&buf[..n], msg);
+                return;
+            }
+        }
+        panic!("no response from {proxy}");
+    }
+
+    #[tokio::test]
+    async fn test_udp_proxy() {
+        let proxy_addr = "127.0.0.1:6170";
+        let (upstream, senders) = echo_upstream().await;
+        let _shutdown = start_proxy(
+            proxy_addr,
+            upstream,
+            DEFAULT_IDLE_TIMEOUT,
+            DEFAULT_MAX_SESSIONS,
+        );
+
+        let client1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        roundtrip(&client1, proxy_addr, b"one").await;
+        roundtrip(&client2, proxy_addr, b"two").await;
+        roundtrip(&client1, proxy_addr, b"three").await;
+
+        let senders = senders.lock().unwrap();
+        // each client has its own session
+        assert_ne!(senders[senders.len() - 3], senders[senders.len() - 2]);
+        assert_eq!(senders[senders.len() - 3], senders[senders.len() - 1]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_proxy_idle_expiry() {
+        let proxy_addr = "127.0.0.1:6171";
+        let (upstream, senders) = echo_upstream().await;
+        let _shutdown = start_proxy(
+            proxy_addr,
+            upstream,
+            Duration::from_millis(100),
+            DEFAULT_MAX_SESSIONS,
+        );
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        roundtrip(&client, proxy_addr, b"one").await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        roundtrip(&client, proxy_addr, b"two").await;
+
+        // the first session expired, a new upstream socket is used
+        let senders = senders.lock().unwrap();
+        assert_ne!(senders[senders.len() - 2], senders[senders.len() - 1]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_proxy_max_sessions() {
+        let proxy_addr = "127.0.0.1:6172";
+        let (upstream, senders) = echo_upstream().await;
+        let _shutdown = start_proxy(proxy_addr, upstream, DEFAULT_IDLE_TIMEOUT, 1);
+
+        let client1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        roundtrip(&client1, proxy_addr, b"one").await;
+        roundtrip(&client2, proxy_addr, b"two").await;
+        roundtrip(&client1, proxy_addr, b"three").await;
+
+        // the session of client1 is evicted by client2, so a new upstream socket is used
+        let senders = senders.lock().unwrap();
+        assert_ne!(senders[senders.len() - 3], senders[senders.len() - 1]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_proxy_evict_idlest() {
+        let proxy_addr = "127.0.0.1:6173";
+        let (upstream, senders) = echo_upstream().await;
+        let _shutdown = start_proxy(proxy_addr, upstream, DEFAULT_IDLE_TIMEOUT, 2);
+
+        let client1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client3 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        roundtrip(&client1, proxy_addr, b"one").await;
+        roundtrip(&client2, proxy_addr, b"two").await;
+        roundtrip(&client1, proxy_addr, b"three").await;
+        // client2 is the idlest, its session is evicted
+        roundtrip(&client3, proxy_addr, b"four").await;
+        roundtrip(&client1, proxy_addr, b"five").await;
+        roundtrip(&client2, proxy_addr, b"six").await;
+
+        let senders = senders.lock().unwrap();
+        let n = senders.len();
+        assert_eq!(senders[n - 6], senders[n - 4]);
+        assert_eq!(senders[n - 6], senders[n - 2]);
+        assert_ne!(senders[n - 5], senders[n - 1]);
+    }
+}
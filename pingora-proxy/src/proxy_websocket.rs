@@ -0,0 +1,376 @@
+// Copyright 2025 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inspection of the messages of upgraded WebSocket sessions
+
+use super::*;
+use bytes::BytesMut;
+use pingora_core::protocols::http::websocket::{
+    Message, MessageDecoder, OpCode, CLOSE_INVALID_DATA, CLOSE_MESSAGE_TOO_BIG,
+    CLOSE_PROTOCOL_ERROR, WS_INVALID_DATA, WS_MESSAGE_TOO_BIG,
+};
+
+/// The direction of the WebSocket messages, see [ProxyHttp::websocket_message_filter()]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSocketDirection {
+    /// From the client (downstream) to the server (upstream)
+    Upstream,
+    /// From the server (upstream) to the client (downstream)
+    Downstream,
+}
+
+// The state of one direction of the session
+struct MessageStream {
+    decoder: MessageDecoder,
+    // whether a close frame is already sent in this direction, after which nothing is forwarded
+    closed: bool,
+}
+
+impl MessageStream {
+    fn new(direction: WebSocketDirection, max_message_size: usize) -> Self {
+        // frames sent by clients are masked
+        let masked = direction == WebSocketDirection::Upstream;
+        MessageStream {
+            decoder: MessageDecoder::new(masked, max_message_size),
+            closed: false,
+        }
+    }
+}
+
+// The WebSocket inspection state of a session
+pub(crate) struct WebSocketCtx {
+    // whether the upgrade is accepted, before which the data is forwarded as is
+    upgraded: bool,
+    to_upstream: MessageStream,
+    to_downstream: MessageStream,
+}
+
+impl WebSocketCtx {
+    pub(crate) fn new(max_message_size: usize) -> Self {
+        WebSocketCtx {
+            upgraded: false,
+            to_upstream: MessageStream::new(WebSocketDirection::Upstream, max_message_size),
+            to_downstream: MessageStream::new(WebSocketDirection::Downstream, max_message_size),
+        }
+    }
+
+    fn stream(&mut self, direction: WebSocketDirection) -> &mut MessageStream {
+        match direction {
+            WebSocketDirection::Upstream => &mut self.to_upstream,
+            WebSocketDirection::Downstream => &mut self.to_downstream,
+        }
+    }
+}
+
+pub(crate) fn is_websocket_upgrade(req: &RequestHeader) -> bool {
+    req.headers
+        .get(header::UPGRADE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+impl<SV> HttpProxy<SV> {
+    // Start inspecting the messages of the WebSocket upgrade request `req` if the user asks to.
+    pub(crate) fn websocket_request_filter(
+        &self,
+        session: &mut Session,
+        req: &mut RequestHeader,
+        ctx: &SV::CTX,
+    ) where
+        SV: ProxyHttp,
+    {
+        session.websocket = None;
+        if !is_websocket_upgrade(req) {
+            return;
+        }
+        let Some(max_message_size) = self.inner.websocket_max_message_size(session, ctx) else {
+            return;
+        };
+        // compressed messages cannot be inspected
+        req.remove_header(&header::SEC_WEBSOCKET_EXTENSIONS);
+        session.websocket = Some(WebSocketCtx::new(max_message_size));
+    }
+
+    // Parse the data of an upgraded WebSocket session into messages, run them through
+    // websocket_message_filter() and encode them back into `data`.
+    pub(crate) async fn websocket_body_filter(
+        &self,
+        session: &mut Session,
+        data: &mut Option<Bytes>,
+        direction: WebSocketDirection,
+        ctx: &mut SV::CTX,
+    ) -> Result<()>
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let Some(ws) = session.websocket.as_mut().filter(|ws| ws.upgraded) else {
+            return Ok(());
+        };
+        let Some(input) = data.as_ref() else {
+            return Ok(());
+        };
+        let stream = ws.stream(direction);
+        if stream.closed {
+            // nothing is allowed after a close frame
+            *data = Some(Bytes::new());
+            return Ok(());
+        }
+        stream.decoder.push(input);
+
+        // frames sent by clients are masked
+        let mask = direction == WebSocketDirection::Upstream;
+        let mut output = BytesMut::new();
+        while let Some(ws) = session.websocket.as_mut() {
+            let stream = ws.stream(direction);
+            if stream.closed {
+                break;
+            }
+            let message = match stream.decoder.next_message() {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    // close on behalf of the sender, the receiver should echo it back
+                    let code = if e.etype() == &WS_MESSAGE_TOO_BIG {
+                        CLOSE_MESSAGE_TOO_BIG
+                    } else if e.etype() == &WS_INVALID_DATA {
+                        CLOSE_INVALID_DATA
+                    } else {
+                        CLOSE_PROTOCOL_ERROR
+                    };
+                    stream.closed = true;
+                    Message::close(code, "").encode(mask, &mut output);
+                    warn!(
+                        "Closing WebSocket {direction:?}: {e}, {}",
+                        self.inner.request_summary(session, ctx)
+                    );
+                    break;
+                }
+            };
+            let mut messages = vec![message];
+            // control frames are passed through
+            if !messages[0].opcode.is_control() {
+                self.inner
+                    .websocket_message_filter(session, direction, &mut messages, ctx)
+                    .await?;
+            }
+            for message in messages {
+                message.encode(mask, &mut output);
+                if message.opcode == OpCode::Close {
+                    if let Some(ws) = session.websocket.as_mut() {
+                        ws.stream(direction).closed = true;
+                    }
+                    break;
+                }
+            }
+        }
+        *data = Some(output.freeze());
+        Ok(())
+    }
+
+    // Start inspecting the messages once the upstream accepts the upgrade.
+    pub(crate) fn websocket_response_filter(&self, session: &mut Session, resp: &ResponseHeader) {
+        let Some(ws) = session.websocket.as_mut() else {
+            return;
+        };
+        if resp.status == 101 {
+            ws.upgraded = true;
+        } else if !resp.status.is_informational() {
+            // the upgrade is refused, this is a regular response
+            session.websocket = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_core::server::configuration::ServerConf;
+    use tokio_test::io::Builder;
+
+    struct Inspector;
+
+    #[async_trait]
+    impl ProxyHttp for Inspector {
+        type CTX = ();
+        fn new_ctx(&self) {}
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut (),
+        ) -> Result<Box<HttpPeer>> {
+            unreachable!()
+        }
+
+        fn websocket_max_message_size(&self, _session: &Session, _ctx: &()) -> Option<usize> {
+            Some(16)
+        }
+
+        async fn websocket_message_filter(
+            &self,
+            _session: &mut Session,
+            _direction: WebSocketDirection,
+            messages: &mut Vec<Message>,
+            _ctx: &mut (),
+        ) -> Result<()> {
+            match messages[0].as_text() {
+                Some("drop") => messages.clear(),
+                Some(text) => {
+                    messages[0] = Message::text(text.to_uppercase());
+                    messages.push(Message::text("injected"));
+                }
+                None => {}
+            }
+            Ok(())
+        }
+    }
+
+    fn encode(messages: &[Message], mask: bool) -> Option<Bytes> {
+        let mut buf = BytesMut::new();
+        for message in messages {
+            message.encode(mask, &mut buf);
+        }
+        Some(buf.freeze())
+    }
+
+    fn decode(data: Option<Bytes>, masked: bool) -> Vec<Message> {
+        let mut decoder = MessageDecoder::new(masked, usize::MAX);
+        decoder.push(&data.unwrap());
+        let mut messages = vec![];
+        while let Some(message) = decoder.next_message().unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_websocket_filters() {
+        let proxy = HttpProxy::new(Inspector, Arc::new(ServerConf::default()));
+        let request = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let mut session = Session::new_h1(Box::new(Builder::new().read(request).build()));
+        session.read_request().await.unwrap();
+
+        let mut req = session.req_header().clone();
+        req.insert_header(header::SEC_WEBSOCKET_EXTENSIONS, "permessage-deflate")
+            .unwrap();
+        proxy.websocket_request_filter(&mut session, &mut req, &());
+        assert!(req.headers.get(header::SEC_WEBSOCKET_EXTENSIONS).is_none());
+
+        // forwarded as is until the upgrade is accepted
+        let mut data = Some(Bytes::from_static(b"raw"));
+        let up = WebSocketDirection::Upstream;
+        let down = WebSocketDirection::Downstream;
+        proxy
+            .websocket_body_filter(&mut session, &mut data, up, &mut ())
+            .await
+            .unwrap();
+        assert_eq!(data.unwrap(), "raw");
+        let resp = ResponseHeader::build(101, None).unwrap();
+        proxy.websocket_response_filter(&mut session, &resp);
+
+        // a message split across two chunks, a ping and a dropped message
+        let input = encode(
+            &[
+                Message::text("hello"),
+                Message::ping("p"),
+                Message::text("drop"),
+            ],
+            true,
+        )
+        .unwrap();
+        let mut data = Some(input.slice(..3));
+        proxy
+            .websocket_body_filter(&mut session, &mut data, up, &mut ())
+            .await
+            .unwrap();
+        assert!(data.unwrap().is_empty());
+        let mut data = Some(input.slice(3..));
+        proxy
+            .websocket_body_filter(&mut session, &mut data, up, &mut ())
+            .await
+            .unwrap();
+        assert_eq!(
+            decode(data, true),
+            vec![
+                Message::text("HELLO"),
+                Message::text("injected"),
+                Message::ping("p")
+            ]
+        );
+
+        // too big: the receiver gets a close on behalf of the sender, then nothing
+        let mut data = encode(&[Message::binary(vec![0u8; 17])], false);
+        proxy
+            .websocket_body_filter(&mut session, &mut data, down, &mut ())
+            .await
+            .unwrap();
+        let close = decode(data, false);
+        assert_eq!(close.len(), 1);
+        assert_eq!(close[0].close_code(), Some(CLOSE_MESSAGE_TOO_BIG));
+        let mut data = encode(&[Message::text("more")], false);
+        proxy
+            .websocket_body_filter(&mut session, &mut data, down, &mut ())
+            .await
+            .unwrap();
+        assert!(data.unwrap().is_empty());
+
+        // the other direction is still open until it sends a close
+        let mut data = encode(&[Message::close(1000, ""), Message::text("late")], true);
+        proxy
+            .websocket_body_filter(&mut session, &mut data, up, &mut ())
+            .await
+            .unwrap();
+        assert_eq!(decode(data, true), vec![Message::close(1000, "")]);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_invalid_frames() {
+        let proxy = HttpProxy::new(Inspector, Arc::new(ServerConf::default()));
+        let request = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let mut session = Session::new_h1(Box::new(Builder::new().read(request).build()));
+        session.read_request().await.unwrap();
+        let mut req = session.req_header().clone();
+        proxy.websocket_request_filter(&mut session, &mut req, &());
+        let resp = ResponseHeader::build(101, None).unwrap();
+        proxy.websocket_response_filter(&mut session, &resp);
+
+        // an unmasked frame from the client
+        let mut data = encode(&[Message::text("hi")], false);
+        proxy
+            .websocket_body_filter(
+                &mut session,
+                &mut data,
+                WebSocketDirection::Upstream,
+                &mut (),
+            )
+            .await
+            .unwrap();
+        let close = decode(data, true);
+        assert_eq!(close[0].close_code(), Some(CLOSE_PROTOCOL_ERROR));
+
+        // invalid UTF-8 from the server
+        let mut data = Some(Bytes::from_static(b"\x81\x02\xC3\x28"));
+        proxy
+            .websocket_body_filter(
+                &mut session,
+                &mut data,
+                WebSocketDirection::Downstream,
+                &mut (),
+            )
+            .await
+            .unwrap();
+        let close = decode(data, false);
+        assert_eq!(close[0].close_code(), Some(CLOSE_INVALID_DATA));
+    }
+}
@@ -17,20 +17,36 @@
 
 #![warn(clippy::all)]
 
+use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
+use std::sync::Arc;
 
 use log::warn;
 pub use no_debug::{Ellipses, NoDebug, WithTypeInfo};
 use pingora_error::{Error, ErrorType, OrErr, Result};
-pub use rustls::{version, ClientConfig, RootCertStore, ServerConfig, Stream};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::SecureRandom;
+pub use ring::rand::SystemRandom;
+pub use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+pub use rustls::client::{ClientSessionMemoryCache, Resumption};
+pub use rustls::server::danger::ClientCertVerifier;
+pub use rustls::server::{ClientHello, ProducesTickets, ResolvesServerCert, WebPkiClientVerifier};
+pub use rustls::sign::CertifiedKey;
+pub use rustls::{
+    version, ClientConfig, HandshakeKind, RootCertStore, ServerConfig, SignatureScheme, Stream,
+};
 pub use rustls_native_certs::load_native_certs;
 use rustls_pemfile::Item;
-pub use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+pub use rustls_pki_types::{
+    CertificateDer, CertificateRevocationListDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName,
+};
 pub use tokio_rustls::client::TlsStream as ClientTlsStream;
 pub use tokio_rustls::server::TlsStream as ServerTlsStream;
-pub use tokio_rustls::{Accept, Connect, TlsAcceptor, TlsConnector, TlsStream};
+pub use tokio_rustls::{
+    Accept, Connect, LazyConfigAcceptor, StartHandshake, TlsAcceptor, TlsConnector, TlsStream,
+};
 
 /// Load the given file from disk as a buffered reader and use the pingora Error
 /// type instead of the std::io version
@@ -81,6 +97,24 @@ where
     Ok(())
 }
 
+/// Load the certificate revocation lists from the given pem file path
+pub fn load_crls<P>(path: P) -> Result<Vec<CertificateRevocationListDer<'static>>>
+where
+    P: AsRef<Path>,
+{
+    let crls = load_pem_file(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::Crl(crl) => Some(crl),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if crls.is_empty() {
+        return Error::e_explain(ErrorType::InvalidCert, "No CRL found in pem file");
+    }
+    Ok(crls)
+}
+
 /// Attempt to load the native cas into the given root-certificate store
 pub fn load_platform_certs_incl_env_into_store(ca_certs: &mut RootCertStore) -> Result<()> {
     // this includes handling of ENV vars SSL_CERT_FILE & SSL_CERT_DIR
@@ -135,6 +169,22 @@ pub fn load_certs_and_key_files<'a>(
     }
 }
 
+/// Load the certificates and private key files into a [CertifiedKey] which can be served by a
+/// [ResolvesServerCert]
+pub fn load_certified_key(cert: &str, key: &str) -> Result<CertifiedKey> {
+    let Some((certs, key)) = load_certs_and_key_files(cert, key)? else {
+        return Error::e_explain(
+            ErrorType::InvalidCert,
+            "No certificate or private key found in the provided files",
+        );
+    };
+    // the builder installs the process default provider according to the crate features
+    let provider = ServerConfig::builder().crypto_provider().clone();
+    CertifiedKey::from_der(certs, key, &provider).explain_err(ErrorType::InvalidCert, |e| {
+        format!("Invalid certified key: {e}")
+    })
+}
+
 /// Load the certificate
 pub fn load_pem_file_ca(path: &String) -> Result<Vec<u8>> {
     let mut reader = load_file(path)?;
@@ -167,3 +217,96 @@ pub fn hash_certificate(cert: &CertificateDer) -> Vec<u8> {
     let hash = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
     hash.as_ref().to_vec()
 }
+
+/// The length of a session ticket key: a 16-byte key name, a 32-byte HMAC secret and a 32-byte
+/// AES-256 key, in this order
+pub const TICKET_KEY_LEN: usize = 80;
+
+const TICKET_KEY_NAME_LEN: usize = 16;
+
+type TicketKeyLookup = Box<dyn Fn(Option<&[u8]>) -> Option<[u8; TICKET_KEY_LEN]> + Send + Sync>;
+
+/// Create a [ProducesTickets] that encrypts session tickets with the keys given by `lookup`
+///
+/// `lookup(None)` returns the key to encrypt new tickets with, `lookup(Some(name))` the key with
+/// the given name to decrypt a ticket with. Tickets are encrypted with AES-256-GCM under the AES
+/// key, the HMAC secret is not used. Clients are told that tickets are valid for `lifetime`
+/// seconds.
+pub fn ticketer<F>(lookup: F, lifetime: u32) -> Arc<dyn ProducesTickets>
+where
+    F: Fn(Option<&[u8]>) -> Option<[u8; TICKET_KEY_LEN]> + Send + Sync + 'static,
+{
+    Arc::new(Ticketer {
+        lookup: Box::new(lookup),
+        lifetime,
+    })
+}
+
+struct Ticketer {
+    lookup: TicketKeyLookup,
+    lifetime: u32,
+}
+
+impl fmt::Debug for Ticketer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ticketer")
+            .field("lifetime", &self.lifetime)
+            .finish_non_exhaustive()
+    }
+}
+
+fn ticket_aead_key(key: &[u8; TICKET_KEY_LEN]) -> Option<LessSafeKey> {
+    let aes_key = &key[TICKET_KEY_LEN - 32..];
+    UnboundKey::new(&AES_256_GCM, aes_key)
+        .ok()
+        .map(LessSafeKey::new)
+}
+
+// A ticket is the key name, the nonce and then the encrypted session with its tag
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let key = (self.lookup)(None)?;
+        let name = &key[..TICKET_KEY_NAME_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        ticket_aead_key(&key)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name),
+                &mut sealed,
+            )
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(TICKET_KEY_NAME_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(name);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < TICKET_KEY_NAME_LEN + NONCE_LEN {
+            return None;
+        }
+        let (name, rest) = ticket.split_at(TICKET_KEY_NAME_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let key = (self.lookup)(Some(name))?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut plain = sealed.to_vec();
+        let len = ticket_aead_key(&key)?
+            .open_in_place(nonce, Aad::from(name), &mut plain)
+            .ok()?
+            .len();
+        plain.truncate(len);
+        Some(plain)
+    }
+}